@@ -7,6 +7,7 @@ use std::io;
 
 type MessageSize = u16;
 pub type WireError = Error;
+pub use bincode::ErrorKind as WireErrorKind;
 
 /// LengthSDOptions is the delegate that overrides
 /// serialization/deserialization of the length of some sequence
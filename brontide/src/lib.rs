@@ -2,7 +2,7 @@
 #![allow(non_shorthand_field_patterns)]
 
 mod machine;
-pub use self::machine::{Machine, HandshakeError, BrontideStream};
+pub use self::machine::{Machine, HandshakeError, BrontideStream, TowerCodec};
 
 // brontide reexport the type in order to reduce dependencies
 pub use binformat::WireError;
@@ -9,6 +9,7 @@ use secp256k1::{PublicKey, SecretKey};
 use std::time::Duration;
 
 use super::handshake::{Machine, HandshakeIn, HandshakeOut, HandshakeError};
+use super::serde_m::TowerCodec;
 
 pub struct BrontideStream<T>
 where
@@ -121,6 +122,11 @@ where
     pub fn framed(self) -> Framed<T, Machine> {
         self.noise.framed(self.stream)
     }
+
+    // the watchtower protocol
+    pub fn framed_tower(self) -> Framed<T, TowerCodec> {
+        TowerCodec(self.noise).framed(self.stream)
+    }
 }
 
 impl<T> AsRef<T> for BrontideStream<T>
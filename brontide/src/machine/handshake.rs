@@ -632,6 +632,7 @@ impl HandshakeOutActTwo {
 
 use bytes::BytesMut;
 use binformat::{BinarySD, WireError};
+use wire::{Message, TowerMessage};
 use serde::{Serialize, de::DeserializeOwned};
 use std::io::Write;
 
@@ -721,6 +722,54 @@ impl Machine {
     where
         T: DeserializeOwned,
     {
+        match self.read_frame(src)? {
+            Some(length) => self.deserialize_frame(length).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the message skipping the unknown messages of odd type,
+    /// the unknown message of even type is an error, see BOLT #1 "it's ok to be odd"
+    pub fn read_message(&mut self, src: &mut BytesMut) -> Result<Option<(Message, Vec<u8>)>, WireError> {
+        self.read_known(src, Message::is_known_type)
+    }
+
+    /// Reads the message of the watchtower protocol, the same rules as `read_message`
+    pub fn read_tower_message(&mut self, src: &mut BytesMut) -> Result<Option<(TowerMessage, Vec<u8>)>, WireError> {
+        self.read_known(src, TowerMessage::is_known_type)
+    }
+
+    fn read_known<T>(&mut self, src: &mut BytesMut, is_known_type: fn(u16) -> bool) -> Result<Option<(T, Vec<u8>)>, WireError>
+    where
+        T: DeserializeOwned,
+    {
+        use serde::ser::Error;
+
+        loop {
+            let length = match self.read_frame(src)? {
+                Some(length) => length,
+                None => return Ok(None),
+            };
+            let message_type = {
+                let buffer = self.message_buffer.read().unwrap();
+                if length < 2 {
+                    return Err(WireError::custom("the message is too short to contain its type"));
+                }
+                u16::from_be_bytes([buffer[0], buffer[1]])
+            };
+            if is_known_type(message_type) {
+                return self.deserialize_frame(length).map(Some);
+            } else if message_type % 2 == 1 {
+                println!("WARNING: skipped unknown message of odd type {}", message_type);
+            } else {
+                let description = format!("unknown message of even type {}", message_type);
+                return Err(WireError::custom(description));
+            }
+        }
+    }
+
+    // decrypts the next message into the buffer, returns its length
+    fn read_frame(&mut self, src: &mut BytesMut) -> Result<Option<usize>, WireError> {
         use chacha20_poly1305_aead::DecryptError;
         use serde::ser::Error;
 
@@ -773,22 +822,25 @@ impl Machine {
                         },
                     })?;
 
-                let buffer = self.message_buffer.read().unwrap();
-                let mut cursor = io::Cursor::new(buffer.as_ref());
-                BinarySD::deserialize(&mut cursor)
-                    .map(|m| {
-                        let read = cursor.position() as usize;
-                        let extra_size = length - read;
-                        let mut extra_data = Vec::with_capacity(extra_size);
-                        extra_data.resize(extra_size, 0);
-                        extra_data.as_mut_slice().copy_from_slice(&cursor.into_inner()[read..length]);
-                        (m, extra_data)
-                    })
-                    .map(Some)
+                Ok(Some(length))
             }
         }
     }
 
+    fn deserialize_frame<T>(&self, length: usize) -> Result<(T, Vec<u8>), WireError>
+    where
+        T: DeserializeOwned,
+    {
+        let buffer = self.message_buffer.read().unwrap();
+        let mut cursor = io::Cursor::new(&buffer[..length]);
+        BinarySD::deserialize(&mut cursor)
+            .map(|m| {
+                let read = cursor.position() as usize;
+                let extra_data = cursor.into_inner()[read..length].to_vec();
+                (m, extra_data)
+            })
+    }
+
     #[cfg(test)]
     pub fn send_cipher_key(&self) -> [u8; 32] {
         self.send_cipher.secret_key()
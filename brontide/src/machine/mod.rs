@@ -9,3 +9,4 @@ mod test_bolt0008;
 
 pub use self::brontide_stream::BrontideStream;
 pub use self::handshake::{HandshakeError, Machine};
+pub use self::serde_m::TowerCodec;
@@ -6,14 +6,15 @@ use super::handshake::Machine;
 use tokio::codec::{Encoder, Decoder};
 use bytes::BytesMut;
 use binformat::WireError;
-use wire::MessageExt;
+use wire::{MessageExt, TowerMessage};
 
 impl Encoder for Machine {
     type Item = MessageExt;
     type Error = WireError;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.write(item.message, item.extra_data, dst)
+        let (message, extra_data) = item.into_raw_parts();
+        self.write(message, extra_data, dst)
     }
 }
 
@@ -22,12 +23,30 @@ impl Decoder for Machine {
     type Error = WireError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.read(src)
-            .map(|v|
-                v.map(|(message, extra_data)| MessageExt {
-                    message: message,
-                    extra_data: extra_data,
-                })
-            )
+        self.read_message(src)
+            .map(|v| v.map(|(message, extra_data)| MessageExt::new(message, extra_data)))
+    }
+}
+
+/// The codec of the watchtower protocol over the same transport
+pub struct TowerCodec(pub(crate) Machine);
+
+impl Encoder for TowerCodec {
+    type Item = TowerMessage;
+    type Error = WireError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.write(item, Vec::new(), dst)
+    }
+}
+
+impl Decoder for TowerCodec {
+    type Item = TowerMessage;
+    type Error = WireError;
+
+    // the tower messages have no optional fields, the extra data is dropped
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.0.read_tower_message(src)
+            .map(|v| v.map(|(message, _)| message))
     }
 }
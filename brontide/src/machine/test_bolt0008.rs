@@ -161,3 +161,38 @@ fn test_bolt0008_internal() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_unknown_message_types() {
+    test_unknown_message_types_internal().unwrap();
+}
+
+fn test_unknown_message_types_internal() -> Result<(), Box<dyn Error>> {
+    use bytes::BytesMut;
+    use wire::{Message, Ping};
+
+    let rs_priv = SecretKey::from_slice(&[0x21; 32])?;
+    let rs_pub = PublicKey::from_secret_key(&Secp256k1::new(), &rs_priv);
+    let ls_priv = SecretKey::from_slice(&[0x11; 32])?;
+
+    let (act_one, machine) = HandshakeOut::new(ls_priv, rs_pub)?.gen_act_one()?;
+    let (act_two, responder_machine) = HandshakeIn::new(rs_priv)?.receive_act_one(act_one)?.gen_act_two()?;
+    let (act_three, mut machine) = machine.receive_act_two(act_two)?.gen_act_three()?;
+    let mut responder_machine = responder_machine.receive_act_three(act_three)?;
+
+    // the unknown odd message is skipped, the next one is read
+    let ping = Message::Ping(Ping::new(4, 8).unwrap());
+    let mut buffer = BytesMut::with_capacity(0x100);
+    machine.write((0x8001u16, 1u8, 2u8), Vec::new(), &mut buffer)?;
+    machine.write(ping.clone(), Vec::new(), &mut buffer)?;
+    let (message, extra_data) = responder_machine.read_message(&mut buffer)?.unwrap();
+    assert_eq!(message, ping);
+    assert!(extra_data.is_empty());
+
+    // the unknown even message is an error
+    let mut buffer = BytesMut::with_capacity(0x100);
+    machine.write((0x8000u16, 1u8, 2u8), Vec::new(), &mut buffer)?;
+    assert!(responder_machine.read_message(&mut buffer).is_err());
+
+    Ok(())
+}
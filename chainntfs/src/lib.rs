@@ -3,6 +3,7 @@ use dependencies::futures;
 use dependencies::tokio_core;
 use dependencies::bitcoin;
 use dependencies::bitcoin_hashes;
+use dependencies::bitcoin_rpc_client;
 
 use bitcoin::{
     consensus::deserialize,
@@ -10,6 +11,7 @@ use bitcoin::{
 };
 use bitcoin_hashes::sha256d;
 use futures::{Poll, Async, Stream};
+use bitcoin_rpc_client::{Client, RpcApi, Auth};
 
 use std::sync::mpsc::{self, Sender, Receiver};
 
@@ -62,6 +64,25 @@ impl ZMQMessageProducer {
         assert!(socket.connect(DEFAULT_ZMQ_ADDR).is_ok());
         Self { socket }
     }
+
+    // blocks until bitcoind sends the next message
+    pub fn recv(&mut self) -> ZMQMessage {
+        let msg_type = ZMQMessageType::from(self.socket.recv_string(0).unwrap().unwrap().as_str());
+        let bytes = self.socket.recv_bytes(0).unwrap();
+        let msg = match msg_type {
+            ZMQMessageType::RawBlock => {
+                let block: Block = deserialize(&bytes).unwrap();
+                ZMQMessage::Block(block)
+
+            },
+            ZMQMessageType::RawTx => {
+                let tx: Transaction = deserialize(&bytes).unwrap();
+                ZMQMessage::Tx(tx)
+            },
+        };
+        self.socket.recv_string(0).unwrap().unwrap().as_str();
+        msg
+    }
 }
 
 impl Stream for ZMQMessageProducer {
@@ -75,27 +96,51 @@ impl Stream for ZMQMessageProducer {
                 futures::task::current().notify();
                 Ok(Async::NotReady)
             },
-            _ => {
-                let msg_type = ZMQMessageType::from(self.socket.recv_string(0).unwrap().unwrap().as_str());
-                let bytes = self.socket.recv_bytes(0).unwrap();
-                let msg = match msg_type {
-                    ZMQMessageType::RawBlock => {
-                        let block: Block = deserialize(&bytes).unwrap();
-                        ZMQMessage::Block(block)
-
-                    },
-                    ZMQMessageType::RawTx => {
-                        let tx: Transaction = deserialize(&bytes).unwrap();
-                        ZMQMessage::Tx(tx)
-                    },
-                };
-                self.socket.recv_string(0).unwrap().unwrap().as_str();
-                Ok(Async::Ready(Some(msg)))
-            }
+            _ => Ok(Async::Ready(Some(self.recv()))),
         }
     }
 }
 
+/// Reads bitcoind's best chain, zmq tells only about the new tip,
+/// so the blocks missed while the node was down and the reorganized blocks are read there
+pub struct BlockSource {
+    client: Client,
+}
+
+impl BlockSource {
+    pub fn new() -> Result<Self, String> {
+        let auth = Auth::UserPass(DEFAULT_RPC_USER.to_owned(), DEFAULT_RPC_PASS.to_owned());
+        Client::new(DEFAULT_RPC_ADDR.to_owned(), auth)
+            .map(|client| BlockSource { client: client })
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    pub fn best_height(&self) -> Result<u32, String> {
+        self.client.get_block_count()
+            .map(|count| count as u32)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    pub fn hash_at(&self, height: u32) -> Result<sha256d::Hash, String> {
+        self.client.get_block_hash(height as u64)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    pub fn block(&self, hash: &sha256d::Hash) -> Result<Block, String> {
+        self.client.get_block(hash)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    /// bitcoind's `estimatesmartfee` in satoshi per kilo vbyte for the confirmation
+    /// within the number of blocks, `None` while bitcoind has not seen enough blocks
+    pub fn estimate_fee(&self, num_blocks: u32) -> Result<Option<u64>, String> {
+        let result: serde_json::Value = self.client.call("estimatesmartfee", &[num_blocks.into()])
+            .map_err(|e| format!("{:?}", e))?;
+        // in bitcoin per kilo vbyte
+        Ok(result.get("feerate").and_then(serde_json::Value::as_f64).map(|rate| (rate * 100_000_000.0) as u64))
+    }
+}
+
 #[derive(Debug)]
 pub enum ConfirmationEvent {
     Mempool(ConfirmationEventMempool),
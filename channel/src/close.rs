@@ -0,0 +1,770 @@
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+use dependencies::secp256k1;
+
+use secp256k1::{PublicKey, SecretKey, Signature, Secp256k1, Message};
+use bitcoin::OutPoint;
+use bitcoin_hashes::{sha256d, Hash};
+use bitcoin::blockdata::script::{Script};
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+use bitcoin::util::bip143;
+use super::bip69;
+use super::tools::{new_2x2_multisig, spending_witness_2x2_multisig, to_local_script, to_remote_anchor_script, p2pkh};
+
+// the witness spending the funding output: the segwit marker and flag, the number of items,
+// the empty item, two signatures and the 2x2 multisig script
+pub const CLOSING_WITNESS_WEIGHT: u64 = 2 + 1 + 1 + 2 * (1 + 73) + 1 + 71;
+
+/// The mutual close transaction, spends the funding output to the scripts
+/// from the `shutdown` messages, the funder pays the fee
+#[derive(Clone, Debug)]
+pub struct ClosingTx {
+    pub funding_amount: u64,
+    pub local_funding_pubkey: PublicKey,
+    pub remote_funding_pubkey: PublicKey,
+
+    pub funding_tx_id: sha256d::Hash,
+    pub funding_output_index: u32,
+
+    // each output is trimmed against the dust limit of its owner
+    pub local_dust_limit_satoshi: u64,
+    pub remote_dust_limit_satoshi: u64,
+
+    pub local_script: Script,
+    pub remote_script: Script,
+
+    // the balances before the fee is paid
+    pub to_local_msat: u64,
+    pub to_remote_msat: u64,
+
+    pub local_is_funder: bool,
+    pub fee: u64,
+}
+
+impl ClosingTx {
+    pub fn get_tx(&self) -> Transaction {
+        let mut tx = Transaction{
+            version: 2,
+            input: vec![TxIn{
+                previous_output: OutPoint{
+                    txid: self.funding_tx_id,
+                    vout: self.funding_output_index
+                },
+                sequence: 0xffffffff,
+                script_sig: Script::new(),
+                witness: vec![]
+            }],
+            output: vec![
+            ],
+            lock_time: 0
+        };
+
+        let mut to_local = self.to_local_msat / 1000;
+        let mut to_remote = self.to_remote_msat / 1000;
+        if self.local_is_funder {
+            to_local = to_local.saturating_sub(self.fee);
+        } else {
+            to_remote = to_remote.saturating_sub(self.fee);
+        }
+
+        if to_local >= self.local_dust_limit_satoshi {
+            tx.output.push(TxOut{
+                value: to_local,
+                script_pubkey: self.local_script.clone(),
+            });
+        }
+
+        if to_remote >= self.remote_dust_limit_satoshi {
+            tx.output.push(TxOut{
+                value: to_remote,
+                script_pubkey: self.remote_script.clone(),
+            });
+        }
+
+        bip69::reorder_tx(&mut tx);
+
+        tx
+    }
+
+    // the weight of the signed transaction, the fee is estimated from it
+    pub fn weight(&self) -> u64 {
+        self.get_tx().get_weight() as u64 + CLOSING_WITNESS_WEIGHT
+    }
+
+    fn sighash(&self) -> Message {
+        let tx = self.get_tx();
+
+        let funding_lock_script = new_2x2_multisig(
+            &self.local_funding_pubkey.serialize(),
+            &self.remote_funding_pubkey.serialize()
+        );
+        let tx_sig_hash = bip143::SighashComponents::new(&tx)
+            .sighash_all(
+                &tx.input[0],
+                &funding_lock_script,
+                self.funding_amount
+            );
+        Message::from_slice(&tx_sig_hash.into_inner()[..]).unwrap()
+    }
+
+    pub fn sign(&self, priv_key: &SecretKey) -> Signature {
+        let sec = Secp256k1::new();
+        sec.sign(&self.sighash(), priv_key)
+    }
+
+    pub fn verify(&self, sig: &Signature, pub_key: &PublicKey) -> bool {
+        let sec = Secp256k1::verification_only();
+        sec.verify(&self.sighash(), sig, pub_key).is_ok()
+    }
+
+    // the transaction ready to broadcast
+    pub fn signed_tx(&self, local_sig: &Signature, remote_sig: &Signature) -> Transaction {
+        let mut tx = self.get_tx();
+        tx.input[0].witness = spending_witness_2x2_multisig(
+            &self.local_funding_pubkey,
+            &self.remote_funding_pubkey,
+            local_sig,
+            remote_sig,
+        );
+        tx
+    }
+}
+
+/// Spends our `to_local` output of the commitment transaction
+/// to the wallet after `to_self_delay` blocks, used when the channel is force closed
+#[derive(Clone, Debug)]
+pub struct DelayedSweepTx {
+    pub commitment_tx_id: sha256d::Hash,
+    pub output_index: u32,
+    // the value of the spent output
+    pub amount: u64,
+
+    pub local_delayedpubkey: PublicKey,
+    pub local_revocation_pubkey: PublicKey,
+    pub to_self_delay: u64,
+
+    pub destination: Script,
+    pub fee: u64,
+}
+
+impl DelayedSweepTx {
+    pub fn witness_script(&self) -> Script {
+        to_local_script(&self.local_delayedpubkey, self.to_self_delay, &self.local_revocation_pubkey)
+    }
+
+    pub fn get_tx(&self) -> Transaction {
+        Transaction{
+            version: 2,
+            input: vec![TxIn{
+                previous_output: OutPoint{
+                    txid: self.commitment_tx_id,
+                    vout: self.output_index
+                },
+                // relative lock time of the `OP_CSV` branch
+                sequence: self.to_self_delay as u32,
+                script_sig: Script::new(),
+                witness: vec![]
+            }],
+            output: vec![TxOut{
+                value: self.amount.saturating_sub(self.fee),
+                script_pubkey: self.destination.clone(),
+            }],
+            lock_time: 0
+        }
+    }
+
+    // the weight of the signed transaction: the segwit marker and flag, the number of items,
+    // the signature, the empty item choosing the delayed branch and the script
+    pub fn weight(&self) -> u64 {
+        let script_len = self.witness_script().len() as u64;
+        self.get_tx().get_weight() as u64 + 2 + 1 + (1 + 73) + 1 + (1 + script_len)
+    }
+
+    fn sighash(&self) -> Message {
+        let tx = self.get_tx();
+        let tx_sig_hash = bip143::SighashComponents::new(&tx)
+            .sighash_all(
+                &tx.input[0],
+                &self.witness_script(),
+                self.amount
+            );
+        Message::from_slice(&tx_sig_hash.into_inner()[..]).unwrap()
+    }
+
+    // signed by the key derived from the delayed payment basepoint
+    pub fn sign(&self, priv_key: &SecretKey) -> Signature {
+        let sec = Secp256k1::new();
+        sec.sign(&self.sighash(), priv_key)
+    }
+
+    pub fn verify(&self, sig: &Signature, pub_key: &PublicKey) -> bool {
+        let sec = Secp256k1::verification_only();
+        sec.verify(&self.sighash(), sig, pub_key).is_ok()
+    }
+
+    pub fn signed_tx(&self, sig: &Signature) -> Transaction {
+        let mut sig_ser = sig.serialize_der().as_ref().to_vec();
+        sig_ser.push(1);
+
+        let mut tx = self.get_tx();
+        tx.input[0].witness = vec![sig_ser, vec![], self.witness_script().as_bytes().to_vec()];
+        tx
+    }
+}
+
+/// Spends our `to_remote` output of the peer's commitment, it is P2WPKH
+/// to the key derived from our payment basepoint and the peer's per-commitment point,
+/// or P2WSH delayed by one block with anchors
+#[derive(Clone, Debug)]
+pub struct ToRemoteSweepTx {
+    pub commitment_tx_id: sha256d::Hash,
+    pub output_index: u32,
+    // the value of the spent output
+    pub amount: u64,
+
+    pub remotepubkey: PublicKey,
+    pub anchors: bool,
+
+    pub destination: Script,
+    pub fee: u64,
+}
+
+impl ToRemoteSweepTx {
+    // BIP 143: the script code of P2WPKH is the P2PKH script of the key
+    fn script_code(&self) -> Script {
+        if self.anchors {
+            to_remote_anchor_script(&self.remotepubkey)
+        } else {
+            p2pkh(&self.remotepubkey)
+        }
+    }
+
+    pub fn get_tx(&self) -> Transaction {
+        Transaction{
+            version: 2,
+            input: vec![TxIn{
+                previous_output: OutPoint{
+                    txid: self.commitment_tx_id,
+                    vout: self.output_index
+                },
+                // the `1 OP_CHECKSEQUENCEVERIFY` of the anchor commitment
+                sequence: if self.anchors { 1 } else { 0xffffffff },
+                script_sig: Script::new(),
+                witness: vec![]
+            }],
+            output: vec![TxOut{
+                value: self.amount.saturating_sub(self.fee),
+                script_pubkey: self.destination.clone(),
+            }],
+            lock_time: 0
+        }
+    }
+
+    // the weight of the signed transaction: the segwit marker and flag, the number of items,
+    // the signature and the public key or the script
+    pub fn weight(&self) -> u64 {
+        let last = if self.anchors { self.script_code().len() as u64 } else { 33 };
+        self.get_tx().get_weight() as u64 + 2 + 1 + (1 + 73) + (1 + last)
+    }
+
+    fn sighash(&self) -> Message {
+        let tx = self.get_tx();
+        let tx_sig_hash = bip143::SighashComponents::new(&tx)
+            .sighash_all(
+                &tx.input[0],
+                &self.script_code(),
+                self.amount
+            );
+        Message::from_slice(&tx_sig_hash.into_inner()[..]).unwrap()
+    }
+
+    // signed by the key derived from the payment basepoint
+    pub fn sign(&self, priv_key: &SecretKey) -> Signature {
+        let sec = Secp256k1::new();
+        sec.sign(&self.sighash(), priv_key)
+    }
+
+    pub fn verify(&self, sig: &Signature, pub_key: &PublicKey) -> bool {
+        let sec = Secp256k1::verification_only();
+        sec.verify(&self.sighash(), sig, pub_key).is_ok()
+    }
+
+    pub fn signed_tx(&self, sig: &Signature) -> Transaction {
+        let mut sig_ser = sig.serialize_der().as_ref().to_vec();
+        sig_ser.push(1);
+
+        let last = if self.anchors {
+            self.script_code().as_bytes().to_vec()
+        } else {
+            self.remotepubkey.serialize().to_vec()
+        };
+        let mut tx = self.get_tx();
+        tx.input[0].witness = vec![sig_ser, last];
+        tx
+    }
+}
+
+/// The output of the peer's revoked commitment, the revocation key spends
+/// the peer's outputs immediately, our own output needs no revocation
+#[derive(Clone, Debug)]
+pub enum RevokedOutput {
+    // the peer's `to_local`, spent by the revocation branch of the script
+    ToLocal(Script),
+    // offered or accepted, the script starts with the revocation check
+    Htlc(Script),
+    // our P2WPKH output, the key derived from our payment basepoint
+    ToRemote(PublicKey),
+    // our output of the anchor commitment, P2WSH delayed by one block
+    ToRemoteAnchor(PublicKey),
+}
+
+#[derive(Clone, Debug)]
+pub struct JusticeInput {
+    pub output_index: u32,
+    // the value of the spent output
+    pub amount: u64,
+    pub output: RevokedOutput,
+}
+
+/// Spends the outputs of the peer's revoked commitment, or the output of the peer's
+/// HTLC transaction spending it, to the wallet
+#[derive(Clone, Debug)]
+pub struct JusticeTx {
+    // the revoked commitment or the peer's HTLC transaction
+    pub commitment_tx_id: sha256d::Hash,
+    pub inputs: Vec<JusticeInput>,
+
+    pub revocationpubkey: PublicKey,
+
+    pub destination: Script,
+    pub fee: u64,
+}
+
+impl JusticeTx {
+    pub fn amount(&self) -> u64 {
+        self.inputs.iter().map(|i| i.amount).sum()
+    }
+
+    pub fn get_tx(&self) -> Transaction {
+        Transaction{
+            version: 2,
+            input: self.inputs.iter()
+                .map(|i| TxIn{
+                    previous_output: OutPoint{
+                        txid: self.commitment_tx_id,
+                        vout: i.output_index
+                    },
+                    sequence: match &i.output {
+                        &RevokedOutput::ToRemoteAnchor(_) => 1,
+                        _ => 0xffffffff,
+                    },
+                    script_sig: Script::new(),
+                    witness: vec![]
+                })
+                .collect(),
+            output: vec![TxOut{
+                value: self.amount().saturating_sub(self.fee),
+                script_pubkey: self.destination.clone(),
+            }],
+            lock_time: 0
+        }
+    }
+
+    // the weight of the signed transaction: the segwit marker and flag, then for each input
+    // the number of items, the signature and either the branch selector and the script,
+    // the revocation key and the script or the public key
+    pub fn weight(&self) -> u64 {
+        let witnesses = self.inputs.iter()
+            .map(|i| 1 + (1 + 73) + match &i.output {
+                &RevokedOutput::ToLocal(ref script) => 1 + 1 + (1 + script.len() as u64),
+                &RevokedOutput::Htlc(ref script) => (1 + 33) + (1 + script.len() as u64),
+                &RevokedOutput::ToRemote(_) => 1 + 33,
+                &RevokedOutput::ToRemoteAnchor(ref remotepubkey) => 1 + to_remote_anchor_script(remotepubkey).len() as u64,
+            })
+            .sum::<u64>();
+        self.get_tx().get_weight() as u64 + 2 + witnesses
+    }
+
+    fn script_code(output: &RevokedOutput) -> Script {
+        match output {
+            &RevokedOutput::ToLocal(ref script) => script.clone(),
+            &RevokedOutput::Htlc(ref script) => script.clone(),
+            &RevokedOutput::ToRemote(ref remotepubkey) => p2pkh(remotepubkey),
+            &RevokedOutput::ToRemoteAnchor(ref remotepubkey) => to_remote_anchor_script(remotepubkey),
+        }
+    }
+
+    fn sighash(&self, index: usize) -> Message {
+        let tx = self.get_tx();
+        let input = &self.inputs[index];
+        let tx_sig_hash = bip143::SighashComponents::new(&tx)
+            .sighash_all(
+                &tx.input[index],
+                &Self::script_code(&input.output),
+                input.amount
+            );
+        Message::from_slice(&tx_sig_hash.into_inner()[..]).unwrap()
+    }
+
+    // a signature per input, the revocation key signs the peer's outputs,
+    // the key derived from the payment basepoint signs ours
+    pub fn sign(&self, revocation_key: &SecretKey, payment_key: &SecretKey) -> Vec<Signature> {
+        let sec = Secp256k1::new();
+        self.inputs.iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let priv_key = match &input.output {
+                    &RevokedOutput::ToRemote(_) | &RevokedOutput::ToRemoteAnchor(_) => payment_key,
+                    _ => revocation_key,
+                };
+                sec.sign(&self.sighash(index), priv_key)
+            })
+            .collect()
+    }
+
+    pub fn verify(&self, index: usize, sig: &Signature, pub_key: &PublicKey) -> bool {
+        let sec = Secp256k1::verification_only();
+        sec.verify(&self.sighash(index), sig, pub_key).is_ok()
+    }
+
+    pub fn signed_tx(&self, sigs: &[Signature]) -> Transaction {
+        let mut tx = self.get_tx();
+        for (index, (input, sig)) in self.inputs.iter().zip(sigs.iter()).enumerate() {
+            let mut sig_ser = sig.serialize_der().as_ref().to_vec();
+            sig_ser.push(1);
+
+            tx.input[index].witness = match &input.output {
+                &RevokedOutput::ToLocal(ref script) =>
+                    vec![sig_ser, vec![1], script.as_bytes().to_vec()],
+                &RevokedOutput::Htlc(ref script) =>
+                    vec![sig_ser, self.revocationpubkey.serialize().to_vec(), script.as_bytes().to_vec()],
+                &RevokedOutput::ToRemote(ref remotepubkey) =>
+                    vec![sig_ser, remotepubkey.serialize().to_vec()],
+                &RevokedOutput::ToRemoteAnchor(ref remotepubkey) =>
+                    vec![sig_ser, to_remote_anchor_script(remotepubkey).as_bytes().to_vec()],
+            };
+        }
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::spec_example::get_example;
+    use super::super::tools::{v0_p2wpkh, s2script, to_local_script, to_remote_anchor_script};
+    use super::super::commit::CommitTx;
+    use super::super::derivation::derive_revocation_privkey;
+    use super::super::commit::{HTLC, HTLCDirection};
+    use super::super::tools::offered_htlc;
+    use super::{ClosingTx, DelayedSweepTx, ToRemoteSweepTx, JusticeTx, JusticeInput, RevokedOutput};
+
+    fn example_closing_tx(to_local_msat: u64, fee: u64) -> ClosingTx {
+        let ex = get_example();
+
+        ClosingTx{
+            funding_amount: ex.funding_amount_satoshi as u64,
+            local_funding_pubkey: ex.local_funding_pubkey,
+            remote_funding_pubkey: ex.remote_funding_pubkey,
+
+            funding_tx_id: ex.funding_tx_id,
+            funding_output_index: ex.funding_output_index as u32,
+
+            local_dust_limit_satoshi: ex.local_dust_limit_satoshi as u64,
+            remote_dust_limit_satoshi: ex.local_dust_limit_satoshi as u64,
+
+            local_script: v0_p2wpkh(&ex.localpubkey),
+            remote_script: s2script("a914f9b59ffc2ad87d1fa76b4d04e3e22ee7c4ef8cf887"),
+
+            to_local_msat: to_local_msat,
+            to_remote_msat: (ex.funding_amount_satoshi as u64) * 1000 - to_local_msat,
+
+            local_is_funder: true,
+            fee: fee,
+        }
+    }
+
+    #[test]
+    fn test_closing_tx_funder_pays_fee() {
+        let closing_tx = example_closing_tx(7000000000, 1000);
+        let tx = closing_tx.get_tx();
+
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].sequence, 0xffffffff);
+        assert_eq!(tx.lock_time, 0);
+        assert_eq!(tx.output.len(), 2);
+        let local = tx.output.iter().find(|o| o.script_pubkey == closing_tx.local_script).unwrap();
+        let remote = tx.output.iter().find(|o| o.script_pubkey == closing_tx.remote_script).unwrap();
+        assert_eq!(local.value, 7000000 - 1000);
+        assert_eq!(remote.value, 3000000);
+        // bip69 order
+        assert!(tx.output[0].value <= tx.output[1].value);
+    }
+
+    #[test]
+    fn test_closing_tx_omits_dust() {
+        let closing_tx = example_closing_tx(10000000000 - 545000, 1000);
+        let tx = closing_tx.get_tx();
+
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].script_pubkey, closing_tx.local_script);
+        assert_eq!(tx.output[0].value, 10000000 - 545 - 1000);
+    }
+
+    #[test]
+    fn test_closing_tx_trims_against_owner_dust_limit() {
+        // the remote output is above its owner's dust limit, but below ours
+        let mut closing_tx = example_closing_tx(10000000000 - 600000, 1000);
+        closing_tx.local_dust_limit_satoshi = 1000;
+        closing_tx.remote_dust_limit_satoshi = 546;
+        let tx = closing_tx.get_tx();
+        assert_eq!(tx.output.len(), 2);
+        let remote = tx.output.iter().find(|o| o.script_pubkey == closing_tx.remote_script).unwrap();
+        assert_eq!(remote.value, 600);
+
+        closing_tx.remote_dust_limit_satoshi = 1000;
+        let tx = closing_tx.get_tx();
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].script_pubkey, closing_tx.local_script);
+    }
+
+    #[test]
+    fn test_closing_tx_signatures() {
+        let ex = get_example();
+        let closing_tx = example_closing_tx(7000000000, 1000);
+
+        let local_sig = closing_tx.sign(&ex.local_funding_privkey);
+        let remote_sig = closing_tx.sign(&ex.internal.remote_funding_privkey);
+        assert!(closing_tx.verify(&local_sig, &ex.local_funding_pubkey));
+        assert!(closing_tx.verify(&remote_sig, &ex.remote_funding_pubkey));
+        assert!(!closing_tx.verify(&local_sig, &ex.remote_funding_pubkey));
+
+        // the signature commits to the fee
+        let other_fee = example_closing_tx(7000000000, 2000);
+        assert!(!other_fee.verify(&local_sig, &ex.local_funding_pubkey));
+
+        let tx = closing_tx.signed_tx(&local_sig, &remote_sig);
+        assert_eq!(tx.input[0].witness.len(), 4);
+        assert!(tx.get_weight() as u64 <= closing_tx.weight());
+    }
+
+    #[test]
+    fn test_delayed_sweep_spends_to_local() {
+        let ex = get_example();
+
+        let commit_tx = CommitTx{
+            funding_amount: ex.funding_amount_satoshi,
+            local_funding_pubkey: ex.local_funding_pubkey,
+            remote_funding_pubkey: ex.remote_funding_pubkey,
+
+            local_feerate_per_kw: 15000,
+            dust_limit_satoshi: 546,
+
+            to_local_msat: 7000000000,
+            to_remote_msat: 3000000000,
+            obscured_commit_number: ex.obscuring_factor ^ ex.commitment_number,
+
+            local_htlc_pubkey: ex.localpubkey.clone(),
+            remote_htlc_pubkey: ex.remotepubkey.clone(),
+
+            local_revocation_pubkey: ex.local_revocation_pubkey.clone(),
+            local_delayedpubkey: ex.local_delayedpubkey.clone(),
+            local_delay: ex.local_delay as u64,
+
+            remotepubkey: ex.remotepubkey.clone(),
+
+            funding_tx_id: ex.funding_tx_id.clone(),
+            funding_output_index: ex.funding_output_index as u32,
+
+            htlcs: vec![],
+            local_is_funder: true,
+            anchors: false,
+        };
+        let tx = commit_tx.get_tx().unwrap();
+        let to_local = to_local_script(&ex.local_delayedpubkey, ex.local_delay as u64, &ex.local_revocation_pubkey).to_v0_p2wsh();
+        let output_index = tx.output.iter().position(|o| o.script_pubkey == to_local).unwrap();
+
+        let sweep_tx = DelayedSweepTx{
+            commitment_tx_id: tx.txid(),
+            output_index: output_index as u32,
+            amount: tx.output[output_index].value,
+
+            local_delayedpubkey: ex.local_delayedpubkey.clone(),
+            local_revocation_pubkey: ex.local_revocation_pubkey.clone(),
+            to_self_delay: ex.local_delay as u64,
+
+            destination: v0_p2wpkh(&ex.localpubkey),
+            fee: 1000,
+        };
+        assert_eq!(sweep_tx.witness_script().to_v0_p2wsh(), to_local);
+
+        let sweep = sweep_tx.get_tx();
+        assert_eq!(sweep.input[0].previous_output.txid, tx.txid());
+        assert_eq!(sweep.input[0].sequence, ex.local_delay as u32);
+        assert_eq!(sweep.output[0].value, tx.output[output_index].value - 1000);
+
+        let sig = sweep_tx.sign(&ex.internal.local_delayed_privkey);
+        assert!(sweep_tx.verify(&sig, &ex.local_delayedpubkey));
+        assert!(!sweep_tx.verify(&sig, &ex.local_revocation_pubkey));
+
+        let signed = sweep_tx.signed_tx(&sig);
+        assert_eq!(signed.input[0].witness.len(), 3);
+        assert!(signed.input[0].witness[1].is_empty());
+        assert!(signed.get_weight() as u64 <= sweep_tx.weight());
+    }
+
+    #[test]
+    fn test_to_remote_sweep_spends_p2wpkh() {
+        let ex = get_example();
+
+        let sweep_tx = ToRemoteSweepTx{
+            commitment_tx_id: ex.funding_tx_id.clone(),
+            output_index: 1,
+            amount: 3000000,
+
+            remotepubkey: ex.remotepubkey.clone(),
+            anchors: false,
+
+            destination: v0_p2wpkh(&ex.localpubkey),
+            fee: 1000,
+        };
+
+        let sweep = sweep_tx.get_tx();
+        assert_eq!(sweep.input[0].previous_output.vout, 1);
+        assert_eq!(sweep.output[0].value, 3000000 - 1000);
+
+        let sig = sweep_tx.sign(&ex.internal.remote_privkey);
+        assert!(sweep_tx.verify(&sig, &ex.remotepubkey));
+        assert!(!sweep_tx.verify(&sig, &ex.localpubkey));
+
+        let signed = sweep_tx.signed_tx(&sig);
+        assert_eq!(signed.input[0].witness.len(), 2);
+        assert_eq!(signed.input[0].witness[1], ex.remotepubkey.serialize().to_vec());
+        assert!(signed.get_weight() as u64 <= sweep_tx.weight());
+    }
+
+    #[test]
+    fn test_to_remote_sweep_of_anchor_commitment() {
+        let ex = get_example();
+
+        let sweep_tx = ToRemoteSweepTx{
+            commitment_tx_id: ex.funding_tx_id.clone(),
+            output_index: 1,
+            amount: 3000000,
+
+            remotepubkey: ex.remotepubkey.clone(),
+            anchors: true,
+
+            destination: v0_p2wpkh(&ex.localpubkey),
+            fee: 1000,
+        };
+
+        // the output is spendable one block after the commitment
+        let sweep = sweep_tx.get_tx();
+        assert_eq!(sweep.input[0].sequence, 1);
+
+        let sig = sweep_tx.sign(&ex.internal.remote_privkey);
+        assert!(sweep_tx.verify(&sig, &ex.remotepubkey));
+
+        let signed = sweep_tx.signed_tx(&sig);
+        assert_eq!(signed.input[0].witness.len(), 2);
+        assert_eq!(signed.input[0].witness[1], to_remote_anchor_script(&ex.remotepubkey).as_bytes().to_vec());
+        assert!(signed.get_weight() as u64 <= sweep_tx.weight());
+    }
+
+    #[test]
+    fn test_justice_tx_spends_revoked_outputs() {
+        let ex = get_example();
+
+        let payment_hash = [7u8; 32];
+        let commit_tx = CommitTx{
+            funding_amount: ex.funding_amount_satoshi,
+            local_funding_pubkey: ex.local_funding_pubkey,
+            remote_funding_pubkey: ex.remote_funding_pubkey,
+
+            local_feerate_per_kw: 0,
+            dust_limit_satoshi: 546,
+
+            to_local_msat: 6000000000,
+            to_remote_msat: 3000000000,
+            obscured_commit_number: ex.obscuring_factor ^ ex.commitment_number,
+
+            local_htlc_pubkey: ex.localpubkey.clone(),
+            remote_htlc_pubkey: ex.remotepubkey.clone(),
+
+            local_revocation_pubkey: ex.local_revocation_pubkey.clone(),
+            local_delayedpubkey: ex.local_delayedpubkey.clone(),
+            local_delay: ex.local_delay as u64,
+
+            remotepubkey: ex.remotepubkey.clone(),
+
+            funding_tx_id: ex.funding_tx_id.clone(),
+            funding_output_index: ex.funding_output_index as u32,
+
+            htlcs: vec![HTLC{
+                direction: HTLCDirection::Offered,
+                amount_msat: 1000000000,
+                expiry: 500,
+                payment_hash: payment_hash,
+            }],
+            local_is_funder: true,
+            anchors: false,
+        };
+        let tx = commit_tx.get_tx().unwrap();
+        assert_eq!(tx.output.len(), 3);
+
+        let to_local = to_local_script(&ex.local_delayedpubkey, ex.local_delay as u64, &ex.local_revocation_pubkey);
+        let htlc = offered_htlc(&ex.local_revocation_pubkey, &ex.remotepubkey, &ex.localpubkey, payment_hash);
+        let inputs = tx.output.iter()
+            .enumerate()
+            .map(|(index, o)| {
+                let output = if o.script_pubkey == to_local.to_v0_p2wsh() {
+                    RevokedOutput::ToLocal(to_local.clone())
+                } else if o.script_pubkey == htlc.to_v0_p2wsh() {
+                    RevokedOutput::Htlc(htlc.clone())
+                } else {
+                    assert_eq!(o.script_pubkey, v0_p2wpkh(&ex.remotepubkey));
+                    RevokedOutput::ToRemote(ex.remotepubkey.clone())
+                };
+                JusticeInput{ output_index: index as u32, amount: o.value, output: output }
+            })
+            .collect();
+
+        let justice_tx = JusticeTx{
+            commitment_tx_id: tx.txid(),
+            inputs: inputs,
+            revocationpubkey: ex.local_revocation_pubkey.clone(),
+            destination: v0_p2wpkh(&ex.localpubkey),
+            fee: 2000,
+        };
+
+        let justice = justice_tx.get_tx();
+        assert_eq!(justice.input.len(), 3);
+        assert!(justice.input.iter().all(|i| i.previous_output.txid == tx.txid() && i.sequence == 0xffffffff));
+        assert_eq!(justice.output[0].value, 10000000 - 2000);
+
+        let revocation_key = derive_revocation_privkey(
+            &ex.internal.remote_revocation_basepoint_secret,
+            &ex.internal.x_local_per_commitment_secret,
+        );
+        let sigs = justice_tx.sign(&revocation_key, &ex.internal.remote_privkey);
+        for (index, input) in justice_tx.inputs.iter().enumerate() {
+            match &input.output {
+                &RevokedOutput::ToRemote(_) => assert!(justice_tx.verify(index, &sigs[index], &ex.remotepubkey)),
+                _ => {
+                    assert!(justice_tx.verify(index, &sigs[index], &ex.local_revocation_pubkey));
+                    assert!(!justice_tx.verify(index, &sigs[index], &ex.local_delayedpubkey));
+                },
+            }
+        }
+
+        let signed = justice_tx.signed_tx(&sigs);
+        for (index, input) in justice_tx.inputs.iter().enumerate() {
+            match &input.output {
+                &RevokedOutput::ToLocal(_) => assert_eq!(signed.input[index].witness[1], vec![1]),
+                &RevokedOutput::Htlc(_) => assert_eq!(signed.input[index].witness[1], ex.local_revocation_pubkey.serialize().to_vec()),
+                &RevokedOutput::ToRemote(_) => assert_eq!(signed.input[index].witness.len(), 2),
+            }
+        }
+        assert!(signed.get_weight() as u64 <= justice_tx.weight());
+    }
+}
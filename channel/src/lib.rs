@@ -3,5 +3,6 @@
 pub mod bip69;
 pub mod tools;
 pub mod commit;
+pub mod close;
 pub mod spec_example;
 pub mod derivation;
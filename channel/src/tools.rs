@@ -205,7 +205,17 @@ pub fn get_obscuring_number(local_payment_basepoint: &[u8], remote_payment_basep
 //    OP_ENDIF
 //OP_ENDIF
 pub fn offered_htlc(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32]) -> Script {
-    let sc = Builder::new()
+    offered_htlc_script(revocationpubkey, remote_htlcpubkey, local_htlcpubkey, payment_hash, false)
+}
+
+// option_anchors: the same with `1 OP_CHECKSEQUENCEVERIFY OP_DROP` before the last `OP_ENDIF`,
+// the second stage transaction cannot be in the same block with the commitment
+pub fn offered_htlc_anchors(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32]) -> Script {
+    offered_htlc_script(revocationpubkey, remote_htlcpubkey, local_htlcpubkey, payment_hash, true)
+}
+
+fn offered_htlc_script(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32], anchors: bool) -> Script {
+    let builder = Builder::new()
         .push_opcode(OP_DUP)
         .push_opcode(OP_HASH160)
         .push_slice(&hash160::Hash::hash(&revocationpubkey.serialize()).into_inner()[..])
@@ -234,10 +244,10 @@ pub fn offered_htlc(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey,
                 .push_slice(&ripemd160::Hash::hash(&payment_hash).into_inner()[..])
                 .push_opcode(OP_EQUALVERIFY)
                 .push_opcode(OP_CHECKSIG)
-            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_ENDIF);
+    one_block_delay(builder, anchors)
         .push_opcode(OP_ENDIF)
-        .into_script();
-    return sc;
+        .into_script()
 }
 
 //# To remote node with revocation key
@@ -258,7 +268,16 @@ pub fn offered_htlc(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey,
 //    OP_ENDIF
 //OP_ENDIF
 pub fn accepted_htlc(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32], cltv_expiry: u32) -> Script {
-    let sc = Builder::new()
+    accepted_htlc_script(revocationpubkey, remote_htlcpubkey, local_htlcpubkey, payment_hash, cltv_expiry, false)
+}
+
+// option_anchors: the same with `1 OP_CHECKSEQUENCEVERIFY OP_DROP` before the last `OP_ENDIF`
+pub fn accepted_htlc_anchors(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32], cltv_expiry: u32) -> Script {
+    accepted_htlc_script(revocationpubkey, remote_htlcpubkey, local_htlcpubkey, payment_hash, cltv_expiry, true)
+}
+
+fn accepted_htlc_script(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey, local_htlcpubkey: &PublicKey, payment_hash: [u8; 32], cltv_expiry: u32, anchors: bool) -> Script {
+    let builder = Builder::new()
         .push_opcode(OP_DUP)
         .push_opcode(OP_HASH160)
         .push_slice(&hash160::Hash::hash(&revocationpubkey.serialize()).into_inner()[..])
@@ -288,10 +307,48 @@ pub fn accepted_htlc(revocationpubkey: &PublicKey, remote_htlcpubkey: &PublicKey
                 .push_opcode(OP_CLTV)
                 .push_opcode(OP_DROP)
                 .push_opcode(OP_CHECKSIG)
-            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_ENDIF);
+    one_block_delay(builder, anchors)
         .push_opcode(OP_ENDIF)
-        .into_script();
-    return sc;
+        .into_script()
+}
+
+// `1 OP_CHECKSEQUENCEVERIFY OP_DROP`
+fn one_block_delay(builder: Builder, anchors: bool) -> Builder {
+    if !anchors {
+        return builder;
+    }
+    builder
+        .push_int(1)
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+}
+
+//<local_funding_pubkey/remote_funding_pubkey> OP_CHECKSIG OP_IFDUP
+//OP_NOTIF
+//    OP_16 OP_CHECKSEQUENCEVERIFY
+//OP_ENDIF
+pub fn anchor_script(funding_pubkey: &PublicKey) -> Script {
+    Builder::new()
+        .push_slice(&funding_pubkey.serialize())
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_IFDUP)
+        .push_opcode(OP_NOTIF)
+            .push_int(16)
+            .push_opcode(OP_CSV)
+        .push_opcode(OP_ENDIF)
+        .into_script()
+}
+
+// option_anchors: `to_remote` is delayed by one block, so it cannot carry the fee of a child
+//<remotepubkey> OP_CHECKSIGVERIFY 1 OP_CHECKSEQUENCEVERIFY
+pub fn to_remote_anchor_script(remotepubkey: &PublicKey) -> Script {
+    Builder::new()
+        .push_slice(&remotepubkey.serialize())
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_int(1)
+        .push_opcode(OP_CSV)
+        .into_script()
 }
 
 
@@ -0,0 +1,206 @@
+use dependencies::secp256k1;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+use serde_derive::{Serialize, Deserialize};
+
+use wire::{
+    Message, AnnounceSignatures, AnnouncementChannel, AnnouncementChannelData, UpdateChannel, UpdateChannelData,
+    ChannelId, ShortChannelId, Sha256, RawSignature, RawPublicKey, RawFeatureVector, MilliSatoshi,
+};
+
+use common_types::ac;
+use common_types::secp256k1_m::{Signed, Data};
+
+use crate::commitment::Commitments;
+
+// BOLT 7: the channel is announced when the funding transaction has 6 confirmations
+pub(crate) const ANNOUNCEMENT_DEPTH: u32 = 6;
+
+// our relay policy of the channel, it is not configurable yet
+const TIME_LOCK_DELTA: u16 = 144;
+// in millisatoshi
+const BASE_FEE: u32 = 1000;
+// in millionths of the amount
+const FEE_RATE: u32 = 1;
+
+// BOLT 7: `message_flags`, the update has `htlc_maximum_msat`
+const OPTION_CHANNEL_HTLC_MAX: u8 = 1;
+
+/// The announcement of our public channel signed by both sides and our first update of it,
+/// the node should insert them into the routing state and broadcast them
+#[derive(Debug, Clone)]
+pub struct ChannelAnnouncement {
+    pub announcement: AnnouncementChannel,
+    pub update: UpdateChannel,
+}
+
+// The public channel is announced when the funding is deep enough,
+// the node key is not known to the channel, the node gives it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct AnnouncementState {
+    chain_hash: Sha256,
+    short_channel_id: ShortChannelId,
+    // the confirmations of the funding transaction
+    confirmations: u32,
+    // our `announce_signatures` is sent, it is sent again after the reconnection
+    sent: bool,
+    // the peer's node signature and bitcoin signature, they may come before the funding is deep enough for us
+    their_signatures: Option<(RawSignature, RawSignature)>,
+    announced: bool,
+}
+
+impl AnnouncementState {
+    pub(crate) fn new(chain_hash: Sha256, short_channel_id: ShortChannelId, confirmations: u32) -> Self {
+        AnnouncementState {
+            chain_hash: chain_hash,
+            short_channel_id: short_channel_id,
+            confirmations: confirmations,
+            sent: false,
+            their_signatures: None,
+            announced: false,
+        }
+    }
+
+    // the funding confirmations are not needed after the channel is deep enough
+    pub(crate) fn deep(&self) -> bool {
+        self.confirmations >= ANNOUNCEMENT_DEPTH
+    }
+
+    pub(crate) fn handle_confirmations(&mut self, confirmations: u32) {
+        self.confirmations = confirmations;
+    }
+
+    // BOLT 7: our signatures are retransmitted after the reconnection, the peer might not receive them
+    pub(crate) fn reconnected(&mut self) {
+        self.sent = false;
+    }
+
+    pub(crate) fn handle_announce_signatures_msg(&mut self, msg: AnnounceSignatures) -> Result<(), String> {
+        if msg.short_channel_id != self.short_channel_id {
+            return Err(format!(
+                "announce_signatures for short channel id {:?}, expected {:?}",
+                msg.short_channel_id, self.short_channel_id,
+            ));
+        }
+        if !self.announced {
+            self.their_signatures = Some((msg.node_signature, msg.bitcoin_signature));
+        }
+        Ok(())
+    }
+
+    // Our `announce_signatures` if it is not sent yet, and the announcement when both sides signed it,
+    // the announcement is produced once, the invalid signatures of the peer are dropped
+    pub(crate) fn announce(
+        &mut self,
+        channel_id: ChannelId,
+        commitments: &Commitments,
+        node_secret: &SecretKey,
+        their_node_id: &PublicKey,
+    ) -> (Option<Message>, Option<ChannelAnnouncement>) {
+        if !self.deep() {
+            return (None, None);
+        }
+
+        let context = Secp256k1::signing_only();
+        let our_node_id = PublicKey::from_secret_key(&context, node_secret);
+        let (funding_secret, their_funding_key) = commitments.funding_keys();
+        let our_funding_key = PublicKey::from_secret_key(&context, &funding_secret);
+
+        // BOLT 7: the node with the lesser key is the first, the bitcoin keys are in the same order
+        let local_first = our_node_id.serialize()[..] < their_node_id.serialize()[..];
+        let order = |ours: RawPublicKey, theirs: RawPublicKey| if local_first { (ours, theirs) } else { (theirs, ours) };
+        let data = Data(AnnouncementChannelData {
+            features: RawFeatureVector::new(),
+            chain_hash: self.chain_hash,
+            short_channel_id: self.short_channel_id.clone(),
+            node_id: order(our_node_id.into(), their_node_id.clone().into()),
+            bitcoin_key: order(our_funding_key.into(), their_funding_key.into()),
+        });
+        let hash = ac::Data::double_hash(&data);
+        let node_signature = RawSignature(context.sign(&hash, node_secret));
+        let bitcoin_signature = RawSignature(context.sign(&hash, &funding_secret));
+
+        let message = if self.sent {
+            None
+        } else {
+            self.sent = true;
+            Some(Message::AnnounceSignatures(AnnounceSignatures {
+                channel_id: channel_id,
+                short_channel_id: self.short_channel_id.clone(),
+                node_signature: node_signature.clone(),
+                bitcoin_signature: bitcoin_signature.clone(),
+            }))
+        };
+        if self.announced {
+            return (message, None);
+        }
+        let (their_node_signature, their_bitcoin_signature) = match self.their_signatures.take() {
+            Some(signatures) => signatures,
+            None => return (message, None),
+        };
+
+        let node_signatures = order_signatures(local_first, node_signature, their_node_signature);
+        let bitcoin_signatures = order_signatures(local_first, bitcoin_signature, their_bitcoin_signature);
+        let announcement = Signed {
+            signature: node_signatures.0,
+            data: Signed {
+                signature: node_signatures.1,
+                data: Signed {
+                    signature: bitcoin_signatures.0,
+                    data: Signed {
+                        signature: bitcoin_signatures.1,
+                        data: data,
+                    },
+                },
+            },
+        };
+        if let Err(e) = verify_announcement(announcement.clone()) {
+            println!("WARNING: wrong announce_signatures of channel {:?}: {:?}", channel_id, e);
+            return (message, None);
+        }
+        self.announced = true;
+        println!("INFO: channel {:?} is announced as {:?}", channel_id, self.short_channel_id);
+
+        let (htlc_minimum, htlc_maximum) = commitments.htlc_limits();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let update = UpdateChannelData {
+            hash: self.chain_hash,
+            short_channel_id: self.short_channel_id.clone(),
+            timestamp: timestamp,
+            message_flags: OPTION_CHANNEL_HTLC_MAX,
+            // the direction bit, it is set if we are the second node
+            channel_flags: if local_first { 0 } else { 1 },
+            time_lock_delta: TIME_LOCK_DELTA,
+            htlc_minimum: MilliSatoshi::from(htlc_minimum),
+            base_fee: BASE_FEE,
+            fee_rate: FEE_RATE,
+            htlc_maximum: MilliSatoshi::from(htlc_maximum),
+        };
+        let update: UpdateChannel = ac::Signed::sign(Data(update), &context, node_secret);
+
+        (message, Some(ChannelAnnouncement {
+            announcement: announcement,
+            update: update,
+        }))
+    }
+}
+
+fn order_signatures(local_first: bool, ours: RawSignature, theirs: RawSignature) -> (RawSignature, RawSignature) {
+    if local_first { (ours, theirs) } else { (theirs, ours) }
+}
+
+// the same checks the routing state does for the announcements of other channels
+fn verify_announcement(announcement: AnnouncementChannel) -> Result<(), secp256k1::Error> {
+    use common_types::ac::Signed;
+
+    let context = Secp256k1::verification_only();
+    announcement
+        .verify_key_inside(&context, |data| data.node_id.0.as_ref())?
+        .verify_key_inside(&context, |data| data.node_id.1.as_ref())?
+        .verify_key_inside(&context, |data| data.bitcoin_key.0.as_ref())?
+        .verify_key_inside(&context, |data| data.bitcoin_key.1.as_ref())
+        .map(|_| ())
+}
@@ -7,66 +7,258 @@ use dependencies::rand;
 use wire::{
     Message, AcceptChannel, OpenChannel,
     FundingSigned, ChannelId, FundingLocked, Satoshi, MilliSatoshi, CsvDelay, FundingCreated,
-    ChannelKeys, ChannelPrivateKeys, RawSignature,
+    ChannelKeys, ChannelPrivateKeys, RawSignature, Sha256, SatoshiPerKiloWeight, ChannelFlags,
+    FundingTxid, OutputIndex, Error, OnionBlob, ShutdownChannel, ReestablishChannel, RawPublicKey,
+    ShortChannelId, AnnounceSignatures, RawFeatureVector, FeatureBit,
 };
 
-use secp256k1::PublicKey;
+use secp256k1::{PublicKey, SecretKey, Secp256k1};
 
-use bitcoin_hashes::{sha256d, sha256};
-use bitcoin_hashes::Hash;
+use serde_derive::{Serialize, Deserialize};
 
-use bitcoin::consensus::encode::Encodable;
+use bitcoin_hashes::sha256d;
+
+use bitcoin::{Transaction, Script};
 
 use channel::derivation::{derive_pubkey, derive_revocation_pubkey};
-use channel::tools::get_obscuring_number;
+use channel::tools::{get_obscuring_number, new_2x2_wsh_lock_script};
 use channel::commit::CommitTx;
 
-#[derive(Debug, Copy, Clone, Default)]
-struct PartnerConfig {
-    dust_limit: u64,
-    max_htlc_value_in_flight: u64,
-    chanel_reserve: u64,
-    htlc_minimum: u64,
-    csv_delay: u16,
-    max_accepted_htlc_number: u16,
-    local_fee_rate: u32,
+use crate::commitment::{Commitments, FundingOutput, Resync, BreachRemedy, commitment_tx, per_commitment_point};
+use crate::closing::{ClosingState, ForceClosingState, DataLossState, PunishingState, ClosedState, check_shutdown_script, handle_spend, sweep_destination};
+use crate::policy::ChannelPolicy;
+use crate::announcement::{AnnouncementState, ChannelAnnouncement};
+
+// BOLT 2: the receiver of `accept_channel` may reject unreasonably large values
+const MAX_MINIMUM_DEPTH: u32 = 144;
+pub(crate) const MAX_CSV_DELAY: u16 = 2016;
+pub(crate) const MAX_ACCEPTED_HTLC_NUMBER: u16 = 483;
+
+/// Creates the funding transaction of the channel we open, broadcasts the channel's transactions
+pub trait FundingWallet {
+    // creates and signs the transaction paying `amount` satoshi to `script_pubkey`, does not broadcast it
+    fn fund(&mut self, script_pubkey: Script, amount: u64) -> Result<Transaction, String>;
+
+    fn publish(&mut self, transaction: &Transaction) -> Result<(), String>;
+
+    // our funds go there when the channel is closed
+    fn shutdown_script(&mut self) -> Result<Script, String>;
+
+    // the estimate of the fee rate of the commitment transactions, in satoshi per kiloweight
+    fn fee_rate(&mut self) -> u32;
+
+    // our payment basepoint when `option_static_remotekey` is negotiated, our funds of the peer's
+    // commitments are paid to it, so it should be recoverable without the channel state,
+    // it is asked once per channel, the channel keeps it
+    fn payment_key(&mut self) -> Result<SecretKey, String>;
 }
 
+/// Parameters of the channel we open, see `ChannelState::open`
 #[derive(Debug, Clone)]
-struct PartnerInfo {
-    keys: ChannelKeys,
-    private_keys: Option<ChannelPrivateKeys>,
-    config: PartnerConfig,
+pub struct OpenChannelParams {
+    pub chain_hash: Sha256,
+    pub temporary_channel_id: ChannelId,
+    // in satoshi
+    pub funding: u64,
+    // in millisatoshi
+    pub push: u64,
+    pub dust_limit: u64,
+    pub max_htlc_value_in_flight: u64,
+    pub channel_reserve: u64,
+    pub htlc_minimum: u64,
+    pub fee_rate: u32,
+    // the delay of the peer's to-self outputs
+    pub csv_delay: u16,
+    pub max_accepted_htlc_number: u16,
+    pub announce: bool,
+}
+
+impl OpenChannelParams {
+    // the defaults for the given amounts, the temporary channel id is random
+    pub fn new(funding: u64, push: u64) -> Self {
+        OpenChannelParams {
+            chain_hash: Sha256::BITCOIN_CHAIN_HASH,
+            temporary_channel_id: ChannelId::from(rand::random::<[u8; 32]>()),
+            funding: funding,
+            push: push,
+            dust_limit: 546,
+            max_htlc_value_in_flight: funding * 1000,
+            channel_reserve: funding / 100,
+            htlc_minimum: 1000,
+            fee_rate: 253,
+            csv_delay: 144,
+            max_accepted_htlc_number: MAX_ACCEPTED_HTLC_NUMBER,
+            announce: true,
+        }
+    }
+}
+
+/// We support `option_static_remotekey`, so the channel uses it if the peer's `init` offers it
+pub fn static_remotekey(their_features: &RawFeatureVector) -> bool {
+    their_features.is_set_bit(&FeatureBit::StaticRemoteKeyRequired)
+        || their_features.is_set_bit(&FeatureBit::StaticRemoteKeyOptional)
+}
+
+/// We support `option_anchors_zero_fee_htlc_tx`, the channel uses the anchor outputs format
+/// if the peer's `init` offers it along with `option_static_remotekey` which it depends on
+pub fn anchors(their_features: &RawFeatureVector) -> bool {
+    static_remotekey(their_features)
+        && (their_features.is_set_bit(&FeatureBit::AnchorsZeroFeeHtlcTxRequired)
+            || their_features.is_set_bit(&FeatureBit::AnchorsZeroFeeHtlcTxOptional))
+}
+
+// BOLT 2: `funding_txid` XOR `funding_output_index`, the index alters the last 2 bytes
+fn derive_channel_id(funding_txid: &FundingTxid, funding_output_index: u16) -> ChannelId {
+    let mut data = <[u8; 32]>::from(funding_txid.clone());
+    data[30] ^= (funding_output_index >> 8) as u8;
+    data[31] ^= (funding_output_index & 0xff) as u8;
+    ChannelId::from(data)
+}
+
+pub(crate) fn error_message(channel_id: ChannelId, description: &str) -> Message {
+    println!("ERROR: channel {:?} failed: {}", channel_id, description);
+    Message::Error(Error {
+        channel_id: channel_id,
+        data: description.as_bytes().to_vec(),
+    })
+}
+
+// the error is sent to the peer, the channel fails
+fn fail(channel_id: ChannelId, description: String) -> (ChannelState, Option<Message>) {
+    let message = error_message(channel_id, &description);
+    (ChannelState::Opening(OpeningState::Error(description)), Some(message))
+}
+
+// the operating channel fails, the error is sent to the peer and our latest commitment is broadcast
+pub(crate) fn fail_channel(
+    channel_id: ChannelId,
+    commitments: Commitments,
+    description: String,
+    wallet: &mut dyn FundingWallet,
+) -> (ChannelState, Option<Message>) {
+    let message = error_message(channel_id, &description);
+    let data = ForceClosingState::new(channel_id, commitments, wallet);
+    (ChannelState::ForceClosing(data), Some(message))
+}
+
+// BOLT 1: the peer failed the channel, our latest commitment is broadcast
+pub(crate) fn failed_by_peer(
+    channel_id: ChannelId,
+    commitments: Commitments,
+    msg: Error,
+    wallet: &mut dyn FundingWallet,
+) -> ChannelState {
+    println!("ERROR: the peer failed channel {:?}: {}", channel_id, String::from_utf8_lossy(&msg.data));
+    ChannelState::ForceClosing(ForceClosingState::new(channel_id, commitments, wallet))
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub(crate) struct PartnerConfig {
+    pub(crate) dust_limit: u64,
+    pub(crate) max_htlc_value_in_flight: u64,
+    pub(crate) chanel_reserve: u64,
+    pub(crate) htlc_minimum: u64,
+    pub(crate) csv_delay: u16,
+    pub(crate) max_accepted_htlc_number: u16,
+    pub(crate) local_fee_rate: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PartnerInfo {
+    pub(crate) keys: ChannelKeys,
+    pub(crate) private_keys: Option<ChannelPrivateKeys>,
+    // our per commitment secrets are produced from it, the first one is `keys.first_per_commitment`
+    pub(crate) per_commitment_seed: Option<Sha256>,
+    // BOLT 2 `option_upfront_shutdown_script`, the channel can be closed only to this script
+    #[serde(with = "crate::codec::option_script")]
+    pub(crate) upfront_shutdown_script: Option<Script>,
+    // our funds are swept there, the wallet is asked once per channel
+    #[serde(default, with = "crate::codec::option_script")]
+    pub(crate) sweep_script: Option<Script>,
+    pub(crate) config: PartnerConfig,
     // TODO(mkl): add flag to indicate if info contains private info
     // TODO(mkl): add flag to indicate if it is an initiator info
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
 struct FundingInfo {
     temporary_channel_id: ChannelId,
+    chain_hash: Sha256,
     funding: u64,
     push: u64,
+    // the funder wants the channel to be public
+    announce: bool,
+    #[serde(default)]
+    static_remotekey: bool,
+    #[serde(default)]
+    anchors: bool,
 }
 
 impl FundingInfo {
     fn from_open_channel_msg(msg: &OpenChannel) -> FundingInfo {
         FundingInfo {
             temporary_channel_id: msg.temporary_channel_id,
+            chain_hash: msg.chain_hash,
             funding: u64::from(msg.funding),
-            push: u64::from(msg.push)
+            push: u64::from(msg.push),
+            announce: msg.flags.0 & ChannelFlags::FF_ANNOUNCE_CHANNEL.0 != 0,
+            static_remotekey: false,
+            anchors: false,
         }
     }
 }
 
+// the empty script means the peer does not commit to any
+fn upfront_shutdown_script(field: &Option<Vec<u8>>) -> Option<Script> {
+    match field {
+        &Some(ref script) if !script.is_empty() => Some(Script::from(script.clone())),
+        _ => None,
+    }
+}
+
+// the upfront script should be valid as the shutdown script
+pub(crate) fn check_upfront_shutdown_script(field: &Option<Vec<u8>>) -> Result<(), String> {
+    match upfront_shutdown_script(field) {
+        Some(script) => check_shutdown_script(&script, None).map_err(|e| format!("upfront {}", e)),
+        None => Ok(()),
+    }
+}
+
 impl PartnerInfo {
+    fn from_accept_channel_msg(msg: &AcceptChannel, local_fee_rate: u32) -> PartnerInfo {
+        let config = PartnerConfig {
+            dust_limit: u64::from(msg.dust_limit),
+            max_htlc_value_in_flight: u64::from(msg.max_htlc_value_in_flight),
+            chanel_reserve: u64::from(msg.chanel_reserve),
+            htlc_minimum: u64::from(msg.htlc_minimum),
+            csv_delay: u16::from(msg.csv_delay),
+            max_accepted_htlc_number: msg.max_accepted_htlc_number,
+            local_fee_rate: local_fee_rate,
+        };
+
+        PartnerInfo {
+            keys: msg.keys.clone(),
+            private_keys: None,
+            per_commitment_seed: None,
+            upfront_shutdown_script: upfront_shutdown_script(&msg.shutdown_script),
+            sweep_script: None,
+            config,
+        }
+    }
+
     // Create new PartnerInfo with random info
-    fn new_random() -> PartnerInfo {
+    pub(crate) fn new_random() -> PartnerInfo {
         let private_keys: ChannelPrivateKeys = rand::random();
-        let keys = ChannelKeys::new(&private_keys);
+        let per_commitment_seed = Sha256::from(rand::random::<[u8; 32]>());
+        let mut keys = ChannelKeys::new(&private_keys);
+        keys.first_per_commitment = per_commitment_point(&per_commitment_seed, 0).into();
 
         PartnerInfo {
             keys: keys,
             private_keys: Some(private_keys),
+            per_commitment_seed: Some(per_commitment_seed),
+            upfront_shutdown_script: None,
+            sweep_script: None,
             config: Default::default(),
         }
     }
@@ -85,46 +277,96 @@ impl PartnerInfo {
         PartnerInfo {
             keys: msg.keys.clone(),
             private_keys: None,
+            per_commitment_seed: None,
+            upfront_shutdown_script: upfront_shutdown_script(&msg.shutdown_script),
+            sweep_script: None,
             config,
         }
     }
 
-    fn htlc_pubkey(&self) -> PublicKey {
-        derive_pubkey(&self.keys.htlc(), &self.keys.first_per_commitment())
+    // the script we commit to, the empty one if we do not
+    fn shutdown_script_field(&self) -> Option<Vec<u8>> {
+        Some(self.upfront_shutdown_script.as_ref().map(|script| script.as_bytes().to_vec()).unwrap_or_default())
     }
 
-    // Revocation pubkey for our commitment transaction
-    // We require partners revocation_pk to generate it
-    // and our commitment point
-    fn revocation_pubkey(&self, partner_revocation_pk: &PublicKey) -> PublicKey {
-        derive_revocation_pubkey(partner_revocation_pk, &self.keys.first_per_commitment())
+    // `option_static_remotekey`, the wallet's key is our payment basepoint
+    fn set_payment_key(&mut self, payment_key: SecretKey) {
+        let payment = PublicKey::from_secret_key(&Secp256k1::signing_only(), &payment_key);
+        self.keys.payment = RawPublicKey::from(payment);
+        self.private_keys.as_mut().unwrap().set_payment_sk(payment_key);
+    }
+
+    pub(crate) fn htlc_pubkey(&self, point: &PublicKey) -> PublicKey {
+        derive_pubkey(&self.keys.htlc(), point)
+    }
+
+    // Revocation pubkey for the partner's commitment transaction,
+    // it is derived from our revocation basepoint and the partner's commitment point
+    pub(crate) fn revocation_pubkey(&self, point: &PublicKey) -> PublicKey {
+        derive_revocation_pubkey(&self.keys.revocation(), point)
     }
 
     // We use this pubkey to send money to ourself
-    fn delayed_pubkey(&self) -> PublicKey {
-        derive_pubkey(&self.keys.delayed_payment(), &self.keys.first_per_commitment())
+    pub(crate) fn delayed_pubkey(&self, point: &PublicKey) -> PublicKey {
+        derive_pubkey(&self.keys.delayed_payment(), point)
     }
 
-    fn payment_pubkey(&self) -> PublicKey {
-        derive_pubkey(&self.keys.payment(), &self.keys.first_per_commitment())
+    pub(crate) fn payment_pubkey(&self, point: &PublicKey) -> PublicKey {
+        derive_pubkey(&self.keys.payment(), point)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct InitialState;
-
-// TODO(mkl): refactor into enum
-#[derive(Debug, Clone)]
-pub struct ReadyState {
-    channel_id: ChannelId,
-    our_info: PartnerInfo,
-    their_info: PartnerInfo,
+// The first commitment transaction of the `local` side, the funder pays the fee.
+fn first_commitment(
+    local: &PartnerInfo,
+    remote: &PartnerInfo,
+    funding: &FundingInfo,
+    local_is_funder: bool,
     obscuring_factor: u64,
     funding_tx_id: sha256d::Hash,
     funding_output_index: u16,
+) -> CommitTx {
+    let funder_msat = 1000 * funding.funding - funding.push;
+    let (to_local_msat, to_remote_msat) = if local_is_funder {
+        (funder_msat, funding.push)
+    } else {
+        (funding.push, funder_msat)
+    };
+    let output = FundingOutput {
+        amount: funding.funding,
+        tx_id: funding_tx_id,
+        output_index: funding_output_index,
+        obscuring_factor: obscuring_factor,
+        static_remotekey: funding.static_remotekey,
+        anchors: funding.anchors,
+    };
+
+    commitment_tx(
+        local, remote, local.keys.first_per_commitment(), &output, local_is_funder, 0,
+        local.config.local_fee_rate, to_local_msat, to_remote_msat, vec![],
+    )
 }
 
-#[derive(Debug, Clone)]
+// the channel the peer may open, it should satisfy our policy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InitialState {
+    policy: ChannelPolicy,
+    // both peers support `option_static_remotekey`
+    static_remotekey: bool,
+    // both peers support `option_anchors_zero_fee_htlc_tx`
+    #[serde(default)]
+    anchors: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadyState {
+    channel_id: ChannelId,
+    commitments: Commitments,
+    // `None` if the channel is private
+    announcement: Option<AnnouncementState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ChannelState {
 
     // Initial state of the system. No channels
@@ -137,59 +379,74 @@ pub enum ChannelState {
     Ready(ReadyState),
 
     // When channel in process of cooperative closing
-    Closing,
+    Closing(ClosingState),
 
     // When channel in process of not cooperative closing
-    ForceClosing,
+    ForceClosing(ForceClosingState),
+
+    // When we lost the channel state (restored from old backup)
+    // and wait for partner to close the channel
+    DataLoss(DataLossState),
 
     // When channel is closed.
     // TODO: maybe split in two cooperative and not-cooperative
-    Closed,
+    Closed(ClosedState),
 
     // When error occured during channel operation
     Error,
 
     // When we detect that partner is cheating
     // and start punishing him
-    PunishingPartner,
+    PunishingPartner(PunishingState),
 
     // When we successfully punish partner
     // for his cheating
-    PunishedPartner,
+    PunishedPartner(ClosedState),
 
     // When partner successfully cheat on us
-    Robbed,
+    Robbed(ClosedState),
 
     // When we try to cheat (or restore from old backup)
     // and get punished
-    PunishedByPartner,
+    PunishedByPartner(ClosedState),
 }
 
-// UpdateInfo represents update applied to channel
-enum UpdateInfo {
-    AddHtlc {
-        id: i64,
-        amount: u64,
-        payment_hash: [u8; 32],
-        expiry: u32,
-    },
-    FulfillHtlc {
-        id: i64,
-        payment_preimage: [u8; 32]
-    },
-    FailHtlc {
-        id: i64,
-        reason: Vec<u8>,
-    }
+// Channel opening. We are opening channel
+// We --- OpenChannel   --->  Partner
+// We <-- AcceptChannel ---   Partner
+// We --- FundingCreated -->  Partner
+// We <-- FundingSigned ---   Partner
+// ...... broadcast the funding transaction, after blockchain confirmations
+// We --- FundingLocked --> Partner
+// We <-- FundingLocked --- Partner
+
+// Data for opening channel state
+// and channel is opened by us
+// When we already sent OpenChannel message and now
+// waiting for AcceptChannel message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WaitAcceptChannelData {
+    temp_channel_id: ChannelId,
+    our_info: PartnerInfo,
+    funding: FundingInfo,
 }
 
-struct Channel {
+// Data for opening channel state
+// and channel is opened by us
+// When we already sent FundingCreated message and now
+// waiting for FundingSigned message, the funding transaction is not broadcast yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WaitFundingSignedData {
+    temp_channel_id: ChannelId,
+    channel_id: ChannelId,
     our_info: PartnerInfo,
     their_info: PartnerInfo,
+    obscuring_factor: u64,
     funding: FundingInfo,
-    // TODO: add our information
-    //    our_commit_tx: CommitTx,
-    their_commit_tx: Option<CommitTx>
+    #[serde(with = "crate::codec::transaction")]
+    funding_tx: Transaction,
+    funding_output_index: u16,
+    minimum_depth: u32,
 }
 
 // Channel opening. We are receiving channel
@@ -205,19 +462,21 @@ struct Channel {
 // and channel is opened by other side
 // When we already sent AcceptChannel message and now
 // waiting for FundingCreated message
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WaitFundingCreatedData {
     temp_channel_id: ChannelId,
     our_info: PartnerInfo,
     their_info: PartnerInfo,
     funding: FundingInfo,
+    // the value we sent in `AcceptChannel`
+    minimum_depth: u32,
 }
 
 // Data for opening channel state
 // and channel is opened by other side
 // When we already sent FundingSigned message
 // and now wait for FundingLocked
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WaitFundingLockedData {
     temp_channel_id: ChannelId,
     channel_id: ChannelId,
@@ -225,20 +484,33 @@ pub struct WaitFundingLockedData {
     their_info: PartnerInfo,
     obscuring_factor: u64,
     funding: FundingInfo,
+    #[serde(with = "crate::codec::txid")]
     funding_tx_id: sha256d::Hash,
     funding_output_index: u16,
+    // the confirmations of the funding transaction required before `FundingLocked`
+    minimum_depth: u32,
+    // the confirmations seen so far, our `FundingLocked` is sent when they reach `minimum_depth`
+    confirmations: u32,
+    // the position of the funding output in the chain, it is known after the first confirmation
+    short_channel_id: Option<ShortChannelId>,
+    local_is_funder: bool,
+    // the peer's signature of our first commitment
+    our_commit_signature: RawSignature,
+    // the peer's `FundingLocked` may come before the funding is deep enough for us
+    #[serde(with = "crate::codec::option_public_key")]
+    their_next_point: Option<PublicKey>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum OpeningState {
     Initial,
+    WaitAcceptChannel(WaitAcceptChannelData),
+    WaitFundingSigned(WaitFundingSignedData),
     WaitFundingCreated(WaitFundingCreatedData),
     WaitFundingLocked(WaitFundingLockedData),
     Error(String)
 }
 
-// TODO(mkl): add states for us opening channels
-
 #[derive(Debug, Clone)]
 enum Event {
     MessageEvent(Message),
@@ -249,13 +521,232 @@ enum Event {
 
 impl ChannelState {
     pub fn new() -> ChannelState {
-        ChannelState::Initial(InitialState)
+        ChannelState::with_policy(ChannelPolicy::default(), &RawFeatureVector::new())
+    }
+
+    // the channel the peer may open, the features are of the peer's `init`
+    pub fn with_policy(policy: ChannelPolicy, their_features: &RawFeatureVector) -> ChannelState {
+        ChannelState::Initial(InitialState {
+            policy: policy,
+            static_remotekey: static_remotekey(their_features),
+            anchors: anchors(their_features),
+        })
+    }
+
+    // the channel opened by us, the message should be sent to the peer,
+    // the features are of the peer's `init`
+    pub fn open(params: OpenChannelParams, their_features: &RawFeatureVector, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        let static_remotekey = static_remotekey(their_features);
+        let mut our_info = PartnerInfo::new_random();
+        if static_remotekey {
+            match wallet.payment_key() {
+                Ok(payment_key) => our_info.set_payment_key(payment_key),
+                Err(description) => {
+                    // the peer does not know about the channel yet
+                    println!("ERROR: cannot open channel {:?}, no payment key: {}", params.temporary_channel_id, description);
+                    return (ChannelState::Opening(OpeningState::Error(description)), None);
+                },
+            }
+        }
+        match wallet.shutdown_script() {
+            Ok(script) => our_info.sweep_script = Some(script),
+            Err(description) => {
+                println!("ERROR: cannot open channel {:?}, no sweep script: {}", params.temporary_channel_id, description);
+                return (ChannelState::Opening(OpeningState::Error(description)), None);
+            },
+        }
+        our_info.config = PartnerConfig {
+            dust_limit: params.dust_limit,
+            max_htlc_value_in_flight: params.max_htlc_value_in_flight,
+            chanel_reserve: params.channel_reserve,
+            htlc_minimum: params.htlc_minimum,
+            csv_delay: params.csv_delay,
+            max_accepted_htlc_number: params.max_accepted_htlc_number,
+            local_fee_rate: params.fee_rate,
+        };
+        let open_channel_msg = OpenChannel {
+            chain_hash: params.chain_hash,
+            temporary_channel_id: params.temporary_channel_id,
+            funding: Satoshi::from(params.funding),
+            push: MilliSatoshi::from(params.push),
+            dust_limit: Satoshi::from(params.dust_limit),
+            max_in_flight: MilliSatoshi::from(params.max_htlc_value_in_flight),
+            channel_reserve: Satoshi::from(params.channel_reserve),
+            htlc_minimum: MilliSatoshi::from(params.htlc_minimum),
+            fee: SatoshiPerKiloWeight::from(params.fee_rate),
+            csv_delay: CsvDelay::from(params.csv_delay),
+            max_accepted_htlc_number: params.max_accepted_htlc_number,
+            keys: our_info.keys.clone(),
+            flags: if params.announce { ChannelFlags::FF_ANNOUNCE_CHANNEL } else { ChannelFlags::default() },
+            shutdown_script: our_info.shutdown_script_field(),
+        };
+        let mut funding = FundingInfo::from_open_channel_msg(&open_channel_msg);
+        funding.static_remotekey = static_remotekey;
+        funding.anchors = anchors(their_features);
+        let data = WaitAcceptChannelData {
+            temp_channel_id: params.temporary_channel_id,
+            our_info: our_info,
+            funding: funding,
+        };
+        (
+            ChannelState::Opening(OpeningState::WaitAcceptChannel(data)),
+            Some(Message::OpenChannel(open_channel_msg))
+        )
+    }
+
+    // the final id of the channel, it is known after the funding transaction is created
+    pub fn channel_id(&self) -> Option<ChannelId> {
+        match self {
+            &ChannelState::Opening(OpeningState::WaitFundingSigned(ref data)) => Some(data.channel_id),
+            &ChannelState::Opening(OpeningState::WaitFundingLocked(ref data)) => Some(data.channel_id),
+            &ChannelState::Ready(ref data) => Some(data.channel_id),
+            &ChannelState::Closing(ref data) => Some(data.channel_id()),
+            &ChannelState::ForceClosing(ref data) => Some(data.channel_id()),
+            &ChannelState::DataLoss(ref data) => Some(data.channel_id()),
+            &ChannelState::Closed(ref data) => Some(data.channel_id()),
+            &ChannelState::PunishingPartner(ref data) => Some(data.channel_id()),
+            &ChannelState::PunishedPartner(ref data) => Some(data.channel_id()),
+            &ChannelState::Robbed(ref data) => Some(data.channel_id()),
+            &ChannelState::PunishedByPartner(ref data) => Some(data.channel_id()),
+            _ => None,
+        }
+    }
+
+    // the transactions which the channel waits to be confirmed, the node should report their confirmations
+    pub fn watched_txids(&self) -> Vec<sha256d::Hash> {
+        match self {
+            &ChannelState::Opening(OpeningState::WaitFundingLocked(ref data)) => vec![data.funding_tx_id],
+            // the public channel is announced when the funding is deep enough
+            &ChannelState::Ready(ref data) => match data.announcement {
+                Some(ref announcement) if !announcement.deep() => vec![data.commitments.funding_outpoint().0],
+                _ => Vec::new(),
+            },
+            &ChannelState::ForceClosing(ref data) => vec![data.commitment_txid()],
+            &ChannelState::PunishingPartner(ref data) => data.justice_txids(),
+            _ => Vec::new(),
+        }
+    }
+
+    // the outputs which the channel waits to be spent, the node should report the spending transactions,
+    // the funding output of the funded channel is watched for the peer's revoked commitments,
+    // the outputs of the revoked commitment for the peer's HTLC transactions
+    pub fn watched_outpoints(&self) -> Vec<(sha256d::Hash, u32)> {
+        match self {
+            &ChannelState::Ready(ref data) => vec![data.commitments.funding_outpoint()],
+            &ChannelState::Closing(ref data) => vec![data.funding_outpoint()],
+            &ChannelState::ForceClosing(ref data) => vec![data.funding_outpoint()],
+            &ChannelState::DataLoss(ref data) => vec![data.funding_outpoint()],
+            &ChannelState::PunishingPartner(ref data) => data.spent_outpoints(),
+            _ => Vec::new(),
+        }
+    }
+
+    // the transactions which are not confirmed yet are broadcast again, the node should call it on every block
+    pub fn rebroadcast(&self, wallet: &mut dyn FundingWallet) {
+        if let &ChannelState::PunishingPartner(ref data) = self {
+            data.rebroadcast(wallet);
+        }
+    }
+
+    // the watched output is spent by the transaction
+    pub fn spent(self, tx: &Transaction, wallet: &mut dyn FundingWallet) -> ChannelState {
+        match self {
+            ChannelState::Ready(st) => match handle_spend(st.channel_id, &st.commitments, tx, wallet) {
+                Some(state) => state,
+                None => ChannelState::Ready(st),
+            },
+            ChannelState::Closing(st) => st.handle_spend(tx, wallet),
+            ChannelState::ForceClosing(st) => st.handle_spend(tx, wallet),
+            ChannelState::DataLoss(st) => st.handle_commitment(tx, wallet),
+            ChannelState::PunishingPartner(st) => st.handle_spend(tx, wallet),
+            st => st,
+        }
+    }
+
+    // Our `channel_reestablish`, it is sent after the reconnection before any other message
+    // of the channel, the channel which is not funded or already closed has nothing to reestablish
+    pub fn reestablish(&self) -> Option<Message> {
+        match self {
+            &ChannelState::Opening(OpeningState::WaitFundingLocked(ref data)) => Some(data.reestablish_message()),
+            &ChannelState::Ready(ref data) => Some(Message::ReestablishChannel(data.commitments.reestablish_message())),
+            &ChannelState::Closing(ref data) => Some(Message::ReestablishChannel(data.commitments.reestablish_message())),
+            _ => None,
+        }
+    }
+
+    // the peer's `channel_reestablish`, the response is the messages the peer has lost
+    pub fn handle_reestablish(self, msg: ReestablishChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Vec<Message>) {
+        match self {
+            ChannelState::Ready(mut st) => match st.commitments.receive_reestablish(msg) {
+                Ok(Resync::Retransmit(messages)) => {
+                    if let Some(ref mut announcement) = st.announcement {
+                        announcement.reconnected();
+                    }
+                    (ChannelState::Ready(st), messages)
+                },
+                Ok(Resync::DataLoss(their_point)) => DataLossState::new(st.channel_id, st.commitments, their_point),
+                Err(description) => {
+                    let (state, message) = fail_channel(st.channel_id, st.commitments, description, wallet);
+                    (state, message.into_iter().collect())
+                },
+            },
+            ChannelState::Closing(st) => st.handle_reestablish_msg(msg, wallet),
+            // our `funding_locked` is retransmitted if it is sent already
+            ChannelState::Opening(OpeningState::WaitFundingLocked(st)) => {
+                let messages = if st.locked() { vec![st.funding_locked_message()] } else { Vec::new() };
+                (ChannelState::Opening(OpeningState::WaitFundingLocked(st)), messages)
+            },
+            st => {
+                println!("WARNING: channel_reestablish in state {:?}, ignoring", &st);
+                (st, Vec::new())
+            },
+        }
+    }
+
+    // the mutual close transaction or our commitment, when it is broadcast
+    // the preimage of the HTLC we offered, the peer's `update_fulfill_htlc` is accepted
+    pub fn payment_preimage(&self, htlc_id: u64) -> Option<[u8; 32]> {
+        match self {
+            &ChannelState::Ready(ref data) => data.commitments.their_preimage(htlc_id),
+            &ChannelState::Closing(ref data) => data.their_preimage(htlc_id),
+            _ => None,
+        }
+    }
+
+    // the funding output, known when the funding transaction is created
+    pub fn funding_outpoint(&self) -> Option<(sha256d::Hash, u32)> {
+        match self {
+            &ChannelState::Opening(OpeningState::WaitFundingLocked(ref data)) => Some((data.funding_tx_id, data.funding_output_index as u32)),
+            &ChannelState::Ready(ref data) => Some(data.commitments.funding_outpoint()),
+            &ChannelState::Closing(ref data) => Some(data.funding_outpoint()),
+            &ChannelState::ForceClosing(ref data) => Some(data.funding_outpoint()),
+            &ChannelState::DataLoss(ref data) => Some(data.funding_outpoint()),
+            _ => None,
+        }
+    }
+
+    pub fn closing_txid(&self) -> Option<sha256d::Hash> {
+        match self {
+            &ChannelState::ForceClosing(ref data) => Some(data.commitment_txid()),
+            &ChannelState::Closed(ref data) => Some(data.closing_txid()),
+            &ChannelState::PunishingPartner(ref data) => Some(data.commitment_txid()),
+            &ChannelState::PunishedPartner(ref data) => Some(data.closing_txid()),
+            &ChannelState::Robbed(ref data) => Some(data.closing_txid()),
+            &ChannelState::PunishedByPartner(ref data) => Some(data.closing_txid()),
+            _ => None,
+        }
     }
 
-    pub fn next(self, msg: Message) -> (ChannelState, Option<Message>) {
+    pub fn next(self, msg: Message, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
         match (self, msg) {
             (ChannelState::Initial(st), Message::OpenChannel(msg)) => {
-                st.handle_open_channel_msg(msg)
+                st.handle_open_channel_msg(msg, wallet)
+            },
+            (ChannelState::Opening(OpeningState::WaitAcceptChannel(st)), Message::AcceptChannel(msg)) => {
+                st.handle_accept_channel_msg(msg, wallet)
+            },
+            (ChannelState::Opening(OpeningState::WaitFundingSigned(st)), Message::FundingSigned(msg)) => {
+                st.handle_funding_signed_msg(msg, wallet)
             },
             (ChannelState::Opening(OpeningState::WaitFundingCreated(st)), Message::FundingCreated(msg)) => {
                 st.handle_funding_created_msg(msg)
@@ -263,35 +754,362 @@ impl ChannelState {
             (ChannelState::Opening(OpeningState::WaitFundingLocked(st)), Message::FundingLocked(msg)) => {
                 st.handle_funding_locked_msg(msg)
             },
+            (ChannelState::Ready(st), Message::UpdateAddHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_add_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::UpdateFulfillHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fulfill_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::UpdateFailHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fail_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::UpdateFailMalformedHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fail_malformed_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::UpdateFee(msg)) => {
+                let estimate = wallet.fee_rate();
+                st.handle_update(wallet, |c| c.receive_update_fee(msg, estimate).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::CommitmentSigned(msg)) => {
+                st.handle_update(wallet, |c| c.receive_commitment_signed(msg).map(|m| Some(Message::RevokeAndAck(m))))
+            },
+            (ChannelState::Ready(st), Message::RevokeAndAck(msg)) => {
+                st.handle_update(wallet, |c| c.receive_revoke_and_ack(msg).map(|()| None))
+            },
+            (ChannelState::Ready(st), Message::ShutdownChannel(msg)) => {
+                st.handle_shutdown_msg(msg, wallet)
+            },
+            (ChannelState::Ready(st), Message::AnnounceSignatures(msg)) => {
+                st.handle_announce_signatures_msg(msg)
+            },
+            (ChannelState::Closing(st), Message::ShutdownChannel(msg)) => {
+                st.handle_shutdown_msg(msg, wallet)
+            },
+            // BOLT 2: no new HTLCs after `shutdown`
+            (ChannelState::Closing(st), Message::UpdateAddHtlc(_)) => {
+                st.fail("update_add_htlc after shutdown".to_owned(), wallet)
+            },
+            (ChannelState::Closing(st), Message::UpdateFulfillHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fulfill_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Closing(st), Message::UpdateFailHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fail_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Closing(st), Message::UpdateFailMalformedHtlc(msg)) => {
+                st.handle_update(wallet, |c| c.receive_fail_malformed_htlc(msg).map(|()| None))
+            },
+            (ChannelState::Closing(st), Message::UpdateFee(msg)) => {
+                let estimate = wallet.fee_rate();
+                st.handle_update(wallet, |c| c.receive_update_fee(msg, estimate).map(|()| None))
+            },
+            (ChannelState::Closing(st), Message::CommitmentSigned(msg)) => {
+                st.handle_update(wallet, |c| c.receive_commitment_signed(msg).map(|m| Some(Message::RevokeAndAck(m))))
+            },
+            (ChannelState::Closing(st), Message::RevokeAndAck(msg)) => {
+                st.handle_update(wallet, |c| c.receive_revoke_and_ack(msg).map(|()| None))
+            },
+            (ChannelState::Closing(st), Message::ClosingSigned(msg)) => {
+                st.handle_closing_signed_msg(msg, wallet)
+            },
+            (ChannelState::Ready(st), Message::Error(msg)) => {
+                (failed_by_peer(st.channel_id, st.commitments, msg, wallet), None)
+            },
+            (ChannelState::Closing(st), Message::Error(msg)) => {
+                (st.handle_error_msg(msg, wallet), None)
+            },
+            // the funding may be broadcast already, our first commitment takes our funds back
+            (ChannelState::Opening(OpeningState::WaitFundingLocked(st)), Message::Error(msg)) => {
+                (st.fail(msg, wallet), None)
+            },
+            // nothing is funded yet, the channel is forgotten
+            (ChannelState::Opening(_), Message::Error(msg)) => {
+                let description = String::from_utf8_lossy(&msg.data).into_owned();
+                println!("ERROR: the peer failed the channel {:?}: {}", msg.channel_id, description);
+                (ChannelState::Opening(OpeningState::Error(description)), None)
+            },
+            // the channel is closing already
+            (st, Message::Error(msg)) => {
+                println!("WARNING: the peer failed the channel {:?}: {}", msg.channel_id, String::from_utf8_lossy(&msg.data));
+                (st, None)
+            },
             (st, msg) => {
                 println!("Unknown combination state/message: {:?}/{:?}", &st, &msg);
                 (st, None)
             }
         }
     }
+
+    // we offer the HTLC to the peer, the command is rejected if the channel is not operating
+    pub fn add_htlc(self, amount: u64, payment_hash: [u8; 32], expiry: u32, onion_blob: OnionBlob) -> (ChannelState, Result<Message, String>) {
+        if let &ChannelState::Closing(_) = &self {
+            return (self, Err("the channel is closing".to_owned()));
+        }
+        self.command(|c| c.add_htlc(amount, payment_hash, expiry, onion_blob).map(Message::UpdateAddHtlc))
+    }
+
+    pub fn fulfill_htlc(self, id: u64, payment_preimage: [u8; 32]) -> (ChannelState, Result<Message, String>) {
+        self.command(|c| c.fulfill_htlc(id, payment_preimage).map(Message::UpdateFulfillHtlc))
+    }
+
+    pub fn fail_htlc(self, id: u64, reason: Vec<u8>) -> (ChannelState, Result<Message, String>) {
+        self.command(|c| c.fail_htlc(id, reason).map(Message::UpdateFailHtlc))
+    }
+
+    // the HTLCs are still resolved after `shutdown`, but no new HTLCs are offered
+    // as the funder we change the fee rate of the commitments
+    pub fn update_fee(self, fee_rate: u32) -> (ChannelState, Result<Message, String>) {
+        self.command(|c| c.update_fee(fee_rate).map(Message::UpdateFee))
+    }
+
+    // we are the funder and the fee rate of the channel is too far from the estimate
+    pub fn needs_fee_update(&self, estimate: u32) -> bool {
+        match self {
+            &ChannelState::Ready(ref data) => data.commitments.needs_fee_update(estimate),
+            &ChannelState::Closing(ref data) => data.commitments.needs_fee_update(estimate),
+            _ => false,
+        }
+    }
+
+    // the peer revoked its commitment, the node backs up the justice to the watchtowers
+    pub fn breach_remedy(&self, wallet: &mut dyn FundingWallet) -> Option<BreachRemedy> {
+        let commitments = self.commitments()?;
+        let destination = sweep_destination(commitments, wallet)?;
+        commitments.breach_remedy(destination)
+            .map(|(commitment_tx, justice_tx)| BreachRemedy {
+                commitment_txid: commitment_tx.txid(),
+                justice_tx: justice_tx,
+            })
+    }
+
+    pub(crate) fn commitments(&self) -> Option<&Commitments> {
+        match self {
+            &ChannelState::Ready(ref data) => Some(&data.commitments),
+            &ChannelState::Closing(ref data) => Some(&data.commitments),
+            _ => None,
+        }
+    }
+
+    fn command<F>(self, f: F) -> (ChannelState, Result<Message, String>)
+    where
+        F: FnOnce(&mut Commitments) -> Result<Message, String>,
+    {
+        match self {
+            ChannelState::Ready(mut st) => {
+                let result = f(&mut st.commitments);
+                (ChannelState::Ready(st), result)
+            },
+            ChannelState::Closing(mut st) => {
+                let result = f(&mut st.commitments);
+                (ChannelState::Closing(st), result)
+            },
+            st => (st, Err("the channel is not operating".to_owned())),
+        }
+    }
+
+    // Broadcasts our latest commitment, the peer is not involved, it is refused while the HTLCs are pending,
+    // the node should report the confirmations of the returned transaction
+    pub fn force_close(self, wallet: &mut dyn FundingWallet) -> (ChannelState, Result<sha256d::Hash, String>) {
+        let pending = match &self {
+            &ChannelState::Ready(ref st) => st.commitments.has_pending_htlcs(),
+            &ChannelState::Closing(ref st) => st.has_pending_htlcs(),
+            _ => false,
+        };
+        // the HTLC outputs of the commitment are not swept, their funds would be lost
+        if pending {
+            return (self, Err("the channel has pending HTLCs".to_owned()));
+        }
+        let data = match self {
+            ChannelState::Ready(st) => ForceClosingState::new(st.channel_id, st.commitments, wallet),
+            ChannelState::Closing(st) => st.force_close(wallet),
+            st @ ChannelState::ForceClosing(_) => return (st, Err("the channel is already force closing".to_owned())),
+            st => return (st, Err("the channel is not operating".to_owned())),
+        };
+        let txid = data.commitment_txid();
+        (ChannelState::ForceClosing(data), Ok(txid))
+    }
+
+    // The transaction got the given number of confirmations, the message should be sent to the peer,
+    // the transaction is at `tx_index` in the block at `block_height`
+    pub fn confirmed(
+        self,
+        transaction: &Transaction,
+        block_height: u32,
+        tx_index: u32,
+        confirmations: u32,
+        wallet: &mut dyn FundingWallet,
+    ) -> (ChannelState, Option<Message>) {
+        let txid = transaction.txid();
+        match self {
+            ChannelState::Opening(OpeningState::WaitFundingLocked(st)) => if st.funding_tx_id.eq(&txid) {
+                st.handle_confirmations(transaction, block_height, tx_index, confirmations)
+            } else {
+                (ChannelState::Opening(OpeningState::WaitFundingLocked(st)), None)
+            },
+            ChannelState::Ready(mut st) => {
+                if st.commitments.funding_outpoint().0.eq(&txid) {
+                    if let Some(ref mut announcement) = st.announcement {
+                        announcement.handle_confirmations(confirmations);
+                    }
+                }
+                (ChannelState::Ready(st), None)
+            },
+            ChannelState::ForceClosing(st) => if st.commitment_txid().eq(&txid) {
+                (st.handle_confirmations(confirmations, wallet), None)
+            } else {
+                (ChannelState::ForceClosing(st), None)
+            },
+            ChannelState::PunishingPartner(st) => if st.justice_txids().contains(&txid) {
+                (st.handle_confirmations(&txid, confirmations), None)
+            } else {
+                (ChannelState::PunishingPartner(st), None)
+            },
+            st => (st, None),
+        }
+    }
+
+    // Our `announce_signatures` and the announcement of the public channel when both sides signed it,
+    // the node should call it after the channel's messages and confirmations are handled,
+    // the channel does not know the node keys
+    pub fn announce(self, node_secret: &SecretKey, their_node_id: &PublicKey) -> (ChannelState, Option<Message>, Option<ChannelAnnouncement>) {
+        match self {
+            ChannelState::Ready(mut st) => {
+                let (message, announcement) = match st.announcement {
+                    Some(ref mut announcement) => announcement.announce(st.channel_id, &st.commitments, node_secret, their_node_id),
+                    None => (None, None),
+                };
+                (ChannelState::Ready(st), message, announcement)
+            },
+            st => (st, None, None),
+        }
+    }
+
+    // we initiate the cooperative close, the message should be sent to the peer
+    pub fn shutdown(self, wallet: &mut dyn FundingWallet) -> (ChannelState, Result<Message, String>) {
+        match self {
+            ChannelState::Ready(st) => {
+                // BOLT 2: the peer's commitment should include all our updates
+                if st.commitments.has_unsigned_updates() {
+                    return (ChannelState::Ready(st), Err("the updates are not signed yet, try later".to_owned()));
+                }
+                let our_script = match st.our_shutdown_script(wallet) {
+                    Ok(script) => script,
+                    Err(description) => return (ChannelState::Ready(st), Err(description)),
+                };
+                let closing = ClosingState::new(st.channel_id, st.commitments, our_script, None);
+                let message = closing.shutdown_message();
+                (ChannelState::Closing(closing), Ok(message))
+            },
+            st @ ChannelState::Closing(_) => (st, Err("the channel is already closing".to_owned())),
+            st => (st, Err("the channel is not operating".to_owned())),
+        }
+    }
+
+    // Signs the peer's commitment if it lacks some updates, or proposes the closing fee,
+    // should be called after the updates are sent or the peer's messages are handled
+    pub fn commit(self) -> (ChannelState, Option<Message>) {
+        match self {
+            ChannelState::Ready(mut st) => {
+                let message = st.commitments.sign_commitment().map(Message::CommitmentSigned);
+                (ChannelState::Ready(st), message)
+            },
+            ChannelState::Closing(st) => st.commit(),
+            st => (st, None),
+        }
+    }
+}
+
+impl ReadyState {
+    // the peer's update or commitment, the channel fails if it is invalid
+    fn handle_update<F>(mut self, wallet: &mut dyn FundingWallet, f: F) -> (ChannelState, Option<Message>)
+    where
+        F: FnOnce(&mut Commitments) -> Result<Option<Message>, String>,
+    {
+        match f(&mut self.commitments) {
+            Ok(response) => (ChannelState::Ready(self), response),
+            Err(description) => fail_channel(self.channel_id, self.commitments, description, wallet),
+        }
+    }
+
+    // BOLT 7: the signatures of the private channel or for other channel are ignored
+    fn handle_announce_signatures_msg(mut self, msg: AnnounceSignatures) -> (ChannelState, Option<Message>) {
+        let result = match self.announcement {
+            Some(ref mut announcement) => announcement.handle_announce_signatures_msg(msg),
+            None => Err("announce_signatures for the private channel".to_owned()),
+        };
+        if let Err(description) = result {
+            println!("WARNING: channel {:?}: {}, ignoring", self.channel_id, description);
+        }
+        (ChannelState::Ready(self), None)
+    }
+
+    fn our_shutdown_script(&self, wallet: &mut dyn FundingWallet) -> Result<Script, String> {
+        match self.commitments.our_upfront_shutdown_script().or(self.commitments.sweep_script()) {
+            Some(script) => Ok(script.clone()),
+            None => {
+                let script = wallet.shutdown_script()?;
+                check_shutdown_script(&script, None)?;
+                Ok(script)
+            },
+        }
+    }
+
+    // the peer initiates the cooperative close, we reply with our `shutdown`
+    // when the peer's commitment includes all our updates
+    fn handle_shutdown_msg(self, msg: ShutdownChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        let their_script = Script::from(msg.script);
+        if let Err(description) = check_shutdown_script(&their_script, self.commitments.their_upfront_shutdown_script()) {
+            return fail_channel(self.channel_id, self.commitments, description, wallet);
+        }
+        let our_script = match self.our_shutdown_script(wallet) {
+            Ok(script) => script,
+            Err(description) => {
+                let description = format!("cannot close the channel: {}", description);
+                return fail_channel(self.channel_id, self.commitments, description, wallet);
+            },
+        };
+        ClosingState::new(self.channel_id, self.commitments, our_script, Some(their_script)).reply_shutdown()
+    }
 }
 
 impl InitialState {
-    fn handle_open_channel_msg(self, msg: OpenChannel) -> (ChannelState, Option<Message>) {
+    fn handle_open_channel_msg(self, msg: OpenChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        if let Err(description) = self.policy.check_open_channel(&msg, self.anchors) {
+            return fail(msg.temporary_channel_id, description);
+        }
         let their_info = PartnerInfo::from_open_channel_msg(&msg);
         let mut our_info = PartnerInfo::new_random();
-        our_info.config = their_info.config.clone();
+        if self.static_remotekey {
+            match wallet.payment_key() {
+                Ok(payment_key) => our_info.set_payment_key(payment_key),
+                Err(description) => return fail(msg.temporary_channel_id, format!("no payment key: {}", description)),
+            }
+        }
+        match wallet.shutdown_script() {
+            Ok(script) => our_info.sweep_script = Some(script),
+            Err(description) => return fail(msg.temporary_channel_id, format!("no sweep script: {}", description)),
+        }
+        our_info.config = self.policy.our_config(&msg);
         let accept_channel_msg = AcceptChannel {
             temporary_channel_id: msg.temporary_channel_id.clone(),
-            dust_limit: Satoshi::from(their_info.config.dust_limit),
-            max_htlc_value_in_flight: MilliSatoshi::from(their_info.config.max_htlc_value_in_flight),
-            chanel_reserve: Satoshi::from(their_info.config.chanel_reserve),
-            htlc_minimum: MilliSatoshi::from(their_info.config.htlc_minimum),
-            minimum_accept_depth: 1,
-            csv_delay: CsvDelay::from(their_info.config.csv_delay),
-            max_accepted_htlc_number: their_info.config.max_accepted_htlc_number,
+            dust_limit: Satoshi::from(our_info.config.dust_limit),
+            max_htlc_value_in_flight: MilliSatoshi::from(our_info.config.max_htlc_value_in_flight),
+            chanel_reserve: Satoshi::from(our_info.config.chanel_reserve),
+            htlc_minimum: MilliSatoshi::from(our_info.config.htlc_minimum),
+            minimum_accept_depth: self.policy.minimum_depth,
+            csv_delay: CsvDelay::from(our_info.config.csv_delay),
+            max_accepted_htlc_number: our_info.config.max_accepted_htlc_number,
             keys: our_info.keys.clone(),
+            shutdown_script: our_info.shutdown_script_field(),
         };
+        let mut funding = FundingInfo::from_open_channel_msg(&msg);
+        funding.static_remotekey = self.static_remotekey;
+        funding.anchors = self.anchors;
         let data = WaitFundingCreatedData {
             our_info,
             their_info,
             temp_channel_id: msg.temporary_channel_id.into(),
-            funding: FundingInfo::from_open_channel_msg(&msg)
+            funding: funding,
+            minimum_depth: self.policy.minimum_depth,
         };
         (
             ChannelState::Opening(OpeningState::WaitFundingCreated(data)),
@@ -300,71 +1118,181 @@ impl InitialState {
     }
 }
 
-impl WaitFundingCreatedData {
-    fn handle_funding_created_msg(self, msg: FundingCreated) -> (ChannelState, Option<Message>) {
-        let local_htlc_pubkey = self.their_info.htlc_pubkey();
-        let remote_htlc_pubkey = self.our_info.htlc_pubkey();
-
-        let local_revocation_pubkey = self.their_info.revocation_pubkey(&self.our_info.keys.revocation());
+impl WaitAcceptChannelData {
+    fn check_accept_channel_msg(&self, msg: &AcceptChannel) -> Result<(), String> {
+        let our_config = &self.our_info.config;
+        if msg.minimum_accept_depth > MAX_MINIMUM_DEPTH {
+            return Err(format!("minimum depth {} is too large", msg.minimum_accept_depth));
+        }
+        if u16::from(msg.csv_delay) > MAX_CSV_DELAY {
+            return Err(format!("to self delay {} is too large", u16::from(msg.csv_delay)));
+        }
+        if msg.max_accepted_htlc_number > MAX_ACCEPTED_HTLC_NUMBER {
+            return Err(format!("max accepted htlcs {} is greater than {}", msg.max_accepted_htlc_number, MAX_ACCEPTED_HTLC_NUMBER));
+        }
+        if u64::from(msg.chanel_reserve) < our_config.dust_limit {
+            return Err(format!("channel reserve {:?} is less than our dust limit", msg.chanel_reserve));
+        }
+        if u64::from(msg.dust_limit) > our_config.chanel_reserve {
+            return Err(format!("dust limit {:?} is greater than our channel reserve", msg.dust_limit));
+        }
+        check_upfront_shutdown_script(&msg.shutdown_script)?;
+        Ok(())
+    }
 
-        let local_delayed_pubkey = self.their_info.delayed_pubkey();
+    fn handle_accept_channel_msg(self, msg: AcceptChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        if msg.temporary_channel_id != self.temp_channel_id {
+            return fail(self.temp_channel_id, "wrong temporary channel id".to_owned());
+        }
+        if let Err(description) = self.check_accept_channel_msg(&msg) {
+            return fail(self.temp_channel_id, description);
+        }
+        let their_info = PartnerInfo::from_accept_channel_msg(&msg, self.our_info.config.local_fee_rate);
 
-        let remote_pubkey = self.our_info.payment_pubkey();
+        let script_pubkey = new_2x2_wsh_lock_script(
+            &self.our_info.keys.funding().serialize(),
+            &their_info.keys.funding().serialize(),
+        );
+        let funding_tx = match wallet.fund(script_pubkey.clone(), self.funding.funding) {
+            Ok(tx) => tx,
+            Err(description) => return fail(self.temp_channel_id, format!("cannot fund the channel: {}", description)),
+        };
+        let funding_output_index = match funding_tx.output.iter().position(|o| o.script_pubkey == script_pubkey && o.value == self.funding.funding) {
+            Some(index) => index as u16,
+            None => return fail(self.temp_channel_id, "the funding transaction does not pay to the channel".to_owned()),
+        };
+        let funding_tx_id = funding_tx.txid();
+        let funding_txid = FundingTxid::from_sha256d(&funding_tx_id);
 
         let obscuring_factor = get_obscuring_number(
-            &self.their_info.keys.payment().serialize(),
-            &self.our_info.keys.payment().serialize()
+            &self.our_info.keys.payment().serialize(),
+            &their_info.keys.payment().serialize()
         );
 
-        let commit_tx = CommitTx {
-            funding_amount: self.funding.funding as i64,
-            local_funding_pubkey: self.their_info.keys.funding().clone(),
-            remote_funding_pubkey: self.our_info.keys.funding().clone(),
+        // we sign the partner's commitment transaction
+        let their_commit_tx = first_commitment(
+            &their_info, &self.our_info, &self.funding, false,
+            obscuring_factor, funding_tx_id, funding_output_index,
+        );
+        let sig = match their_commit_tx.sign(self.our_info.private_keys.clone().unwrap().funding_sk()) {
+            Ok(sig) => sig,
+            Err(description) => return fail(self.temp_channel_id, format!("cannot sign the first commitment: {}", description)),
+        };
 
-            local_feerate_per_kw: u32::from(self.their_info.config.local_fee_rate) as i64,
-            dust_limit_satoshi: u64::from(self.their_info.config.dust_limit) as i64,
+        let funding_created = FundingCreated {
+            temporary_channel_id: self.temp_channel_id,
+            funding_txid: funding_txid,
+            output_index: OutputIndex::from_u16(funding_output_index),
+            signature: RawSignature(sig),
+        };
+        let data = WaitFundingSignedData {
+            temp_channel_id: self.temp_channel_id,
+            channel_id: derive_channel_id(&funding_txid, funding_output_index),
+            our_info: self.our_info,
+            their_info: their_info,
+            obscuring_factor: obscuring_factor,
+            funding: self.funding,
+            funding_tx: funding_tx,
+            funding_output_index: funding_output_index,
+            minimum_depth: msg.minimum_accept_depth,
+        };
+        (
+            ChannelState::Opening(OpeningState::WaitFundingSigned(data)),
+            Some(Message::FundingCreated(funding_created))
+        )
+    }
+}
 
-            to_local_msat: (1000 * self.funding.funding) as i64,
-            to_remote_msat: self.funding.push as i64,
+impl WaitFundingSignedData {
+    fn handle_funding_signed_msg(self, msg: FundingSigned, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        if msg.channel_id != self.channel_id {
+            return fail(self.channel_id, "wrong channel id".to_owned());
+        }
 
-            obscured_commit_number: 0 ^ obscuring_factor,
+        // the partner signs our commitment transaction, check it before broadcasting the funding
+        let funding_tx_id = self.funding_tx.txid();
+        let our_commit_tx = first_commitment(
+            &self.our_info, &self.their_info, &self.funding, true,
+            self.obscuring_factor, funding_tx_id, self.funding_output_index,
+        );
+        if !our_commit_tx.verify(&msg.signature.0, self.their_info.keys.funding()) {
+            return fail(self.channel_id, "wrong signature of the commitment transaction".to_owned());
+        }
 
-            local_htlc_pubkey: local_htlc_pubkey,
-            remote_htlc_pubkey: remote_htlc_pubkey,
+        if let Err(description) = wallet.publish(&self.funding_tx) {
+            return fail(self.channel_id, format!("cannot broadcast the funding transaction: {}", description));
+        }
+        println!("INFO: funding transaction {} of channel {:?} is broadcast", funding_tx_id, self.channel_id);
 
-            local_revocation_pubkey: local_revocation_pubkey,
-            local_delayedpubkey: local_delayed_pubkey,
-            local_delay: self.their_info.config.csv_delay.into(),
+        let data = WaitFundingLockedData {
+            temp_channel_id: self.temp_channel_id,
+            channel_id: self.channel_id,
+            our_info: self.our_info,
+            their_info: self.their_info,
+            obscuring_factor: self.obscuring_factor,
+            funding: self.funding,
+            funding_tx_id: funding_tx_id,
+            funding_output_index: self.funding_output_index,
+            minimum_depth: self.minimum_depth,
+            confirmations: 0,
+            short_channel_id: None,
+            local_is_funder: true,
+            our_commit_signature: msg.signature,
+            their_next_point: None,
+        };
+        (ChannelState::Opening(OpeningState::WaitFundingLocked(data)), None)
+    }
+}
+
+impl WaitFundingCreatedData {
+    fn handle_funding_created_msg(self, msg: FundingCreated) -> (ChannelState, Option<Message>) {
+        let obscuring_factor = get_obscuring_number(
+            &self.their_info.keys.payment().serialize(),
+            &self.our_info.keys.payment().serialize()
+        );
 
-            remotepubkey: remote_pubkey,
+        let funding_tx_id = msg.funding_txid.to_sha256d();
+        let funding_output_index = u16::from(msg.output_index);
 
-            funding_tx_id: msg.funding_txid.to_sha256d(),
-            funding_output_index: u16::from(msg.output_index) as u32,
+        // the partner signs our commitment transaction
+        let our_commit_tx = first_commitment(
+            &self.our_info, &self.their_info, &self.funding, false,
+            obscuring_factor, funding_tx_id, funding_output_index,
+        );
+        if !our_commit_tx.verify(&msg.signature.0, self.their_info.keys.funding()) {
+            return fail(self.temp_channel_id, "wrong signature of the commitment transaction".to_owned());
+        }
 
-            htlcs: vec![],
+        // we sign the partner's commitment transaction
+        let their_commit_tx = first_commitment(
+            &self.their_info, &self.our_info, &self.funding, true,
+            obscuring_factor, funding_tx_id, funding_output_index,
+        );
+        let sig = match their_commit_tx.sign(self.our_info.private_keys.clone().unwrap().funding_sk()) {
+            Ok(sig) => sig,
+            Err(description) => return fail(self.temp_channel_id, format!("cannot sign the first commitment: {}", description)),
         };
-        let sig = commit_tx.sign(self.our_info.private_keys.clone().unwrap().funding_sk());
-        let tx = commit_tx.get_tx();
-        let mut a = vec![];
-        tx.consensus_encode(&mut a).unwrap();
-
-        let mut channel_id = <[u8; 32]>::from(msg.funding_txid);
-        let ind = u16::from(msg.output_index);
-        channel_id[0] ^= (ind & 0xFF) as u8;
-        channel_id[1] ^= (ind >> 8) as u8;
+
+        let channel_id = derive_channel_id(&msg.funding_txid, funding_output_index);
         let funding_signed = FundingSigned {
-            channel_id: ChannelId::from(channel_id),
+            channel_id: channel_id,
             signature: RawSignature(sig),
         };
         let data = WaitFundingLockedData {
             temp_channel_id: self.temp_channel_id,
-            channel_id: channel_id.into(),
+            channel_id: channel_id,
             funding: self.funding,
             our_info: self.our_info,
             obscuring_factor,
             their_info: self.their_info,
-            funding_tx_id: msg.funding_txid.to_sha256d(),
-            funding_output_index: msg.output_index.into(),
+            funding_tx_id: funding_tx_id,
+            funding_output_index: funding_output_index,
+            minimum_depth: self.minimum_depth,
+            confirmations: 0,
+            short_channel_id: None,
+            local_is_funder: false,
+            our_commit_signature: msg.signature,
+            their_next_point: None,
         };
         (
             ChannelState::Opening(OpeningState::WaitFundingLocked(data)),
@@ -374,23 +1302,137 @@ impl WaitFundingCreatedData {
 }
 
 impl WaitFundingLockedData {
-    fn handle_funding_locked_msg(self, msg: FundingLocked) -> (ChannelState, Option<Message>) {
-        // TODO(mkl): check blockchain
-        let funding_locked = FundingLocked {
-            channel_id: msg.channel_id,
-            next_per_commitment_point: msg.next_per_commitment_point,
+    // no commitment is revoked yet
+    fn reestablish_message(&self) -> Message {
+        Message::ReestablishChannel(ReestablishChannel {
+            channel_id: self.channel_id,
+            next_local_commitment_number: 1,
+            next_remote_revocation_number: 0,
+            last_remote_commit_secret: [0; 32],
+            local_unrevoked_commit_point: RawPublicKey::from(self.our_info.keys.first_per_commitment().clone()),
+        })
+    }
+
+    // the funding transaction is deep enough, our `FundingLocked` is sent
+    fn locked(&self) -> bool {
+        self.confirmations > 0 && self.confirmations >= self.minimum_depth
+    }
+
+    fn funding_locked_message(&self) -> Message {
+        let next_point = per_commitment_point(self.our_info.per_commitment_seed.as_ref().unwrap(), 1);
+        Message::FundingLocked(FundingLocked {
+            channel_id: self.channel_id,
+            next_per_commitment_point: next_point.into(),
+        })
+    }
+
+    // the funding transaction should pay the funding amount to the 2-of-2 multisig of the funding keys
+    fn check_funding_output(&self, transaction: &Transaction) -> Result<(), String> {
+        let script_pubkey = new_2x2_wsh_lock_script(
+            &self.our_info.keys.funding().serialize(),
+            &self.their_info.keys.funding().serialize(),
+        );
+        match transaction.output.get(self.funding_output_index as usize) {
+            Some(output) if output.script_pubkey == script_pubkey && output.value == self.funding.funding => Ok(()),
+            _ => Err(format!(
+                "the funding transaction {} does not pay {} satoshi to the channel",
+                self.funding_tx_id, self.funding.funding,
+            )),
+        }
+    }
+
+    fn handle_confirmations(mut self, transaction: &Transaction, block_height: u32, tx_index: u32, confirmations: u32) -> (ChannelState, Option<Message>) {
+        if self.locked() {
+            self.confirmations = confirmations;
+            return (ChannelState::Opening(OpeningState::WaitFundingLocked(self)), None);
+        }
+        if let Err(description) = self.check_funding_output(transaction) {
+            return fail(self.channel_id, description);
+        }
+
+        self.confirmations = confirmations;
+        self.short_channel_id = Some(ShortChannelId {
+            block_height: block_height,
+            tx_index: tx_index,
+            tx_position: self.funding_output_index,
+        });
+        if !self.locked() {
+            println!(
+                "INFO: funding transaction of channel {:?} has {} of {} confirmations",
+                self.channel_id, confirmations, self.minimum_depth,
+            );
+            return (ChannelState::Opening(OpeningState::WaitFundingLocked(self)), None);
+        }
+        println!("INFO: funding transaction of channel {:?} is locked", self.channel_id);
+        let funding_locked = self.funding_locked_message();
+        match self.their_next_point.take() {
+            Some(their_next_point) => (self.into_ready(their_next_point), Some(funding_locked)),
+            None => (ChannelState::Opening(OpeningState::WaitFundingLocked(self)), Some(funding_locked)),
+        }
+    }
+
+    // the channel operates when both sides sent `FundingLocked`
+    fn handle_funding_locked_msg(mut self, msg: FundingLocked) -> (ChannelState, Option<Message>) {
+        if msg.channel_id != self.channel_id {
+            return fail(self.channel_id, "wrong channel id".to_owned());
+        }
+        let their_next_point = msg.next_per_commitment_point.as_ref().clone();
+        if self.locked() {
+            (self.into_ready(their_next_point), None)
+        } else {
+            self.their_next_point = Some(their_next_point);
+            (ChannelState::Opening(OpeningState::WaitFundingLocked(self)), None)
+        }
+    }
+
+    fn into_ready(self, their_next_point: PublicKey) -> ChannelState {
+        // the funding is confirmed, so its position is known
+        let announcement = match (self.funding.announce, self.short_channel_id) {
+            (true, Some(short_channel_id)) => Some(AnnouncementState::new(self.funding.chain_hash, short_channel_id, self.confirmations)),
+            _ => None,
         };
-        let data = ReadyState {
+        ChannelState::Ready(ReadyState {
             channel_id: self.channel_id,
-            our_info: self.our_info,
-            their_info: self.their_info,
+            commitments: self.into_commitments(their_next_point),
+            announcement: announcement,
+        })
+    }
+
+    // our first commitment is signed by the peer, so it can be broadcast
+    fn into_commitments(self, their_next_point: PublicKey) -> Commitments {
+        let funder_msat = 1000 * self.funding.funding - self.funding.push;
+        let (our_balance, their_balance) = if self.local_is_funder {
+            (funder_msat, self.funding.push)
+        } else {
+            (self.funding.push, funder_msat)
+        };
+        let funding = FundingOutput {
+            amount: self.funding.funding,
+            tx_id: self.funding_tx_id,
+            output_index: self.funding_output_index,
             obscuring_factor: self.obscuring_factor,
-            funding_tx_id: self.funding_tx_id,
-            funding_output_index: self.funding_output_index,
+            static_remotekey: self.funding.static_remotekey,
+            anchors: self.funding.anchors,
         };
-        (
-            ChannelState::Ready(data),
-            Some(Message::FundingLocked(funding_locked))
+        Commitments::new(
+            self.channel_id,
+            self.our_info,
+            self.their_info,
+            funding,
+            self.local_is_funder,
+            our_balance,
+            their_balance,
+            self.our_commit_signature,
+            their_next_point,
         )
     }
+
+    // the peer failed the channel before `funding_locked`, the peer's next point
+    // is not needed to broadcast our commitment, its first point is taken if the next one is unknown
+    fn fail(mut self, msg: Error, wallet: &mut dyn FundingWallet) -> ChannelState {
+        let channel_id = self.channel_id;
+        let their_next_point = self.their_next_point.take()
+            .unwrap_or(self.their_info.keys.first_per_commitment().clone());
+        failed_by_peer(channel_id, self.into_commitments(their_next_point), msg, wallet)
+    }
 }
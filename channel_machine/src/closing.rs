@@ -0,0 +1,754 @@
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+use dependencies::secp256k1;
+
+use bitcoin::{Transaction, Script};
+use bitcoin_hashes::sha256d;
+use secp256k1::PublicKey;
+
+use serde_derive::{Serialize, Deserialize};
+
+use wire::{Message, ChannelId, ShutdownChannel, ClosingSigned, Satoshi, RawSignature, ReestablishChannel, Error};
+
+use crate::b_box::{ChannelState, FundingWallet, fail_channel, failed_by_peer, error_message};
+use crate::commitment::{Commitments, Resync, Spend};
+
+// BOLT 2: the `scriptpubkey` of `shutdown` is P2PKH, P2SH, P2WPKH or P2WSH,
+// it should be the same as the upfront shutdown script, if there is one
+pub(crate) fn check_shutdown_script(script: &Script, upfront: Option<&Script>) -> Result<(), String> {
+    if !(script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wpkh() || script.is_v0_p2wsh()) {
+        return Err(format!("shutdown script {:?} is not standard", script));
+    }
+    match upfront {
+        Some(upfront) if upfront.ne(script) => Err(format!("shutdown script {:?} is not the upfront script {:?}", script, upfront)),
+        _ => Ok(()),
+    }
+}
+
+// the justice transaction this deep is final
+pub(crate) const JUSTICE_DEPTH: u32 = 6;
+
+// the channel's script for our funds, they are swept there when the channel is closed,
+// the wallet is asked for the channels opened before the script is kept
+pub(crate) fn sweep_destination(commitments: &Commitments, wallet: &mut dyn FundingWallet) -> Option<Script> {
+    if let Some(script) = commitments.sweep_script() {
+        return Some(script.clone());
+    }
+    wallet.shutdown_script()
+        .map_err(|description| println!("ERROR: cannot sweep the output of channel {:?}: {}", commitments.channel_id(), description))
+        .ok()
+}
+
+// the peer broadcast its commitment, our output is not delayed and is swept at once,
+// `false` if the sweep is not broadcast
+fn sweep_their_commitment(
+    channel_id: ChannelId,
+    commitments: &Commitments,
+    commitment_tx: &Transaction,
+    their_point: &PublicKey,
+    wallet: &mut dyn FundingWallet,
+) -> bool {
+    let destination = match sweep_destination(commitments, wallet) {
+        Some(script) => script,
+        None => return false,
+    };
+    match commitments.to_remote_sweep(commitment_tx, their_point, destination) {
+        Some((sweep_tx, payment_sk)) => {
+            let sweep = sweep_tx.signed_tx(&sweep_tx.sign(&payment_sk));
+            if let Err(description) = wallet.publish(&sweep) {
+                println!("ERROR: cannot broadcast the sweep transaction {}: {}", sweep.txid(), description);
+                return false;
+            }
+            println!("INFO: the output of channel {:?} is recovered by {}", channel_id, sweep.txid());
+        },
+        None => println!("WARNING: the commitment {} of channel {:?} has no output to recover", commitment_tx.txid(), channel_id),
+    }
+    true
+}
+
+// The funding output of the operating channel is spent. The peer's revoked commitment is punished,
+// our output of the peer's current commitment is swept, our revoked commitment is lost. `None` if the channel is left as it is:
+// our commitment is handled by force closing, the mutual close by closing
+pub(crate) fn handle_spend(
+    channel_id: ChannelId,
+    commitments: &Commitments,
+    tx: &Transaction,
+    wallet: &mut dyn FundingWallet,
+) -> Option<ChannelState> {
+    match commitments.classify_spend(tx) {
+        Spend::Revoked(number) => {
+            println!("WARNING: the peer broadcast the revoked commitment {} {} of channel {:?}", number, tx.txid(), channel_id);
+            let destination = sweep_destination(commitments, wallet)?;
+            let justice_txs = commitments.justice(tx, number, destination.clone());
+            if justice_txs.is_empty() {
+                println!("ERROR: nothing to take from the revoked commitment {} of channel {:?}", tx.txid(), channel_id);
+                return Some(ChannelState::Robbed(ClosedState {
+                    channel_id: channel_id,
+                    closing_tx: tx.clone(),
+                }));
+            }
+            let data = PunishingState {
+                channel_id: channel_id,
+                commitments: commitments.clone(),
+                number: number,
+                commitment_tx: tx.clone(),
+                destination: destination,
+                justice_txs: justice_txs,
+            };
+            data.rebroadcast(wallet);
+            Some(ChannelState::PunishingPartner(data))
+        },
+        Spend::Remote(their_point) => {
+            println!("INFO: the peer broadcast the commitment {} of channel {:?}", tx.txid(), channel_id);
+            if !sweep_their_commitment(channel_id, commitments, tx, &their_point, wallet) {
+                return None;
+            }
+            Some(ChannelState::Closed(ClosedState {
+                channel_id: channel_id,
+                closing_tx: tx.clone(),
+            }))
+        },
+        Spend::LocalRevoked(number) => {
+            println!("ERROR: our revoked commitment {} {} of channel {:?} is broadcast", number, tx.txid(), channel_id);
+            Some(ChannelState::PunishedByPartner(ClosedState {
+                channel_id: channel_id,
+                closing_tx: tx.clone(),
+            }))
+        },
+        Spend::Local | Spend::Other => None,
+    }
+}
+
+// Channel closing, either side sends `shutdown` first
+// We --- Shutdown ---> Partner
+// We <-- Shutdown ---  Partner
+// ...... the HTLCs are fulfilled or failed, no new HTLCs
+// We --- ClosingSigned ---> Partner    the funder proposes the fee first
+// We <-- ClosingSigned ---  Partner    until both sign the same fee
+// ...... broadcast the closing transaction
+
+/// The channel after `shutdown`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClosingState {
+    channel_id: ChannelId,
+    pub(crate) commitments: Commitments,
+    #[serde(with = "crate::codec::script")]
+    our_script: Script,
+    // unknown until the peer's `shutdown`
+    #[serde(with = "crate::codec::option_script")]
+    their_script: Option<Script>,
+    // we owe the peer our `shutdown`, it is sent when the peer's commitment includes all our updates
+    #[serde(default)]
+    shutdown_pending: bool,
+    // the fees of the latest `closing_signed` of each side
+    our_fee: Option<u64>,
+    their_fee: Option<u64>,
+}
+
+/// Our latest commitment is broadcast, our funds are locked until it is
+/// buried under `to_self_delay` blocks, then they are swept to the wallet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForceClosingState {
+    channel_id: ChannelId,
+    commitments: Commitments,
+    #[serde(with = "crate::codec::transaction")]
+    commitment_tx: Transaction,
+}
+
+/// We lost the latest state of the channel, our commitment is revoked and must not be broadcast.
+/// The peer is asked to broadcast its commitment, our output of it is swept with the peer's point
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataLossState {
+    channel_id: ChannelId,
+    commitments: Commitments,
+    // the per commitment point of the peer's latest commitment
+    #[serde(with = "crate::codec::public_key")]
+    their_point: PublicKey,
+}
+
+/// The peer broadcast its revoked commitment, each of its outputs is taken by a justice transaction,
+/// the peer's HTLC transactions are punished too, we wait for all the justice to be confirmed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PunishingState {
+    channel_id: ChannelId,
+    commitments: Commitments,
+    // the number of the revoked commitment
+    number: u64,
+    #[serde(with = "crate::codec::transaction")]
+    commitment_tx: Transaction,
+    #[serde(with = "crate::codec::script")]
+    destination: Script,
+    // not deep enough yet, they are broadcast again on every block
+    #[serde(with = "crate::codec::transactions")]
+    justice_txs: Vec<Transaction>,
+}
+
+/// The closing transaction is broadcast, or our commitment and the sweep of our output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClosedState {
+    channel_id: ChannelId,
+    #[serde(with = "crate::codec::transaction")]
+    closing_tx: Transaction,
+}
+
+impl ClosedState {
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub fn closing_txid(&self) -> sha256d::Hash {
+        self.closing_tx.txid()
+    }
+}
+
+impl DataLossState {
+    // the error asks the peer to close the channel
+    pub(crate) fn new(channel_id: ChannelId, commitments: Commitments, their_point: PublicKey) -> (ChannelState, Vec<Message>) {
+        let message = error_message(channel_id, "our channel state is outdated, please close the channel");
+        let data = DataLossState {
+            channel_id: channel_id,
+            commitments: commitments,
+            their_point: their_point,
+        };
+        (ChannelState::DataLoss(data), vec![message])
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    // the peer's commitment spends it
+    pub fn funding_outpoint(&self) -> (sha256d::Hash, u32) {
+        self.commitments.funding_outpoint()
+    }
+
+    // the peer broadcast its commitment, our output is not delayed and is swept at once
+    pub(crate) fn handle_commitment(self, commitment_tx: &Transaction, wallet: &mut dyn FundingWallet) -> ChannelState {
+        if !sweep_their_commitment(self.channel_id, &self.commitments, commitment_tx, &self.their_point, wallet) {
+            return ChannelState::DataLoss(self);
+        }
+        ChannelState::Closed(ClosedState {
+            channel_id: self.channel_id,
+            closing_tx: commitment_tx.clone(),
+        })
+    }
+}
+
+impl PunishingState {
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub fn commitment_txid(&self) -> sha256d::Hash {
+        self.commitment_tx.txid()
+    }
+
+    pub fn justice_txids(&self) -> Vec<sha256d::Hash> {
+        self.justice_txs.iter().map(Transaction::txid).collect()
+    }
+
+    // the outputs spent by the justice, the peer may spend the HTLC outputs first
+    pub fn spent_outpoints(&self) -> Vec<(sha256d::Hash, u32)> {
+        self.justice_txs.iter()
+            .flat_map(|tx| tx.input.iter().map(|i| (i.previous_output.txid, i.previous_output.vout)))
+            .collect()
+    }
+
+    // the justice is not confirmed yet, the wallet may have failed or the transaction is evicted
+    pub(crate) fn rebroadcast(&self, wallet: &mut dyn FundingWallet) {
+        for justice_tx in self.justice_txs.iter() {
+            match wallet.publish(justice_tx) {
+                Ok(()) => println!("INFO: justice transaction {} of channel {:?} is broadcast", justice_tx.txid(), self.channel_id),
+                Err(description) => println!("ERROR: cannot broadcast the justice transaction {}: {}", justice_tx.txid(), description),
+            }
+        }
+    }
+
+    // The output is spent by the peer's HTLC transaction, its output is revoked too
+    // and is taken instead, the justice spending the output is dropped
+    pub(crate) fn handle_spend(mut self, tx: &Transaction, wallet: &mut dyn FundingWallet) -> ChannelState {
+        let txid = tx.txid();
+        if self.justice_txs.iter().any(|justice_tx| justice_tx.txid() == txid) {
+            return ChannelState::PunishingPartner(self);
+        }
+        let spent = tx.input.iter()
+            .map(|i| (i.previous_output.txid, i.previous_output.vout))
+            .collect::<Vec<_>>();
+        self.justice_txs.retain(|justice_tx| {
+            !justice_tx.input.iter().any(|i| spent.contains(&(i.previous_output.txid, i.previous_output.vout)))
+        });
+        match self.commitments.second_stage_justice(tx, self.number, self.destination.clone()) {
+            Some(justice_tx) => {
+                println!("WARNING: the peer spent the revoked HTLC of channel {:?} by {}", self.channel_id, txid);
+                match wallet.publish(&justice_tx) {
+                    Ok(()) => println!("INFO: justice transaction {} of channel {:?} is broadcast", justice_tx.txid(), self.channel_id),
+                    Err(description) => println!("ERROR: cannot broadcast the justice transaction {}: {}", justice_tx.txid(), description),
+                }
+                self.justice_txs.push(justice_tx);
+            },
+            None => println!("ERROR: the revoked output of channel {:?} is lost to {}", self.channel_id, txid),
+        }
+        self.punished()
+    }
+
+    pub(crate) fn handle_confirmations(mut self, txid: &sha256d::Hash, confirmations: u32) -> ChannelState {
+        if confirmations < JUSTICE_DEPTH {
+            return ChannelState::PunishingPartner(self);
+        }
+        self.justice_txs.retain(|justice_tx| justice_tx.txid().ne(txid));
+        self.punished()
+    }
+
+    // all the justice is final
+    fn punished(self) -> ChannelState {
+        if !self.justice_txs.is_empty() {
+            return ChannelState::PunishingPartner(self);
+        }
+        println!("INFO: the peer is punished for the breach of channel {:?}", self.channel_id);
+        ChannelState::PunishedPartner(ClosedState {
+            channel_id: self.channel_id,
+            closing_tx: self.commitment_tx,
+        })
+    }
+}
+
+impl ForceClosingState {
+    // the commitment is broadcast even if the wallet fails, the node should retry later
+    pub(crate) fn new(channel_id: ChannelId, commitments: Commitments, wallet: &mut dyn FundingWallet) -> Self {
+        let commitment_tx = commitments.signed_local_commitment();
+        match wallet.publish(&commitment_tx) {
+            Ok(()) => println!("INFO: commitment transaction {} of channel {:?} is broadcast", commitment_tx.txid(), channel_id),
+            Err(description) => println!("ERROR: cannot broadcast the commitment transaction {}: {}", commitment_tx.txid(), description),
+        }
+        ForceClosingState {
+            channel_id: channel_id,
+            commitments: commitments,
+            commitment_tx: commitment_tx,
+        }
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub fn commitment_txid(&self) -> sha256d::Hash {
+        self.commitment_tx.txid()
+    }
+
+    pub fn funding_outpoint(&self) -> (sha256d::Hash, u32) {
+        self.commitments.funding_outpoint()
+    }
+
+    // the peer's commitment may get into the chain before ours
+    pub(crate) fn handle_spend(self, tx: &Transaction, wallet: &mut dyn FundingWallet) -> ChannelState {
+        match handle_spend(self.channel_id, &self.commitments, tx, wallet) {
+            Some(state) => state,
+            None => ChannelState::ForceClosing(self),
+        }
+    }
+
+    // the commitment got one more confirmation, our output is swept when the delay expires,
+    // the HTLC outputs are not swept, so the force close is refused while the HTLCs are pending
+    pub(crate) fn handle_confirmations(self, confirmations: u32, wallet: &mut dyn FundingWallet) -> ChannelState {
+        if (confirmations as u64) < self.commitments.local_delay() {
+            return ChannelState::ForceClosing(self);
+        }
+
+        let destination = match sweep_destination(&self.commitments, wallet) {
+            Some(script) => script,
+            None => return ChannelState::ForceClosing(self),
+        };
+        match self.commitments.delayed_sweep(destination) {
+            Some((sweep_tx, delayed_sk)) => {
+                let sweep = sweep_tx.signed_tx(&sweep_tx.sign(&delayed_sk));
+                if let Err(description) = wallet.publish(&sweep) {
+                    println!("ERROR: cannot broadcast the sweep transaction {}: {}", sweep.txid(), description);
+                    return ChannelState::ForceClosing(self);
+                }
+                println!("INFO: the output of channel {:?} is swept by {}", self.channel_id, sweep.txid());
+            },
+            None => println!("INFO: channel {:?} has no output to sweep", self.channel_id),
+        }
+        ChannelState::Closed(ClosedState {
+            channel_id: self.channel_id,
+            closing_tx: self.commitment_tx,
+        })
+    }
+}
+
+impl ClosingState {
+    pub(crate) fn new(channel_id: ChannelId, commitments: Commitments, our_script: Script, their_script: Option<Script>) -> Self {
+        ClosingState {
+            channel_id: channel_id,
+            commitments: commitments,
+            our_script: our_script,
+            their_script: their_script,
+            shutdown_pending: false,
+            our_fee: None,
+            their_fee: None,
+        }
+    }
+
+    // BOLT 2: the reply to the peer's `shutdown` waits until there are no outstanding updates
+    pub(crate) fn reply_shutdown(mut self) -> (ChannelState, Option<Message>) {
+        if self.commitments.has_unsigned_updates() {
+            self.shutdown_pending = true;
+            (ChannelState::Closing(self), None)
+        } else {
+            let message = self.shutdown_message();
+            (ChannelState::Closing(self), Some(message))
+        }
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub fn funding_outpoint(&self) -> (sha256d::Hash, u32) {
+        self.commitments.funding_outpoint()
+    }
+
+    // the peer may broadcast a commitment instead of negotiating
+    pub(crate) fn handle_spend(self, tx: &Transaction, wallet: &mut dyn FundingWallet) -> ChannelState {
+        match handle_spend(self.channel_id, &self.commitments, tx, wallet) {
+            Some(state) => state,
+            None => ChannelState::Closing(self),
+        }
+    }
+
+    pub(crate) fn has_pending_htlcs(&self) -> bool {
+        self.commitments.has_pending_htlcs()
+    }
+
+    pub(crate) fn their_preimage(&self, id: u64) -> Option<[u8; 32]> {
+        self.commitments.their_preimage(id)
+    }
+
+    // the peer does not cooperate
+    pub(crate) fn force_close(self, wallet: &mut dyn FundingWallet) -> ForceClosingState {
+        ForceClosingState::new(self.channel_id, self.commitments, wallet)
+    }
+
+    pub(crate) fn shutdown_message(&self) -> Message {
+        Message::ShutdownChannel(ShutdownChannel {
+            channel_id: self.channel_id,
+            script: self.our_script.as_bytes().to_vec(),
+        })
+    }
+
+    fn closing_signed(&mut self, fee: u64) -> Message {
+        let their_script = self.their_script.clone().unwrap();
+        let closing_tx = self.commitments.closing_tx(&self.our_script, &their_script, fee);
+        self.our_fee = Some(fee);
+        Message::ClosingSigned(ClosingSigned {
+            channel_id: self.channel_id,
+            fee: Satoshi::from(fee),
+            signature: RawSignature(self.commitments.sign_closing(&closing_tx)),
+        })
+    }
+
+    // BOLT 2: `shutdown` is retransmitted after the reconnection, the fee negotiation starts over
+    pub(crate) fn handle_reestablish_msg(mut self, msg: ReestablishChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Vec<Message>) {
+        match self.commitments.receive_reestablish(msg) {
+            Ok(Resync::Retransmit(mut messages)) => {
+                if !self.shutdown_pending {
+                    messages.push(self.shutdown_message());
+                }
+                self.our_fee = None;
+                self.their_fee = None;
+                (ChannelState::Closing(self), messages)
+            },
+            Ok(Resync::DataLoss(their_point)) => DataLossState::new(self.channel_id, self.commitments, their_point),
+            Err(description) => {
+                let (state, message) = self.fail(description, wallet);
+                (state, message.into_iter().collect())
+            },
+        }
+    }
+
+    // the HTLCs are resolved as in the operating channel
+    pub(crate) fn handle_update<F>(mut self, wallet: &mut dyn FundingWallet, f: F) -> (ChannelState, Option<Message>)
+    where
+        F: FnOnce(&mut Commitments) -> Result<Option<Message>, String>,
+    {
+        match f(&mut self.commitments) {
+            Ok(response) => (ChannelState::Closing(self), response),
+            Err(description) => self.fail(description, wallet),
+        }
+    }
+
+    // the peer violated the protocol, our latest commitment is broadcast
+    pub(crate) fn fail(self, description: String, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        fail_channel(self.channel_id, self.commitments, description, wallet)
+    }
+
+    pub(crate) fn handle_error_msg(self, msg: Error, wallet: &mut dyn FundingWallet) -> ChannelState {
+        failed_by_peer(self.channel_id, self.commitments, msg, wallet)
+    }
+
+    // the peer's `shutdown` after ours
+    pub(crate) fn handle_shutdown_msg(mut self, msg: ShutdownChannel, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        if self.their_script.is_some() {
+            return self.fail("duplicate shutdown".to_owned(), wallet);
+        }
+        let script = Script::from(msg.script);
+        if let Err(description) = check_shutdown_script(&script, self.commitments.their_upfront_shutdown_script()) {
+            return self.fail(description, wallet);
+        }
+        self.their_script = Some(script);
+        (ChannelState::Closing(self), None)
+    }
+
+    // Signs the peer's commitment while the HTLCs are resolved, sends our `shutdown` if we owe it,
+    // then the funder starts the fee negotiation
+    pub(crate) fn commit(mut self) -> (ChannelState, Option<Message>) {
+        if let Some(commitment_signed) = self.commitments.sign_commitment() {
+            return (ChannelState::Closing(self), Some(Message::CommitmentSigned(commitment_signed)));
+        }
+        if self.shutdown_pending {
+            return if self.commitments.has_unsigned_updates() {
+                (ChannelState::Closing(self), None)
+            } else {
+                self.shutdown_pending = false;
+                let message = self.shutdown_message();
+                (ChannelState::Closing(self), Some(message))
+            };
+        }
+        let ready = self.their_script.is_some()
+            && self.our_fee.is_none()
+            && self.commitments.local_is_funder()
+            && self.commitments.is_clear();
+        if ready {
+            let fee = self.commitments.closing_fee(&self.our_script, self.their_script.as_ref().unwrap());
+            let message = self.closing_signed(fee);
+            (ChannelState::Closing(self), Some(message))
+        } else {
+            (ChannelState::Closing(self), None)
+        }
+    }
+
+    // the fee is agreed when both sides sign the same fee, otherwise we propose
+    // the fee strictly between our previous fee and the fee of the peer
+    pub(crate) fn handle_closing_signed_msg(mut self, msg: ClosingSigned, wallet: &mut dyn FundingWallet) -> (ChannelState, Option<Message>) {
+        let their_script = match self.their_script.clone() {
+            Some(script) => script,
+            None => return self.fail("closing_signed before shutdown".to_owned(), wallet),
+        };
+        if self.shutdown_pending {
+            return self.fail("closing_signed before our shutdown".to_owned(), wallet);
+        }
+        if !self.commitments.is_clear() {
+            return self.fail("closing_signed while there are pending updates".to_owned(), wallet);
+        }
+        let fee = u64::from(msg.fee);
+        if fee > self.commitments.max_closing_fee() {
+            return self.fail(format!("closing fee {} is greater than the commitment fee", fee), wallet);
+        }
+        if let (Some(our_fee), Some(their_fee)) = (self.our_fee, self.their_fee) {
+            let between = (fee > our_fee && fee < their_fee) || (fee < our_fee && fee > their_fee);
+            if fee != our_fee && !between {
+                return self.fail(format!("closing fee {} is not between {} and {}", fee, our_fee, their_fee), wallet);
+            }
+        }
+        let closing_tx = self.commitments.closing_tx(&self.our_script, &their_script, fee);
+        if !self.commitments.verify_closing(&closing_tx, &msg.signature.0) {
+            return self.fail("wrong signature of the closing transaction".to_owned(), wallet);
+        }
+        self.their_fee = Some(fee);
+
+        let our_fee = self.our_fee.unwrap_or(self.commitments.closing_fee(&self.our_script, &their_script));
+        let next_fee = (our_fee + fee) / 2;
+        let response = if Some(fee) == self.our_fee {
+            // the peer accepted our fee
+            None
+        } else if next_fee == fee || next_fee == our_fee {
+            Some(self.closing_signed(fee))
+        } else {
+            let message = self.closing_signed(next_fee);
+            return (ChannelState::Closing(self), Some(message));
+        };
+
+        let our_signature = self.commitments.sign_closing(&closing_tx);
+        let tx = closing_tx.signed_tx(&our_signature, &msg.signature.0);
+        match wallet.publish(&tx) {
+            Ok(()) => println!("INFO: closing transaction {} of channel {:?} is broadcast", tx.txid(), self.channel_id),
+            // the peer has the same transaction and will broadcast it as well
+            Err(description) => println!("ERROR: cannot broadcast the closing transaction {}: {}", tx.txid(), description),
+        }
+        let data = ClosedState {
+            channel_id: self.channel_id,
+            closing_tx: tx,
+        };
+        (ChannelState::Closed(data), response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::{Secp256k1, SecretKey};
+    use wire::OnionBlob;
+    use channel::tools::v0_p2wpkh;
+
+    use crate::commitment::tests::{FUNDING, channel};
+    use crate::testing::MockWallet;
+    use super::*;
+
+    fn script(secret: u8) -> Script {
+        let sk = SecretKey::from_slice(&[secret; 32]).unwrap();
+        v0_p2wpkh(&PublicKey::from_secret_key(&Secp256k1::new(), &sk))
+    }
+
+    fn closing(state: ChannelState) -> ClosingState {
+        match state {
+            ChannelState::Closing(data) => data,
+            state => panic!("the channel is not closing: {:?}", state),
+        }
+    }
+
+    fn closing_signed(message: Option<Message>) -> ClosingSigned {
+        match message {
+            Some(Message::ClosingSigned(msg)) => msg,
+            message => panic!("expected closing_signed: {:?}", message),
+        }
+    }
+
+    // both sides exchanged `shutdown`, the funder is the first
+    fn shut_down() -> (ClosingState, ClosingState) {
+        let (funder, fundee) = channel();
+        let channel_id = funder.channel_id;
+        let mut wallet = MockWallet::default();
+
+        let funder = ClosingState::new(channel_id, funder, script(3), None);
+        let shutdown = match funder.shutdown_message() {
+            Message::ShutdownChannel(msg) => msg,
+            _ => unreachable!(),
+        };
+        let (fundee, reply) = ClosingState::new(channel_id, fundee, script(4), Some(Script::from(shutdown.script))).reply_shutdown();
+        let reply = match reply {
+            Some(Message::ShutdownChannel(msg)) => msg,
+            message => panic!("expected shutdown: {:?}", message),
+        };
+        let (funder, message) = funder.handle_shutdown_msg(reply, &mut wallet);
+        assert!(message.is_none());
+        (closing(funder), closing(fundee))
+    }
+
+    // passes `closing_signed` back and forth until one side closes the channel
+    fn negotiate(mut from: ClosingState, mut to: ClosingState, mut msg: ClosingSigned) -> (Transaction, Transaction) {
+        let (mut from_wallet, mut to_wallet) = (MockWallet::default(), MockWallet::default());
+        for _ in 0..32 {
+            match to.handle_closing_signed_msg(msg, &mut to_wallet) {
+                (ChannelState::Closing(data), response) => {
+                    msg = closing_signed(response);
+                    to = from;
+                    from = data;
+                    std::mem::swap(&mut from_wallet, &mut to_wallet);
+                },
+                (ChannelState::Closed(data), response) => {
+                    // the side which closed has the last word, the other one closes with its `closing_signed`
+                    let last = closing_signed(response);
+                    match from.handle_closing_signed_msg(last, &mut from_wallet) {
+                        (ChannelState::Closed(other), None) => return (data.closing_tx, other.closing_tx),
+                        (state, message) => panic!("the channel is not closed: {:?} {:?}", state, message),
+                    }
+                },
+                (state, _) => panic!("the negotiation failed: {:?}", state),
+            }
+        }
+        panic!("the negotiation does not converge")
+    }
+
+    // both sides propose the same fee, the fundee signs it at once
+    #[test]
+    fn cooperative_close_agrees_on_fee() {
+        let (funder, fundee) = shut_down();
+        let expected_fee = funder.commitments.closing_fee(&script(3), &script(4));
+
+        let (funder, message) = funder.commit();
+        let msg = closing_signed(message);
+        assert_eq!(u64::from(msg.fee), expected_fee);
+
+        let (fundee_tx, funder_tx) = negotiate(closing(funder), fundee, msg);
+        assert_eq!(fundee_tx, funder_tx);
+        let output: u64 = fundee_tx.output.iter().map(|o| o.value).sum();
+        assert_eq!(output + expected_fee, FUNDING);
+    }
+
+    // the fees differ, each side proposes the fee between the latest two until they agree
+    #[test]
+    fn closing_fee_negotiation_converges() {
+        let (mut funder, fundee) = shut_down();
+        let fundee_fee = fundee.commitments.closing_fee(&script(4), &script(3));
+        let funder_fee = fundee_fee / 3;
+
+        let msg = closing_signed(Some(funder.closing_signed(funder_fee)));
+        let (fundee_tx, funder_tx) = negotiate(funder, fundee, msg);
+        assert_eq!(fundee_tx, funder_tx);
+        let output: u64 = fundee_tx.output.iter().map(|o| o.value).sum();
+        let fee = FUNDING - output;
+        assert!(fee > funder_fee && fee < fundee_fee, "the fee {} is not between {} and {}", fee, funder_fee, fundee_fee);
+    }
+
+    // BOLT 2: the next fee must be between the latest two proposals
+    #[test]
+    fn closing_fee_outside_range_force_closes() {
+        let (mut funder, fundee) = shut_down();
+        let fundee_fee = fundee.commitments.closing_fee(&script(4), &script(3));
+        let funder_fee = fundee_fee / 3;
+        let mut wallet = MockWallet::default();
+
+        let msg = closing_signed(Some(funder.closing_signed(funder_fee)));
+        let (fundee, response) = fundee.handle_closing_signed_msg(msg, &mut wallet);
+        let counter = closing_signed(response);
+        let (funder, response) = funder.handle_closing_signed_msg(counter, &mut wallet);
+        closing_signed(response);
+        let mut funder = closing(funder);
+
+        // lower than the previous proposal of the funder
+        let msg = closing_signed(Some(funder.closing_signed(funder_fee - 1)));
+        match closing(fundee).handle_closing_signed_msg(msg, &mut wallet) {
+            (ChannelState::ForceClosing(_), Some(Message::Error(_))) => (),
+            (state, message) => panic!("the channel is not force closed: {:?} {:?}", state, message),
+        }
+        assert_eq!(wallet.published.len(), 1);
+    }
+
+    #[test]
+    fn wrong_closing_signature_force_closes() {
+        let (mut funder, fundee) = shut_down();
+        let expected = fundee.commitments.signed_local_commitment();
+        let fee = fundee.commitments.closing_fee(&script(4), &script(3));
+        let mut msg = closing_signed(Some(funder.closing_signed(fee)));
+        msg.fee = Satoshi::from(fee - 1);
+
+        let mut wallet = MockWallet::default();
+        match fundee.handle_closing_signed_msg(msg, &mut wallet) {
+            (ChannelState::ForceClosing(ref data), Some(Message::Error(_))) => assert_eq!(data.commitment_txid(), expected.txid()),
+            (state, message) => panic!("the channel is not force closed: {:?} {:?}", state, message),
+        }
+        assert_eq!(wallet.published, vec![expected]);
+    }
+
+    // BOLT 2: our `shutdown` waits until the peer's commitment includes all our updates
+    #[test]
+    fn shutdown_reply_waits_for_pending_updates() {
+        let (mut funder, mut fundee) = channel();
+        let channel_id = funder.channel_id;
+        let update = funder.add_htlc(50_000_000, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).unwrap();
+        fundee.receive_add_htlc(update).unwrap();
+
+        let (state, message) = ClosingState::new(channel_id, funder, script(3), Some(script(4))).reply_shutdown();
+        assert!(message.is_none());
+
+        let (state, message) = closing(state).commit();
+        match message {
+            Some(Message::CommitmentSigned(_)) => (),
+            message => panic!("expected commitment_signed: {:?}", message),
+        }
+        let (_, message) = closing(state).commit();
+        match message {
+            Some(Message::ShutdownChannel(ref msg)) => assert_eq!(Script::from(msg.script.clone()), script(3)),
+            message => panic!("expected shutdown: {:?}", message),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// The channel state is stored by the node, these are serde adapters
+// for the fields of the types which do not implement serde themselves,
+// use them as `#[serde(with = "crate::codec::public_key")]`
+
+pub(crate) mod public_key {
+    use dependencies::secp256k1;
+
+    use secp256k1::PublicKey;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use wire::RawPublicKey;
+
+    pub fn serialize<S>(key: &PublicKey, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        RawPublicKey::from(key.clone()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PublicKey, D::Error> where D: Deserializer<'de> {
+        RawPublicKey::deserialize(deserializer).map(|key| key.as_ref().clone())
+    }
+}
+
+pub(crate) mod option_public_key {
+    use dependencies::secp256k1;
+
+    use secp256k1::PublicKey;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use wire::RawPublicKey;
+
+    pub fn serialize<S>(key: &Option<PublicKey>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        key.clone().map(RawPublicKey::from).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<PublicKey>, D::Error> where D: Deserializer<'de> {
+        Option::<RawPublicKey>::deserialize(deserializer).map(|key| key.map(|key| key.as_ref().clone()))
+    }
+}
+
+pub(crate) mod script {
+    use dependencies::bitcoin;
+
+    use bitcoin::Script;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(script: &Script, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        script.as_bytes().to_vec().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Script, D::Error> where D: Deserializer<'de> {
+        Vec::<u8>::deserialize(deserializer).map(Script::from)
+    }
+}
+
+pub(crate) mod option_script {
+    use dependencies::bitcoin;
+
+    use bitcoin::Script;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(script: &Option<Script>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        script.as_ref().map(|script| script.as_bytes().to_vec()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Script>, D::Error> where D: Deserializer<'de> {
+        Option::<Vec<u8>>::deserialize(deserializer).map(|script| script.map(Script::from))
+    }
+}
+
+// the consensus encoding
+pub(crate) mod transaction {
+    use dependencies::bitcoin;
+
+    use bitcoin::Transaction;
+    use bitcoin::consensus::encode;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(tx: &Transaction, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        encode::serialize(tx).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Transaction, D::Error> where D: Deserializer<'de> {
+        use serde::de::Error;
+
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        encode::deserialize(&bytes).map_err(|e| D::Error::custom(format!("bad transaction: {:?}", e)))
+    }
+}
+
+pub(crate) mod option_transaction {
+    use dependencies::bitcoin;
+
+    use bitcoin::Transaction;
+    use bitcoin::consensus::encode;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(tx: &Option<Transaction>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        tx.as_ref().map(encode::serialize).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Transaction>, D::Error> where D: Deserializer<'de> {
+        use serde::de::Error;
+
+        match Option::<Vec<u8>>::deserialize(deserializer)? {
+            Some(bytes) => encode::deserialize(&bytes)
+                .map(Some)
+                .map_err(|e| D::Error::custom(format!("bad transaction: {:?}", e))),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) mod transactions {
+    use dependencies::bitcoin;
+
+    use bitcoin::Transaction;
+    use bitcoin::consensus::encode;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(txs: &Vec<Transaction>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        txs.iter().map(encode::serialize).collect::<Vec<_>>().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Transaction>, D::Error> where D: Deserializer<'de> {
+        use serde::de::Error;
+
+        Vec::<Vec<u8>>::deserialize(deserializer)?
+            .iter()
+            .map(|bytes| encode::deserialize(bytes).map_err(|e| D::Error::custom(format!("bad transaction: {:?}", e))))
+            .collect()
+    }
+}
+
+pub(crate) mod txid {
+    use dependencies::bitcoin_hashes;
+
+    use bitcoin_hashes::{sha256d, Hash};
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(txid: &sha256d::Hash, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        txid.into_inner().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<sha256d::Hash, D::Error> where D: Deserializer<'de> {
+        <[u8; 32]>::deserialize(deserializer).map(sha256d::Hash::from_inner)
+    }
+}
@@ -0,0 +1,1742 @@
+use std::cmp;
+
+use dependencies::secp256k1;
+use dependencies::bitcoin_hashes;
+use dependencies::bitcoin;
+
+use secp256k1::{PublicKey, SecretKey, Secp256k1, Signature};
+
+use bitcoin_hashes::{sha256, sha256d};
+use bitcoin_hashes::Hash;
+
+use bitcoin::{Script, Transaction};
+
+use serde_derive::{Serialize, Deserialize};
+
+use wire::{
+    ChannelId, Sha256, OnionBlob, HtlcId, MilliSatoshi, RawSignature, RawPublicKey,
+    UpdateAddHtlc, UpdateFulfillHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc,
+    CommitmentSigned, RevokeAndAck, ReestablishChannel, FundingLocked, Message,
+    UpdateFee, SatoshiPerKiloWeight,
+};
+
+use channel::commit::{CommitTx, HTLC, HTLCDirection, commitment_weight, commitment_fee, htlc_timeout_weight, htlc_success_weight};
+use channel::close::{ClosingTx, DelayedSweepTx, ToRemoteSweepTx, JusticeTx, JusticeInput, RevokedOutput};
+use channel::derivation::{derive_privkey, derive_revocation_privkey};
+use channel::tools::{spending_witness_2x2_multisig, to_local_script, to_remote_anchor_script, v0_p2wpkh};
+
+use shachain::LeafIndex;
+use shachain::producer_tree::ProducerTree;
+use shachain::store_tree::StoreTree;
+
+use crate::b_box::PartnerInfo;
+
+// BOLT 4: the `failure_code` of `update_fail_malformed_htlc` must have this bit set
+const BADONION: u16 = 0x8000;
+
+// the minimal relay fee of bitcoind, 1 satoshi per vbyte, rounded up, in satoshi per kiloweight
+const MIN_FEE_RATE: u32 = 253;
+// the funder's fee rate is accepted if it is within these percents of our estimate
+const MIN_FEE_RATE_PERCENT: u64 = 50;
+const MAX_FEE_RATE_PERCENT: u64 = 500;
+// as the funder we update the fee when our estimate deviates from the current fee rate more than that
+const FEE_UPDATE_THRESHOLD_PERCENT: u64 = 20;
+
+// the secret of the commitment with the given number, see BOLT 3 per-commitment secret requirements
+pub(crate) fn per_commitment_secret(seed: &Sha256, number: u64) -> SecretKey {
+    let producer = ProducerTree::new(seed.clone());
+    let secret = producer.leaf(LeafIndex::new(number));
+    // the hash is a valid secret key with overwhelming probability
+    SecretKey::from_slice(&secret[..]).unwrap()
+}
+
+pub(crate) fn per_commitment_point(seed: &Sha256, number: u64) -> PublicKey {
+    PublicKey::from_secret_key(&Secp256k1::signing_only(), &per_commitment_secret(seed, number))
+}
+
+/// The funding output and the values which are fixed when the channel is opened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct FundingOutput {
+    // in satoshi
+    pub(crate) amount: u64,
+    #[serde(with = "crate::codec::txid")]
+    pub(crate) tx_id: sha256d::Hash,
+    pub(crate) output_index: u16,
+    pub(crate) obscuring_factor: u64,
+    // BOLT 3 `option_static_remotekey`, the `to_remote` output pays to the payment basepoint
+    #[serde(default)]
+    pub(crate) static_remotekey: bool,
+    // `option_anchors_zero_fee_htlc_tx`, the anchor outputs and the zero fee HTLC transactions
+    #[serde(default)]
+    pub(crate) anchors: bool,
+}
+
+// The commitment transaction of the `local` side,
+// all keys are derived from the per commitment point of the `local` side.
+// The fee is paid by the funder, `local_is_funder` is relative to the `local` side.
+pub(crate) fn commitment_tx(
+    local: &PartnerInfo,
+    remote: &PartnerInfo,
+    point: &PublicKey,
+    funding: &FundingOutput,
+    local_is_funder: bool,
+    number: u64,
+    fee_rate: u32,
+    to_local_msat: u64,
+    to_remote_msat: u64,
+    htlcs: Vec<HTLC>,
+) -> CommitTx {
+    CommitTx {
+        funding_amount: funding.amount as i64,
+        local_funding_pubkey: local.keys.funding().clone(),
+        remote_funding_pubkey: remote.keys.funding().clone(),
+
+        local_feerate_per_kw: fee_rate as i64,
+        dust_limit_satoshi: local.config.dust_limit as i64,
+
+        to_local_msat: to_local_msat as i64,
+        to_remote_msat: to_remote_msat as i64,
+
+        obscured_commit_number: number ^ funding.obscuring_factor,
+
+        local_htlc_pubkey: local.htlc_pubkey(point),
+        remote_htlc_pubkey: remote.htlc_pubkey(point),
+
+        local_revocation_pubkey: remote.revocation_pubkey(point),
+        local_delayedpubkey: local.delayed_pubkey(point),
+        // the delay is requested by the remote side
+        local_delay: remote.config.csv_delay.into(),
+
+        remotepubkey: if funding.static_remotekey {
+            remote.keys.payment().clone()
+        } else {
+            remote.payment_pubkey(point)
+        },
+
+        funding_tx_id: funding.tx_id,
+        funding_output_index: funding.output_index as u32,
+
+        htlcs: htlcs,
+        local_is_funder: local_is_funder,
+        anchors: funding.anchors,
+    }
+}
+
+// UpdateInfo represents update applied to channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum UpdateInfo {
+    AddHtlc {
+        id: u64,
+        // in millisatoshi
+        amount: u64,
+        payment_hash: [u8; 32],
+        expiry: u32,
+        onion_blob: OnionBlob,
+    },
+    FulfillHtlc {
+        id: u64,
+        payment_preimage: [u8; 32],
+    },
+    FailHtlc {
+        id: u64,
+        reason: Vec<u8>,
+    },
+    FailMalformedHtlc {
+        id: u64,
+        sha256_of_onion: Sha256,
+        failure_code: u16,
+    },
+    // only the funder changes the fee rate, in satoshi per kiloweight
+    Fee {
+        fee_rate: u32,
+    },
+}
+
+impl UpdateInfo {
+    // the id of the HTLC which the update removes
+    fn removes(&self) -> Option<u64> {
+        match self {
+            &UpdateInfo::AddHtlc { .. } => None,
+            &UpdateInfo::FulfillHtlc { id, .. } => Some(id),
+            &UpdateInfo::FailHtlc { id, .. } => Some(id),
+            &UpdateInfo::FailMalformedHtlc { id, .. } => Some(id),
+            &UpdateInfo::Fee { .. } => None,
+        }
+    }
+
+    fn fee_rate(&self) -> Option<u32> {
+        match self {
+            &UpdateInfo::Fee { fee_rate } => Some(fee_rate),
+            _ => None,
+        }
+    }
+}
+
+// Whether the commitment of some side includes the update.
+// Our updates get into the peer's commitment first, when we sign it,
+// and into our commitment after the peer revokes its previous commitment.
+// The peer's updates go the opposite way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+enum Stage {
+    Absent,
+    // the next signed commitment will include the update
+    Pending,
+    // the latest signed commitment includes the update
+    Committed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LogEntry {
+    update: UpdateInfo,
+    // proposed by us
+    ours: bool,
+    local: Stage,
+    remote: Stage,
+}
+
+impl LogEntry {
+    fn stage(&self, local: bool) -> Stage {
+        if local { self.local } else { self.remote }
+    }
+
+    fn locked_in(&self) -> bool {
+        self.local == Stage::Committed && self.remote == Stage::Committed
+    }
+}
+
+// The balances and the HTLCs of the latest commitment of the side,
+// HTLC direction is from the point of view of that side
+fn view(log: &[LogEntry], our_balance: u64, their_balance: u64, local: bool) -> (u64, u64, Vec<HTLC>) {
+    let included = |e: &&LogEntry| e.stage(local) == Stage::Committed;
+
+    let (mut ours, mut theirs) = (our_balance, their_balance);
+    let mut htlcs = Vec::new();
+    for add in log.iter().filter(included) {
+        if let UpdateInfo::AddHtlc { id, amount, payment_hash, expiry, .. } = add.update {
+            if add.ours { ours -= amount } else { theirs -= amount }
+            let removal = log.iter()
+                .filter(included)
+                .find(|e| e.ours != add.ours && e.update.removes() == Some(id));
+            match removal.map(|e| &e.update) {
+                // the receiver of the HTLC gets the money
+                Some(&UpdateInfo::FulfillHtlc { .. }) => if add.ours { theirs += amount } else { ours += amount },
+                Some(_) => if add.ours { ours += amount } else { theirs += amount },
+                None => htlcs.push(HTLC {
+                    direction: if add.ours == local { HTLCDirection::Offered } else { HTLCDirection::Accepted },
+                    amount_msat: amount as i64,
+                    expiry: expiry as i32,
+                    payment_hash: payment_hash,
+                }),
+            }
+        }
+    }
+    (ours, theirs, htlcs)
+}
+
+// the fee rate of the latest commitment of the side, the latest update of the fee it includes applies
+fn committed_fee_rate(log: &[LogEntry], fee_rate: u32, local: bool) -> u32 {
+    log.iter()
+        .filter(|e| e.stage(local) == Stage::Committed)
+        .filter_map(|e| e.update.fee_rate())
+        .last()
+        .unwrap_or(fee_rate)
+}
+
+// The HTLC output of the peer's commitments which are revoked, the output script
+// is rebuilt from it if the peer broadcasts one of them. The HTLC stays in many
+// consecutive commitments, they share one entry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RevokedHtlc {
+    first_number: u64,
+    last_number: u64,
+    // offered by the peer
+    offered: bool,
+    payment_hash: [u8; 32],
+    expiry: u32,
+}
+
+/// How the transaction spending the funding output closes the channel
+#[derive(Debug)]
+pub(crate) enum Spend {
+    // our latest commitment
+    Local,
+    // our revoked commitment, the peer takes all its outputs
+    LocalRevoked(u64),
+    // the peer's commitment which is not revoked, its per commitment point is given
+    Remote(PublicKey),
+    // the peer's revoked commitment, all its outputs are ours
+    Revoked(u64),
+    // the mutual close
+    Other,
+}
+
+/// The peer's revoked commitment and the signed transaction which punishes it,
+/// the watchtowers broadcast the justice when the commitment appears on chain
+#[derive(Debug, Clone)]
+pub struct BreachRemedy {
+    pub commitment_txid: sha256d::Hash,
+    pub justice_tx: Transaction,
+}
+
+/// Both commitment transactions of the operating channel and the updates
+/// which are not yet irrevocably committed to both of them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "CommitmentsRecord", into = "CommitmentsRecord")]
+pub struct Commitments {
+    channel_id: ChannelId,
+    our_info: PartnerInfo,
+    their_info: PartnerInfo,
+    funding: FundingOutput,
+    local_is_funder: bool,
+    // paid by the funder
+    fee_rate: u32,
+
+    // the balances after all irrevocably committed updates, in millisatoshi
+    our_balance: u64,
+    their_balance: u64,
+    log: Vec<LogEntry>,
+    next_our_htlc_id: u64,
+    next_their_htlc_id: u64,
+
+    // our latest commitment and the peer's signatures for it,
+    // we can broadcast it at any moment
+    local_number: u64,
+    local_commit: CommitTx,
+    local_signature: RawSignature,
+    local_htlc_signatures: Vec<RawSignature>,
+
+    // the peer's latest commitment signed by us
+    remote_number: u64,
+    remote_commit: CommitTx,
+    their_point: PublicKey,
+    // the point of the peer's next commitment, it is unknown while the peer has not revoked
+    their_next_point: Option<PublicKey>,
+    // the point of the commitment which the peer should revoke
+    their_revoked_point: Option<PublicKey>,
+    // the secrets of the peer's revoked commitments
+    their_secrets: StoreTree,
+    // the HTLC outputs of the peer's previous commitments, the justice transactions need the scripts
+    their_revoked_htlcs: Vec<RevokedHtlc>,
+    // the peer's commitment before the latest one, the watchtowers get its justice transaction
+    their_revoked_commitment: Option<Transaction>,
+    // retransmitted if the peer did not receive it before the disconnection
+    last_commitment_signed: Option<CommitmentSigned>,
+}
+
+// The stored form of `Commitments`, the latest commitment transactions
+// are not stored, they are rebuilt from the log
+#[derive(Serialize, Deserialize)]
+struct CommitmentsRecord {
+    channel_id: ChannelId,
+    our_info: PartnerInfo,
+    their_info: PartnerInfo,
+    funding: FundingOutput,
+    local_is_funder: bool,
+    fee_rate: u32,
+    our_balance: u64,
+    their_balance: u64,
+    log: Vec<LogEntry>,
+    next_our_htlc_id: u64,
+    next_their_htlc_id: u64,
+    local_number: u64,
+    local_signature: RawSignature,
+    local_htlc_signatures: Vec<RawSignature>,
+    remote_number: u64,
+    #[serde(with = "crate::codec::public_key")]
+    their_point: PublicKey,
+    #[serde(with = "crate::codec::option_public_key")]
+    their_next_point: Option<PublicKey>,
+    #[serde(with = "crate::codec::option_public_key")]
+    their_revoked_point: Option<PublicKey>,
+    their_secrets: StoreTree,
+    their_revoked_htlcs: Vec<RevokedHtlc>,
+    #[serde(default, with = "crate::codec::option_transaction")]
+    their_revoked_commitment: Option<Transaction>,
+    last_commitment_signed: Option<CommitmentSigned>,
+}
+
+impl From<Commitments> for CommitmentsRecord {
+    fn from(c: Commitments) -> Self {
+        CommitmentsRecord {
+            channel_id: c.channel_id,
+            our_info: c.our_info,
+            their_info: c.their_info,
+            funding: c.funding,
+            local_is_funder: c.local_is_funder,
+            fee_rate: c.fee_rate,
+            our_balance: c.our_balance,
+            their_balance: c.their_balance,
+            log: c.log,
+            next_our_htlc_id: c.next_our_htlc_id,
+            next_their_htlc_id: c.next_their_htlc_id,
+            local_number: c.local_number,
+            local_signature: c.local_signature,
+            local_htlc_signatures: c.local_htlc_signatures,
+            remote_number: c.remote_number,
+            their_point: c.their_point,
+            their_next_point: c.their_next_point,
+            their_revoked_point: c.their_revoked_point,
+            their_secrets: c.their_secrets,
+            their_revoked_htlcs: c.their_revoked_htlcs,
+            their_revoked_commitment: c.their_revoked_commitment,
+            last_commitment_signed: c.last_commitment_signed,
+        }
+    }
+}
+
+impl From<CommitmentsRecord> for Commitments {
+    fn from(r: CommitmentsRecord) -> Self {
+        let our_point = per_commitment_point(r.our_info.per_commitment_seed.as_ref().unwrap(), r.local_number);
+        let (ours, theirs, htlcs) = view(&r.log, r.our_balance, r.their_balance, true);
+        let local_commit = commitment_tx(
+            &r.our_info, &r.their_info, &our_point, &r.funding, r.local_is_funder, r.local_number,
+            committed_fee_rate(&r.log, r.fee_rate, true), ours, theirs, htlcs,
+        );
+        let (ours, theirs, htlcs) = view(&r.log, r.our_balance, r.their_balance, false);
+        let remote_commit = commitment_tx(
+            &r.their_info, &r.our_info, &r.their_point, &r.funding, !r.local_is_funder, r.remote_number,
+            committed_fee_rate(&r.log, r.fee_rate, false), theirs, ours, htlcs,
+        );
+
+        Commitments {
+            channel_id: r.channel_id,
+            our_info: r.our_info,
+            their_info: r.their_info,
+            funding: r.funding,
+            local_is_funder: r.local_is_funder,
+            fee_rate: r.fee_rate,
+            our_balance: r.our_balance,
+            their_balance: r.their_balance,
+            log: r.log,
+            next_our_htlc_id: r.next_our_htlc_id,
+            next_their_htlc_id: r.next_their_htlc_id,
+            local_number: r.local_number,
+            local_commit: local_commit,
+            local_signature: r.local_signature,
+            local_htlc_signatures: r.local_htlc_signatures,
+            remote_number: r.remote_number,
+            remote_commit: remote_commit,
+            their_point: r.their_point,
+            their_next_point: r.their_next_point,
+            their_revoked_point: r.their_revoked_point,
+            their_secrets: r.their_secrets,
+            their_revoked_htlcs: r.their_revoked_htlcs,
+            their_revoked_commitment: r.their_revoked_commitment,
+            last_commitment_signed: r.last_commitment_signed,
+        }
+    }
+}
+
+/// What the peer's `channel_reestablish` tells about the channel
+#[derive(Debug)]
+pub(crate) enum Resync {
+    // the messages the peer has lost, in the order they were sent
+    Retransmit(Vec<Message>),
+    // we lost the latest state, the peer's current per commitment point is given
+    DataLoss(PublicKey),
+}
+
+impl Commitments {
+    pub(crate) fn new(
+        channel_id: ChannelId,
+        our_info: PartnerInfo,
+        their_info: PartnerInfo,
+        funding: FundingOutput,
+        local_is_funder: bool,
+        our_balance: u64,
+        their_balance: u64,
+        local_signature: RawSignature,
+        their_next_point: PublicKey,
+    ) -> Self {
+        let fee_rate = our_info.config.local_fee_rate;
+        let our_point = our_info.keys.first_per_commitment().clone();
+        let their_point = their_info.keys.first_per_commitment().clone();
+        let local_commit = commitment_tx(
+            &our_info, &their_info, &our_point, &funding, local_is_funder, 0,
+            fee_rate, our_balance, their_balance, vec![],
+        );
+        let remote_commit = commitment_tx(
+            &their_info, &our_info, &their_point, &funding, !local_is_funder, 0,
+            fee_rate, their_balance, our_balance, vec![],
+        );
+
+        Commitments {
+            channel_id: channel_id,
+            our_info: our_info,
+            their_info: their_info,
+            funding: funding,
+            local_is_funder: local_is_funder,
+            fee_rate: fee_rate,
+            our_balance: our_balance,
+            their_balance: their_balance,
+            log: Vec::new(),
+            next_our_htlc_id: 0,
+            next_their_htlc_id: 0,
+            local_number: 0,
+            local_commit: local_commit,
+            local_signature: local_signature,
+            local_htlc_signatures: Vec::new(),
+            remote_number: 0,
+            remote_commit: remote_commit,
+            their_point: their_point,
+            their_next_point: Some(their_next_point),
+            their_revoked_point: None,
+            their_secrets: StoreTree::new(),
+            their_revoked_htlcs: Vec::new(),
+            their_revoked_commitment: None,
+            last_commitment_signed: None,
+        }
+    }
+
+    fn our_seed(&self) -> &Sha256 {
+        self.our_info.per_commitment_seed.as_ref().unwrap()
+    }
+
+    fn view(&self, local: bool) -> (u64, u64, Vec<HTLC>) {
+        view(&self.log, self.our_balance, self.their_balance, local)
+    }
+
+    // the balances after all updates in the log, including not committed
+    fn projected_balances(&self) -> (u64, u64) {
+        let (mut ours, mut theirs) = (self.our_balance, self.their_balance);
+        for add in self.log.iter() {
+            if let UpdateInfo::AddHtlc { id, amount, .. } = add.update {
+                if add.ours { ours -= amount } else { theirs -= amount }
+                let removal = self.log.iter()
+                    .find(|e| e.ours != add.ours && e.update.removes() == Some(id));
+                match removal.map(|e| &e.update) {
+                    Some(&UpdateInfo::FulfillHtlc { .. }) => if add.ours { theirs += amount } else { ours += amount },
+                    Some(_) => if add.ours { ours += amount } else { theirs += amount },
+                    None => (),
+                }
+            }
+        }
+        (ours, theirs)
+    }
+
+    // the HTLC offered by us if `ours`, it should be committed to both sides and not removed yet
+    fn find_htlc(&self, ours: bool, id: u64) -> Result<&UpdateInfo, String> {
+        let add = self.log.iter()
+            .find(|e| e.ours == ours && match e.update {
+                UpdateInfo::AddHtlc { id: add_id, .. } => add_id == id,
+                _ => false,
+            })
+            .ok_or(format!("unknown htlc {}", id))?;
+        if !add.locked_in() {
+            return Err(format!("htlc {} is not committed yet", id));
+        }
+        if self.log.iter().any(|e| e.ours != ours && e.update.removes() == Some(id)) {
+            return Err(format!("htlc {} is already removed", id));
+        }
+        Ok(&add.update)
+    }
+
+    // the HTLC offered by the side `ours`, checked against the limits of the receiver `receiver`
+    fn check_add(&self, ours: bool, amount: u64, receiver: &PartnerInfo, balance: u64, reserve: u64) -> Result<(), String> {
+        let config = &receiver.config;
+        if amount == 0 || amount < config.htlc_minimum {
+            return Err(format!("htlc amount {} is less than minimum {}", amount, config.htlc_minimum));
+        }
+        let (count, in_flight) = self.log.iter()
+            .filter(|e| e.ours == ours)
+            .fold((0u64, 0u64), |(count, in_flight), e| match e.update {
+                UpdateInfo::AddHtlc { amount, .. } => (count + 1, in_flight + amount),
+                _ => (count, in_flight),
+            });
+        if count + 1 > config.max_accepted_htlc_number as u64 {
+            return Err(format!("too many htlcs, the limit is {}", config.max_accepted_htlc_number));
+        }
+        if in_flight + amount > config.max_htlc_value_in_flight {
+            return Err(format!("htlcs in flight exceed {}", config.max_htlc_value_in_flight));
+        }
+        if balance < amount + reserve {
+            return Err(format!("cannot afford htlc {}, the balance is {}", amount, balance));
+        }
+        Ok(())
+    }
+
+    // the updates which are irrevocably committed to both sides are applied to the balances
+    fn compact(&mut self) {
+        if self.their_revoked_point.is_some() {
+            // the peer can still broadcast the previous commitment
+            return;
+        }
+
+        let settled: Vec<(bool, u64)> = self.log.iter()
+            .filter(|e| e.locked_in())
+            .filter_map(|e| e.update.removes().map(|id| (!e.ours, id)))
+            .filter(|&(offered_by_us, id)| self.log.iter().any(|e| e.ours == offered_by_us && e.locked_in() && match e.update {
+                UpdateInfo::AddHtlc { id: add_id, .. } => add_id == id,
+                _ => false,
+            }))
+            .collect();
+
+        let (ours, theirs) = {
+            let (mut ours, mut theirs) = (self.our_balance, self.their_balance);
+            for &(offered_by_us, id) in &settled {
+                let amount = self.log.iter()
+                    .filter(|e| e.ours == offered_by_us)
+                    .filter_map(|e| match e.update {
+                        UpdateInfo::AddHtlc { id: add_id, amount, .. } if add_id == id => Some(amount),
+                        _ => None,
+                    })
+                    .next()
+                    .unwrap_or(0);
+                let fulfilled = self.log.iter().any(|e| e.ours != offered_by_us && match e.update {
+                    UpdateInfo::FulfillHtlc { id: removed_id, .. } => removed_id == id,
+                    _ => false,
+                });
+                match (offered_by_us, fulfilled) {
+                    (true, true) => { ours -= amount; theirs += amount },
+                    (false, true) => { theirs -= amount; ours += amount },
+                    // failed, nothing moves
+                    (_, false) => (),
+                }
+            }
+            (ours, theirs)
+        };
+        self.our_balance = ours;
+        self.their_balance = theirs;
+
+        // the fee rate committed to both sides replaces the initial one
+        if let Some(fee_rate) = self.log.iter().filter(|e| e.locked_in()).filter_map(|e| e.update.fee_rate()).last() {
+            self.fee_rate = fee_rate;
+        }
+
+        self.log.retain(|e| {
+            let (offered_by_us, id) = match e.update {
+                UpdateInfo::AddHtlc { id, .. } => (e.ours, id),
+                UpdateInfo::Fee { .. } => return !e.locked_in(),
+                ref update => (!e.ours, update.removes().unwrap()),
+            };
+            !settled.contains(&(offered_by_us, id))
+        });
+    }
+
+    // BOLT 2: the funder can pay the fee of both commitments with the new HTLC offered by the side `ours`
+    // at the fee rate the commitments will have, and keeps the reserve the fundee requires
+    fn check_funder_fee(&self, ours: bool, amount: u64) -> Result<(), String> {
+        let (our_balance, their_balance) = self.projected_balances();
+        let (funder_balance, reserve) = if self.local_is_funder {
+            (our_balance, self.their_info.config.chanel_reserve)
+        } else {
+            (their_balance, self.our_info.config.chanel_reserve)
+        };
+        // the balance covers the amount, `check_add` made sure
+        let funder_balance = if ours == self.local_is_funder { funder_balance - amount } else { funder_balance };
+        self.check_fee(self.proposed_fee_rate(), funder_balance, reserve, Some((ours, amount)))
+    }
+
+    // we offer the HTLC
+    pub(crate) fn add_htlc(&mut self, amount: u64, payment_hash: [u8; 32], expiry: u32, onion_blob: OnionBlob) -> Result<UpdateAddHtlc, String> {
+        let (ours, _) = self.projected_balances();
+        let reserve = self.their_info.config.chanel_reserve * 1000;
+        self.check_add(true, amount, &self.their_info, ours, reserve)?;
+        self.check_funder_fee(true, amount)?;
+
+        let id = self.next_our_htlc_id;
+        self.next_our_htlc_id += 1;
+        self.log.push(LogEntry {
+            update: UpdateInfo::AddHtlc {
+                id: id,
+                amount: amount,
+                payment_hash: payment_hash,
+                expiry: expiry,
+                onion_blob: onion_blob.clone(),
+            },
+            ours: true,
+            local: Stage::Absent,
+            remote: Stage::Pending,
+        });
+        Ok(UpdateAddHtlc {
+            channel_id: self.channel_id,
+            id: HtlcId::from_u64(id),
+            amount: MilliSatoshi::from(amount),
+            payment_hash: Sha256::from(payment_hash),
+            expiry: expiry,
+            onion_blob: onion_blob,
+        })
+    }
+
+    // we settle the HTLC offered by the peer
+    pub(crate) fn fulfill_htlc(&mut self, id: u64, payment_preimage: [u8; 32]) -> Result<UpdateFulfillHtlc, String> {
+        match self.find_htlc(false, id)? {
+            &UpdateInfo::AddHtlc { ref payment_hash, .. } => {
+                if sha256::Hash::hash(&payment_preimage[..]).into_inner().ne(payment_hash) {
+                    return Err(format!("wrong preimage of htlc {}", id));
+                }
+            },
+            _ => unreachable!(),
+        }
+        self.log.push(LogEntry {
+            update: UpdateInfo::FulfillHtlc {
+                id: id,
+                payment_preimage: payment_preimage,
+            },
+            ours: true,
+            local: Stage::Absent,
+            remote: Stage::Pending,
+        });
+        Ok(UpdateFulfillHtlc {
+            channel_id: self.channel_id,
+            id: HtlcId::from_u64(id),
+            payment_preimage: Sha256::from(payment_preimage),
+        })
+    }
+
+    // we fail the HTLC offered by the peer
+    pub(crate) fn fail_htlc(&mut self, id: u64, reason: Vec<u8>) -> Result<UpdateFailHtlc, String> {
+        self.find_htlc(false, id)?;
+        self.log.push(LogEntry {
+            update: UpdateInfo::FailHtlc {
+                id: id,
+                reason: reason.clone(),
+            },
+            ours: true,
+            local: Stage::Absent,
+            remote: Stage::Pending,
+        });
+        Ok(UpdateFailHtlc {
+            channel_id: self.channel_id,
+            id: HtlcId::from_u64(id),
+            reason: reason,
+        })
+    }
+
+    pub(crate) fn receive_add_htlc(&mut self, msg: UpdateAddHtlc) -> Result<(), String> {
+        let id = msg.id.to_u64();
+        if id != self.next_their_htlc_id {
+            return Err(format!("wrong htlc id {}, expected {}", id, self.next_their_htlc_id));
+        }
+        let amount = u64::from(msg.amount);
+        let (_, theirs) = self.projected_balances();
+        let reserve = self.our_info.config.chanel_reserve * 1000;
+        self.check_add(false, amount, &self.our_info, theirs, reserve)?;
+        self.check_funder_fee(false, amount)?;
+
+        self.next_their_htlc_id += 1;
+        self.log.push(LogEntry {
+            update: UpdateInfo::AddHtlc {
+                id: id,
+                amount: amount,
+                payment_hash: msg.payment_hash.into(),
+                expiry: msg.expiry,
+                onion_blob: msg.onion_blob,
+            },
+            ours: false,
+            local: Stage::Pending,
+            remote: Stage::Absent,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn receive_fulfill_htlc(&mut self, msg: UpdateFulfillHtlc) -> Result<(), String> {
+        let id = msg.id.to_u64();
+        let payment_preimage: [u8; 32] = msg.payment_preimage.into();
+        match self.find_htlc(true, id)? {
+            &UpdateInfo::AddHtlc { ref payment_hash, .. } => {
+                if sha256::Hash::hash(&payment_preimage[..]).into_inner().ne(payment_hash) {
+                    return Err(format!("wrong preimage of htlc {}", id));
+                }
+            },
+            _ => unreachable!(),
+        }
+        println!("INFO: htlc {} of channel {:?} is fulfilled", id, self.channel_id);
+        self.log.push(LogEntry {
+            update: UpdateInfo::FulfillHtlc {
+                id: id,
+                payment_preimage: payment_preimage,
+            },
+            ours: false,
+            local: Stage::Pending,
+            remote: Stage::Absent,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn receive_fail_htlc(&mut self, msg: UpdateFailHtlc) -> Result<(), String> {
+        let id = msg.id.to_u64();
+        self.find_htlc(true, id)?;
+        self.log.push(LogEntry {
+            update: UpdateInfo::FailHtlc {
+                id: id,
+                reason: msg.reason,
+            },
+            ours: false,
+            local: Stage::Pending,
+            remote: Stage::Absent,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn receive_fail_malformed_htlc(&mut self, msg: UpdateFailMalformedHtlc) -> Result<(), String> {
+        let id = msg.id.to_u64();
+        if msg.failure_code & BADONION == 0 {
+            return Err(format!("failure code {} of malformed htlc has no BADONION bit", msg.failure_code));
+        }
+        self.find_htlc(true, id)?;
+        self.log.push(LogEntry {
+            update: UpdateInfo::FailMalformedHtlc {
+                id: id,
+                sha256_of_onion: msg.sha256_of_onion,
+                failure_code: msg.failure_code,
+            },
+            ours: false,
+            local: Stage::Pending,
+            remote: Stage::Absent,
+        });
+        Ok(())
+    }
+
+    // the fee rate of the latest update of the fee, even not committed
+    fn proposed_fee_rate(&self) -> u32 {
+        self.log.iter()
+            .filter_map(|e| e.update.fee_rate())
+            .last()
+            .unwrap_or(self.fee_rate)
+    }
+
+    // the funder pays the fee of the commitment with all HTLCs in flight and the new one
+    // offered by the side `ours` if any, and keeps the reserve,
+    // BOLT 3: the trimmed HTLCs have no output, so they add no weight
+    fn check_fee(&self, fee_rate: u32, funder_balance: u64, reserve: u64, new_htlc: Option<(bool, u64)>) -> Result<(), String> {
+        let untrimmed = |local: bool| {
+            let dust_limit = if local { self.our_info.config.dust_limit } else { self.their_info.config.dust_limit };
+            self.log.iter()
+                .filter_map(|e| match e.update {
+                    UpdateInfo::AddHtlc { id, amount, .. } => Some((e.ours, id, amount)),
+                    _ => None,
+                })
+                .filter(|&(ours, id, _)| !self.log.iter().any(|e| e.ours != ours && e.update.removes() == Some(id)))
+                .map(|(ours, _, amount)| (ours, amount))
+                .chain(new_htlc)
+                .filter(|&(ours, amount)| {
+                    // the owner of the commitment offered the HTLC, the timeout transaction spends it
+                    let anchors = self.funding.anchors;
+                    let weight = if ours == local { htlc_timeout_weight(anchors) } else { htlc_success_weight(anchors) } as u64;
+                    amount / 1000 >= dust_limit + weight * (fee_rate as u64) / 1000
+                })
+                .count() as u64
+        };
+        let htlcs = cmp::max(untrimmed(true), untrimmed(false));
+        let fee = commitment_fee(fee_rate as i64, htlcs as i64, self.funding.anchors) as u64;
+        if funder_balance < (fee + reserve) * 1000 {
+            return Err(format!("the funder cannot afford the fee {} at the fee rate {}", fee, fee_rate));
+        }
+        Ok(())
+    }
+
+    // as the funder we follow our estimate when it deviates from the current fee rate too much
+    pub(crate) fn needs_fee_update(&self, estimate: u32) -> bool {
+        let estimate = cmp::max(estimate, MIN_FEE_RATE) as u64;
+        let current = self.proposed_fee_rate() as u64;
+        let deviation = if estimate > current { estimate - current } else { current - estimate };
+        self.local_is_funder && deviation * 100 > current * FEE_UPDATE_THRESHOLD_PERCENT
+    }
+
+    // we are the funder and change the fee rate of both commitments
+    pub(crate) fn update_fee(&mut self, fee_rate: u32) -> Result<UpdateFee, String> {
+        if !self.local_is_funder {
+            return Err("only the funder updates the fee".to_owned());
+        }
+        let fee_rate = cmp::max(fee_rate, MIN_FEE_RATE);
+        let (ours, _) = self.projected_balances();
+        self.check_fee(fee_rate, ours, self.their_info.config.chanel_reserve, None)?;
+
+        println!("INFO: updating the fee rate of channel {:?} to {}", self.channel_id, fee_rate);
+        self.log.push(LogEntry {
+            update: UpdateInfo::Fee {
+                fee_rate: fee_rate,
+            },
+            ours: true,
+            local: Stage::Absent,
+            remote: Stage::Pending,
+        });
+        Ok(UpdateFee {
+            channel_id: self.channel_id,
+            fee: SatoshiPerKiloWeight::from(fee_rate),
+        })
+    }
+
+    // the funder's fee rate should be close to our estimate
+    pub(crate) fn receive_update_fee(&mut self, msg: UpdateFee, estimate: u32) -> Result<(), String> {
+        if self.local_is_funder {
+            return Err("update_fee from the fundee".to_owned());
+        }
+        let fee_rate = u32::from(msg.fee);
+        let estimate = cmp::max(estimate, MIN_FEE_RATE) as u64;
+        let (min, max) = (estimate * MIN_FEE_RATE_PERCENT / 100, estimate * MAX_FEE_RATE_PERCENT / 100);
+        if fee_rate < MIN_FEE_RATE || (fee_rate as u64) < min || (fee_rate as u64) > max {
+            return Err(format!("fee rate {} is not between {} and {}", fee_rate, min, max));
+        }
+        let (_, theirs) = self.projected_balances();
+        self.check_fee(fee_rate, theirs, self.our_info.config.chanel_reserve, None)?;
+
+        self.log.push(LogEntry {
+            update: UpdateInfo::Fee {
+                fee_rate: fee_rate,
+            },
+            ours: false,
+            local: Stage::Pending,
+            remote: Stage::Absent,
+        });
+        Ok(())
+    }
+
+    // there are updates which the peer's commitment does not include
+    pub(crate) fn can_sign(&self) -> bool {
+        self.their_revoked_point.is_none()
+            && self.their_next_point.is_some()
+            && self.log.iter().any(|e| e.remote == Stage::Pending)
+    }
+
+    // we sign the peer's next commitment, the peer should revoke the previous one
+    pub(crate) fn sign_commitment(&mut self) -> Option<CommitmentSigned> {
+        if !self.can_sign() {
+            return None;
+        }
+
+        let mut log = self.log.clone();
+        for e in log.iter_mut() {
+            if e.remote == Stage::Pending {
+                e.remote = Stage::Committed;
+            }
+        }
+        let point = self.their_next_point.clone().unwrap();
+        let number = self.remote_number + 1;
+        let (ours, theirs, htlcs) = view(&log, self.our_balance, self.their_balance, false);
+        let commit = commitment_tx(
+            &self.their_info, &self.our_info, &point, &self.funding, !self.local_is_funder, number,
+            committed_fee_rate(&log, self.fee_rate, false), theirs, ours, htlcs,
+        );
+
+        // the updates are checked, so the funder can pay the fee, we never sign the commitment otherwise
+        let private_keys = self.our_info.private_keys.clone().unwrap();
+        let signed = commit.sign(private_keys.funding_sk())
+            .and_then(|signature| commit.htlc_txs().map(|htlc_txs| (signature, htlc_txs)));
+        let (signature, htlc_txs) = match signed {
+            Ok(signed) => signed,
+            Err(description) => {
+                println!("ERROR: channel {:?}, cannot sign commitment {}: {}", self.channel_id, number, description);
+                return None;
+            },
+        };
+        let htlc_sk = derive_privkey(private_keys.htlc_sk(), &point);
+        let htlc_signatures = htlc_txs.iter()
+            .map(|htlc_tx| RawSignature(htlc_tx.sign(&htlc_sk)))
+            .collect();
+        self.log = log;
+        self.their_next_point = None;
+
+        // the previous commitment is revoked soon, its HTLC outputs are remembered,
+        // the trimmed HTLCs have no output, it was signed, so it is valid
+        let revoked_tx = self.remote_commit.get_tx().unwrap();
+        let revoked_number = self.remote_number;
+        for h in self.remote_commit.htlcs.iter() {
+            let script_pubkey = self.remote_commit.htlc_script(h).to_v0_p2wsh();
+            if !revoked_tx.output.iter().any(|o| o.script_pubkey == script_pubkey) {
+                continue;
+            }
+            let offered = match h.direction { HTLCDirection::Offered => true, HTLCDirection::Accepted => false };
+            let expiry = h.expiry as u32;
+            let previous = self.their_revoked_htlcs.iter_mut()
+                .find(|r| r.last_number + 1 == revoked_number && r.offered == offered && r.payment_hash == h.payment_hash && r.expiry == expiry);
+            match previous {
+                Some(previous) => previous.last_number = revoked_number,
+                None => self.their_revoked_htlcs.push(RevokedHtlc {
+                    first_number: revoked_number,
+                    last_number: revoked_number,
+                    offered: offered,
+                    payment_hash: h.payment_hash,
+                    expiry: expiry,
+                }),
+            }
+        }
+        self.their_revoked_commitment = Some(revoked_tx);
+
+        self.their_revoked_point = Some(self.their_point.clone());
+        self.their_point = point;
+        self.remote_number = number;
+        self.remote_commit = commit;
+
+        let commitment_signed = CommitmentSigned {
+            channel_id: self.channel_id,
+            signature: RawSignature(signature),
+            htlc_signatures: htlc_signatures,
+        };
+        self.last_commitment_signed = Some(commitment_signed.clone());
+        Some(commitment_signed)
+    }
+
+    // the peer signed our next commitment, we revoke the previous one
+    pub(crate) fn receive_commitment_signed(&mut self, msg: CommitmentSigned) -> Result<RevokeAndAck, String> {
+        if !self.log.iter().any(|e| e.local == Stage::Pending) {
+            return Err("commitment_signed without changes".to_owned());
+        }
+
+        for e in self.log.iter_mut() {
+            if e.local == Stage::Pending {
+                e.local = Stage::Committed;
+            }
+        }
+        let number = self.local_number + 1;
+        let point = per_commitment_point(self.our_seed(), number);
+        let (ours, theirs, htlcs) = self.view(true);
+        let commit = commitment_tx(
+            &self.our_info, &self.their_info, &point, &self.funding, self.local_is_funder, number,
+            committed_fee_rate(&self.log, self.fee_rate, true), ours, theirs, htlcs,
+        );
+
+        if !commit.verify(&msg.signature.0, self.their_info.keys.funding()) {
+            return Err(format!("wrong signature of commitment {}", number));
+        }
+        let htlc_txs = commit.htlc_txs()?;
+        if htlc_txs.len() != msg.htlc_signatures.len() {
+            return Err(format!("expected {} htlc signatures, got {}", htlc_txs.len(), msg.htlc_signatures.len()));
+        }
+        for (htlc_tx, signature) in htlc_txs.iter().zip(msg.htlc_signatures.iter()) {
+            if !htlc_tx.verify(&signature.0, &commit.remote_htlc_pubkey) {
+                return Err(format!("wrong htlc signature of commitment {}", number));
+            }
+        }
+
+        self.local_number = number;
+        self.local_commit = commit;
+        self.local_signature = msg.signature;
+        self.local_htlc_signatures = msg.htlc_signatures;
+
+        // the peer's updates are acknowledged, they go to the peer's commitment
+        for e in self.log.iter_mut() {
+            if !e.ours && e.local == Stage::Committed && e.remote == Stage::Absent {
+                e.remote = Stage::Pending;
+            }
+        }
+        self.compact();
+
+        Ok(self.last_revoke_and_ack())
+    }
+
+    // revokes our previous commitment, the peer gets the point of the commitment after the latest
+    fn last_revoke_and_ack(&self) -> RevokeAndAck {
+        let secret = per_commitment_secret(self.our_seed(), self.local_number - 1);
+        let mut revocation_preimage = [0; 32];
+        revocation_preimage.copy_from_slice(&secret[..]);
+        RevokeAndAck {
+            channel_id: self.channel_id,
+            revocation_preimage: Sha256::from(revocation_preimage),
+            next_per_commitment_point: RawPublicKey::from(per_commitment_point(self.our_seed(), self.local_number + 1)),
+        }
+    }
+
+    // the peer revoked its previous commitment
+    pub(crate) fn receive_revoke_and_ack(&mut self, msg: RevokeAndAck) -> Result<(), String> {
+        let revoked_point = self.their_revoked_point.clone()
+            .ok_or("unexpected revoke_and_ack".to_owned())?;
+        let secret = SecretKey::from_slice(&msg.revocation_preimage[..])
+            .map_err(|e| format!("bad revocation secret: {:?}", e))?;
+        if PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret).ne(&revoked_point) {
+            return Err(format!("wrong revocation secret of commitment {}", self.remote_number - 1));
+        }
+        self.their_secrets.add_leaf(msg.revocation_preimage)
+            .map_err(|e| format!("cannot store revocation secret: {}", e))?;
+
+        self.their_revoked_point = None;
+        self.their_next_point = Some(msg.next_per_commitment_point.as_ref().clone());
+        self.last_commitment_signed = None;
+
+        // our updates are acknowledged, they go to our commitment
+        for e in self.log.iter_mut() {
+            if e.ours && e.remote == Stage::Committed && e.local == Stage::Absent {
+                e.local = Stage::Pending;
+            }
+        }
+        self.compact();
+        Ok(())
+    }
+
+    pub(crate) fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    pub(crate) fn local_is_funder(&self) -> bool {
+        self.local_is_funder
+    }
+
+    pub(crate) fn our_upfront_shutdown_script(&self) -> Option<&Script> {
+        self.our_info.upfront_shutdown_script.as_ref()
+    }
+
+    pub(crate) fn their_upfront_shutdown_script(&self) -> Option<&Script> {
+        self.their_info.upfront_shutdown_script.as_ref()
+    }
+
+    pub(crate) fn sweep_script(&self) -> Option<&Script> {
+        self.our_info.sweep_script.as_ref()
+    }
+
+    // our funding secret and the peer's funding key, the public channel is announced with them
+    pub(crate) fn funding_keys(&self) -> (SecretKey, PublicKey) {
+        let private_keys = self.our_info.private_keys.as_ref().unwrap();
+        (private_keys.funding_sk().clone(), self.their_info.keys.funding().clone())
+    }
+
+    // the HTLCs we may offer to the peer, the minimum and the maximum in millisatoshi
+    pub(crate) fn htlc_limits(&self) -> (u64, u64) {
+        let config = &self.their_info.config;
+        (config.htlc_minimum, cmp::min(config.max_htlc_value_in_flight, self.funding.amount * 1000))
+    }
+
+    // both commitments are the same, there are no HTLCs and no updates in flight
+    pub(crate) fn is_clear(&self) -> bool {
+        self.log.is_empty() && self.their_revoked_point.is_none()
+    }
+
+    // the preimage of the HTLC we offered, the peer fulfilled it
+    pub(crate) fn their_preimage(&self, id: u64) -> Option<[u8; 32]> {
+        self.log.iter()
+            .filter(|e| !e.ours)
+            .filter_map(|e| match e.update {
+                UpdateInfo::FulfillHtlc { id: fulfilled_id, payment_preimage } if fulfilled_id == id => Some(payment_preimage),
+                _ => None,
+            })
+            .next()
+    }
+
+    // the HTLCs which may have the outputs on the commitments, their removal is not locked in
+    pub(crate) fn has_pending_htlcs(&self) -> bool {
+        self.log.iter().any(|e| match e.update {
+            UpdateInfo::AddHtlc { id, .. } => !self.log.iter().any(|r| r.ours != e.ours && r.locked_in() && r.update.removes() == Some(id)),
+            _ => false,
+        })
+    }
+
+    // our updates which the peer's commitment does not include yet
+    pub(crate) fn has_unsigned_updates(&self) -> bool {
+        self.log.iter().any(|e| e.ours && e.remote != Stage::Committed)
+    }
+
+    // BOLT 2: the closing fee is not greater than the base fee of the final commitment
+    pub(crate) fn max_closing_fee(&self) -> u64 {
+        (commitment_weight(0, self.funding.anchors) as u64) * (self.fee_rate as u64) / 1000
+    }
+
+    // the mutual close transaction, the balances are final when the commitments are clear
+    pub(crate) fn closing_tx(&self, our_script: &Script, their_script: &Script, fee: u64) -> ClosingTx {
+        ClosingTx {
+            funding_amount: self.funding.amount,
+            local_funding_pubkey: self.our_info.keys.funding().clone(),
+            remote_funding_pubkey: self.their_info.keys.funding().clone(),
+            funding_tx_id: self.funding.tx_id,
+            funding_output_index: self.funding.output_index as u32,
+            local_dust_limit_satoshi: self.our_info.config.dust_limit,
+            remote_dust_limit_satoshi: self.their_info.config.dust_limit,
+            local_script: our_script.clone(),
+            remote_script: their_script.clone(),
+            to_local_msat: self.our_balance,
+            to_remote_msat: self.their_balance,
+            local_is_funder: self.local_is_funder,
+            fee: fee,
+        }
+    }
+
+    // the fee we consider fair for the closing transaction
+    pub(crate) fn closing_fee(&self, our_script: &Script, their_script: &Script) -> u64 {
+        let weight = self.closing_tx(our_script, their_script, 0).weight();
+        let fee = weight * (self.fee_rate as u64) / 1000;
+        if fee > self.max_closing_fee() { self.max_closing_fee() } else { fee }
+    }
+
+    pub(crate) fn sign_closing(&self, closing_tx: &ClosingTx) -> Signature {
+        closing_tx.sign(self.our_info.private_keys.clone().unwrap().funding_sk())
+    }
+
+    pub(crate) fn verify_closing(&self, closing_tx: &ClosingTx, signature: &Signature) -> bool {
+        closing_tx.verify(signature, self.their_info.keys.funding())
+    }
+
+    // our latest commitment, the peer signed it, so the funder can pay the fee
+    fn local_tx(&self) -> Transaction {
+        self.local_commit.get_tx().unwrap()
+    }
+
+    // our latest commitment with both signatures, we can broadcast it at any moment
+    pub(crate) fn signed_local_commitment(&self) -> Transaction {
+        let our_signature = self.local_commit.sign(self.our_info.private_keys.clone().unwrap().funding_sk()).unwrap();
+        let mut tx = self.local_tx();
+        tx.input[0].witness = spending_witness_2x2_multisig(
+            self.our_info.keys.funding(),
+            self.their_info.keys.funding(),
+            &our_signature,
+            &self.local_signature.0,
+        );
+        tx
+    }
+
+    // the blocks our latest commitment should be buried under before `to_local` is spendable
+    pub(crate) fn local_delay(&self) -> u64 {
+        self.local_commit.local_delay
+    }
+
+    // Spends our `to_local` output of the latest commitment and the key to sign it,
+    // there is nothing to sweep if the output is dust or less than the fee
+    pub(crate) fn delayed_sweep(&self, destination: Script) -> Option<(DelayedSweepTx, SecretKey)> {
+        let commit = &self.local_commit;
+        let tx = self.local_tx();
+        let script_pubkey = to_local_script(&commit.local_delayedpubkey, commit.local_delay, &commit.local_revocation_pubkey)
+            .to_v0_p2wsh();
+        let output_index = tx.output.iter().position(|o| o.script_pubkey == script_pubkey)?;
+
+        let mut sweep_tx = DelayedSweepTx {
+            commitment_tx_id: tx.txid(),
+            output_index: output_index as u32,
+            amount: tx.output[output_index].value,
+            local_delayedpubkey: commit.local_delayedpubkey.clone(),
+            local_revocation_pubkey: commit.local_revocation_pubkey.clone(),
+            to_self_delay: commit.local_delay,
+            destination: destination,
+            fee: 0,
+        };
+        sweep_tx.fee = sweep_tx.weight() * (self.fee_rate as u64) / 1000;
+        if sweep_tx.fee + self.our_info.config.dust_limit > sweep_tx.amount {
+            return None;
+        }
+
+        let point = per_commitment_point(self.our_seed(), self.local_number);
+        let private_keys = self.our_info.private_keys.clone().unwrap();
+        let delayed_sk = derive_privkey(private_keys.delayed_payment_sk(), &point);
+        Some((sweep_tx, delayed_sk))
+    }
+
+    // the message which carries our update to the peer
+    fn update_message(&self, update: &UpdateInfo) -> Message {
+        match update {
+            &UpdateInfo::AddHtlc { id, amount, payment_hash, expiry, ref onion_blob } => Message::UpdateAddHtlc(UpdateAddHtlc {
+                channel_id: self.channel_id,
+                id: HtlcId::from_u64(id),
+                amount: MilliSatoshi::from(amount),
+                payment_hash: Sha256::from(payment_hash),
+                expiry: expiry,
+                onion_blob: onion_blob.clone(),
+            }),
+            &UpdateInfo::FulfillHtlc { id, payment_preimage } => Message::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                channel_id: self.channel_id,
+                id: HtlcId::from_u64(id),
+                payment_preimage: Sha256::from(payment_preimage),
+            }),
+            &UpdateInfo::FailHtlc { id, ref reason } => Message::UpdateFailHtlc(UpdateFailHtlc {
+                channel_id: self.channel_id,
+                id: HtlcId::from_u64(id),
+                reason: reason.clone(),
+            }),
+            &UpdateInfo::FailMalformedHtlc { id, ref sha256_of_onion, failure_code } => Message::UpdateFailMalformedHtlc(UpdateFailMalformedHtlc {
+                channel_id: self.channel_id,
+                id: HtlcId::from_u64(id),
+                sha256_of_onion: sha256_of_onion.clone(),
+                failure_code: failure_code,
+            }),
+            &UpdateInfo::Fee { fee_rate } => Message::UpdateFee(UpdateFee {
+                channel_id: self.channel_id,
+                fee: SatoshiPerKiloWeight::from(fee_rate),
+            }),
+        }
+    }
+
+    // the number of the peer's oldest commitment which is not revoked
+    fn their_unrevoked_number(&self) -> u64 {
+        if self.their_revoked_point.is_some() { self.remote_number - 1 } else { self.remote_number }
+    }
+
+    // BOLT 2 `channel_reestablish` with `option_data_loss_protect` fields,
+    // sent to the peer after the reconnection
+    pub(crate) fn reestablish_message(&self) -> ReestablishChannel {
+        let next_revocation_number = self.their_unrevoked_number();
+        let mut last_secret = [0; 32];
+        if next_revocation_number > 0 {
+            if let Ok(secret) = self.their_secrets.lookup(LeafIndex::new(next_revocation_number - 1)) {
+                last_secret.copy_from_slice(&secret[..]);
+            }
+        }
+        ReestablishChannel {
+            channel_id: self.channel_id,
+            next_local_commitment_number: self.local_number + 1,
+            next_remote_revocation_number: next_revocation_number,
+            last_remote_commit_secret: last_secret,
+            local_unrevoked_commit_point: RawPublicKey::from(per_commitment_point(self.our_seed(), self.local_number)),
+        }
+    }
+
+    // Compares the peer's view of the channel with ours after the reconnection.
+    // The peer's updates which we did not receive `commitment_signed` for are forgotten,
+    // the peer retransmits them. Our updates and signatures the peer has lost are retransmitted.
+    pub(crate) fn receive_reestablish(&mut self, msg: ReestablishChannel) -> Result<Resync, String> {
+        let next_commitment = msg.next_local_commitment_number;
+        let next_revocation = msg.next_remote_revocation_number;
+
+        // the peer has revoked our commitment which we do not have, we lost the state,
+        // the secret proves the peer does not lie about it
+        if next_revocation > self.local_number {
+            let secret = per_commitment_secret(self.our_seed(), next_revocation - 1);
+            if secret[..] != msg.last_remote_commit_secret[..] {
+                return Err(format!("the peer claims our revoked commitment {} with wrong secret", next_revocation - 1));
+            }
+            return Ok(Resync::DataLoss(msg.local_unrevoked_commit_point.as_ref().clone()));
+        }
+        if next_revocation > 0 {
+            let secret = per_commitment_secret(self.our_seed(), next_revocation - 1);
+            if secret[..] != msg.last_remote_commit_secret[..] {
+                return Err(format!("wrong secret of our revoked commitment {}", next_revocation - 1));
+            }
+        }
+
+        let their_point = if next_commitment == self.remote_number + 1 {
+            &self.their_point
+        } else if next_commitment == self.remote_number && self.last_commitment_signed.is_some() {
+            self.their_revoked_point.as_ref().unwrap()
+        } else {
+            return Err(format!("the peer expects commitment {}, our latest signed is {}", next_commitment, self.remote_number));
+        };
+        if their_point.ne(msg.local_unrevoked_commit_point.as_ref()) {
+            return Err(format!("wrong current per commitment point of commitment {}", next_commitment - 1));
+        }
+
+        let forgotten_adds = self.log.iter()
+            .filter(|e| !e.ours && e.local == Stage::Pending)
+            .filter(|e| e.update.removes().is_none())
+            .count() as u64;
+        self.log.retain(|e| e.ours || e.local != Stage::Pending);
+        self.next_their_htlc_id -= forgotten_adds;
+
+        let mut messages = Vec::new();
+        // BOLT 2: `funding_locked` is retransmitted while there are no updates
+        if self.local_number == 0 && next_commitment == 1 {
+            messages.push(Message::FundingLocked(FundingLocked {
+                channel_id: self.channel_id,
+                next_per_commitment_point: RawPublicKey::from(per_commitment_point(self.our_seed(), 1)),
+            }));
+        }
+        // we answer `commitment_signed` with `revoke_and_ack` before signing anything,
+        // so the revocation goes first
+        if next_revocation + 1 == self.local_number {
+            messages.push(Message::RevokeAndAck(self.last_revoke_and_ack()));
+        } else if next_revocation != self.local_number {
+            return Err(format!("the peer expects revocation {}, our latest is {}", next_revocation, self.local_number));
+        }
+        if next_commitment == self.remote_number {
+            let signed = self.log.iter()
+                .filter(|e| e.ours && e.remote == Stage::Committed && e.local == Stage::Absent)
+                .map(|e| self.update_message(&e.update));
+            messages.extend(signed);
+            messages.push(Message::CommitmentSigned(self.last_commitment_signed.clone().unwrap()));
+        }
+        // the peer forgot our updates which were not signed
+        let unsigned = self.log.iter()
+            .filter(|e| e.ours && e.remote == Stage::Pending)
+            .map(|e| self.update_message(&e.update));
+        messages.extend(unsigned);
+        Ok(Resync::Retransmit(messages))
+    }
+
+    pub(crate) fn funding_outpoint(&self) -> (sha256d::Hash, u32) {
+        (self.funding.tx_id, self.funding.output_index as u32)
+    }
+
+    // our key of the `to_remote` output of the peer's commitment with the given point,
+    // with `option_static_remotekey` it is the same for every commitment
+    fn our_payment_key(&self, their_point: &PublicKey) -> SecretKey {
+        let private_keys = self.our_info.private_keys.clone().unwrap();
+        if self.funding.static_remotekey {
+            private_keys.payment_sk().clone()
+        } else {
+            derive_privkey(private_keys.payment_sk(), their_point)
+        }
+    }
+
+    // Spends our `to_remote` output of the peer's commitment with the given point,
+    // and the key to sign it, there is nothing to sweep if the commitment lacks the output
+    pub(crate) fn to_remote_sweep(&self, commitment_tx: &Transaction, their_point: &PublicKey, destination: Script) -> Option<(ToRemoteSweepTx, SecretKey)> {
+        let remotepubkey = if self.funding.static_remotekey {
+            self.our_info.keys.payment().clone()
+        } else {
+            self.our_info.payment_pubkey(their_point)
+        };
+        let script_pubkey = if self.funding.anchors {
+            to_remote_anchor_script(&remotepubkey).to_v0_p2wsh()
+        } else {
+            v0_p2wpkh(&remotepubkey)
+        };
+        let output_index = commitment_tx.output.iter().position(|o| o.script_pubkey == script_pubkey)?;
+
+        let mut sweep_tx = ToRemoteSweepTx {
+            commitment_tx_id: commitment_tx.txid(),
+            output_index: output_index as u32,
+            amount: commitment_tx.output[output_index].value,
+            remotepubkey: remotepubkey,
+            anchors: self.funding.anchors,
+            destination: destination,
+            fee: 0,
+        };
+        sweep_tx.fee = sweep_tx.weight() * (self.fee_rate as u64) / 1000;
+        if sweep_tx.fee + self.our_info.config.dust_limit > sweep_tx.amount {
+            return None;
+        }
+
+        Some((sweep_tx, self.our_payment_key(their_point)))
+    }
+
+    // BOLT 3: the commitment number is obscured in the lower 24 bits
+    // of the sequence and the locktime, the upper 8 bits mark the commitment
+    pub(crate) fn classify_spend(&self, tx: &Transaction) -> Spend {
+        if tx.txid() == self.local_tx().txid() {
+            return Spend::Local;
+        }
+        let sequence = tx.input[0].sequence as u64;
+        let lock_time = tx.lock_time as u64;
+        if sequence >> 24 != 0x80 || lock_time >> 24 != 0x20 {
+            return Spend::Other;
+        }
+        let obscured = ((sequence & 0xffffff) << 24) | (lock_time & 0xffffff);
+        let number = obscured ^ self.funding.obscuring_factor;
+
+        if number < self.local_number && self.is_local_commitment(tx, number) {
+            Spend::LocalRevoked(number)
+        } else if number < self.their_unrevoked_number() {
+            Spend::Revoked(number)
+        } else if number == self.remote_number {
+            Spend::Remote(self.their_point.clone())
+        } else if number + 1 == self.remote_number && self.their_revoked_point.is_some() {
+            Spend::Remote(self.their_revoked_point.clone().unwrap())
+        } else {
+            Spend::Other
+        }
+    }
+
+    // both sides' commitments have the same numbers, ours has our `to_local` or the peer's `to_remote`
+    fn is_local_commitment(&self, tx: &Transaction, number: u64) -> bool {
+        let point = per_commitment_point(self.our_seed(), number);
+        let commit = commitment_tx(
+            &self.our_info, &self.their_info, &point, &self.funding, self.local_is_funder, number,
+            0, 0, 0, vec![],
+        );
+        let to_local = to_local_script(&commit.local_delayedpubkey, commit.local_delay, &commit.local_revocation_pubkey)
+            .to_v0_p2wsh();
+        let to_remote = commit.to_remote_script_pubkey();
+        tx.output.iter().any(|o| o.script_pubkey == to_local || o.script_pubkey == to_remote)
+    }
+
+    // the secret and the point of the peer's revoked commitment, and the commitment without outputs,
+    // only its keys are needed, the peer is the local side of it
+    fn revoked_commitment(&self, number: u64) -> Option<(SecretKey, PublicKey, CommitTx)> {
+        let secret = self.their_secrets.lookup(LeafIndex::new(number)).ok()?;
+        let secret = SecretKey::from_slice(&secret[..]).ok()?;
+        let point = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret);
+        let commit = commitment_tx(
+            &self.their_info, &self.our_info, &point, &self.funding, !self.local_is_funder, number,
+            0, 0, 0, vec![],
+        );
+        Some((secret, point, commit))
+    }
+
+    // the outputs of the peer's revoked commitment which the revocation key or our payment key spends
+    fn justice_inputs(&self, commitment_tx: &Transaction, number: u64, commit: &CommitTx) -> Vec<JusticeInput> {
+        let to_local = to_local_script(&commit.local_delayedpubkey, commit.local_delay, &commit.local_revocation_pubkey);
+        let to_remote = commit.to_remote_script_pubkey();
+        let htlc_scripts = self.their_revoked_htlcs.iter()
+            .filter(|h| h.first_number <= number && number <= h.last_number)
+            .map(|h| commit.htlc_script(&HTLC {
+                direction: if h.offered { HTLCDirection::Offered } else { HTLCDirection::Accepted },
+                amount_msat: 0,
+                expiry: h.expiry as i32,
+                payment_hash: h.payment_hash,
+            }))
+            .collect::<Vec<_>>();
+
+        commitment_tx.output.iter()
+            .enumerate()
+            .filter_map(|(index, o)| {
+                let output = if o.script_pubkey == to_local.to_v0_p2wsh() {
+                    RevokedOutput::ToLocal(to_local.clone())
+                } else if o.script_pubkey == to_remote && commit.anchors {
+                    RevokedOutput::ToRemoteAnchor(commit.remotepubkey.clone())
+                } else if o.script_pubkey == to_remote {
+                    RevokedOutput::ToRemote(commit.remotepubkey.clone())
+                } else {
+                    let script = htlc_scripts.iter().find(|s| s.to_v0_p2wsh() == o.script_pubkey)?;
+                    RevokedOutput::Htlc(script.clone())
+                };
+                Some(JusticeInput {
+                    output_index: index as u32,
+                    amount: o.value,
+                    output: output,
+                })
+            })
+            .collect()
+    }
+
+    // the signed justice transaction, `None` if the outputs do not cover the fee
+    fn sign_justice(
+        &self,
+        spent_tx_id: sha256d::Hash,
+        inputs: Vec<JusticeInput>,
+        revoked: &(SecretKey, PublicKey, CommitTx),
+        destination: Script,
+    ) -> Option<Transaction> {
+        let &(ref secret, ref point, ref commit) = revoked;
+        let mut justice_tx = JusticeTx {
+            commitment_tx_id: spent_tx_id,
+            inputs: inputs,
+            revocationpubkey: commit.local_revocation_pubkey.clone(),
+            destination: destination,
+            fee: 0,
+        };
+        justice_tx.fee = justice_tx.weight() * (self.fee_rate as u64) / 1000;
+        if justice_tx.inputs.is_empty() || justice_tx.fee + self.our_info.config.dust_limit > justice_tx.amount() {
+            return None;
+        }
+
+        let private_keys = self.our_info.private_keys.clone().unwrap();
+        let revocation_sk = derive_revocation_privkey(private_keys.revocation_sk(), secret);
+        let payment_sk = self.our_payment_key(point);
+        let signatures = justice_tx.sign(&revocation_sk, &payment_sk);
+        Some(justice_tx.signed_tx(&signatures))
+    }
+
+    // The signed justice transactions of the peer's revoked commitment, one for each output,
+    // so the peer's HTLC transaction spending one output does not invalidate the justice of the others,
+    // empty if the secret is unknown or no output covers the fee
+    pub(crate) fn justice(&self, commitment_tx: &Transaction, number: u64, destination: Script) -> Vec<Transaction> {
+        let revoked = match self.revoked_commitment(number) {
+            Some(revoked) => revoked,
+            None => return Vec::new(),
+        };
+        self.justice_inputs(commitment_tx, number, &revoked.2).into_iter()
+            .filter_map(|input| self.sign_justice(commitment_tx.txid(), vec![input], &revoked, destination.clone()))
+            .collect()
+    }
+
+    // The peer spent the HTLC output of its revoked commitment by the HTLC-timeout or HTLC-success
+    // transaction, its output has the same script as the peer's `to_local` and is spent by the revocation key
+    pub(crate) fn second_stage_justice(&self, htlc_tx: &Transaction, number: u64, destination: Script) -> Option<Transaction> {
+        let revoked = self.revoked_commitment(number)?;
+        let to_local = {
+            let commit = &revoked.2;
+            to_local_script(&commit.local_delayedpubkey, commit.local_delay, &commit.local_revocation_pubkey)
+        };
+        let output = htlc_tx.output.first()?;
+        if output.script_pubkey != to_local.to_v0_p2wsh() {
+            return None;
+        }
+        let input = JusticeInput {
+            output_index: 0,
+            amount: output.value,
+            output: RevokedOutput::ToLocal(to_local),
+        };
+        self.sign_justice(htlc_tx.txid(), vec![input], &revoked, destination)
+    }
+
+    // The peer's commitment revoked by the latest `revoke_and_ack` and the justice transaction for the watchtowers,
+    // `None` while the peer has not revoked it. The towers take the balances, the peer cannot spend them
+    // before us, the HTLC outputs are left to the node
+    pub(crate) fn breach_remedy(&self, destination: Script) -> Option<(Transaction, Transaction)> {
+        if self.their_revoked_point.is_some() {
+            return None;
+        }
+        let commitment_tx = self.their_revoked_commitment.as_ref()?;
+        let number = self.remote_number - 1;
+        let revoked = self.revoked_commitment(number)?;
+        let inputs = self.justice_inputs(commitment_tx, number, &revoked.2).into_iter()
+            .filter(|input| match &input.output {
+                &RevokedOutput::Htlc(_) => false,
+                _ => true,
+            })
+            .collect();
+        let justice_tx = self.sign_justice(commitment_tx.txid(), inputs, &revoked, destination)?;
+        Some((commitment_tx.clone(), justice_tx))
+    }
+}
+
+// the inspection of the commitments by the simulation of two channels
+#[cfg(test)]
+impl Commitments {
+    pub(crate) fn funding_amount(&self) -> u64 {
+        self.funding.amount
+    }
+
+    // the irrevocably committed balances, in millisatoshi
+    pub(crate) fn balances(&self) -> (u64, u64) {
+        (self.our_balance, self.their_balance)
+    }
+
+    // the number and the transaction of our latest commitment if `local`, otherwise of the peer's
+    pub(crate) fn latest_commitment(&self, local: bool) -> (u64, &CommitTx) {
+        if local { (self.local_number, &self.local_commit) } else { (self.remote_number, &self.remote_commit) }
+    }
+
+    // the peer's signatures of our latest commitment and of its HTLC transactions are valid
+    pub(crate) fn local_commitment_is_signed(&self) -> bool {
+        let commit = &self.local_commit;
+        let htlc_txs = match commit.htlc_txs() {
+            Ok(htlc_txs) => htlc_txs,
+            Err(_) => return false,
+        };
+        commit.verify(&self.local_signature.0, self.their_info.keys.funding())
+            && htlc_txs.len() == self.local_htlc_signatures.len()
+            && htlc_txs.iter().zip(self.local_htlc_signatures.iter())
+                .all(|(htlc_tx, signature)| htlc_tx.verify(&signature.0, &commit.remote_htlc_pubkey))
+    }
+
+    // every update is committed to both sides and the peer revoked its previous commitment
+    pub(crate) fn is_quiescent(&self) -> bool {
+        self.their_revoked_point.is_none() && self.log.iter().all(LogEntry::locked_in)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use secp256k1::Message as SecpMessage;
+    use wire::Error;
+
+    use crate::b_box::{ChannelState, PartnerConfig, fail_channel, failed_by_peer};
+    use crate::testing::MockWallet;
+    use super::*;
+
+    // in satoshi
+    pub(crate) const FUNDING: u64 = 1_000_000;
+
+    fn partner() -> PartnerInfo {
+        let mut info = PartnerInfo::new_random();
+        info.config = PartnerConfig {
+            dust_limit: 546,
+            max_htlc_value_in_flight: FUNDING * 1000,
+            chanel_reserve: 10_000,
+            htlc_minimum: 1,
+            csv_delay: 144,
+            max_accepted_htlc_number: 483,
+            local_fee_rate: MIN_FEE_RATE,
+        };
+        info
+    }
+
+    // what the peer knows about us
+    fn public(info: &PartnerInfo) -> PartnerInfo {
+        let mut info = info.clone();
+        info.private_keys = None;
+        info.per_commitment_seed = None;
+        info.sweep_script = None;
+        info
+    }
+
+    // both sides of the funded channel, the funder is the first,
+    // each holds the peer's signature of its first commitment
+    pub(crate) fn channel() -> (Commitments, Commitments) {
+        let channel_id = ChannelId::from([7; 32]);
+        let funding = FundingOutput {
+            amount: FUNDING,
+            tx_id: sha256d::Hash::hash(b"funding"),
+            output_index: 0,
+            obscuring_factor: 0,
+            static_remotekey: false,
+            anchors: false,
+        };
+        let (funder_info, fundee_info) = (partner(), partner());
+        let funder_next = per_commitment_point(funder_info.per_commitment_seed.as_ref().unwrap(), 1);
+        let fundee_next = per_commitment_point(fundee_info.per_commitment_seed.as_ref().unwrap(), 1);
+        let funder_sk = funder_info.private_keys.as_ref().unwrap().funding_sk().clone();
+        let fundee_sk = fundee_info.private_keys.as_ref().unwrap().funding_sk().clone();
+        // replaced by the peer's signature
+        let placeholder = RawSignature(Secp256k1::signing_only().sign(&SecpMessage::from_slice(&[1; 32]).unwrap(), &funder_sk));
+
+        let mut funder = Commitments::new(
+            channel_id, funder_info.clone(), public(&fundee_info), funding.clone(), true,
+            FUNDING * 1000, 0, placeholder.clone(), fundee_next,
+        );
+        let mut fundee = Commitments::new(
+            channel_id, fundee_info, public(&funder_info), funding, false,
+            0, FUNDING * 1000, placeholder, funder_next,
+        );
+        funder.local_signature = RawSignature(funder.local_commit.sign(&fundee_sk).unwrap());
+        fundee.local_signature = RawSignature(fundee.local_commit.sign(&funder_sk).unwrap());
+        (funder, fundee)
+    }
+
+    // the dust HTLCs have no output, the funder does not pay the fee for them
+    #[test]
+    fn trimmed_htlcs_do_not_count_in_fee() {
+        let (mut funder, _) = channel();
+        for _ in 0..400 {
+            funder.add_htlc(1_000_000, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).unwrap();
+        }
+        // every HTLC is below the dust limit and the fee of the timeout transaction
+        funder.update_fee(10_000).unwrap();
+
+        // the HTLC which has the output is paid
+        funder.add_htlc(400_000_000, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).unwrap();
+        assert!(funder.update_fee(300_000).is_err());
+        assert!(funder.update_fee(200_000).is_ok());
+    }
+
+    // BOLT 2: the funder keeps the reserve and the fee of the commitment with the new HTLC
+    #[test]
+    fn funder_affords_the_fee_of_the_new_htlc() {
+        let (mut funder, _) = channel();
+        let fee = commitment_fee(MIN_FEE_RATE as i64, 1, false) as u64;
+        let edge = FUNDING * 1000 - (10_000 + fee) * 1000;
+        assert!(funder.add_htlc(edge + 1000, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).is_err());
+        assert!(funder.add_htlc(edge, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).is_ok());
+    }
+
+    // BOLT 2: the fundee fails the channel when the funder cannot pay the fee with the offered HTLC
+    #[test]
+    fn unaffordable_htlc_of_the_funder_is_refused() {
+        let (mut funder, mut fundee) = channel();
+        let fee = commitment_fee(MIN_FEE_RATE as i64, 1, false) as u64;
+        let edge = FUNDING * 1000 - (10_000 + fee) * 1000;
+        let mut update = funder.add_htlc(edge, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).unwrap();
+        update.amount = (u64::from(update.amount) + 1000).into();
+        assert!(fundee.receive_add_htlc(update).is_err());
+    }
+
+    // the peer's commitment signature is wrong, the channel fails,
+    // our latest commitment signed by the peer is broadcast
+    #[test]
+    fn wrong_commitment_signature_force_closes() {
+        let (mut funder, mut fundee) = channel();
+        let update = funder.add_htlc(50_000_000, [1; 32], 500_000, OnionBlob { data: [0; 1366] }).unwrap();
+        fundee.receive_add_htlc(update).unwrap();
+        let mut commitment_signed = funder.sign_commitment().unwrap();
+        commitment_signed.signature = RawSignature(fundee.local_commit.sign(&SecretKey::from_slice(&[2; 32]).unwrap()).unwrap());
+
+        assert!(fundee.local_commitment_is_signed());
+        let expected = fundee.signed_local_commitment();
+        let description = fundee.receive_commitment_signed(commitment_signed).unwrap_err();
+
+        let mut wallet = MockWallet::default();
+        let (state, message) = fail_channel(fundee.channel_id, fundee, description, &mut wallet);
+        match state {
+            ChannelState::ForceClosing(ref data) => assert_eq!(data.commitment_txid(), expected.txid()),
+            state => panic!("the channel is not force closed: {:?}", state),
+        }
+        match message {
+            Some(Message::Error(_)) => (),
+            message => panic!("the peer is not told: {:?}", message),
+        }
+        assert_eq!(wallet.published, vec![expected]);
+    }
+
+    // BOLT 1: the peer's `error` fails the channel, our latest commitment is broadcast
+    #[test]
+    fn peer_error_force_closes() {
+        let (funder, _) = channel();
+        let expected = funder.signed_local_commitment();
+        let msg = Error {
+            channel_id: funder.channel_id,
+            data: b"internal error".to_vec(),
+        };
+
+        let mut wallet = MockWallet::default();
+        match failed_by_peer(funder.channel_id, funder, msg, &mut wallet) {
+            ChannelState::ForceClosing(ref data) => assert_eq!(data.commitment_txid(), expected.txid()),
+            state => panic!("the channel is not force closed: {:?}", state),
+        }
+        assert_eq!(wallet.published, vec![expected]);
+    }
+}
@@ -1,4 +1,21 @@
 #![forbid(unsafe_code)]
 
 mod b_box;
-pub use self::b_box::{ChannelState, InitialState, ReadyState, OpeningState, WaitFundingCreatedData, WaitFundingLockedData};
+mod commitment;
+mod closing;
+mod policy;
+mod codec;
+mod announcement;
+#[cfg(test)]
+mod simulation;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub use self::b_box::{
+    ChannelState, InitialState, ReadyState, OpeningState, OpenChannelParams, FundingWallet,
+    WaitAcceptChannelData, WaitFundingSignedData, WaitFundingCreatedData, WaitFundingLockedData,
+    static_remotekey, anchors,
+};
+pub use self::commitment::{Commitments, UpdateInfo, BreachRemedy};
+pub use self::closing::{ClosingState, ForceClosingState, DataLossState, PunishingState, ClosedState};
+pub use self::policy::ChannelPolicy;
+pub use self::announcement::ChannelAnnouncement;
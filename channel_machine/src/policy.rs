@@ -0,0 +1,287 @@
+use std::cmp;
+
+use wire::{OpenChannel, Sha256};
+
+use serde_derive::{Serialize, Deserialize};
+
+use channel::commit::commitment_fee;
+
+use crate::b_box::{PartnerConfig, OpenChannelParams, MAX_CSV_DELAY, MAX_ACCEPTED_HTLC_NUMBER, check_upfront_shutdown_script};
+
+// BOLT 2: the funding is less than 2^24 satoshi
+const MAX_FUNDING: u64 = (1 << 24) - 1;
+
+// BOLT 3: the outputs below it are not standard
+const MIN_DUST_LIMIT: u64 = 354;
+
+/// The limits of the channels which the peers open with us, and our parameters of such channels,
+/// they do not depend on the parameters of the peer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelPolicy {
+    // the chain of the node's wallet, the default is the mainnet
+    pub chain_hash: Sha256,
+    // in satoshi
+    pub min_funding: u64,
+    pub max_funding: u64,
+    // the delay of our to-self outputs which the peer requires
+    pub max_csv_delay: u16,
+    // the reserve which the peer requires us to keep, the maximum is in percents of the funding
+    pub min_channel_reserve: u64,
+    pub max_channel_reserve_percent: u64,
+    // the dust limit of the peer's commitment, in satoshi
+    pub min_dust_limit: u64,
+    pub max_dust_limit: u64,
+
+    // our parameters
+    pub dust_limit: u64,
+    // the reserve we require the peer to keep, in percents of the funding
+    pub channel_reserve_percent: u64,
+    // in millisatoshi
+    pub htlc_minimum: u64,
+    // the delay of the peer's to-self outputs
+    pub csv_delay: u16,
+    pub max_accepted_htlc_number: u16,
+    // the confirmations of the funding transaction before the channel operates
+    pub minimum_depth: u32,
+}
+
+impl Default for ChannelPolicy {
+    fn default() -> Self {
+        ChannelPolicy {
+            chain_hash: Sha256::BITCOIN_CHAIN_HASH,
+            min_funding: 20_000,
+            max_funding: MAX_FUNDING,
+            max_csv_delay: MAX_CSV_DELAY,
+            min_channel_reserve: 546,
+            max_channel_reserve_percent: 20,
+            min_dust_limit: MIN_DUST_LIMIT,
+            max_dust_limit: 20_000,
+            dust_limit: 546,
+            channel_reserve_percent: 1,
+            htlc_minimum: 1000,
+            csv_delay: 144,
+            max_accepted_htlc_number: MAX_ACCEPTED_HTLC_NUMBER,
+            minimum_depth: 3,
+        }
+    }
+}
+
+impl ChannelPolicy {
+    // BOLT 2: the reserve is not less than the peer's dust limit
+    fn channel_reserve(&self, funding: u64, their_dust_limit: u64) -> u64 {
+        let reserve = funding * self.channel_reserve_percent / 100;
+        if reserve < their_dust_limit { their_dust_limit } else { reserve }
+    }
+
+    // the peer's `open_channel` is rejected if it violates BOLT 2 or the policy,
+    // `anchors` is the commitment format of the channel
+    pub fn check_open_channel(&self, msg: &OpenChannel, anchors: bool) -> Result<(), String> {
+        if msg.chain_hash != self.chain_hash {
+            return Err(format!("unknown chain {:?}", msg.chain_hash));
+        }
+        let funding = u64::from(msg.funding);
+        if funding < self.min_funding || funding > self.max_funding {
+            return Err(format!("funding {} is not between {} and {}", funding, self.min_funding, self.max_funding));
+        }
+        let push = u64::from(msg.push);
+        if push > funding * 1000 {
+            return Err(format!("push {} msat is greater than the funding", push));
+        }
+        let csv_delay = u16::from(msg.csv_delay);
+        if csv_delay > self.max_csv_delay {
+            return Err(format!("to self delay {} is greater than {}", csv_delay, self.max_csv_delay));
+        }
+        let channel_reserve = u64::from(msg.channel_reserve);
+        let max_channel_reserve = funding * self.max_channel_reserve_percent / 100;
+        if channel_reserve < self.min_channel_reserve || channel_reserve > max_channel_reserve {
+            return Err(format!("channel reserve {} is not between {} and {}", channel_reserve, self.min_channel_reserve, max_channel_reserve));
+        }
+        if channel_reserve < self.dust_limit {
+            return Err(format!("channel reserve {} is less than our dust limit {}", channel_reserve, self.dust_limit));
+        }
+        let dust_limit = u64::from(msg.dust_limit);
+        if dust_limit < self.min_dust_limit || dust_limit > self.max_dust_limit {
+            return Err(format!("dust limit {} is not between {} and {}", dust_limit, self.min_dust_limit, self.max_dust_limit));
+        }
+        if dust_limit > channel_reserve {
+            return Err(format!("dust limit {} is greater than the channel reserve {}", dust_limit, channel_reserve));
+        }
+        let max_accepted_htlc_number = u16::from(msg.max_accepted_htlc_number);
+        if max_accepted_htlc_number == 0 || max_accepted_htlc_number > MAX_ACCEPTED_HTLC_NUMBER {
+            return Err(format!("max accepted htlcs {} is not between 1 and {}", max_accepted_htlc_number, MAX_ACCEPTED_HTLC_NUMBER));
+        }
+        // the funder pays the fee of the first commitment and keeps the reserve we require
+        let fee = commitment_fee(u32::from(msg.fee) as i64, 0, anchors) as u64;
+        let funder = funding - push / 1000;
+        if funder < fee + self.channel_reserve(funding, dust_limit) {
+            return Err(format!("the funder cannot pay the commitment fee {} and keep the reserve", fee));
+        }
+        check_upfront_shutdown_script(&msg.shutdown_script)?;
+        Ok(())
+    }
+
+    // the channel we open follows our parameters, the peer checks them against its own policy
+    pub fn open_params(&self, funding: u64, push: u64) -> OpenChannelParams {
+        let mut params = OpenChannelParams::new(funding, push);
+        params.chain_hash = self.chain_hash;
+        params.dust_limit = self.dust_limit;
+        params.channel_reserve = cmp::max(funding * self.channel_reserve_percent / 100, self.dust_limit);
+        params.htlc_minimum = self.htlc_minimum;
+        params.csv_delay = self.csv_delay;
+        params.max_accepted_htlc_number = self.max_accepted_htlc_number;
+        params
+    }
+
+    // our side of the channel opened by the peer, the peer chooses the fee rate
+    pub(crate) fn our_config(&self, msg: &OpenChannel) -> PartnerConfig {
+        let funding = u64::from(msg.funding);
+        PartnerConfig {
+            dust_limit: self.dust_limit,
+            max_htlc_value_in_flight: funding * 1000,
+            chanel_reserve: self.channel_reserve(funding, u64::from(msg.dust_limit)),
+            htlc_minimum: self.htlc_minimum,
+            csv_delay: self.csv_delay,
+            max_accepted_htlc_number: self.max_accepted_htlc_number,
+            local_fee_rate: u32::from(msg.fee),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wire::{ChannelId, Satoshi, MilliSatoshi, SatoshiPerKiloWeight, CsvDelay, ChannelFlags};
+
+    use super::*;
+    use crate::b_box::PartnerInfo;
+
+    // the channel which the default policy accepts
+    fn open_channel() -> OpenChannel {
+        OpenChannel {
+            chain_hash: Sha256::BITCOIN_CHAIN_HASH,
+            temporary_channel_id: ChannelId::from([1; 32]),
+            funding: Satoshi::from(1_000_000),
+            push: MilliSatoshi::from(0),
+            dust_limit: Satoshi::from(546),
+            max_in_flight: MilliSatoshi::from(1_000_000_000),
+            channel_reserve: Satoshi::from(10_000),
+            htlc_minimum: MilliSatoshi::from(1000),
+            fee: SatoshiPerKiloWeight::from(253),
+            csv_delay: CsvDelay::from(144),
+            max_accepted_htlc_number: 483,
+            keys: PartnerInfo::new_random().keys,
+            flags: ChannelFlags::FF_ANNOUNCE_CHANNEL,
+            shutdown_script: None,
+        }
+    }
+
+    fn check<F>(policy: &ChannelPolicy, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut OpenChannel),
+    {
+        let mut msg = open_channel();
+        f(&mut msg);
+        policy.check_open_channel(&msg, false)
+    }
+
+    #[test]
+    fn acceptable_channel() {
+        assert_eq!(check(&ChannelPolicy::default(), |_| ()), Ok(()));
+    }
+
+    #[test]
+    fn other_chain_is_rejected() {
+        let policy = ChannelPolicy::default();
+        assert!(check(&policy, |msg| msg.chain_hash = Sha256::TEST_HASH).is_err());
+
+        let mut policy = ChannelPolicy::default();
+        policy.chain_hash = Sha256::TEST_HASH;
+        assert!(check(&policy, |_| ()).is_err());
+        assert_eq!(check(&policy, |msg| msg.chain_hash = Sha256::TEST_HASH), Ok(()));
+    }
+
+    #[test]
+    fn funding_and_push_limits() {
+        let policy = ChannelPolicy::default();
+        assert!(check(&policy, |msg| msg.funding = Satoshi::from(policy.min_funding - 1)).is_err());
+        assert!(check(&policy, |msg| msg.funding = Satoshi::from(MAX_FUNDING + 1)).is_err());
+        assert!(check(&policy, |msg| msg.push = MilliSatoshi::from(1_000_000_001)).is_err());
+        // the funder keeps nothing for the fee and the reserve
+        assert!(check(&policy, |msg| msg.push = MilliSatoshi::from(1_000_000_000)).is_err());
+    }
+
+    #[test]
+    fn funder_pays_for_the_anchors() {
+        let policy = ChannelPolicy::default();
+        // the funder keeps the reserve and 500 sat, the legacy fee is 183 sat,
+        // the anchor commitment costs 284 sat and two anchors of 330 sat
+        let mut msg = open_channel();
+        msg.push = MilliSatoshi::from(989_500_000);
+        assert_eq!(policy.check_open_channel(&msg, false), Ok(()));
+        assert!(policy.check_open_channel(&msg, true).is_err());
+    }
+
+    #[test]
+    fn delay_and_htlc_limits() {
+        let policy = ChannelPolicy::default();
+        assert!(check(&policy, |msg| msg.csv_delay = CsvDelay::from(MAX_CSV_DELAY + 1)).is_err());
+        assert!(check(&policy, |msg| msg.max_accepted_htlc_number = 0).is_err());
+        assert!(check(&policy, |msg| msg.max_accepted_htlc_number = MAX_ACCEPTED_HTLC_NUMBER + 1).is_err());
+
+        let mut policy = ChannelPolicy::default();
+        policy.max_csv_delay = 100;
+        assert!(check(&policy, |_| ()).is_err());
+        assert_eq!(check(&policy, |msg| msg.csv_delay = CsvDelay::from(100)), Ok(()));
+    }
+
+    #[test]
+    fn reserve_and_dust_limits() {
+        let policy = ChannelPolicy::default();
+        assert!(check(&policy, |msg| msg.channel_reserve = Satoshi::from(policy.min_channel_reserve - 1)).is_err());
+        // more than 20% of the funding
+        assert!(check(&policy, |msg| msg.channel_reserve = Satoshi::from(200_001)).is_err());
+        assert!(check(&policy, |msg| msg.dust_limit = Satoshi::from(MIN_DUST_LIMIT - 1)).is_err());
+        assert!(check(&policy, |msg| msg.dust_limit = Satoshi::from(policy.max_dust_limit + 1)).is_err());
+        // the dust limit is greater than the reserve
+        assert!(check(&policy, |msg| {
+            msg.dust_limit = Satoshi::from(15_000);
+            msg.channel_reserve = Satoshi::from(12_000);
+        }).is_err());
+
+        // the reserve the peer requires is less than our dust limit
+        let mut policy = ChannelPolicy::default();
+        policy.dust_limit = 20_000;
+        assert!(check(&policy, |_| ()).is_err());
+
+        let mut policy = ChannelPolicy::default();
+        policy.min_channel_reserve = 20_000;
+        assert!(check(&policy, |_| ()).is_err());
+        policy.max_dust_limit = 500;
+        policy.min_channel_reserve = 546;
+        assert!(check(&policy, |_| ()).is_err());
+    }
+
+    #[test]
+    fn bad_upfront_shutdown_script_is_rejected() {
+        let policy = ChannelPolicy::default();
+        assert_eq!(check(&policy, |msg| msg.shutdown_script = Some(Vec::new())), Ok(()));
+        assert!(check(&policy, |msg| msg.shutdown_script = Some(vec![0x6a])).is_err());
+    }
+
+    #[test]
+    fn our_parameters_follow_the_policy() {
+        let mut policy = ChannelPolicy::default();
+        policy.chain_hash = Sha256::TEST_HASH;
+        policy.dust_limit = 1_000;
+        policy.channel_reserve_percent = 2;
+        policy.max_accepted_htlc_number = 30;
+
+        let params = policy.open_params(1_000_000, 0);
+        assert_eq!(params.chain_hash, Sha256::TEST_HASH);
+        assert_eq!((params.dust_limit, params.channel_reserve, params.max_accepted_htlc_number), (1_000, 20_000, 30));
+        // the reserve is not below our dust limit
+        assert_eq!(policy.open_params(20_000, 0).channel_reserve, 1_000);
+
+        let config = policy.our_config(&open_channel());
+        assert_eq!((config.dust_limit, config.chanel_reserve, config.max_accepted_htlc_number), (1_000, 20_000, 30));
+    }
+}
@@ -0,0 +1,986 @@
+// Two channels run against each other in memory, the chain and the wallets are mocks.
+// The network between the sides delivers the messages one by one, it may lose, duplicate
+// or reorder them and break the connection, after the reconnection the channel is reestablished.
+// The randomness of the harness comes from the seed, so every run can be repeated,
+// only the channel keys are random.
+
+use dependencies::secp256k1;
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+use dependencies::rand;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::rc::Rc;
+
+use secp256k1::{Secp256k1, SecretKey, PublicKey};
+use bitcoin::{Transaction, TxIn, TxOut, OutPoint, Script};
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use wire::{Message, OnionBlob, RawFeatureVector, FeatureBit};
+use channel::tools::{v0_p2wpkh, to_local_script, anchor_script, to_remote_anchor_script};
+use channel::commit::{CommitTx, HTLCDirection, ANCHOR_OUTPUT_VALUE, commitment_fee, htlc_timeout_weight, htlc_success_weight};
+
+use crate::b_box::{ChannelState, OpenChannelParams};
+use crate::policy::ChannelPolicy;
+use crate::announcement::{ChannelAnnouncement, ANNOUNCEMENT_DEPTH};
+use crate::commitment::Commitments;
+use crate::closing::JUSTICE_DEPTH;
+use crate::testing::MockWallet;
+
+// in satoshi
+const FUNDING: u64 = 1_000_000;
+// in millisatoshi, the fundee can offer HTLCs from the start
+const PUSH: u64 = 100_000_000;
+// in satoshi per kiloweight, the estimate of both wallets
+const FEE_RATE: u32 = 253;
+// the default policy of the fundee
+const MINIMUM_DEPTH: u32 = 3;
+// of the scenarios
+const SEED: u64 = 0;
+
+// the published transactions are mined in the next block
+#[derive(Default)]
+struct MockChain {
+    height: u32,
+    mempool: Vec<Transaction>,
+    // the transaction, the height of its block and its index in the block
+    blocks: Vec<(Transaction, u32, u32)>,
+}
+
+impl MockChain {
+    fn mine(&mut self) {
+        self.height += 1;
+        let mempool = mem::replace(&mut self.mempool, Vec::new());
+        // the coinbase goes first
+        for (index, tx) in mempool.into_iter().enumerate() {
+            self.blocks.push((tx, self.height, index as u32 + 1));
+        }
+    }
+
+    // the transaction, its position and the confirmations
+    fn find(&self, txid: &sha256d::Hash) -> Option<(Transaction, u32, u32, u32)> {
+        self.blocks.iter()
+            .find(|&&(ref tx, _, _)| tx.txid().eq(txid))
+            .map(|&(ref tx, height, index)| (tx.clone(), height, index, self.height - height + 1))
+    }
+
+    // the known transaction is ignored, the one spending the spent output is rejected
+    fn publish(&mut self, transaction: &Transaction) -> Result<(), String> {
+        let txid = transaction.txid();
+        if self.find(&txid).is_some() || self.mempool.iter().any(|tx| tx.txid().eq(&txid)) {
+            return Ok(());
+        }
+        let conflicts = |tx: &Transaction| tx.input.iter()
+            .any(|i| transaction.input.iter().any(|j| j.previous_output == i.previous_output));
+        if self.blocks.iter().any(|&(ref tx, _, _)| conflicts(tx)) || self.mempool.iter().any(|tx| conflicts(tx)) {
+            return Err(format!("the transaction {} spends the spent output", txid));
+        }
+        self.mempool.push(transaction.clone());
+        Ok(())
+    }
+
+    // the transaction of the latest block which spends the output
+    fn spending(&self, outpoint: (sha256d::Hash, u32)) -> Option<Transaction> {
+        self.blocks.iter()
+            .filter(|&&(_, height, _)| height == self.height)
+            .find(|&&(ref tx, _, _)| tx.input.iter().any(|i| (i.previous_output.txid, i.previous_output.vout) == outpoint))
+            .map(|&(ref tx, _, _)| tx.clone())
+    }
+}
+
+type SharedChain = Rc<RefCell<MockChain>>;
+
+type SharedRng = Rc<RefCell<StdRng>>;
+
+fn random_key(rng: &mut StdRng) -> (SecretKey, PublicKey) {
+    let secret = SecretKey::from_slice(&rng.gen::<[u8; 32]>()[..]).unwrap();
+    let public = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret);
+    (secret, public)
+}
+
+// the channel of the node
+struct Side {
+    state: ChannelState,
+    wallet: MockWallet,
+    node_secret: SecretKey,
+    node_id: PublicKey,
+    announcement: Option<ChannelAnnouncement>,
+    // the side sent `error`, it found the peer violating the protocol
+    failed: bool,
+}
+
+impl Side {
+    fn new(state: ChannelState, chain: &SharedChain, rng: &SharedRng) -> Self {
+        let (node_secret, node_id) = random_key(&mut rng.borrow_mut());
+        let mut wallet = MockWallet::default();
+        wallet.payment_key = random_key(&mut rng.borrow_mut()).0;
+        wallet.fee_rate = FEE_RATE;
+        wallet.seed = rng.borrow_mut().gen();
+        let chain = chain.clone();
+        wallet.publisher = Some(Box::new(move |transaction| chain.borrow_mut().publish(transaction)));
+        Side {
+            state: state,
+            wallet: wallet,
+            node_secret: node_secret,
+            node_id: node_id,
+            announcement: None,
+            failed: false,
+        }
+    }
+
+    fn commitments(&self) -> &Commitments {
+        self.state.commitments().expect("the channel is not operating")
+    }
+
+    // the same as the node does with the peer's message
+    fn handle(&mut self, message: Message, their_node_id: &PublicKey) -> Vec<Message> {
+        let state = mem::replace(&mut self.state, ChannelState::Error);
+        let (state, responses) = match message {
+            Message::ReestablishChannel(msg) => state.handle_reestablish(msg, &mut self.wallet),
+            message => {
+                let (state, response) = state.next(message, &mut self.wallet);
+                (state, response.into_iter().collect())
+            },
+        };
+        self.finish(state, responses, their_node_id)
+    }
+
+    fn confirmed(&mut self, tx: &Transaction, height: u32, index: u32, confirmations: u32, their_node_id: &PublicKey) -> Vec<Message> {
+        let state = mem::replace(&mut self.state, ChannelState::Error);
+        let (state, message) = state.confirmed(tx, height, index, confirmations, &mut self.wallet);
+        self.finish(state, message.into_iter().collect(), their_node_id)
+    }
+
+    fn spent(&mut self, tx: &Transaction) {
+        let state = mem::replace(&mut self.state, ChannelState::Error);
+        self.state = state.spent(tx, &mut self.wallet);
+    }
+
+    // the updates are signed and the public channel is announced after every transition
+    fn finish(&mut self, state: ChannelState, mut messages: Vec<Message>, their_node_id: &PublicKey) -> Vec<Message> {
+        let (state, commitment) = state.commit();
+        let (state, signatures, announcement) = state.announce(&self.node_secret, their_node_id);
+        messages.extend(commitment);
+        messages.extend(signatures);
+        if let Some(announcement) = announcement {
+            assert!(self.announcement.is_none(), "the channel is announced twice");
+            self.announcement = Some(announcement);
+        }
+        self.failed |= messages.iter().any(|m| match m {
+            &Message::Error(_) => true,
+            _ => false,
+        });
+        self.state = state;
+        messages
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    // the connection breaks, the messages in flight are lost
+    Disconnect,
+    // the message is lost, the connection breaks
+    Drop,
+    Duplicate,
+    // the message goes after the next one
+    Reorder,
+}
+
+#[derive(Debug, Clone)]
+struct Htlc {
+    // the index of the offering side
+    from: usize,
+    id: u64,
+    amount: u64,
+    preimage: [u8; 32],
+    // `Some(true)` if fulfilled, `Some(false)` if failed
+    settled: Option<bool>,
+}
+
+struct Simulation {
+    chain: SharedChain,
+    rng: SharedRng,
+    // the funder is the first
+    sides: [Side; 2],
+    // the messages in flight to the side with the same index
+    in_flight: [VecDeque<Message>; 2],
+    htlcs: Vec<Htlc>,
+    // the number of the delivered messages
+    delivered: usize,
+    // injected before the delivery with the given number
+    faults: HashMap<usize, Fault>,
+}
+
+impl Simulation {
+    // the funder sent `open_channel`, the features are offered by both `init`
+    fn new(push: u64, features: RawFeatureVector, seed: u64) -> Self {
+        let chain = SharedChain::default();
+        let rng = Rc::new(RefCell::new(StdRng::seed_from_u64(seed)));
+        let mut params = OpenChannelParams::new(FUNDING, push);
+        params.fee_rate = FEE_RATE;
+        let mut funder = Side::new(ChannelState::Error, &chain, &rng);
+        let (state, open_channel) = ChannelState::open(params, &features, &mut funder.wallet);
+        let open_channel = open_channel.unwrap();
+        funder.state = state;
+        let fundee = Side::new(ChannelState::with_policy(ChannelPolicy::default(), &features), &chain, &rng);
+        let mut in_flight = [VecDeque::new(), VecDeque::new()];
+        in_flight[1].push_back(open_channel);
+        Simulation {
+            sides: [funder, fundee],
+            chain: chain,
+            rng: rng,
+            in_flight: in_flight,
+            htlcs: Vec::new(),
+            delivered: 0,
+            faults: HashMap::new(),
+        }
+    }
+
+    // the funding is locked, the channel operates
+    fn open_channel(push: u64) -> Self {
+        Simulation::open(push, RawFeatureVector::new(), SEED)
+    }
+
+    fn open(push: u64, features: RawFeatureVector, seed: u64) -> Self {
+        let mut simulation = Simulation::new(push, features, seed);
+        simulation.flush();
+        simulation.mine(MINIMUM_DEPTH);
+        simulation.flush();
+        for side in simulation.sides.iter() {
+            match &side.state {
+                &ChannelState::Ready(_) => (),
+                state => panic!("the channel is not open: {:?}", state),
+            }
+        }
+        simulation.check_agreement();
+        simulation
+    }
+
+    // delivers the message to the side, the responses go back
+    fn transmit(&mut self, to: usize, message: Message) {
+        let their_node_id = self.sides[1 - to].node_id.clone();
+        let responses = self.sides[to].handle(message, &their_node_id);
+        self.in_flight[1 - to].extend(responses);
+    }
+
+    // delivers the next message in flight to the side, `false` if there is none
+    fn deliver(&mut self, to: usize) -> bool {
+        if self.in_flight[to].is_empty() {
+            return false;
+        }
+        let number = self.delivered;
+        self.delivered += 1;
+        match self.faults.remove(&number) {
+            Some(Fault::Disconnect) => {
+                self.reconnect();
+                return true;
+            },
+            Some(Fault::Drop) => {
+                self.in_flight[to].pop_front();
+                self.reconnect();
+                return true;
+            },
+            Some(Fault::Duplicate) => {
+                let message = self.in_flight[to].front().cloned().unwrap();
+                self.in_flight[to].push_front(message);
+            },
+            Some(Fault::Reorder) => if self.in_flight[to].len() > 1 {
+                self.in_flight[to].swap(0, 1);
+            },
+            None => (),
+        }
+        let message = self.in_flight[to].pop_front().unwrap();
+        self.transmit(to, message);
+        true
+    }
+
+    // delivers the messages until nothing is in flight
+    fn flush(&mut self) {
+        for _ in 0..10_000 {
+            if !self.deliver(0) && !self.deliver(1) {
+                return;
+            }
+        }
+        panic!("the sides keep sending messages");
+    }
+
+    // BOLT 2: `channel_reestablish` is the first message of the channel after the reconnection
+    fn reconnect(&mut self) {
+        self.in_flight = [VecDeque::new(), VecDeque::new()];
+        let reestablish: Vec<Option<Message>> = self.sides.iter().map(|side| side.state.reestablish()).collect();
+        for (from, message) in reestablish.into_iter().enumerate() {
+            if let Some(message) = message {
+                self.transmit(1 - from, message);
+            }
+        }
+    }
+
+    // the sides learn the spending of the watched outputs and the confirmations of the watched transactions,
+    // the unconfirmed transactions are broadcast again
+    fn mine(&mut self, blocks: u32) {
+        for _ in 0..blocks {
+            self.chain.borrow_mut().mine();
+            let chain = self.chain.clone();
+            for side in self.sides.iter_mut() {
+                // the output watched after the spend may be spent in the same block
+                let mut reported = Vec::new();
+                loop {
+                    let spending = side.state.watched_outpoints().into_iter()
+                        .filter(|outpoint| !reported.contains(outpoint))
+                        .filter_map(|outpoint| chain.borrow().spending(outpoint).map(|tx| (outpoint, tx)))
+                        .next();
+                    match spending {
+                        Some((outpoint, tx)) => {
+                            reported.push(outpoint);
+                            side.spent(&tx);
+                        },
+                        None => break,
+                    }
+                }
+            }
+            for index in 0..2 {
+                for txid in self.sides[index].state.watched_txids() {
+                    let found = self.chain.borrow().find(&txid);
+                    if let Some((tx, height, tx_index, confirmations)) = found {
+                        let their_node_id = self.sides[1 - index].node_id.clone();
+                        let messages = self.sides[index].confirmed(&tx, height, tx_index, confirmations, &their_node_id);
+                        self.in_flight[1 - index].extend(messages);
+                    }
+                }
+            }
+            for side in self.sides.iter_mut() {
+                side.state.rebroadcast(&mut side.wallet);
+            }
+        }
+    }
+
+    // the command of the side, its message and the commitment signature go to the peer
+    fn command<F>(&mut self, side: usize, f: F) -> Option<Message>
+    where
+        F: FnOnce(ChannelState, &mut MockWallet) -> (ChannelState, Result<Message, String>),
+    {
+        let their_node_id = self.sides[1 - side].node_id.clone();
+        let state = mem::replace(&mut self.sides[side].state, ChannelState::Error);
+        let (state, result) = f(state, &mut self.sides[side].wallet);
+        let update = result.ok();
+        let messages = self.sides[side].finish(state, update.clone().into_iter().collect(), &their_node_id);
+        self.in_flight[1 - side].extend(messages);
+        update
+    }
+
+    fn add_htlc(&mut self, from: usize, amount: u64) -> bool {
+        let preimage = self.rng.borrow_mut().gen::<[u8; 32]>();
+        let payment_hash = sha256::Hash::hash(&preimage[..]).into_inner();
+        let onion_blob = OnionBlob { data: [0; 1366] };
+        match self.command(from, |state, _| state.add_htlc(amount, payment_hash, 500_000, onion_blob)) {
+            Some(Message::UpdateAddHtlc(msg)) => {
+                self.htlcs.push(Htlc {
+                    from: from,
+                    id: msg.id.to_u64(),
+                    amount: amount,
+                    preimage: preimage,
+                    settled: None,
+                });
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // the side settles the oldest HTLC offered to it, the HTLC should be committed to both sides
+    fn settle_htlc(&mut self, side: usize, fulfill: bool) -> bool {
+        let index = match self.htlcs.iter().position(|htlc| htlc.from != side && htlc.settled.is_none()) {
+            Some(index) => index,
+            None => return false,
+        };
+        let (id, preimage) = (self.htlcs[index].id, self.htlcs[index].preimage);
+        let update = if fulfill {
+            self.command(side, |state, _| state.fulfill_htlc(id, preimage))
+        } else {
+            self.command(side, |state, _| state.fail_htlc(id, vec![0; 32]))
+        };
+        if update.is_some() {
+            self.htlcs[index].settled = Some(fulfill);
+        }
+        update.is_some()
+    }
+
+    fn update_fee(&mut self, fee_rate: u32) -> bool {
+        self.command(0, |state, _| state.update_fee(fee_rate)).is_some()
+    }
+
+    // the side starts the cooperative close
+    fn shutdown(&mut self, side: usize) -> bool {
+        self.command(side, |state, wallet| state.shutdown(wallet)).is_some()
+    }
+
+    // the side broadcasts its latest commitment
+    fn force_close(&mut self, side: usize) -> sha256d::Hash {
+        let side = &mut self.sides[side];
+        let state = mem::replace(&mut side.state, ChannelState::Error);
+        let (state, result) = state.force_close(&mut side.wallet);
+        side.state = state;
+        result.expect("the channel is not force closed")
+    }
+
+    // the mined outputs which pay to the wallet of the side, in satoshi
+    fn paid(&self, side: usize) -> u64 {
+        let scripts = &self.sides[side].wallet.scripts;
+        self.chain.borrow().blocks.iter()
+            .flat_map(|&(ref tx, _, _)| tx.output.iter())
+            .filter(|o| scripts.contains(&o.script_pubkey))
+            .map(|o| o.value)
+            .sum()
+    }
+
+    // the balances of the funder and the fundee after the settled HTLCs, in millisatoshi
+    fn expected_balances(&self, push: u64) -> (u64, u64) {
+        self.htlcs.iter()
+            .filter(|htlc| htlc.settled == Some(true))
+            .fold((FUNDING * 1000 - push, push), |(funder, fundee), htlc| {
+                if htlc.from == 0 { (funder - htlc.amount, fundee + htlc.amount) } else { (funder + htlc.amount, fundee - htlc.amount) }
+            })
+    }
+
+    fn failed(&self) -> bool {
+        self.sides.iter().any(|side| side.failed)
+    }
+
+    // no money appears or disappears, the peer signed every commitment we can broadcast
+    fn check_safety(&self) {
+        for side in self.sides.iter() {
+            let commitments = match side.state.commitments() {
+                Some(commitments) => commitments,
+                None => continue,
+            };
+            for &local in [true, false].iter() {
+                let (number, commit) = commitments.latest_commitment(local);
+                check_outputs(number, commit, commitments.funding_amount());
+            }
+            let (ours, theirs) = commitments.balances();
+            assert_eq!(ours + theirs, commitments.funding_amount() * 1000);
+            assert!(commitments.local_commitment_is_signed(), "our latest commitment is not signed by the peer");
+        }
+    }
+
+    // both sides have the same commitments when nothing is in flight
+    fn check_agreement(&self) {
+        assert!(self.in_flight.iter().all(VecDeque::is_empty));
+        let (funder, fundee) = (self.sides[0].commitments(), self.sides[1].commitments());
+        assert!(funder.is_quiescent() && fundee.is_quiescent(), "some updates are not committed");
+        for &local in [true, false].iter() {
+            let (number, commit) = funder.latest_commitment(local);
+            let (their_number, their_commit) = fundee.latest_commitment(!local);
+            assert_eq!(number, their_number);
+            assert_eq!(commit.get_tx().unwrap().txid(), their_commit.get_tx().unwrap().txid());
+        }
+        let (ours, theirs) = funder.balances();
+        assert_eq!((theirs, ours), fundee.balances());
+    }
+
+    // the payments in both directions, some fail
+    fn payments(&mut self) {
+        for &amount in [50_000_000, 3_000_000, 400_000].iter() {
+            assert!(self.add_htlc(0, amount));
+            self.flush();
+        }
+        assert!(self.add_htlc(1, 20_000_000));
+        self.flush();
+        assert!(self.settle_htlc(1, true));
+        assert!(self.settle_htlc(1, false));
+        self.flush();
+        assert!(self.update_fee(FEE_RATE * 2));
+        assert!(self.settle_htlc(1, true));
+        assert!(self.settle_htlc(0, true));
+        self.flush();
+    }
+}
+
+// The outputs of the commitment transaction pay what it commits to: every HTLC which is not dust
+// has the output of its amount, the fundee gets its balance, the funder pays the fee,
+// the rest of the funding is the fee and the dust
+fn check_outputs(number: u64, commit: &CommitTx, funding: u64) {
+    let tx = commit.get_tx().unwrap();
+    let paid = |script: &Script| -> u64 {
+        tx.output.iter().filter(|o| o.script_pubkey.eq(script)).map(|o| o.value).sum()
+    };
+    let mut untrimmed = 0;
+    let mut htlc_outputs = 0;
+    for htlc in commit.htlcs.iter() {
+        let amount = (htlc.amount_msat / 1000) as u64;
+        match paid(&commit.htlc_script(htlc).to_v0_p2wsh()) {
+            0 => {
+                let weight = match htlc.direction {
+                    HTLCDirection::Offered => htlc_timeout_weight(commit.anchors),
+                    HTLCDirection::Accepted => htlc_success_weight(commit.anchors),
+                };
+                let threshold = commit.dust_limit_satoshi + weight * commit.local_feerate_per_kw / 1000;
+                assert!((amount as i64) < threshold, "commitment {} lost the HTLC of {}", number, amount);
+            },
+            value => {
+                assert_eq!(value, amount, "commitment {} pays the wrong HTLC amount", number);
+                untrimmed += 1;
+                htlc_outputs += value;
+            },
+        }
+    }
+
+    let to_local = to_local_script(&commit.local_delayedpubkey, commit.local_delay, &commit.local_revocation_pubkey).to_v0_p2wsh();
+    let to_remote = commit.to_remote_script_pubkey();
+    let (funder_script, fundee_script, funder_msat, fundee_msat) = if commit.local_is_funder {
+        (to_local, to_remote, commit.to_local_msat, commit.to_remote_msat)
+    } else {
+        (to_remote, to_local, commit.to_remote_msat, commit.to_local_msat)
+    };
+    // the fee and the anchors
+    let fee = commitment_fee(commit.local_feerate_per_kw, untrimmed, commit.anchors);
+    let above_dust = |value: i64| if value >= commit.dust_limit_satoshi { value as u64 } else { 0 };
+    let (funder, fundee) = (paid(&funder_script), paid(&fundee_script));
+    assert_eq!(fundee, above_dust(fundee_msat / 1000), "commitment {} charges the fundee", number);
+    assert_eq!(funder, above_dust(funder_msat / 1000 - fee), "commitment {} charges the funder the wrong fee", number);
+
+    // the anchor of each side which has an output, both while there are HTLC outputs
+    let anchors = if commit.anchors {
+        let anchor = |key, output: u64| {
+            let value = paid(&anchor_script(key).to_v0_p2wsh());
+            let expected = if output > 0 || untrimmed > 0 { ANCHOR_OUTPUT_VALUE as u64 } else { 0 };
+            assert_eq!(value, expected, "commitment {} has the wrong anchors", number);
+            value
+        };
+        let (local, remote) = if commit.local_is_funder { (funder, fundee) } else { (fundee, funder) };
+        anchor(&commit.local_funding_pubkey, local) + anchor(&commit.remote_funding_pubkey, remote)
+    } else {
+        0
+    };
+
+    let total: u64 = tx.output.iter().map(|o| o.value).sum();
+    assert_eq!(total, funder + fundee + htlc_outputs + anchors, "commitment {} pays to unknown scripts", number);
+    assert!(total - anchors + (fee as u64) <= funding, "commitment {} pays more than the funding", number);
+}
+
+#[test]
+fn open_and_announce() {
+    let mut simulation = Simulation::open_channel(0);
+    assert_eq!(simulation.sides[0].commitments().balances(), (FUNDING * 1000, 0));
+
+    simulation.mine(ANNOUNCEMENT_DEPTH - MINIMUM_DEPTH);
+    simulation.flush();
+    let (funder, fundee) = (&simulation.sides[0], &simulation.sides[1]);
+    let announcement = funder.announcement.clone().expect("the funder did not announce the channel");
+    let their_announcement = fundee.announcement.clone().expect("the fundee did not announce the channel");
+    assert_eq!(announcement.announcement, their_announcement.announcement);
+    assert!(funder.state.watched_txids().is_empty() && fundee.state.watched_txids().is_empty());
+}
+
+#[test]
+fn payments_settle() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    simulation.payments();
+    simulation.check_safety();
+    simulation.check_agreement();
+    assert_eq!(simulation.sides[0].commitments().balances(), simulation.expected_balances(PUSH));
+}
+
+#[test]
+fn disconnect_at_every_step() {
+    let mut baseline = Simulation::open_channel(PUSH);
+    let opened = baseline.delivered;
+    baseline.payments();
+    let expected = baseline.sides[0].commitments().balances();
+
+    for step in opened..baseline.delivered {
+        for &fault in [Fault::Disconnect, Fault::Drop].iter() {
+            let mut simulation = Simulation::open_channel(PUSH);
+            simulation.faults.insert(step, fault);
+            simulation.payments();
+            simulation.check_safety();
+            assert!(!simulation.failed(), "the channel failed after {:?} at {}", fault, step);
+            simulation.check_agreement();
+            assert_eq!(simulation.sides[0].commitments().balances(), expected, "{:?} at {}", fault, step);
+        }
+    }
+}
+
+// the random commands and deliveries, the network only breaks the connection,
+// so the sides never fail and always agree in the end
+#[test]
+fn random_disconnects() {
+    random_runs(&[Fault::Disconnect, Fault::Drop], true);
+}
+
+// the peer which duplicates or reorders the messages is caught or harmless,
+// nothing is lost in any case
+#[test]
+fn random_faults() {
+    random_runs(&[Fault::Disconnect, Fault::Drop, Fault::Duplicate, Fault::Reorder], false);
+}
+
+fn random_runs(faults: &[Fault], honest: bool) {
+    for seed in 0..20 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut simulation = Simulation::open(PUSH, RawFeatureVector::new(), seed);
+        for _ in 0..60 {
+            if rng.gen_bool(0.1) {
+                let fault = faults[rng.gen_range(0, faults.len())];
+                simulation.faults.insert(simulation.delivered, fault);
+            }
+            let side = rng.gen_range(0, 2);
+            match rng.gen_range(0, 10) {
+                0 | 1 => {
+                    simulation.add_htlc(side, rng.gen_range(1_000, 10_000_000));
+                },
+                2 => {
+                    simulation.settle_htlc(side, true);
+                },
+                3 => {
+                    simulation.settle_htlc(side, false);
+                },
+                4 => {
+                    simulation.update_fee(rng.gen_range(FEE_RATE, 1_200));
+                },
+                _ => {
+                    simulation.deliver(side);
+                },
+            }
+            simulation.check_safety();
+        }
+        // the duplicated or reordered messages in flight are gone
+        simulation.faults.clear();
+        simulation.reconnect();
+        simulation.flush();
+        simulation.check_safety();
+
+        if honest {
+            assert!(!simulation.failed(), "the channel failed, seed {}", seed);
+        }
+        if !simulation.failed() {
+            simulation.check_agreement();
+            assert_eq!(simulation.sides[0].commitments().balances(), simulation.expected_balances(PUSH), "seed {}", seed);
+        }
+    }
+}
+
+// the funder closes the channel after the payments, each side gets its balance to its wallet,
+// the funder pays the fee
+#[test]
+fn cooperative_close() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    simulation.payments();
+    let (funder, fundee) = simulation.expected_balances(PUSH);
+    assert!(simulation.shutdown(0));
+    simulation.flush();
+    let closing_txid = match (&simulation.sides[0].state, &simulation.sides[1].state) {
+        (&ChannelState::Closed(ref ours), &ChannelState::Closed(ref theirs)) => {
+            assert_eq!(ours.closing_txid(), theirs.closing_txid());
+            ours.closing_txid()
+        },
+        states => panic!("the channel is not closed: {:?}", states),
+    };
+    assert!(!simulation.failed());
+
+    simulation.mine(1);
+    let (closing, _, _, _) = simulation.chain.borrow().find(&closing_txid).expect("the closing transaction is not broadcast");
+    let fee = FUNDING - closing.output.iter().map(|o| o.value).sum::<u64>();
+    assert!(fee > 0 && fee < 10_000);
+    assert_eq!(simulation.paid(1), fundee / 1000);
+    assert_eq!(simulation.paid(0), funder / 1000 - fee);
+}
+
+// the funder broadcasts its latest commitment, the fundee sweeps its output at once,
+// the funder sweeps its own when the delay expires
+#[test]
+fn force_close() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    simulation.payments();
+    let (funder, fundee) = simulation.expected_balances(PUSH);
+    let delay = simulation.sides[0].commitments().local_delay() as u32;
+    let commitment_txid = simulation.force_close(0);
+
+    simulation.mine(1);
+    match &simulation.sides[1].state {
+        &ChannelState::Closed(_) => (),
+        state => panic!("the peer's commitment is not detected: {:?}", state),
+    }
+    simulation.mine(delay);
+    match &simulation.sides[0].state {
+        &ChannelState::Closed(_) => (),
+        state => panic!("our output is not swept after the delay: {:?}", state),
+    }
+    simulation.mine(1);
+
+    let (commitment, _, _, _) = simulation.chain.borrow().find(&commitment_txid).expect("the commitment is not broadcast");
+    assert_eq!(commitment.output.len(), 2);
+    let fee = FUNDING - commitment.output.iter().map(|o| o.value).sum::<u64>();
+    // the sweeps pay their fees too
+    let (funder_paid, fundee_paid) = (simulation.paid(0), simulation.paid(1));
+    assert!(funder_paid < funder / 1000 - fee && funder_paid + 1_000 > funder / 1000 - fee);
+    assert!(fundee_paid < fundee / 1000 && fundee_paid + 1_000 > fundee / 1000);
+}
+
+// the HTLC outputs of the commitment are not swept, the force close waits until the HTLCs are settled
+#[test]
+fn force_close_with_pending_htlcs_is_refused() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    assert!(simulation.add_htlc(0, 50_000_000));
+    simulation.flush();
+
+    let state = mem::replace(&mut simulation.sides[0].state, ChannelState::Error);
+    let (state, result) = state.force_close(&mut simulation.sides[0].wallet);
+    assert!(result.is_err());
+    match &state {
+        &ChannelState::Ready(_) => (),
+        state => panic!("the channel is not operating: {:?}", state),
+    }
+    simulation.sides[0].state = state;
+
+    assert!(simulation.settle_htlc(1, true));
+    simulation.flush();
+    simulation.force_close(0);
+}
+
+// the peer's `update_fulfill_htlc` gives us the preimage of the HTLC we offered
+#[test]
+fn preimage_of_fulfilled_htlc() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    assert!(simulation.add_htlc(0, 50_000_000));
+    simulation.flush();
+    let (id, preimage) = (simulation.htlcs[0].id, simulation.htlcs[0].preimage);
+    assert_eq!(simulation.sides[0].state.payment_preimage(id), None);
+
+    assert!(simulation.settle_htlc(1, true));
+    assert!(simulation.deliver(0));
+    assert_eq!(simulation.sides[0].state.payment_preimage(id), Some(preimage));
+}
+
+// the fundee restarts from the backup made before the payments, the funder's `channel_reestablish`
+// tells it that its state is outdated, so it never broadcasts its revoked commitment,
+// asks the funder to close and sweeps its output of the funder's commitment
+#[test]
+fn reestablish_behind() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    let backup = simulation.sides[1].state.clone();
+    simulation.payments();
+    let (_, fundee) = simulation.expected_balances(PUSH);
+
+    simulation.sides[1].state = backup;
+    simulation.reconnect();
+    simulation.flush();
+    match &simulation.sides[1].state {
+        &ChannelState::DataLoss(_) => (),
+        state => panic!("the data loss is not detected: {:?}", state),
+    }
+    let commitment_txid = match &simulation.sides[0].state {
+        &ChannelState::ForceClosing(ref data) => data.commitment_txid(),
+        state => panic!("the funder does not close the channel: {:?}", state),
+    };
+
+    simulation.mine(2);
+    match &simulation.sides[1].state {
+        &ChannelState::Closed(ref data) => assert_eq!(data.closing_txid(), commitment_txid),
+        state => panic!("the funder's commitment is not swept: {:?}", state),
+    }
+    let fundee_paid = simulation.paid(1);
+    assert!(fundee_paid < fundee / 1000 && fundee_paid + 1_000 > fundee / 1000);
+}
+
+// the fundee broadcasts its revoked commitment, the funder takes all its outputs, one by one
+#[test]
+fn revoked_commitment_is_punished() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    assert!(simulation.add_htlc(0, 50_000_000));
+    assert!(simulation.add_htlc(1, 20_000_000));
+    simulation.flush();
+    let revoked = simulation.sides[1].commitments().signed_local_commitment();
+    // both balances and both HTLCs
+    assert_eq!(revoked.output.len(), 4);
+    assert!(simulation.settle_htlc(1, true));
+    assert!(simulation.settle_htlc(0, false));
+    simulation.flush();
+
+    simulation.chain.borrow_mut().mempool.push(revoked.clone());
+    simulation.mine(1);
+    let justice_txids = match &simulation.sides[0].state {
+        &ChannelState::PunishingPartner(ref data) => data.justice_txids(),
+        state => panic!("the breach is not detected: {:?}", state),
+    };
+    assert_eq!(justice_txids.len(), revoked.output.len());
+    match &simulation.sides[1].state {
+        &ChannelState::PunishedByPartner(_) => (),
+        state => panic!("the cheater does not know it is punished: {:?}", state),
+    }
+
+    simulation.mine(JUSTICE_DEPTH);
+    let chain = simulation.chain.borrow();
+    let mut spent = Vec::new();
+    let mut taken = 0;
+    for txid in justice_txids.iter() {
+        let (justice, _, _, _) = chain.find(txid).expect("the justice is not broadcast");
+        assert_eq!(justice.input.len(), 1);
+        assert_eq!(justice.input[0].previous_output.txid, revoked.txid());
+        spent.push(justice.input[0].previous_output.vout);
+        taken += justice.output[0].value;
+    }
+    spent.sort();
+    assert_eq!(spent, (0..revoked.output.len() as u32).collect::<Vec<_>>());
+    let total: u64 = revoked.output.iter().map(|o| o.value).sum();
+    assert!(taken < total && taken + 10_000 > total);
+    match &simulation.sides[0].state {
+        &ChannelState::PunishedPartner(_) => (),
+        state => panic!("the justice is not final: {:?}", state),
+    }
+}
+
+// the fundee spends the HTLC output of its revoked commitment by the HTLC transaction in the same block,
+// the funder takes the output of the HTLC transaction instead
+#[test]
+fn revoked_htlc_transaction_is_punished() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    assert!(simulation.add_htlc(0, 50_000_000));
+    simulation.flush();
+    let revoked = simulation.sides[1].commitments().signed_local_commitment();
+    assert!(simulation.settle_htlc(0, false));
+    simulation.flush();
+
+    // the output of the HTLC transaction has the script of the fundee's balance,
+    // the mock chain checks no signatures
+    let to_local = revoked.output.iter().find(|o| o.value == PUSH / 1000).unwrap().script_pubkey.clone();
+    let htlc_index = revoked.output.iter().position(|o| o.value == 50_000).unwrap();
+    let htlc_tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: revoked.txid(),
+                vout: htlc_index as u32,
+            },
+            script_sig: Script::new(),
+            sequence: 0,
+            witness: Vec::new(),
+        }],
+        output: vec![TxOut {
+            value: 49_000,
+            script_pubkey: to_local,
+        }],
+    };
+    simulation.chain.borrow_mut().mempool.extend(vec![revoked.clone(), htlc_tx.clone()]);
+    simulation.mine(1);
+    let justice_txids = match &simulation.sides[0].state {
+        &ChannelState::PunishingPartner(ref data) => data.justice_txids(),
+        state => panic!("the breach is not detected: {:?}", state),
+    };
+    // both balances and the output of the HTLC transaction
+    assert_eq!(justice_txids.len(), 3);
+
+    simulation.mine(JUSTICE_DEPTH);
+    let chain = simulation.chain.borrow();
+    let second_stage = justice_txids.iter()
+        .map(|txid| chain.find(txid).expect("the justice is not broadcast").0)
+        .find(|tx| tx.input[0].previous_output.txid == htlc_tx.txid())
+        .expect("the HTLC transaction is not punished");
+    assert!(second_stage.output[0].value < 49_000);
+    match &simulation.sides[0].state {
+        &ChannelState::PunishedPartner(_) => (),
+        state => panic!("the justice is not final: {:?}", state),
+    }
+}
+
+// the justice for the watchtowers spends the commitment which the fundee revoked last
+#[test]
+fn breach_remedy_of_revoked_commitment() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    {
+        let side = &mut simulation.sides[0];
+        assert!(side.state.breach_remedy(&mut side.wallet).is_none());
+    }
+    let revoked = simulation.sides[1].commitments().signed_local_commitment();
+    assert!(simulation.add_htlc(0, 50_000_000));
+    simulation.flush();
+
+    let side = &mut simulation.sides[0];
+    let remedy = side.state.breach_remedy(&mut side.wallet).expect("no justice for the revoked commitment");
+    assert_eq!(remedy.commitment_txid, revoked.txid());
+    assert_eq!(remedy.justice_tx.input.len(), revoked.output.len());
+    assert!(remedy.justice_tx.input.iter().all(|i| i.previous_output.txid == revoked.txid()));
+}
+
+// the fundee broadcasts its latest commitment, the funder sweeps its output at once
+#[test]
+fn their_commitment_is_swept() {
+    let mut simulation = Simulation::open_channel(PUSH);
+    simulation.payments();
+    let commitment = simulation.sides[1].commitments().signed_local_commitment();
+
+    simulation.chain.borrow_mut().mempool.push(commitment.clone());
+    simulation.mine(2);
+    match &simulation.sides[0].state {
+        &ChannelState::Closed(_) => (),
+        state => panic!("the peer's commitment is not detected: {:?}", state),
+    }
+    let chain = simulation.chain.borrow();
+    let sweep = chain.blocks.iter()
+        .find(|&&(ref tx, _, _)| tx.input[0].previous_output.txid == commitment.txid())
+        .expect("our output is not swept");
+    assert_eq!(sweep.0.input.len(), 1);
+}
+
+// with `option_static_remotekey` every commitment of the fundee pays the funder to the wallet's key
+#[test]
+fn static_remotekey_commitment_is_swept() {
+    let features = RawFeatureVector::new().set_bit(FeatureBit::StaticRemoteKeyOptional);
+    let mut simulation = Simulation::open(PUSH, features, SEED);
+    let payment_key = simulation.sides[0].wallet.payment_key.clone();
+    let to_remote = v0_p2wpkh(&PublicKey::from_secret_key(&Secp256k1::signing_only(), &payment_key));
+    let first = simulation.sides[1].commitments().signed_local_commitment();
+    simulation.payments();
+    let commitment = simulation.sides[1].commitments().signed_local_commitment();
+    assert_ne!(first.txid(), commitment.txid());
+    assert!(first.output.iter().any(|o| o.script_pubkey == to_remote));
+    assert!(commitment.output.iter().any(|o| o.script_pubkey == to_remote));
+
+    simulation.chain.borrow_mut().mempool.push(commitment.clone());
+    simulation.mine(2);
+    match &simulation.sides[0].state {
+        &ChannelState::Closed(_) => (),
+        state => panic!("the peer's commitment is not detected: {:?}", state),
+    }
+    let chain = simulation.chain.borrow();
+    let sweep = chain.blocks.iter()
+        .find(|&&(ref tx, _, _)| tx.input[0].previous_output.txid == commitment.txid())
+        .expect("our output is not swept");
+    assert_eq!(sweep.0.input.len(), 1);
+}
+
+// with `option_anchors_zero_fee_htlc_tx` the commitments have anchors, the funder pays for them,
+// and the funder's output of the fundee's commitment is delayed by one block
+#[test]
+fn anchor_commitment_is_swept() {
+    let features = RawFeatureVector::new()
+        .set_bit(FeatureBit::StaticRemoteKeyOptional)
+        .set_bit(FeatureBit::AnchorsZeroFeeHtlcTxOptional);
+    let mut simulation = Simulation::open(PUSH, features, SEED);
+    simulation.payments();
+    simulation.check_safety();
+    simulation.check_agreement();
+    assert_eq!(simulation.sides[0].commitments().balances(), simulation.expected_balances(PUSH));
+
+    let payment_key = simulation.sides[0].wallet.payment_key.clone();
+    let to_remote = to_remote_anchor_script(&PublicKey::from_secret_key(&Secp256k1::signing_only(), &payment_key));
+    let commitment = simulation.sides[1].commitments().signed_local_commitment();
+    let output = commitment.output.iter()
+        .position(|o| o.script_pubkey == to_remote.to_v0_p2wsh())
+        .expect("the commitment does not pay the funder");
+
+    simulation.chain.borrow_mut().mempool.push(commitment.clone());
+    simulation.mine(2);
+    let chain = simulation.chain.borrow();
+    let sweep = chain.blocks.iter()
+        .find(|&&(ref tx, _, _)| tx.input[0].previous_output.txid == commitment.txid())
+        .expect("our output is not swept");
+    assert_eq!(sweep.0.input[0].previous_output.vout, output as u32);
+    assert_eq!(sweep.0.input[0].sequence, 1);
+    assert_eq!(sweep.0.input[0].witness[1], to_remote.as_bytes().to_vec());
+}
@@ -0,0 +1,102 @@
+// The wallet of the tests, it funds the channel with a made-up output and keeps the published transactions,
+// the test which runs the chain gives the wallet its own way to publish.
+
+use dependencies::secp256k1;
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+
+use secp256k1::{Secp256k1, SecretKey, PublicKey};
+use bitcoin::{Transaction, TxIn, TxOut, OutPoint, Script};
+use bitcoin_hashes::{sha256, sha256d, Hash};
+
+use channel::tools::v0_p2wpkh;
+
+use crate::b_box::FundingWallet;
+
+// in satoshi per kiloweight, the minimal fee rate of the commitment
+const FEE_RATE: u32 = 253;
+
+pub struct MockWallet {
+    pub published: Vec<Transaction>,
+    // given to the channel, the funds of the side go there
+    pub scripts: Vec<Script>,
+    pub payment_key: SecretKey,
+    pub fee_rate: u32,
+    // the scripts and the funding inputs of the wallets differ by it
+    pub seed: [u8; 32],
+    // rejects the transaction the chain does not accept, the published transactions are kept otherwise
+    pub publisher: Option<Box<dyn FnMut(&Transaction) -> Result<(), String>>>,
+    // the funding transactions, each spends the made-up output of its own
+    funded: u32,
+}
+
+impl Default for MockWallet {
+    fn default() -> Self {
+        MockWallet {
+            published: Vec::new(),
+            scripts: Vec::new(),
+            payment_key: SecretKey::from_slice(&[1; 32]).unwrap(),
+            fee_rate: FEE_RATE,
+            seed: [0; 32],
+            publisher: None,
+            funded: 0,
+        }
+    }
+}
+
+impl MockWallet {
+    fn derive(&self, purpose: &[u8], index: u32) -> [u8; 32] {
+        let mut data = self.seed.to_vec();
+        data.extend_from_slice(purpose);
+        data.extend_from_slice(&index.to_be_bytes());
+        sha256::Hash::hash(&data).into_inner()
+    }
+}
+
+impl FundingWallet for MockWallet {
+    // the input is never checked, it makes the funding transaction unique
+    fn fund(&mut self, script_pubkey: Script, amount: u64) -> Result<Transaction, String> {
+        let txid = sha256d::Hash::from_inner(self.derive(b"fund", self.funded));
+        self.funded += 1;
+        Ok(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: txid,
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: amount,
+                script_pubkey: script_pubkey,
+            }],
+        })
+    }
+
+    fn publish(&mut self, transaction: &Transaction) -> Result<(), String> {
+        if let Some(ref mut publisher) = self.publisher {
+            publisher(transaction)?;
+        }
+        self.published.push(transaction.clone());
+        Ok(())
+    }
+
+    fn shutdown_script(&mut self) -> Result<Script, String> {
+        let secret = SecretKey::from_slice(&self.derive(b"shutdown", self.scripts.len() as u32)[..]).unwrap();
+        let script = v0_p2wpkh(&PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret));
+        self.scripts.push(script.clone());
+        Ok(script)
+    }
+
+    fn fee_rate(&mut self) -> u32 {
+        self.fee_rate
+    }
+
+    fn payment_key(&mut self) -> Result<SecretKey, String> {
+        Ok(self.payment_key.clone())
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dependencies::secp256k1;
+use dependencies::futures;
+use dependencies::tokio;
+
+use secp256k1::PublicKey;
+use futures::{Future, sync::{mpsc, oneshot}};
+use tokio::timer::Timeout;
+
+use wire::OpenChannel;
+use channel_machine::ChannelPolicy;
+
+/// The channel which the peer opens with us, it waits for the decision of the client
+#[derive(Debug, Clone)]
+pub struct ChannelRequest {
+    pub peer: PublicKey,
+    pub open_channel: OpenChannel,
+    // the commitments pay to our static payment basepoint, the `init` of both peers offers it
+    pub static_remotekey: bool,
+    // the commitments have anchor outputs, the `init` of both peers offers them
+    pub anchors: bool,
+}
+
+/// Our parameters of the accepted channel which differ from the node's policy
+#[derive(Debug, Clone, Default)]
+pub struct ChannelOverrides {
+    // the delay of the peer's to-self outputs
+    pub csv_delay: Option<u16>,
+    // in millisatoshi
+    pub htlc_minimum: Option<u64>,
+    pub max_accepted_htlc_number: Option<u16>,
+    pub minimum_depth: Option<u32>,
+}
+
+impl ChannelOverrides {
+    pub(crate) fn apply(&self, policy: &mut ChannelPolicy) {
+        if let Some(csv_delay) = self.csv_delay {
+            policy.csv_delay = csv_delay;
+        }
+        if let Some(htlc_minimum) = self.htlc_minimum {
+            policy.htlc_minimum = htlc_minimum;
+        }
+        if let Some(max_accepted_htlc_number) = self.max_accepted_htlc_number {
+            policy.max_accepted_htlc_number = max_accepted_htlc_number;
+        }
+        if let Some(minimum_depth) = self.minimum_depth {
+            policy.minimum_depth = minimum_depth;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ChannelDecision {
+    Accept(ChannelOverrides),
+    // the reason goes to the peer
+    Reject(String),
+}
+
+pub type PendingRequest = (ChannelRequest, oneshot::Sender<ChannelDecision>);
+
+struct AcceptorState {
+    client: Option<mpsc::UnboundedSender<PendingRequest>>,
+    timeout: Duration,
+}
+
+/// The client vets the channels which the peers open with us, the node and rpc share it,
+/// the channels are accepted according to the policy while no client is subscribed
+#[derive(Clone)]
+pub struct ChannelAcceptor(Arc<Mutex<AcceptorState>>);
+
+impl Default for ChannelAcceptor {
+    fn default() -> Self {
+        ChannelAcceptor(Arc::new(Mutex::new(AcceptorState {
+            client: None,
+            timeout: Duration::from_secs(30),
+        })))
+    }
+}
+
+impl ChannelAcceptor {
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.0.lock().unwrap().timeout = timeout;
+    }
+
+    // the new client replaces the previous one
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<PendingRequest> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.0.lock().unwrap().client = Some(sender);
+        receiver
+    }
+
+    // the decision of the client, `None` if there is no client,
+    // the channel is rejected if the client does not answer in time
+    pub(crate) fn request(&self, request: ChannelRequest) -> Option<Box<dyn Future<Item=ChannelDecision, Error=()> + Send>> {
+        let mut state = self.0.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let temporary_channel_id = request.open_channel.temporary_channel_id;
+        let sent = match state.client {
+            Some(ref client) => client.unbounded_send((request, sender)).is_ok(),
+            None => return None,
+        };
+        if !sent {
+            println!("INFO: the channel acceptor is gone");
+            state.client = None;
+            return None;
+        }
+        let decision = Timeout::new(receiver, state.timeout)
+            .then(move |result| {
+                let decision = result.unwrap_or_else(|e| {
+                    println!("WARNING: no decision on channel {:?}: {:?}", temporary_channel_id, e);
+                    ChannelDecision::Reject("the channel is not accepted in time".to_owned())
+                });
+                Ok(decision)
+            });
+        Some(Box::new(decision))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dependencies::futures::Stream;
+    use secp256k1::{Secp256k1, SecretKey};
+    use tokio::runtime::Runtime;
+    use common_types::RawPublicKey;
+    use wire::{ChannelId, ChannelKeys, ChannelFlags, Sha256, Satoshi, MilliSatoshi, SatoshiPerKiloWeight, CsvDelay};
+
+    fn request() -> ChannelRequest {
+        let public = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+        let key = RawPublicKey::from(public.clone());
+        ChannelRequest {
+            peer: public,
+            open_channel: OpenChannel {
+                chain_hash: Sha256::BITCOIN_CHAIN_HASH,
+                temporary_channel_id: ChannelId::from([1; 32]),
+                funding: Satoshi::from(1_000_000),
+                push: MilliSatoshi::from(0),
+                dust_limit: Satoshi::from(546),
+                max_in_flight: MilliSatoshi::from(1_000_000_000),
+                channel_reserve: Satoshi::from(10_000),
+                htlc_minimum: MilliSatoshi::from(1000),
+                fee: SatoshiPerKiloWeight::from(253),
+                csv_delay: CsvDelay::from(144),
+                max_accepted_htlc_number: 483,
+                keys: ChannelKeys {
+                    funding: key.clone(),
+                    revocation: key.clone(),
+                    payment: key.clone(),
+                    delayed_payment: key.clone(),
+                    htlc: key.clone(),
+                    first_per_commitment: key,
+                },
+                flags: ChannelFlags::FF_ANNOUNCE_CHANNEL,
+                shutdown_script: None,
+            },
+            static_remotekey: false,
+            anchors: false,
+        }
+    }
+
+    #[test]
+    fn policy_decides_without_client() {
+        let acceptor = ChannelAcceptor::default();
+        assert!(acceptor.request(request()).is_none());
+
+        // the client is gone
+        drop(acceptor.subscribe());
+        assert!(acceptor.request(request()).is_none());
+        assert!(acceptor.0.lock().unwrap().client.is_none());
+    }
+
+    #[test]
+    fn client_decides() {
+        let mut runtime = Runtime::new().unwrap();
+        let acceptor = ChannelAcceptor::default();
+        let requests = acceptor.subscribe();
+
+        let decision = acceptor.request(request()).unwrap();
+        let ((received, sender), _) = runtime.block_on(requests.into_future()).map_err(|_| ()).unwrap();
+        assert_eq!(received.open_channel.temporary_channel_id, ChannelId::from([1; 32]));
+        let overrides = ChannelOverrides {
+            minimum_depth: Some(6),
+            ..ChannelOverrides::default()
+        };
+        sender.send(ChannelDecision::Accept(overrides)).unwrap();
+
+        match runtime.block_on(decision).unwrap() {
+            ChannelDecision::Accept(overrides) => assert_eq!(overrides.minimum_depth, Some(6)),
+            decision => panic!("the channel is not accepted: {:?}", decision),
+        }
+    }
+
+    #[test]
+    fn no_decision_in_time_rejects() {
+        let mut runtime = Runtime::new().unwrap();
+        let acceptor = ChannelAcceptor::default();
+        acceptor.set_timeout(Duration::from_millis(10));
+        let _requests = acceptor.subscribe();
+
+        let decision = acceptor.request(request()).unwrap();
+        match runtime.block_on(decision).unwrap() {
+            ChannelDecision::Reject(_) => (),
+            decision => panic!("the channel is not rejected: {:?}", decision),
+        }
+    }
+}
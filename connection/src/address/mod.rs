@@ -16,9 +16,8 @@ use brontide::{BrontideStream, HandshakeError, Machine};
 use futures::sync::{oneshot, mpsc};
 use either::Either;
 use std::collections::BTreeMap;
-use internal_event::{Event, DirectCommand, ChannelCommand};
+use internal_event::{Event, DirectCommand};
 use std::fmt::Formatter;
-use wire::ChannelId;
 
 
 pub enum TransportError {
@@ -176,11 +175,6 @@ where
         destination: PublicKey,
         command: DirectCommand,
     },
-    ChannelCommand {
-        destination: ChannelId,
-        command: ChannelCommand,
-    },
-    BroadcastTick,
     Terminate,
 }
 
@@ -239,27 +233,16 @@ where
                     destination: destination,
                     command: command,
                 } => {
-                    // TODO: handle errors
-                    self.pipes.get(&destination)
+                    // the error means the peer is disconnected, forget its pipe
+                    let disconnected = self.pipes.get(&destination)
                         .map(|(_, ref ctx)|
-                            ctx.unbounded_send(Event::DirectCommand(command)).unwrap()
-                        );
-                    Ok(NotReady)
-                },
-                Command::ChannelCommand {
-                    destination: channel_id,
-                    command: command,
-                } => {
-                    // TODO: send the command to proper processor
-                    let _ = (channel_id, command);
-                    unimplemented!()
-                },
-                Command::BroadcastTick => {
-                    self.pipes.values()
-                        .for_each(|(_, ref ctx)|
-                            ctx.unbounded_send(Event::TimerTick).unwrap()
-                        );
-                    Ok(NotReady)
+                            ctx.unbounded_send(Event::DirectCommand(command)).is_err()
+                        )
+                        .unwrap_or(false);
+                    if disconnected {
+                        self.pipes.remove(&destination);
+                    }
+                    self.poll()
                 },
                 Command::Terminate => {
                     use std::mem;
@@ -0,0 +1,500 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::thread;
+
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+
+use bitcoin::{Block, BitcoinHash, Transaction};
+use bitcoin::consensus::encode;
+use bitcoin_hashes::{sha256d, Hash};
+use serde_derive::{Serialize, Deserialize};
+use chainntfs::{ZMQMessageProducer, ZMQMessage, BlockSource};
+use internal_event::{Event, EventBus, ChainEvent};
+use state::{DB, DBValue, DBBuilder, DBUser};
+
+// the deeper blocks are not expected to be reorganized, their hashes are forgotten
+const REORG_DEPTH: usize = 144;
+
+// the single record of the watcher
+const STORED_CHAIN_KEY: &'static str = "chain";
+
+struct Watched {
+    // the last processed block
+    height: u32,
+    // the hashes of the recent processed blocks, the last one is at `height`
+    recent: Vec<sha256d::Hash>,
+    // the height of the block which includes the transaction, its index in the block
+    // and the transaction itself, `None` while it is unconfirmed
+    transactions: HashMap<sha256d::Hash, Option<(u32, u32, Transaction)>>,
+    outpoints: HashSet<(sha256d::Hash, u32)>,
+    // the height of the spending block, the output is watched again if the block is reorganized
+    spent: HashMap<(sha256d::Hash, u32), u32>,
+    // the spending is published on every block until it is handled, see `forget_spend`
+    unhandled: HashMap<(sha256d::Hash, u32), Transaction>,
+    // the transactions of the last processed block, the output watched after the block
+    // is processed may be spent there, in the same block as the transaction which created it
+    last_block: Vec<Transaction>,
+}
+
+/// The last processed block and the watch list, the watcher continues from there after restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredChain {
+    pub height: u32,
+    pub recent: Vec<[u8; 32]>,
+    pub transactions: Vec<StoredWatch>,
+    pub outpoints: Vec<([u8; 32], u32)>,
+    pub spent: Vec<([u8; 32], u32, u32)>,
+    // the outpoint and the serialized spending transaction
+    #[serde(default)]
+    pub unhandled: Vec<([u8; 32], u32, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredWatch {
+    pub txid: [u8; 32],
+    // the height, the index in the block and the serialized transaction if it is confirmed
+    pub confirmed: Option<(u32, u32, Vec<u8>)>,
+}
+
+impl DBValue for StoredChain {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "chain"
+    }
+}
+
+impl<'a> From<&'a Watched> for StoredChain {
+    fn from(w: &'a Watched) -> Self {
+        StoredChain {
+            height: w.height,
+            recent: w.recent.iter().map(|hash| hash.into_inner()).collect(),
+            transactions: w.transactions.iter()
+                .map(|(txid, confirmed)| StoredWatch {
+                    txid: txid.into_inner(),
+                    confirmed: confirmed.as_ref()
+                        .map(|&(height, index, ref tx)| (height, index, encode::serialize(tx))),
+                })
+                .collect(),
+            outpoints: w.outpoints.iter().map(|&(txid, vout)| (txid.into_inner(), vout)).collect(),
+            spent: w.spent.iter().map(|(&(txid, vout), &height)| (txid.into_inner(), vout, height)).collect(),
+            unhandled: w.unhandled.iter()
+                .map(|(&(txid, vout), tx)| (txid.into_inner(), vout, encode::serialize(tx)))
+                .collect(),
+        }
+    }
+}
+
+impl From<StoredChain> for Watched {
+    fn from(s: StoredChain) -> Self {
+        Watched {
+            height: s.height,
+            recent: s.recent.into_iter().map(sha256d::Hash::from_inner).collect(),
+            transactions: s.transactions.into_iter()
+                .map(|w| {
+                    // the corrupted transaction is awaited again
+                    let confirmed = w.confirmed.and_then(|(height, index, tx)| {
+                        encode::deserialize(&tx).ok().map(|tx| (height, index, tx))
+                    });
+                    (sha256d::Hash::from_inner(w.txid), confirmed)
+                })
+                .collect(),
+            outpoints: s.outpoints.into_iter().map(|(txid, vout)| (sha256d::Hash::from_inner(txid), vout)).collect(),
+            spent: s.spent.into_iter().map(|(txid, vout, height)| ((sha256d::Hash::from_inner(txid), vout), height)).collect(),
+            unhandled: s.unhandled.into_iter()
+                .filter_map(|(txid, vout, tx)| {
+                    encode::deserialize(&tx).ok().map(|tx| ((sha256d::Hash::from_inner(txid), vout), tx))
+                })
+                .collect(),
+            last_block: Vec::new(),
+        }
+    }
+}
+
+impl Watched {
+    fn connect(&mut self, block: &Block) -> Vec<ChainEvent> {
+        self.height += 1;
+        let height = self.height;
+        self.recent.push(block.bitcoin_hash());
+        if self.recent.len() > REORG_DEPTH {
+            self.recent.remove(0);
+        }
+        self.spent.retain(|_, spent| *spent + REORG_DEPTH as u32 > height);
+
+        self.last_block = block.txdata.clone();
+
+        let mut events = vec![ChainEvent::NewBlock {
+            height: height,
+            hash: block.bitcoin_hash(),
+        }];
+        for (txid, confirmed) in self.transactions.iter_mut() {
+            if confirmed.is_none() {
+                *confirmed = block.txdata.iter()
+                    .position(|tx| tx.txid().eq(txid))
+                    .map(|index| (height, index as u32, block.txdata[index].clone()));
+            }
+            if let &mut Some((confirmed, index, ref transaction)) = confirmed {
+                events.push(ChainEvent::Confirmed {
+                    txid: txid.clone(),
+                    confirmations: height - confirmed + 1,
+                    transaction: transaction.clone(),
+                    block_height: confirmed,
+                    tx_index: index,
+                });
+            }
+        }
+        // the spending which is not handled yet is published again
+        for (&(txid, vout), spending_tx) in &self.unhandled {
+            events.push(ChainEvent::Spent {
+                txid: txid,
+                vout: vout,
+                spending_tx: spending_tx.clone(),
+            });
+        }
+        for tx in &block.txdata {
+            for input in &tx.input {
+                let outpoint = (input.previous_output.txid, input.previous_output.vout);
+                if self.outpoints.remove(&outpoint) {
+                    self.spent.insert(outpoint, height);
+                    self.unhandled.insert(outpoint, tx.clone());
+                    events.push(ChainEvent::Spent {
+                        txid: outpoint.0,
+                        vout: outpoint.1,
+                        spending_tx: tx.clone(),
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    // the tip is reorganized, its transactions are unconfirmed again
+    fn disconnect(&mut self) {
+        let height = self.height;
+        if let Some(hash) = self.recent.pop() {
+            println!("WARNING: the block {} at {} is reorganized", hash, height);
+        }
+        for confirmed in self.transactions.values_mut() {
+            if confirmed.as_ref().map(|&(confirmed, _, _)| confirmed == height).unwrap_or(false) {
+                *confirmed = None;
+            }
+        }
+        let outpoints: Vec<(sha256d::Hash, u32)> = self.spent.iter()
+            .filter(|&(_, &spent)| spent == height)
+            .map(|(outpoint, _)| outpoint.clone())
+            .collect();
+        for outpoint in outpoints {
+            self.spent.remove(&outpoint);
+            self.unhandled.remove(&outpoint);
+            self.outpoints.insert(outpoint);
+        }
+        self.last_block.clear();
+        self.height -= 1;
+    }
+}
+
+/// Follows the best chain of bitcoind from the last processed block, publishes the new blocks and
+/// the confirmations of the watched transactions and the spending of the watched outputs to the bus,
+/// the reorganized blocks are undone, the state is stored after every block,
+/// cheap to clone, every clone refers to the same watch list
+#[derive(Clone)]
+pub struct ChainWatcher {
+    watched: Arc<Mutex<Watched>>,
+    db: Arc<RwLock<DB>>,
+    bus: EventBus,
+}
+
+impl DBUser for ChainWatcher {
+    fn db_prepare(builder: DBBuilder) -> DBBuilder {
+        builder.register::<StoredChain>()
+    }
+}
+
+impl ChainWatcher {
+    // the fresh node starts at the given height
+    pub fn new(db: Arc<RwLock<DB>>, height: u32, bus: EventBus) -> Self {
+        let stored = db.read().unwrap().get::<String, StoredChain>(&STORED_CHAIN_KEY.to_owned()).unwrap();
+        let watched = match stored {
+            Some(stored) => Watched::from(stored),
+            None => Watched {
+                height: height,
+                recent: Vec::new(),
+                transactions: HashMap::new(),
+                outpoints: HashSet::new(),
+                spent: HashMap::new(),
+                unhandled: HashMap::new(),
+                last_block: Vec::new(),
+            },
+        };
+        ChainWatcher {
+            watched: Arc::new(Mutex::new(watched)),
+            db: db,
+            bus: bus,
+        }
+    }
+
+    fn save(&self, watched: &Watched) {
+        if let Err(e) = self.db.read().unwrap().put(&STORED_CHAIN_KEY.to_owned(), StoredChain::from(watched)) {
+            println!("ERROR: cannot store the chain state: {:?}", e);
+        }
+    }
+
+    // the confirmations are published on every new block until the transaction is forgotten
+    pub fn watch(&self, txid: sha256d::Hash) {
+        let mut watched = self.watched.lock().unwrap();
+        if !watched.transactions.contains_key(&txid) {
+            watched.transactions.insert(txid, None);
+            self.save(&watched);
+        }
+    }
+
+    pub fn forget(&self, txid: &sha256d::Hash) {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.transactions.remove(txid).is_some() {
+            self.save(&watched);
+        }
+    }
+
+    // the spending is published on every block until `forget_spend`,
+    // the output may be spent already in the last processed block
+    pub fn watch_spend(&self, outpoint: (sha256d::Hash, u32)) {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.spent.contains_key(&outpoint) || watched.outpoints.contains(&outpoint) {
+            return;
+        }
+        let spending_tx = watched.last_block.iter()
+            .find(|tx| tx.input.iter().any(|i| (i.previous_output.txid, i.previous_output.vout) == outpoint))
+            .cloned();
+        match spending_tx {
+            Some(spending_tx) => {
+                let height = watched.height;
+                watched.spent.insert(outpoint, height);
+                watched.unhandled.insert(outpoint, spending_tx.clone());
+                self.bus.publish(Event::Chain(ChainEvent::Spent {
+                    txid: outpoint.0,
+                    vout: outpoint.1,
+                    spending_tx: spending_tx,
+                }));
+            },
+            None => {
+                watched.outpoints.insert(outpoint);
+            },
+        }
+        self.save(&watched);
+    }
+
+    // the spending is handled and stored, it is not published anymore
+    pub fn forget_spend(&self, outpoint: (sha256d::Hash, u32)) {
+        let mut watched = self.watched.lock().unwrap();
+        if watched.unhandled.remove(&outpoint).is_some() {
+            self.save(&watched);
+        }
+    }
+
+    // the events of the block, which follows the last processed one
+    fn connect(&self, block: &Block) -> Vec<ChainEvent> {
+        let mut watched = self.watched.lock().unwrap();
+        let events = watched.connect(block);
+        self.save(&watched);
+        events
+    }
+
+    // the tip is undone while it is not in the best chain, then the missing blocks are processed
+    fn sync(&self, source: &BlockSource) -> Result<(), String> {
+        let best = source.best_height()?;
+        loop {
+            let (height, tip) = {
+                let watched = self.watched.lock().unwrap();
+                (watched.height, watched.recent.last().cloned())
+            };
+            let tip = match tip {
+                Some(tip) => tip,
+                None => break,
+            };
+            if height <= best && source.hash_at(height)? == tip {
+                break;
+            }
+            let mut watched = self.watched.lock().unwrap();
+            watched.disconnect();
+            if watched.recent.is_empty() {
+                println!("WARNING: the chain is reorganized deeper than {} blocks", REORG_DEPTH);
+            }
+            self.save(&watched);
+        }
+
+        loop {
+            let height = self.watched.lock().unwrap().height;
+            if height >= best {
+                break;
+            }
+            let block = source.block(&source.hash_at(height + 1)?)?;
+            for event in self.connect(&block) {
+                self.bus.publish(Event::Chain(event));
+            }
+        }
+        Ok(())
+    }
+
+    // bitcoind's zmq interface is blocking, so it has the own thread,
+    // the new block only triggers the sync, the blocks are read from bitcoind
+    pub fn run(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            // subscribed before the catch up, so no block is missed
+            let mut producer = ZMQMessageProducer::new();
+            let source = match BlockSource::new() {
+                Ok(source) => source,
+                Err(e) => {
+                    println!("ERROR: cannot connect to bitcoind: {}", e);
+                    return;
+                },
+            };
+            if let Err(e) = self.sync(&source) {
+                println!("WARNING: cannot catch up with the chain: {}", e);
+            }
+            loop {
+                match producer.recv() {
+                    ZMQMessage::Block(_) => if let Err(e) = self.sync(&source) {
+                        println!("WARNING: cannot follow the chain: {}", e);
+                    },
+                    ZMQMessage::Tx(_) => (),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{BlockHeader, TxIn, TxOut, OutPoint, Script};
+    use std::{fs, io};
+
+    fn spending(txid: sha256d::Hash) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: txid,
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn block(nonce: u32, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: sha256d::Hash::default(),
+                merkle_root: sha256d::Hash::default(),
+                time: 0,
+                bits: 0,
+                nonce: nonce,
+            },
+            txdata: txdata,
+        }
+    }
+
+    #[test]
+    fn reorganized_block_is_undone_and_state_survives_restart() {
+        const DB_PATH: &'static str = "../target/db/chain-watcher-test";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<ChainWatcher>()
+            .build(DB_PATH)
+            .unwrap();
+        let db = Arc::new(RwLock::new(db));
+
+        let funding_tx = spending(sha256d::Hash::hash(b"wallet"));
+        let commitment_tx = spending(funding_tx.txid());
+        let watcher = ChainWatcher::new(db.clone(), 100, EventBus::default());
+        watcher.watch(funding_tx.txid());
+        watcher.watch_spend((funding_tx.txid(), 0));
+
+        let events = watcher.connect(&block(1, vec![funding_tx.clone(), commitment_tx.clone()]));
+        assert_eq!(events.len(), 3);
+        match &events[1] {
+            &ChainEvent::Confirmed { confirmations, block_height, .. } => assert_eq!((confirmations, block_height), (1, 101)),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // the node restarts, the watcher continues from the last processed block
+        let watcher = ChainWatcher::new(db, 0, EventBus::default());
+        assert_eq!(watcher.watched.lock().unwrap().height, 101);
+
+        // the block is reorganized, the transaction is unconfirmed, the output is watched again
+        watcher.watched.lock().unwrap().disconnect();
+        {
+            let watched = watcher.watched.lock().unwrap();
+            assert_eq!(watched.height, 100);
+            assert!(watched.recent.is_empty());
+            assert_eq!(watched.transactions.get(&funding_tx.txid()).unwrap().as_ref().map(|c| c.0), None);
+            assert!(watched.outpoints.contains(&(funding_tx.txid(), 0)));
+        }
+
+        // the transaction is in the other block of the new chain
+        let events = watcher.connect(&block(2, vec![funding_tx, commitment_tx]));
+        assert_eq!(events.len(), 3);
+        let events = watcher.connect(&block(3, vec![]));
+        match &events[1] {
+            &ChainEvent::Confirmed { confirmations, block_height, .. } => assert_eq!((confirmations, block_height), (2, 101)),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn spending_is_published_until_it_is_forgotten() {
+        const DB_PATH: &'static str = "../target/db/chain-watcher-spending-test";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<ChainWatcher>()
+            .build(DB_PATH)
+            .unwrap();
+        let db = Arc::new(RwLock::new(db));
+
+        let funding_tx = spending(sha256d::Hash::hash(b"wallet"));
+        let commitment_tx = spending(funding_tx.txid());
+        let outpoint = (funding_tx.txid(), 0);
+        let watcher = ChainWatcher::new(db.clone(), 100, EventBus::default());
+        watcher.watch_spend(outpoint);
+
+        let spent = |events: &[ChainEvent]| events.iter()
+            .filter(|event| match event {
+                &&ChainEvent::Spent { txid, vout, .. } => (txid, vout) == outpoint,
+                _ => false,
+            })
+            .count();
+
+        let events = watcher.connect(&block(1, vec![funding_tx, commitment_tx]));
+        assert_eq!(spent(&events), 1);
+
+        // the keeper did not store the channel, the spending survives the restart
+        let watcher = ChainWatcher::new(db, 0, EventBus::default());
+        let events = watcher.connect(&block(2, vec![]));
+        assert_eq!(spent(&events), 1);
+
+        watcher.forget_spend(outpoint);
+        let events = watcher.connect(&block(3, vec![]));
+        assert_eq!(spent(&events), 0);
+    }
+}
@@ -0,0 +1,349 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+
+use dependencies::secp256k1;
+use dependencies::chrono;
+use dependencies::hex;
+
+use secp256k1::PublicKey;
+use serde_derive::Serialize;
+
+use wire::{Message, MessageExt};
+
+/// The record in the format of `cmd/dump-reader`
+#[derive(Serialize, Debug)]
+struct MessageInfo {
+    // hex-encoded message
+    msg_raw: String,
+
+    // hex-encoded pubkey of peer (in compressed format)
+    peer_pubkey: String,
+
+    // sent or received
+    direction: String,
+
+    #[serde(rename = "type")]
+    type_: String,
+
+    // Unix timestamp
+    time: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            &Direction::Sent => "sent",
+            &Direction::Received => "received",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpConfig {
+    pub path: PathBuf,
+    // the file is rotated when exceeds this size, in bytes
+    pub max_file_size: u64,
+    // how many rotated files to keep, `dump.json.1` is the most recent
+    pub max_files: usize,
+}
+
+impl Default for DumpConfig {
+    fn default() -> Self {
+        DumpConfig {
+            path: PathBuf::from("target/dump.json"),
+            max_file_size: 64 * 1024 * 1024,
+            max_files: 4,
+        }
+    }
+}
+
+// the name of the message as lnd writes it to the dump, `cmd/dump-reader` expects it
+fn lnd_name(message: &Message) -> &'static str {
+    use self::Message::*;
+
+    match message {
+        &Init(_) => "Init",
+        &Error(_) => "Error",
+        &Ping(_) => "Ping",
+        &Pong(_) => "Pong",
+        &OpenChannel(_) => "MsgOpenChannel",
+        &AcceptChannel(_) => "MsgAcceptChannel",
+        &FundingCreated(_) => "MsgFundingCreated",
+        &FundingSigned(_) => "MsgFundingSigned",
+        &FundingLocked(_) => "FundingLocked",
+        &ShutdownChannel(_) => "Shutdown",
+        &ClosingSigned(_) => "ClosingSigned",
+        &UpdateAddHtlc(_) => "UpdateAddHTLC",
+        &UpdateFulfillHtlc(_) => "UpdateFulfillHTLC",
+        &UpdateFailHtlc(_) => "UpdateFailHTLC",
+        &UpdateFailMalformedHtlc(_) => "UpdateFailMalformedHTLC",
+        &CommitmentSigned(_) => "CommitSig",
+        &RevokeAndAck(_) => "RevokeAndAck",
+        &UpdateFee(_) => "UpdateFee",
+        &ReestablishChannel(_) => "ChannelReestablish",
+        &AnnouncementChannel(_) => "ChannelAnnouncement",
+        &AnnouncementNode(_) => "NodeAnnouncement",
+        &UpdateChannel(_) => "ChannelUpdate",
+        &AnnounceSignatures(_) => "AnnounceSignatures",
+        &QueryShortChannelIds(_) => "QueryShortChanIDs",
+        &ReplyShortChannelIdsEnd(_) => "ReplyShortChanIDsEnd",
+        &QueryChannelRange(_) => "QueryChannelRange",
+        &ReplyChannelRange(_) => "ReplyChannelRange",
+        &GossipTimestampRange(_) => "GossipTimestampRange",
+    }
+}
+
+/// Empty set means no filtering, the types are named as in the dump
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    pub peers: HashSet<PublicKey>,
+    pub types: HashSet<String>,
+}
+
+impl DumpFilter {
+    fn pass(&self, peer: &PublicKey, type_name: &str) -> bool {
+        (self.peers.is_empty() || self.peers.contains(peer))
+            && (self.types.is_empty() || self.types.contains(type_name))
+    }
+}
+
+struct Recorder {
+    config: DumpConfig,
+    filter: DumpFilter,
+    enabled: bool,
+    file: Option<File>,
+    written: u64,
+}
+
+impl Recorder {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.config.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        self.file = None;
+        if self.config.max_files == 0 {
+            return fs::remove_file(&self.config.path);
+        }
+        let _ = fs::remove_file(self.rotated_path(self.config.max_files));
+        for index in (1..self.config.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.config.path, self.rotated_path(1))
+    }
+
+    fn open(&mut self) -> Result<&mut File, io::Error> {
+        if self.file.is_none() {
+            if let Some(parent) = self.config.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+            self.written = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        if self.written > 0 && self.written + (line.len() as u64) > self.config.max_file_size {
+            self.rotate()?;
+            self.written = 0;
+        }
+        self.open()?.write_all(line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes every message sent to and received from peers,
+/// the output is readable by `cmd/dump-reader`
+#[derive(Clone)]
+pub struct MessageRecorder(Arc<Mutex<Recorder>>);
+
+impl Default for MessageRecorder {
+    fn default() -> Self {
+        MessageRecorder::new(DumpConfig::default())
+    }
+}
+
+impl MessageRecorder {
+    // the recorder is disabled until `enable` is called
+    pub fn new(config: DumpConfig) -> Self {
+        MessageRecorder(Arc::new(Mutex::new(Recorder {
+            config: config,
+            filter: DumpFilter::default(),
+            enabled: false,
+            file: None,
+            written: 0,
+        })))
+    }
+
+    pub fn configure(&self, config: DumpConfig) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.config = config;
+        recorder.file = None;
+    }
+
+    pub fn enable(&self, path: Option<PathBuf>, filter: DumpFilter) {
+        let mut recorder = self.0.lock().unwrap();
+        if let Some(path) = path {
+            if path != recorder.config.path {
+                recorder.config.path = path;
+                recorder.file = None;
+            }
+        }
+        recorder.filter = filter;
+        recorder.enabled = true;
+        println!("INFO: message dump is enabled, writing to {:?}", recorder.config.path);
+    }
+
+    pub fn disable(&self) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.enabled = false;
+        recorder.file = None;
+        println!("INFO: message dump is disabled");
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.lock().unwrap().enabled
+    }
+
+    pub fn record(&self, peer: &PublicKey, direction: Direction, message: &MessageExt) {
+        use binformat::BinarySD;
+        use chrono::prelude::*;
+
+        let type_name = lnd_name(&message.message);
+        let mut recorder = self.0.lock().unwrap();
+        if !recorder.enabled || !recorder.filter.pass(peer, type_name) {
+            return;
+        }
+
+        let (raw_message, extra_data) = message.clone().into_raw_parts();
+        let mut raw = Vec::new();
+        if let Err(e) = BinarySD::serialize(&mut raw, &raw_message) {
+            println!("ERROR: cannot serialize message for dump: {:?}", e);
+            return;
+        }
+        raw.extend_from_slice(extra_data.as_slice());
+
+        // lnd writes the payload only, without the type
+        let info = MessageInfo {
+            msg_raw: hex::encode(&raw[2..]),
+            peer_pubkey: hex::encode(&peer.serialize()[..]),
+            direction: direction.as_str().to_owned(),
+            type_: type_name.to_owned(),
+            time: Utc::now().timestamp().to_string(),
+        };
+        let mut line = serde_json::to_vec(&info).unwrap();
+        line.push(b'\n');
+
+        if let Err(e) = recorder.write(line.as_slice()) {
+            println!("ERROR: cannot write message dump, disabling it: {:?}", e);
+            recorder.enabled = false;
+            recorder.file = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wire::{Ping, Pong};
+    use secp256k1::{Secp256k1, SecretKey};
+    use std::io::{BufRead, BufReader};
+
+    fn peer() -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap())
+    }
+
+    fn ping() -> MessageExt {
+        MessageExt::from(Message::Ping(Ping::new(4, 8).unwrap()))
+    }
+
+    fn read(path: &PathBuf) -> Vec<serde_json::Value> {
+        BufReader::new(File::open(path).unwrap()).lines()
+            .map(|line| serde_json::from_str(line.unwrap().as_str()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn record_is_readable() {
+        let path = PathBuf::from("../target/dump/record-test.json");
+        let _ = fs::remove_file(&path);
+
+        let recorder = MessageRecorder::new(DumpConfig {
+            path: path.clone(),
+            ..DumpConfig::default()
+        });
+        recorder.record(&peer(), Direction::Sent, &ping());
+        assert!(!path.exists());
+
+        recorder.enable(None, DumpFilter::default());
+        recorder.record(&peer(), Direction::Sent, &ping());
+        recorder.record(&peer(), Direction::Received, &MessageExt::from(Message::Pong(Pong::new(&Ping::new(0, 0).unwrap()))));
+
+        let records = read(&path);
+        assert_eq!(records.len(), 2);
+        // the payload is the pong length and the data of the ping without the type
+        let msg_raw = records[0]["msg_raw"].as_str().unwrap();
+        assert_eq!(msg_raw.len(), 2 * (2 + 2 + 4));
+        assert!(msg_raw.starts_with("00080004"));
+        assert_eq!(records[0]["type"], "Ping");
+        assert_eq!(records[0]["direction"], "sent");
+        assert_eq!(records[0]["peer_pubkey"], hex::encode(&peer().serialize()[..]).as_str());
+        assert_eq!(records[1]["type"], "Pong");
+        assert_eq!(records[1]["direction"], "received");
+
+        // the filter uses the names of the dump
+        let filter = DumpFilter {
+            peers: HashSet::new(),
+            types: vec!["Pong".to_owned()].into_iter().collect(),
+        };
+        recorder.enable(None, filter);
+        recorder.record(&peer(), Direction::Sent, &ping());
+        assert_eq!(read(&path).len(), 2);
+    }
+
+    #[test]
+    fn file_is_rotated() {
+        let path = PathBuf::from("../target/dump/rotation-test.json");
+        let rotated = |index: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        for index in 0..4 {
+            let _ = fs::remove_file(if index == 0 { path.clone() } else { rotated(index) });
+        }
+
+        // every record exceeds the half of the file
+        let recorder = MessageRecorder::new(DumpConfig {
+            path: path.clone(),
+            max_file_size: 256,
+            max_files: 2,
+        });
+        recorder.enable(None, DumpFilter::default());
+        for _ in 0..4 {
+            recorder.record(&peer(), Direction::Sent, &ping());
+        }
+
+        assert_eq!(read(&path).len(), 1);
+        assert_eq!(read(&rotated(1)).len(), 1);
+        assert_eq!(read(&rotated(2)).len(), 1);
+        assert!(!rotated(3).exists());
+    }
+}
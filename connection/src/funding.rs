@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+use std::cmp;
+
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+use dependencies::secp256k1;
+
+use bitcoin::{Transaction, Script, Address, BitcoinHash};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin_hashes::Hash;
+use secp256k1::SecretKey;
+use bitcoin::network::constants::Network;
+use wallet_lib::interface::Wallet;
+use wallet_lib::account::AccountAddressType;
+use wallet::FeeEstimator;
+use wire::{SatoshiPerKiloWeight, SatoshiPerVByte, Sha256};
+use chainntfs::BlockSource;
+
+use channel_machine::FundingWallet;
+
+// the commitment should be confirmed in a few blocks if it is broadcast
+const COMMITMENT_CONFIRMATION_TARGET: u32 = 6;
+
+pub type SharedFeeEstimator = Arc<Mutex<Box<dyn FeeEstimator + Send>>>;
+
+/// BOLT 2 `chain_hash`, the hash of the genesis block of the chain
+pub fn chain_hash(network: Network) -> Sha256 {
+    Sha256::from(genesis_block(network).bitcoin_hash().into_inner())
+}
+
+/// Follows bitcoind's estimate, so the fee of the commitments follows the chain,
+/// the fallback is used while bitcoind has no estimate
+pub struct ChainFeeEstimator {
+    fallback: SatoshiPerVByte,
+}
+
+impl ChainFeeEstimator {
+    pub fn new(fallback: SatoshiPerVByte) -> Self {
+        ChainFeeEstimator {
+            fallback: fallback,
+        }
+    }
+}
+
+impl FeeEstimator for ChainFeeEstimator {
+    fn estimate(&mut self, num_blocks: u32) -> SatoshiPerVByte {
+        match BlockSource::new().and_then(|source| source.estimate_fee(num_blocks)) {
+            // rounded up, the fee rate is whole satoshi per vbyte
+            Ok(Some(rate)) => SatoshiPerVByte::from(cmp::max((rate + 999) / 1000, 1)),
+            Ok(None) => self.fallback,
+            Err(e) => {
+                println!("WARNING: cannot estimate the fee, using {:?}: {}", self.fallback, e);
+                self.fallback
+            },
+        }
+    }
+}
+
+/// The node's wallet funds the channels opened by us and receives the funds of closed channels,
+/// the fee estimator sets the fee rate of the commitments
+pub struct WalletFunding {
+    wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
+    fee_estimator: SharedFeeEstimator,
+    network: Network,
+}
+
+impl WalletFunding {
+    // the network the wallet is created for
+    pub fn new(wallet: Arc<Mutex<Box<dyn Wallet + Send>>>, fee_estimator: SharedFeeEstimator, network: Network) -> Self {
+        WalletFunding {
+            wallet: wallet,
+            fee_estimator: fee_estimator,
+            network: network,
+        }
+    }
+}
+
+impl FundingWallet for WalletFunding {
+    fn fund(&mut self, script_pubkey: Script, amount: u64) -> Result<Transaction, String> {
+        let address = Address::from_script(&script_pubkey, self.network)
+            .ok_or("the funding output script has no address".to_owned())?;
+        // the coins stay locked, the transaction is broadcast only after `funding_signed`
+        self.wallet.lock().unwrap()
+            .send_coins(address.to_string(), amount, true, true, false)
+            .map(|(tx, _)| tx)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn publish(&mut self, transaction: &Transaction) -> Result<(), String> {
+        self.wallet.lock().unwrap()
+            .publish_tx(transaction)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn shutdown_script(&mut self) -> Result<Script, String> {
+        use std::str::FromStr;
+
+        let address = self.wallet.lock().unwrap()
+            .wallet_lib_mut()
+            .get_account_mut(AccountAddressType::P2WKH)
+            .new_address()
+            .map_err(|e| format!("{:?}", e))?;
+        Address::from_str(&address)
+            .map(|address| address.script_pubkey())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn fee_rate(&mut self) -> u32 {
+        let estimate = self.fee_estimator.lock().unwrap().estimate(COMMITMENT_CONFIRMATION_TARGET);
+        u32::from(SatoshiPerKiloWeight::from(estimate))
+    }
+
+    // the key of a new P2WPKH address of the wallet, the `to_remote` output of the peer's
+    // commitment pays to this address, so the wallet sees our funds even if the channel is lost
+    fn payment_key(&mut self) -> Result<SecretKey, String> {
+        let mut wallet = self.wallet.lock().unwrap();
+        let account = wallet.wallet_lib_mut().get_account_mut(AccountAddressType::P2WKH);
+        let address = account.new_address().map_err(|e| format!("{:?}", e))?;
+        account.get_sk(&address).map_err(|e| format!("{:?}", e))
+    }
+}
@@ -0,0 +1,76 @@
+use dependencies::secp256k1;
+use dependencies::either;
+
+use std::sync::{Arc, Mutex};
+
+use secp256k1::PublicKey;
+use wire::{Message, MessageExt, RawFeatureVector};
+use processor::{SingleResponseConsumer, MessageFiltered, MessageRouting};
+use internal_event::{Event, EventBus, PeerEvent};
+use binformat::WireError;
+use either::Either;
+
+#[derive(Debug)]
+pub struct InitMessage(RawFeatureVector);
+
+impl MessageFiltered for InitMessage {
+    fn filter(v: MessageExt) -> Result<Self, MessageExt> {
+        match v.message {
+            Message::Init(init) => Ok(InitMessage(init.local_features)),
+            _ => Err(v),
+        }
+    }
+}
+
+/// The features of the peer's `init`, the consumers of the peer session share them,
+/// they are set before any later message of the peer is consumed
+#[derive(Debug, Clone, Default)]
+pub struct PeerFeatures(Arc<Mutex<Option<RawFeatureVector>>>);
+
+impl PeerFeatures {
+    // `None` until the peer's `init`
+    pub fn get(&self) -> Option<RawFeatureVector> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, features: RawFeatureVector) {
+        *self.0.lock().unwrap() = Some(features);
+    }
+}
+
+/// Observes the peer's `init`, keeps its features and tells the bus about it,
+/// the channels with the peer are reestablished after our `init` is sent,
+/// so the observer should precede the consumer which answers `init`
+pub struct InitObserver {
+    peer: PublicKey,
+    features: PeerFeatures,
+    bus: EventBus,
+}
+
+impl InitObserver {
+    pub fn new(peer: PublicKey, features: PeerFeatures, bus: EventBus) -> Self {
+        InitObserver {
+            peer: peer,
+            features: features,
+            bus: bus,
+        }
+    }
+}
+
+impl SingleResponseConsumer for InitObserver {
+    type Message = InitMessage;
+    type Relevant = ();
+
+    const ROUTING: MessageRouting = MessageRouting::Observe;
+
+    const NAME: &'static str = "init";
+
+    // the observer never responds
+    fn consume_single_response(self, message: Either<Self::Message, Self::Relevant>) -> Result<(Self, Option<MessageExt>), WireError> {
+        if let Either::Left(InitMessage(features)) = message {
+            self.features.set(features.clone());
+            self.bus.publish(Event::Peer(PeerEvent::Initialized(self.peer.clone(), features)));
+        }
+        Ok((self, None))
+    }
+}
@@ -0,0 +1,621 @@
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+
+use dependencies::secp256k1;
+use dependencies::tokio;
+use dependencies::bitcoin;
+
+use secp256k1::{SecretKey, PublicKey};
+use tokio::prelude::{Future, Stream};
+use bitcoin::network::constants::Network;
+use wallet_lib::interface::Wallet;
+use wire::{Message, ChannelId, RawFeatureVector};
+use internal_event::{Event, Topic, EventBus, ChannelCommand, ChannelEvent, NewChannel, ChainEvent};
+use binformat::WireError;
+use routing::SharedState;
+use channel_machine::{ChannelState, ChannelPolicy, FundingWallet, ChannelAnnouncement};
+
+use super::status::{ChannelStatus, status_of};
+use super::funding::{WalletFunding, SharedFeeEstimator};
+use super::chain::ChainWatcher;
+use super::storage::ChannelStorage;
+use super::watchtower::TowerClient;
+
+// the channels outlive the peer session, they are reestablished when the peer reconnects
+#[derive(Default)]
+struct PeerChannels {
+    // keyed by the final id, or by the temporary id while the funding is not created
+    channels: HashMap<ChannelId, ChannelState>,
+    // temporary id -> final id
+    temporary_ids: HashMap<ChannelId, ChannelId>,
+    // the peer session exists and the channels are reestablished
+    online: bool,
+    // our `channel_reestablish` is sent, the peer's one is not received yet
+    reestablishing: HashSet<ChannelId>,
+}
+
+impl PeerChannels {
+    // the id under which the channel is stored
+    fn resolve(&self, channel_id: &ChannelId) -> ChannelId {
+        self.temporary_ids.get(channel_id).cloned().unwrap_or(channel_id.clone())
+    }
+
+    fn temporary_id(&self, channel_id: &ChannelId) -> Option<ChannelId> {
+        self.temporary_ids.iter()
+            .find(|&(_, id)| id.eq(channel_id))
+            .map(|(temporary, _)| temporary.clone())
+    }
+}
+
+// the chain watcher reports the confirmations and the spending the channel waits for
+fn watch(chain: &ChainWatcher, channel: &ChannelState) {
+    for txid in channel.watched_txids() {
+        chain.watch(txid);
+    }
+    for outpoint in channel.watched_outpoints() {
+        chain.watch_spend(outpoint);
+    }
+}
+
+// the stored channels after the restart, the peers are offline until they reconnect
+fn restore(storage: &ChannelStorage, chain: &ChainWatcher) -> HashMap<PublicKey, PeerChannels> {
+    let mut channels = HashMap::new();
+    for stored in storage.load().unwrap() {
+        let channel_id = match stored.state.channel_id() {
+            Some(channel_id) => channel_id,
+            None => continue,
+        };
+        println!("INFO: restored channel {:?} with {}: {:?}", channel_id, stored.peer, stored.state);
+        watch(chain, &stored.state);
+        let peer_channels = channels.entry(stored.peer.as_ref().clone()).or_insert_with(PeerChannels::default);
+        if let Some(temporary_channel_id) = stored.temporary_channel_id {
+            peer_channels.temporary_ids.insert(temporary_channel_id, channel_id);
+        }
+        peer_channels.channels.insert(channel_id, stored.state);
+    }
+    channels
+}
+
+/// Owns the channels of every peer, connected or not, the peer session passes
+/// the peer's messages here, the commands and the chain events are handled here
+/// for all channels, so the channel of the offline peer is closed and protected as well,
+/// the messages to the peer go to the bus, its session sends them in order,
+/// cheap to clone, every clone refers to the same channels
+#[derive(Clone)]
+pub struct ChannelKeeper {
+    channels: Arc<Mutex<HashMap<PublicKey, PeerChannels>>>,
+    storage: ChannelStorage,
+    // the public channels are announced on behalf of the node
+    secret: SecretKey,
+    shared_state: SharedState,
+    wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
+    network: Network,
+    fee_estimator: SharedFeeEstimator,
+    chain: ChainWatcher,
+    // the justice of the peer's revoked commitments goes there
+    towers: TowerClient,
+    bus: EventBus,
+}
+
+impl ChannelKeeper {
+    // the stored channels wait for their peers, the chain is watched for the closing channels
+    pub fn new(
+        storage: ChannelStorage,
+        secret: SecretKey,
+        shared_state: SharedState,
+        wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
+        network: Network,
+        fee_estimator: SharedFeeEstimator,
+        chain: ChainWatcher,
+        towers: TowerClient,
+        bus: EventBus,
+    ) -> Self {
+        ChannelKeeper {
+            channels: Arc::new(Mutex::new(restore(&storage, &chain))),
+            storage: storage,
+            secret: secret,
+            shared_state: shared_state,
+            wallet: wallet,
+            network: network,
+            fee_estimator: fee_estimator,
+            chain: chain,
+            towers: towers,
+            bus: bus,
+        }
+    }
+
+    fn funding(&self) -> WalletFunding {
+        WalletFunding::new(self.wallet.clone(), self.fee_estimator.clone(), self.network)
+    }
+
+    // the peer session sends them
+    fn send<I>(&self, peer: &PublicKey, messages: I)
+    where
+        I: IntoIterator<Item=Message>,
+    {
+        for message in messages {
+            println!("response message: {:?}", message);
+            self.bus.publish(Event::PeerMessage {
+                peer: peer.clone(),
+                message: message,
+            });
+        }
+    }
+
+    // the justice is stored before it is uploaded, so it survives the restart
+    fn back_up(&self, channel: &ChannelState, funding: &mut WalletFunding) {
+        if !self.towers.is_configured() {
+            return;
+        }
+        if let Some(remedy) = channel.breach_remedy(funding) {
+            match self.towers.backup(&remedy) {
+                Ok(()) => self.towers.flush(),
+                Err(e) => println!("ERROR: cannot back up the justice of {} for the watchtowers: {:?}", remedy.commitment_txid, e),
+            }
+        }
+    }
+
+    // the channel is stored before the peer learns about its new state
+    fn persist(&self, peer: &PublicKey, peer_channels: &PeerChannels, channel_id: ChannelId) -> Result<(), WireError> {
+        use std::io;
+
+        let channel = match peer_channels.channels.get(&channel_id) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        self.storage.save(peer, channel_id, peer_channels.temporary_id(&channel_id), channel)
+            .map_err(|e| {
+                println!("ERROR: cannot store channel {:?}: {:?}", channel_id, e);
+                WireError::from(io::Error::new(io::ErrorKind::Other, format!("cannot store channel: {:?}", e)))
+            })
+    }
+
+    fn publish_status(&self, peer: &PublicKey, peer_channels: &PeerChannels, channel_id: ChannelId, status: ChannelStatus) {
+        let channel = peer_channels.channels.get(&channel_id);
+        self.bus.publish(Event::Channel(ChannelEvent {
+            peer: peer.clone(),
+            channel_id: channel_id,
+            temporary_channel_id: peer_channels.temporary_id(&channel_id),
+            status: status,
+            funding_outpoint: channel.and_then(ChannelState::funding_outpoint),
+            closing_txid: channel.and_then(ChannelState::closing_txid),
+        }));
+    }
+
+    // the status is published if the stored channel changed it
+    fn report_status(&self, peer: &PublicKey, peer_channels: &PeerChannels, channel_id: ChannelId, previous: Option<ChannelStatus>) {
+        match peer_channels.channels.get(&channel_id).and_then(status_of) {
+            Some(status) => if previous.as_ref() != Some(&status) {
+                self.publish_status(peer, peer_channels, channel_id, status);
+            },
+            None => (),
+        }
+    }
+
+    // the channel goes back to the store, it is persisted and its new status is reported
+    fn store(&self, peer: &PublicKey, peer_channels: &mut PeerChannels, channel_id: ChannelId, channel: ChannelState, previous: Option<ChannelStatus>) -> Result<(), WireError> {
+        peer_channels.channels.insert(channel_id, channel);
+        self.persist(peer, peer_channels, channel_id)?;
+        self.report_status(peer, peer_channels, channel_id, previous);
+        Ok(())
+    }
+
+    // Our `announce_signatures`, the complete announcement of the public channel
+    // goes to our routing state and to every peer
+    fn announce(&self, peer: &PublicKey, channel: ChannelState) -> (ChannelState, Option<Message>) {
+        let (channel, message, announcement) = channel.announce(&self.secret, peer);
+        if let Some(ChannelAnnouncement { announcement, update }) = announcement {
+            let misbehavior = self.shared_state.0.write().unwrap().announce_channel(announcement.clone(), update.clone());
+            match misbehavior {
+                Some(misbehavior) => println!("ERROR: the routing state rejected our channel: {:?}", misbehavior),
+                None => {
+                    self.bus.publish(Event::Gossip(Message::AnnouncementChannel(announcement)));
+                    self.bus.publish(Event::Gossip(Message::UpdateChannel(update)));
+                },
+            }
+        }
+        (channel, message)
+    }
+
+    // the channel with the peer, the temporary id is resolved
+    pub fn knows(&self, peer: &PublicKey, channel_id: &ChannelId) -> bool {
+        self.channels.lock().unwrap()
+            .get(peer)
+            .map(|peer_channels| peer_channels.channels.contains_key(&peer_channels.resolve(channel_id)))
+            .unwrap_or(false)
+    }
+
+    // the peer sent `init`, our `channel_reestablish` of every channel goes to the peer,
+    // the peer lost the updates which were in flight
+    pub fn reestablish(&self, peer: &PublicKey) {
+        let mut store = self.channels.lock().unwrap();
+        let peer_channels = store.entry(peer.clone()).or_insert_with(PeerChannels::default);
+        peer_channels.online = true;
+        let (ids, messages): (HashSet<ChannelId>, Vec<Message>) = peer_channels.channels.iter()
+            .filter_map(|(id, channel)| channel.reestablish().map(|message| (id.clone(), message)))
+            .inspect(|&(_, ref message)| println!("INFO: reestablishing channel with {}: {:?}", peer, message))
+            .unzip();
+        peer_channels.reestablishing = ids;
+        self.send(peer, messages);
+    }
+
+    // the channel opened by the peer is not created, the peer learns the reason
+    pub fn reject(&self, peer: &PublicKey, channel_id: ChannelId, reason: String) {
+        self.send(peer, Some(Message::Error(wire::Error {
+            channel_id: channel_id,
+            data: reason.into_bytes(),
+        })));
+    }
+
+    // the peer session is over, the channels wait for the peer
+    pub fn disconnected(&self, peer: &PublicKey) {
+        if let Some(peer_channels) = self.channels.lock().unwrap().get_mut(peer) {
+            peer_channels.online = false;
+            peer_channels.reestablishing.clear();
+        }
+    }
+
+    // we are the funder, the channel is stored by the temporary id until `accept_channel`,
+    // our parameters are of the policy, the features are of the peer's `init`
+    pub fn open_channel(&self, peer: &PublicKey, new_channel: NewChannel, policy: &ChannelPolicy, their_features: &RawFeatureVector) {
+        let mut funding = self.funding();
+        let mut params = policy.open_params(u64::from(new_channel.funding), u64::from(new_channel.push));
+        params.temporary_channel_id = new_channel.temporary_channel_id;
+        if let Some(htlc_minimum) = new_channel.htlc_minimum {
+            params.htlc_minimum = u64::from(htlc_minimum);
+        }
+        if let Some(csv_delay) = new_channel.csv_delay {
+            params.csv_delay = u16::from(csv_delay);
+        }
+        params.fee_rate = match new_channel.fee {
+            Some(fee) => u32::from(fee),
+            None => cmp::max(funding.fee_rate(), params.fee_rate),
+        };
+        params.announce = !new_channel.private;
+
+        let temporary_channel_id = params.temporary_channel_id;
+        println!("INFO: opening channel {:?} with {}", temporary_channel_id, peer);
+        let (channel, message) = ChannelState::open(params, their_features, &mut funding);
+
+        // the channel has no final id yet, so it is not written, the record of the same id is deleted,
+        // it goes through the store anyway, so every transition is persisted the same way
+        let mut store = self.channels.lock().unwrap();
+        let peer_channels = store.entry(peer.clone()).or_insert_with(PeerChannels::default);
+        match self.store(peer, peer_channels, temporary_channel_id, channel, None) {
+            Ok(()) => self.send(peer, message),
+            Err(e) => {
+                self.publish_status(peer, peer_channels, temporary_channel_id, ChannelStatus::Error(format!("{:?}", e)));
+                peer_channels.channels.remove(&temporary_channel_id);
+            },
+        }
+    }
+
+    // the peer's message of the channel, the channel opened by the peer gets the policy
+    // and the features of the peer's `init`,
+    // the error means the channel cannot be stored, so the peer session should end
+    pub fn message(
+        &self,
+        peer: &PublicKey,
+        channel_id: ChannelId,
+        message: Message,
+        policy: &ChannelPolicy,
+        their_features: &RawFeatureVector,
+    ) -> Result<(), WireError> {
+        // BOLT 1: the error with zero channel id refers to all channels
+        if let &Message::Error(_) = &message {
+            if channel_id == ChannelId::all() {
+                let ids: Vec<ChannelId> = self.channels.lock().unwrap().get(peer)
+                    .map(|peer_channels| peer_channels.channels.keys().cloned().collect())
+                    .unwrap_or(Vec::new());
+                for id in ids {
+                    self.message(peer, id, message.clone(), policy, their_features)?;
+                }
+                return Ok(());
+            }
+        }
+
+        let mut store = self.channels.lock().unwrap();
+        let peer_channels = store.entry(peer.clone()).or_insert_with(PeerChannels::default);
+
+        let id = peer_channels.resolve(&channel_id);
+        let channel = match peer_channels.channels.remove(&id) {
+            Some(channel) => channel,
+            None => match &message {
+                &Message::OpenChannel(_) => ChannelState::with_policy(policy.clone(), their_features),
+                _ => {
+                    println!("WARNING: unknown channel {:?}, ignoring", channel_id);
+                    return Ok(());
+                },
+            },
+        };
+
+        println!("channel {:?} state: {:?}", id, channel);
+        let status = status_of(&channel);
+        let mut funding = self.funding();
+        let (channel, responses) = match message {
+            Message::ReestablishChannel(msg) => {
+                peer_channels.reestablishing.remove(&id);
+                let (channel, retransmitted) = channel.handle_reestablish(msg, &mut funding);
+                watch(&self.chain, &channel);
+                (channel, retransmitted)
+            },
+            message => {
+                let revoked = match &message {
+                    &Message::RevokeAndAck(_) => true,
+                    _ => false,
+                };
+                let fulfilled = match &message {
+                    &Message::UpdateFulfillHtlc(ref msg) => Some(msg.id.to_u64()),
+                    _ => None,
+                };
+                let (channel, response) = channel.next(message, &mut funding);
+                // the peer settled the HTLC we offered, the preimage goes upstream
+                if let Some(htlc_id) = fulfilled {
+                    if let Some(payment_preimage) = channel.payment_preimage(htlc_id) {
+                        self.bus.publish(Event::HtlcFulfilled {
+                            channel_id: id,
+                            id: htlc_id,
+                            payment_preimage: payment_preimage,
+                        });
+                    }
+                }
+                // the peer revoked its commitment, the towers punish the breach while we are offline
+                if revoked {
+                    self.back_up(&channel, &mut funding);
+                }
+                // the funding transaction is known, its confirmations are awaited,
+                // the funded channel waits for the peer's revoked commitments
+                watch(&self.chain, &channel);
+                (channel, response.into_iter().collect())
+            },
+        };
+        // the peer's updates are acknowledged by our next commitment signature
+        let (channel, commitment) = channel.commit();
+        let (channel, signatures) = self.announce(peer, channel);
+
+        // the channel is re-keyed when the funding transaction is known
+        let new_id = channel.channel_id().unwrap_or(id);
+        if new_id != id {
+            peer_channels.temporary_ids.insert(id, new_id);
+        }
+        self.store(peer, peer_channels, new_id, channel, status)?;
+
+        let responses: Vec<Message> = responses.into_iter()
+            .chain(commitment)
+            .chain(signatures)
+            .collect();
+        if responses.is_empty() {
+            println!("response nothing");
+        }
+        self.send(peer, responses);
+        Ok(())
+    }
+
+    // the forced close does not need the peer, other commands need the reestablished channel
+    pub fn command(&self, channel_id: ChannelId, command: ChannelCommand) {
+        let mut store = self.channels.lock().unwrap();
+        let found = store.iter_mut()
+            .map(|(peer, peer_channels)| {
+                let id = peer_channels.resolve(&channel_id);
+                (peer.clone(), peer_channels, id)
+            })
+            .find(|&(_, ref peer_channels, ref id)| peer_channels.channels.contains_key(id));
+        let (peer, peer_channels, id) = match found {
+            Some(v) => v,
+            None => {
+                println!("WARNING: unknown channel {:?}, ignoring the command", channel_id);
+                self.bus.publish(Event::UnknownChannel(channel_id));
+                return;
+            },
+        };
+
+        let channel = peer_channels.channels.remove(&id).unwrap();
+        let status = status_of(&channel);
+        let mut funding = self.funding();
+        let online = peer_channels.online;
+        let (channel, update) = match command {
+            ChannelCommand::CloseChannel { force: true } => {
+                println!("INFO: force closing channel {:?}", id);
+                let (channel, result) = channel.force_close(&mut funding);
+                let result = result.map(|txid| {
+                    self.chain.watch(txid);
+                    None
+                });
+                (channel, result)
+            },
+            _ if !online => (channel, Err("the peer is offline".to_owned())),
+            ChannelCommand::CloseChannel { force: false } => {
+                println!("INFO: closing channel {:?}", id);
+                let (channel, update) = channel.shutdown(&mut funding);
+                (channel, update.map(Some))
+            },
+            ChannelCommand::AddHtlc { amount, payment_hash, expiry, onion_blob } => {
+                let (channel, update) = channel.add_htlc(u64::from(amount), payment_hash, expiry, onion_blob);
+                (channel, update.map(Some))
+            },
+            ChannelCommand::FulfillHtlc { id: htlc_id, payment_preimage } => {
+                let (channel, update) = channel.fulfill_htlc(htlc_id, payment_preimage);
+                (channel, update.map(Some))
+            },
+            ChannelCommand::FailHtlc { id: htlc_id, reason } => {
+                let (channel, update) = channel.fail_htlc(htlc_id, reason);
+                (channel, update.map(Some))
+            },
+        };
+        let (channel, messages) = match update {
+            Ok(update) => {
+                let (channel, commitment) = channel.commit();
+                (channel, update.into_iter().chain(commitment).collect())
+            },
+            Err(description) => {
+                println!("WARNING: channel {:?} rejected the command: {}", id, description);
+                self.publish_status(&peer, peer_channels, id, ChannelStatus::Error(description));
+                (channel, Vec::new())
+            },
+        };
+        // the failure is reported by `persist`, the peer learns nothing then
+        if self.store(&peer, peer_channels, id, channel, status).is_ok() {
+            self.send(&peer, messages);
+        }
+    }
+
+    // the confirmations and the spending of the channels' transactions, and the new blocks
+    pub fn chain(&self, event: ChainEvent) {
+        let mut store = self.channels.lock().unwrap();
+        // the spending is published again until every channel which watches it is stored
+        let mut stored = true;
+        for (peer, peer_channels) in store.iter_mut() {
+            match &event {
+                &ChainEvent::Confirmed { ref txid, confirmations, ref transaction, block_height, tx_index } => {
+                    let ids: Vec<ChannelId> = peer_channels.channels.iter()
+                        .filter(|&(_, channel)| channel.watched_txids().contains(txid))
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    for id in ids {
+                        let channel = peer_channels.channels.remove(&id).unwrap();
+                        let status = status_of(&channel);
+                        let mut funding = self.funding();
+                        let (channel, message) = channel.confirmed(transaction, block_height, tx_index, confirmations, &mut funding);
+                        // the funding is deep enough for the public channel
+                        let (channel, signatures) = self.announce(peer, channel);
+                        // the channel is locked or closed
+                        if !channel.watched_txids().contains(txid) {
+                            self.chain.forget(txid);
+                        }
+                        watch(&self.chain, &channel);
+                        if self.store(peer, peer_channels, id, channel, status).is_ok() {
+                            self.send(peer, message.into_iter().chain(signatures));
+                        }
+                    }
+                },
+                &ChainEvent::Spent { ref txid, vout, ref spending_tx } => {
+                    let ids: Vec<ChannelId> = peer_channels.channels.iter()
+                        .filter(|&(_, channel)| channel.watched_outpoints().contains(&(*txid, vout)))
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    for id in ids {
+                        let channel = peer_channels.channels.remove(&id).unwrap();
+                        let status = status_of(&channel);
+                        let mut funding = self.funding();
+                        let channel = channel.spent(spending_tx, &mut funding);
+                        // the justice transactions are awaited, and the peer's HTLC transactions
+                        watch(&self.chain, &channel);
+                        if self.store(peer, peer_channels, id, channel, status).is_err() {
+                            stored = false;
+                        }
+                    }
+                },
+                // as the funder we keep the fee rate of the commitments close to the estimate,
+                // the peer should be there to sign the new commitment
+                &ChainEvent::NewBlock { .. } => {
+                    // the justice is broadcast until it is confirmed, the peer is not needed
+                    let mut funding = self.funding();
+                    for channel in peer_channels.channels.values() {
+                        channel.rebroadcast(&mut funding);
+                    }
+                    if !peer_channels.online {
+                        continue;
+                    }
+                    let fee_rate = self.funding().fee_rate();
+                    // the update before the peer's `channel_reestablish` would be lost or retransmitted wrongly
+                    let ids: Vec<ChannelId> = peer_channels.channels.iter()
+                        .filter(|&(id, _)| !peer_channels.reestablishing.contains(id))
+                        .filter(|&(_, channel)| channel.needs_fee_update(fee_rate))
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    for id in ids {
+                        let channel = peer_channels.channels.remove(&id).unwrap();
+                        let status = status_of(&channel);
+                        let (channel, update) = channel.update_fee(fee_rate);
+                        let (channel, messages) = match update {
+                            Ok(update) => {
+                                let (channel, commitment) = channel.commit();
+                                (channel, Some(update).into_iter().chain(commitment).collect())
+                            },
+                            Err(description) => {
+                                println!("WARNING: cannot update the fee of channel {:?}: {}", id, description);
+                                (channel, Vec::new())
+                            },
+                        };
+                        if self.store(peer, peer_channels, id, channel, status).is_ok() {
+                            self.send(peer, messages);
+                        }
+                    }
+                },
+            }
+        }
+        if let ChainEvent::Spent { txid, vout, .. } = event {
+            if stored {
+                self.chain.forget_spend((txid, vout));
+            }
+        }
+    }
+
+    // handles the commands and the chain events until the bus is gone
+    pub fn run(self) -> impl Future<Item=(), Error=()> + Send {
+        self.bus.subscribe(&[Topic::Command, Topic::Chain])
+            .for_each(move |event| {
+                match event {
+                    Event::ChannelCommand { channel_id, command } => self.command(channel_id, command),
+                    Event::Chain(event) => self.chain(event),
+                    _ => (),
+                }
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use std::{fs, io};
+
+    use secp256k1::Secp256k1;
+    use state::DBBuilder;
+    use channel_machine::OpenChannelParams;
+    use channel_machine::testing::MockWallet;
+
+    use super::super::storage::tests::funded;
+
+    fn public_key(byte: u8) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+    }
+
+    // the node restarts, the funded channels come back with their temporary ids, the peers are offline
+    #[test]
+    fn stored_channels_are_restored() {
+        const DB_PATH: &'static str = "../target/db/channel-keeper-restore";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<ChannelStorage>()
+            .user::<ChainWatcher>()
+            .build(DB_PATH)
+            .unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let storage = ChannelStorage::new(db.clone());
+
+        let peer = public_key(2);
+        let (funder, _) = funded();
+        let channel_id = funder.channel_id().expect("the funded channel has no id");
+        let temporary_channel_id = ChannelId::from([9; 32]);
+        storage.save(&peer, channel_id, Some(temporary_channel_id), &funder).unwrap();
+        // the channel which is not funded is not written
+        let (opening, _) = ChannelState::open(OpenChannelParams::new(1_000_000, 0), &RawFeatureVector::new(), &mut MockWallet::default());
+        storage.save(&peer, temporary_channel_id, None, &opening).unwrap();
+
+        let chain = ChainWatcher::new(db, 100, EventBus::default());
+        let channels = restore(&storage, &chain);
+        assert_eq!(channels.len(), 1);
+        let peer_channels = &channels[&peer];
+        assert!(!peer_channels.online);
+        assert_eq!(peer_channels.resolve(&temporary_channel_id), channel_id);
+        assert_eq!(peer_channels.temporary_id(&channel_id), Some(temporary_channel_id));
+        assert_eq!(peer_channels.channels.len(), 1);
+        assert_eq!(format!("{:?}", peer_channels.channels[&channel_id]), format!("{:?}", funder));
+    }
+}
@@ -4,6 +4,25 @@ mod node;
 mod address;
 mod ping;
 mod blockchain;
+mod misbehavior;
+mod dump;
+mod status;
+mod funding;
+mod chain;
+mod init;
+mod storage;
+mod acceptor;
+mod keeper;
+mod watchtower;
 
-pub use self::node::{Node, ChannelStatus};
+pub use self::node::Node;
+pub use self::status::{ChannelStatus, ChannelSubscriptions};
+pub use self::misbehavior::{BanPolicy, BanTarget, Ban, Scoreboard};
+pub use self::dump::{MessageRecorder, DumpConfig, DumpFilter, Direction};
+pub use self::acceptor::{ChannelAcceptor, ChannelRequest, ChannelDecision, ChannelOverrides, PendingRequest};
+pub use self::watchtower::{TowerClient, TowerServer, TowerAddress, breach_hint, encrypt_justice, decrypt_justice};
 pub use self::address::{AbstractAddress, Command, ConnectionStream, Connection, TransportError};
+pub use channel_machine::ChannelPolicy;
+pub use self::funding::ChainFeeEstimator;
+pub use wallet::{FeeEstimator, StaticFeeEstimator};
+pub use wire::SatoshiPerVByte;
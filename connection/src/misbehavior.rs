@@ -0,0 +1,334 @@
+use std::sync::{Arc, RwLock, Mutex};
+use std::collections::HashMap;
+use std::cmp;
+use std::net::{IpAddr, Ipv6Addr};
+
+use dependencies::secp256k1;
+use dependencies::chrono;
+
+use secp256k1::PublicKey;
+use serde_derive::{Serialize, Deserialize};
+
+use state::{DB, DBValue, DBBuilder, DBUser, DBError};
+use processor::{Misbehavior, ScoreKeeper};
+use common_types::RawPublicKey;
+
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    // the peer is disconnected when its score reaches this value
+    pub disconnect_threshold: u32,
+    // the peer is disconnected and banned when its score reaches this value
+    pub ban_threshold: u32,
+    // in seconds
+    pub ban_duration: i64,
+    // in seconds, one point of the score is forgiven each period, so the rare misbehavior does not add up to the ban
+    pub score_decay: i64,
+    // the ip address of the banned peer is banned too, the private and loopback addresses never are,
+    // the address is banned by the RPC otherwise
+    pub ban_address: bool,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        BanPolicy {
+            disconnect_threshold: 50,
+            ban_threshold: 100,
+            ban_duration: 24 * 60 * 60,
+            score_decay: 60,
+            ban_address: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BanTarget {
+    Node(RawPublicKey),
+    Address(IpAddr),
+}
+
+impl BanTarget {
+    fn key(&self) -> String {
+        match self {
+            &BanTarget::Node(ref pk) => format!("node:{}", pk.to_hex()),
+            &BanTarget::Address(ref ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    // unix timestamp in seconds
+    pub until: i64,
+    pub reason: String,
+}
+
+impl DBValue for Ban {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "ban"
+    }
+}
+
+/// Keeps the misbehavior score of connected peers and the bans
+pub struct Scoreboard {
+    policy: BanPolicy,
+    db: Arc<RwLock<DB>>,
+    // the score and the time it was last updated
+    scores: Mutex<HashMap<PublicKey, (u32, i64)>>,
+    // the connected peers and their addresses, so the ip is banned along with the node key
+    addresses: Mutex<HashMap<PublicKey, Option<IpAddr>>>,
+}
+
+impl DBUser for Scoreboard {
+    fn db_prepare(builder: DBBuilder) -> DBBuilder {
+        builder.register::<Ban>()
+    }
+}
+
+impl Scoreboard {
+    pub fn new(policy: BanPolicy, db: Arc<RwLock<DB>>) -> Self {
+        Scoreboard {
+            policy: policy,
+            db: db,
+            scores: Mutex::new(HashMap::new()),
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> &BanPolicy {
+        &self.policy
+    }
+
+    fn now() -> i64 {
+        use chrono::prelude::*;
+
+        Utc::now().timestamp()
+    }
+
+    // the address is shared by many peers behind the private network or the proxy
+    fn is_public(address: &IpAddr) -> bool {
+        match address {
+            &IpAddr::V4(ref ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()),
+            &IpAddr::V6(ref ip) => !(ip.is_loopback() || ip.is_unspecified() || Self::is_local_v6(ip)),
+        }
+    }
+
+    // unique local fc00::/7 and link local fe80::/10
+    fn is_local_v6(ip: &Ipv6Addr) -> bool {
+        let first = ip.segments()[0];
+        first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+    }
+
+    // the score decays since the last update, the clock may go back
+    fn decayed(&self, score: u32, updated: i64, now: i64) -> u32 {
+        let periods = cmp::max(now - updated, 0) / cmp::max(self.policy.score_decay, 1);
+        if periods >= score as i64 { 0 } else { score - periods as u32 }
+    }
+
+    fn report_at(&self, peer: &PublicKey, misbehavior: Misbehavior, now: i64) -> bool {
+        let score = {
+            let mut scores = self.scores.lock().unwrap();
+            let entry = scores.entry(peer.clone()).or_insert((0, now));
+            let score = self.decayed(entry.0, entry.1, now) + misbehavior.penalty();
+            *entry = (score, now);
+            score
+        };
+
+        if score >= self.policy.ban_threshold {
+            self.scores.lock().unwrap().remove(peer);
+            let reason = format!("score {}, last misbehavior: {:?}", score, misbehavior);
+            let duration = self.policy.ban_duration;
+            let address = self.addresses.lock().unwrap().get(peer).cloned().unwrap_or(None);
+            let _ = self.ban(BanTarget::Node(peer.clone().into()), duration, reason.clone())
+                .map_err(|e| println!("ERROR: cannot store ban: {:?}", e));
+            match address {
+                Some(address) if self.policy.ban_address && Self::is_public(&address) => {
+                    let _ = self.ban(BanTarget::Address(address), duration, reason)
+                        .map_err(|e| println!("ERROR: cannot store ban: {:?}", e));
+                },
+                _ => (),
+            }
+        }
+
+        score >= self.policy.disconnect_threshold
+    }
+
+    pub fn connected(&self, peer: &PublicKey, address: Option<IpAddr>) {
+        self.addresses.lock().unwrap().insert(peer.clone(), address);
+    }
+
+    // the score survives the reconnection, so the peer that keeps misbehaving is banned eventually
+    pub fn disconnected(&self, peer: &PublicKey) {
+        self.addresses.lock().unwrap().remove(peer);
+    }
+
+    pub fn ban(&self, target: BanTarget, duration: i64, reason: String) -> Result<Ban, DBError> {
+        let ban = Ban {
+            until: Self::now() + duration,
+            target: target,
+            reason: reason,
+        };
+        println!("INFO: ban {:?}", ban);
+        self.db.read().unwrap().put(&ban.target.key(), ban.clone())?;
+        Ok(ban)
+    }
+
+    pub fn unban(&self, target: &BanTarget) -> Result<(), DBError> {
+        println!("INFO: unban {:?}", target);
+        self.db.read().unwrap().delete::<String, Ban>(&target.key())
+    }
+
+    // expired bans are removed
+    pub fn bans(&self) -> Result<Vec<Ban>, DBError> {
+        let now = Self::now();
+        let db = self.db.read().unwrap();
+        let mut bans = Vec::new();
+        for (key, ban) in db.get_all::<String, Ban>()? {
+            if ban.until > now {
+                bans.push(ban);
+            } else {
+                db.delete::<String, Ban>(&key)?;
+            }
+        }
+        Ok(bans)
+    }
+
+    pub fn is_banned(&self, target: &BanTarget) -> bool {
+        match self.db.read().unwrap().get::<String, Ban>(&target.key()) {
+            Ok(Some(ban)) => ban.until > Self::now(),
+            Ok(None) => false,
+            Err(e) => {
+                println!("ERROR: cannot read ban: {:?}", e);
+                false
+            },
+        }
+    }
+
+    pub fn is_peer_banned(&self, peer: &PublicKey, address: Option<IpAddr>) -> bool {
+        self.is_banned(&BanTarget::Node(peer.clone().into()))
+            || address.map(|a| self.is_banned(&BanTarget::Address(a))).unwrap_or(false)
+    }
+
+    // the connected peers the ban applies to, their sessions should be dropped
+    pub fn banned_peers(&self, target: &BanTarget) -> Vec<PublicKey> {
+        self.addresses.lock().unwrap()
+            .iter()
+            .filter(|&(peer, address)| match target {
+                &BanTarget::Node(ref pk) => peer.eq(pk.as_ref()),
+                &BanTarget::Address(ref ip) => address.as_ref() == Some(ip),
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+}
+
+impl ScoreKeeper for Scoreboard {
+    fn report(&self, peer: &PublicKey, misbehavior: Misbehavior) -> bool {
+        self.report_at(peer, misbehavior, Self::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io};
+
+    use secp256k1::{Secp256k1, SecretKey};
+
+    fn scoreboard(path: &str) -> Scoreboard {
+        scoreboard_with(path, BanPolicy::default())
+    }
+
+    fn scoreboard_with(path: &str, policy: BanPolicy) -> Scoreboard {
+        let () = fs::remove_dir_all(path)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<Scoreboard>()
+            .build(path)
+            .unwrap();
+        Scoreboard::new(policy, Arc::new(RwLock::new(db)))
+    }
+
+    fn peer(secret: u8) -> PublicKey {
+        let secret = SecretKey::from_slice(&[secret; 32]).unwrap();
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret)
+    }
+
+    #[test]
+    fn ban_finds_connected_peers() {
+        let scoreboard = scoreboard("../target/db/scoreboard-connected-test");
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        scoreboard.connected(&peer(1), Some(address));
+        scoreboard.connected(&peer(2), Some(address));
+        scoreboard.connected(&peer(3), Some("10.0.0.2".parse().unwrap()));
+        scoreboard.connected(&peer(4), None);
+
+        let ban = scoreboard.ban(BanTarget::Address(address), 60, "test".to_owned()).unwrap();
+        let mut banned = scoreboard.banned_peers(&ban.target);
+        banned.sort();
+        let mut expected = vec![peer(1), peer(2)];
+        expected.sort();
+        assert_eq!(banned, expected);
+        assert!(scoreboard.is_peer_banned(&peer(3), Some(address)));
+
+        // the peer connected without the ip address is banned by the key
+        let ban = scoreboard.ban(BanTarget::Node(peer(4).into()), 60, "test".to_owned()).unwrap();
+        assert_eq!(scoreboard.banned_peers(&ban.target), vec![peer(4)]);
+    }
+
+    #[test]
+    fn disconnected_peer_is_not_found() {
+        let scoreboard = scoreboard("../target/db/scoreboard-disconnected-test");
+        scoreboard.connected(&peer(1), Some("10.0.0.1".parse().unwrap()));
+        scoreboard.disconnected(&peer(1));
+
+        let ban = scoreboard.ban(BanTarget::Node(peer(1).into()), 60, "test".to_owned()).unwrap();
+        assert!(scoreboard.banned_peers(&ban.target).is_empty());
+        assert!(scoreboard.is_peer_banned(&peer(1), None));
+    }
+
+    #[test]
+    fn score_decays() {
+        let scoreboard = scoreboard("../target/db/scoreboard-decay-test");
+        let address: IpAddr = "8.8.8.8".parse().unwrap();
+        scoreboard.connected(&peer(1), Some(address));
+
+        // the disconnect threshold is reached, but the score is forgiven with the time
+        assert!(!scoreboard.report_at(&peer(1), Misbehavior::ProtocolViolation, 0));
+        assert!(scoreboard.report_at(&peer(1), Misbehavior::ProtocolViolation, 0));
+        assert!(!scoreboard.report_at(&peer(1), Misbehavior::ProtocolViolation, 60 * 60));
+        assert!(!scoreboard.is_banned(&BanTarget::Node(peer(1).into())));
+
+        // the persistent misbehavior is banned, the address is not without the policy
+        assert!(scoreboard.report_at(&peer(1), Misbehavior::InvalidSignature, 60 * 60));
+        assert!(scoreboard.report_at(&peer(1), Misbehavior::InvalidSignature, 60 * 60));
+        assert!(scoreboard.is_banned(&BanTarget::Node(peer(1).into())));
+        assert!(!scoreboard.is_banned(&BanTarget::Address(address)));
+    }
+
+    #[test]
+    fn private_address_is_not_banned() {
+        let mut policy = BanPolicy::default();
+        policy.ban_address = true;
+        let scoreboard = scoreboard_with("../target/db/scoreboard-address-test", policy);
+        let (public, private): (IpAddr, IpAddr) = ("8.8.8.8".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        scoreboard.connected(&peer(1), Some(public));
+        scoreboard.connected(&peer(2), Some(private));
+
+        for target in &[peer(1), peer(2)] {
+            scoreboard.report_at(target, Misbehavior::InvalidSignature, 0);
+            assert!(scoreboard.report_at(target, Misbehavior::InvalidSignature, 0));
+            assert!(scoreboard.is_banned(&BanTarget::Node(target.clone().into())));
+        }
+        assert!(scoreboard.is_banned(&BanTarget::Address(public)));
+        assert!(!scoreboard.is_banned(&BanTarget::Address(private)));
+    }
+}
@@ -4,35 +4,53 @@ use dependencies::secp256k1;
 use dependencies::tokio;
 use dependencies::futures;
 use dependencies::either;
+use dependencies::bitcoin;
 
 use wallet_lib::interface::Wallet;
 
 use secp256k1::{SecretKey, PublicKey};
-use tokio::prelude::{Future, AsyncRead, AsyncWrite, Sink};
+use tokio::prelude::{Future, Stream, AsyncRead, AsyncWrite, Sink};
 use tokio::executor::Spawn;
 use futures::sync::mpsc;
 use secp256k1::Signature;
-use wire::{Message, MessageExt};
-use processor::{MessageConsumer, ConsumingFuture};
+use wire::{Message, MessageExt, ChannelId, RawFeatureVector};
+use bitcoin::network::constants::Network;
+use processor::{MessageConsumer, MessageFiltered, RelevantEvent, ConsumingFuture, PeerReporter, Misbehavior, ScoreKeeper};
+use processor::{Metrics, Probe, Backlog};
+use internal_event::{Event, Topic, EventBus, DirectCommand, PeerEvent, NewChannel};
 use binformat::WireError;
 
 use crate::address::TransportError;
 use super::address::{AbstractAddress, ConnectionStream, Command, Connection};
 use super::ping::PingContext;
 use super::blockchain::Blockchain;
+use super::misbehavior::{Scoreboard, BanPolicy};
+use super::dump::{MessageRecorder, Direction};
+use super::chain::ChainWatcher;
+use super::funding::chain_hash;
+use super::init::{InitObserver, PeerFeatures};
+use super::storage::ChannelStorage;
+use super::keeper::ChannelKeeper;
+use super::acceptor::{ChannelAcceptor, ChannelRequest, ChannelDecision};
+use super::watchtower::{TowerClient, TowerServer};
+use super::status::{ChannelSubscriptions, ChannelStatus};
+#[cfg(feature = "rpc")]
+use super::misbehavior::{Ban, BanTarget};
 
-use state::DB;
+#[cfg(feature = "rpc")]
+use state::DBError;
 
 use routing::{State, SharedState};
-use channel_machine::ChannelState;
+use channel_machine::{ChannelPolicy, static_remotekey, anchors};
+use wallet::FeeEstimator;
 
 use std::path::Path;
 use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
 use either::Either;
 
 #[cfg(feature = "rpc")]
 use interface::routing::{LightningNode, ChannelEdge, Info};
-use std::collections::HashMap;
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct PeerInfo {
@@ -42,138 +60,397 @@ pub struct PeerInfo {
 
 pub struct Node {
     peers: Vec<PeerInfo>,
-    channels: HashMap<PublicKey, mpsc::Receiver<ChannelStatus>>,
+    bus: EventBus,
     shared_state: SharedState,
-    db: Arc<RwLock<DB>>,
+    scoreboard: Arc<Scoreboard>,
+    recorder: MessageRecorder,
+    metrics: Metrics,
     secret: SecretKey,
     blockchain: Blockchain,
+    chain: ChainWatcher,
     wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
+    // owns the channels of every peer
+    keeper: ChannelKeeper,
+    channel_policy: ChannelPolicy,
+    acceptor: ChannelAcceptor,
+    towers: TowerClient,
+    // the embedded tower serves the other nodes if the address is set
+    tower: TowerServer,
+    tower_address: Option<SocketAddr>,
+}
+
+// seconds between the uploads of the justice the towers did not acknowledge
+const TOWER_RETRY_INTERVAL: u64 = 60;
+
+// the messages decoded ahead of the processing, the rest waits in the socket
+const READ_AHEAD: usize = 32;
+
+// the ip of the peer, if the address is a socket address
+fn ip_of(address: &str) -> Option<IpAddr> {
+    address.parse::<SocketAddr>().ok().map(|a| a.ip())
 }
 
-/// Represents the channel, but not the peer
+/// Represents the peer session, passes the peer's messages to the channels
+/// and sends the messages of the channels to the peer
 pub struct Remote {
-    db: Arc<RwLock<DB>>,
-    wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
     public: PublicKey,
-    channel: ChannelState,
-    sender: mpsc::Sender<ChannelStatus>,
+    // the channels live there, the session is only their link to the peer
+    keeper: ChannelKeeper,
+    // the channels opened by the peer should satisfy it
+    policy: ChannelPolicy,
+    // and the client may reject them
+    acceptor: ChannelAcceptor,
+    // of the peer's `init`, the channel type depends on them
+    features: PeerFeatures,
+    // the channels we open before the peer's `init`
+    deferred: Vec<NewChannel>,
+}
+
+/// The message related to some channel, other messages are not for the peer session
+#[derive(Debug)]
+pub struct ChannelMessage {
+    channel_id: ChannelId,
+    message: Message,
+}
+
+impl MessageFiltered for ChannelMessage {
+    fn filter(v: MessageExt) -> Result<Self, MessageExt> {
+        match v.message.channel_id() {
+            Some(channel_id) => Ok(ChannelMessage {
+                channel_id: channel_id,
+                message: v.message,
+            }),
+            None => Err(v),
+        }
+    }
+}
+
+/// The command to the peer session, the messages of the channels
+/// are delivered to every peer session, only the session of the peer sends them,
+/// the same for the peer's `init`, our own gossip is sent by every peer session
+#[derive(Debug)]
+pub enum RemoteCommand {
+    NewChannel(NewChannel),
+    Send(PublicKey, Message),
+    Initialized(PublicKey, RawFeatureVector),
+    Banned(PublicKey),
+    Gossip(Message),
+}
+
+impl RelevantEvent for RemoteCommand {
+    fn topics() -> Vec<Topic> {
+        vec![Topic::Command, Topic::Peer, Topic::Gossip]
+    }
+
+    fn filter(v: Event) -> Result<Self, Event> {
+        match v {
+            Event::DirectCommand(DirectCommand::NewChannel(new_channel)) => Ok(RemoteCommand::NewChannel(new_channel)),
+            Event::PeerMessage { peer, message } => Ok(RemoteCommand::Send(peer, message)),
+            Event::Peer(PeerEvent::Initialized(public, features)) => Ok(RemoteCommand::Initialized(public, features)),
+            Event::Peer(PeerEvent::Banned(public)) => Ok(RemoteCommand::Banned(public)),
+            Event::Gossip(message) => Ok(RemoteCommand::Gossip(message)),
+            v => Err(v),
+        }
+    }
+}
+
+impl Remote {
+    // the client accepted the channel opened by the peer with our parameters, or rejected it,
+    // it decides apart from the session, so the peer's other messages go on meanwhile,
+    // the responses to the peer come back through the bus
+    fn decide<F>(&self, channel_id: ChannelId, message: Message, decision: F)
+    where
+        F: Future<Item=ChannelDecision, Error=()> + Send + 'static,
+    {
+        let (keeper, peer, policy) = (self.keeper.clone(), self.public.clone(), self.policy.clone());
+        let features = self.features.get().unwrap_or_default();
+        tokio::spawn(decision.map(move |decision| match decision {
+            ChannelDecision::Accept(overrides) => {
+                println!("INFO: channel {:?} of {} is accepted: {:?}", channel_id, peer, overrides);
+                let mut policy = policy;
+                overrides.apply(&mut policy);
+                if let Err(e) = keeper.message(&peer, channel_id, message, &policy, &features) {
+                    println!("ERROR: cannot accept channel {:?} of {}: {:?}", channel_id, peer, e);
+                }
+            },
+            ChannelDecision::Reject(reason) => {
+                println!("INFO: channel {:?} of {} is rejected: {}", channel_id, peer, reason);
+                keeper.reject(&peer, channel_id, reason);
+            },
+        }));
+    }
+
+    // the responses of the channel come back through the bus
+    fn handle_message<S>(self, sink: S, channel_id: ChannelId, message: Message, policy: &ChannelPolicy) -> ConsumingFuture<Self, S>
+    where
+        S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
+    {
+        // BOLT 1: `init` is the first message of the peer
+        let features = self.features.get().unwrap_or_default();
+        match self.keeper.message(&self.public, channel_id, message, policy, &features) {
+            Ok(()) => ConsumingFuture::ok(self, sink),
+            Err(e) => ConsumingFuture::err(e),
+        }
+    }
 }
 
 impl MessageConsumer for Remote {
-    type Message = Message;
-    type Relevant = ();
+    type Message = ChannelMessage;
+    type Relevant = RemoteCommand;
+
+    const NAME: &'static str = "channel";
 
     fn consume<S>(mut self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
     where
         Self: Sized,
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
     {
-        // TODO: use them
-        let _ = (&self.db, &self.public, &self.wallet);
-
-        println!("channel state: {:?}", self.channel);
-        println!("received message: {:?}", message);
-
         match message {
-            Either::Left(message) => {
-                match self.channel.next(message) {
-                    (state, Some(response)) => {
-                        println!("response message: {:?}", response);
-                        let send = sink.send(response.into());
-                        self.channel = state;
-                        ConsumingFuture::from_send(self, send)
-                    },
-                    (state, None) => {
-                        println!("response nothing");
-                        self.channel = state;
+            Either::Left(ChannelMessage { channel_id, message }) => {
+                println!("received message: {:?}", message);
+                // the channel opened by the peer waits for the decision of the client,
+                // the channel which violates the policy is rejected without asking
+                let request = match &message {
+                    &Message::OpenChannel(ref msg) if !self.keeper.knows(&self.public, &channel_id) => Some(ChannelRequest {
+                        peer: self.public.clone(),
+                        open_channel: msg.clone(),
+                        static_remotekey: static_remotekey(&self.features.get().unwrap_or_default()),
+                        anchors: anchors(&self.features.get().unwrap_or_default()),
+                    }),
+                    _ => None,
+                };
+                let decision = request
+                    .filter(|request| self.policy.check_open_channel(&request.open_channel, request.anchors).is_ok())
+                    .and_then(|request| self.acceptor.request(request));
+                match decision {
+                    Some(decision) => {
+                        self.decide(channel_id, message, decision);
                         ConsumingFuture::ok(self, sink)
                     },
+                    None => {
+                        let policy = self.policy.clone();
+                        self.handle_message(sink, channel_id, message, &policy)
+                    },
+                }
+            },
+            Either::Right(RemoteCommand::NewChannel(new_channel)) => {
+                match self.features.get() {
+                    Some(features) => self.keeper.open_channel(&self.public, new_channel, &self.policy, &features),
+                    None => self.deferred.push(new_channel),
+                }
+                ConsumingFuture::ok(self, sink)
+            },
+            Either::Right(RemoteCommand::Send(peer, message)) => {
+                if peer.ne(&self.public) {
+                    return ConsumingFuture::ok(self, sink);
+                }
+                let send = sink.send(message.into());
+                ConsumingFuture::from_send(self, send)
+            },
+            Either::Right(RemoteCommand::Initialized(public, features)) => {
+                if public.ne(&self.public) {
+                    return ConsumingFuture::ok(self, sink);
                 }
+                self.keeper.reestablish(&self.public);
+                for new_channel in self.deferred.drain(..) {
+                    self.keeper.open_channel(&self.public, new_channel, &self.policy, &features);
+                }
+                ConsumingFuture::ok(self, sink)
             },
-            Either::Right(event) => {
-                match event {
-                    // process events here
-                    () => ConsumingFuture::ok(self, sink)
+            // the error terminates the session, so the connection is closed
+            Either::Right(RemoteCommand::Banned(public)) => {
+                use std::io;
+
+                if public.ne(&self.public) {
+                    return ConsumingFuture::ok(self, sink);
                 }
+                let description = format!("peer {} is banned", public);
+                ConsumingFuture::err(WireError::from(io::Error::new(io::ErrorKind::ConnectionAborted, description)))
+            },
+            Either::Right(RemoteCommand::Gossip(message)) => {
+                let send = sink.send(message.into());
+                ConsumingFuture::from_send(self, send)
             },
         }
     }
 }
 
-// TODO: move it to proper place
-#[derive(Debug)]
-pub enum ChannelStatus {
-    Pending,
-    Confirmation,
-    Open,
-}
-
 impl Node {
-    pub fn new<P: AsRef<Path>>(wallet: Arc<Mutex<Box<dyn Wallet + Send>>>, secret: [u8; 32], path: P) -> Self {
+    // the channels are on the chain of the network the wallet is created for
+    pub fn new<P: AsRef<Path>>(
+        wallet: Arc<Mutex<Box<dyn Wallet + Send>>>,
+        network: Network,
+        secret: [u8; 32],
+        path: P,
+        ban_policy: BanPolicy,
+        channel_policy: ChannelPolicy,
+        fee_estimator: Box<dyn FeeEstimator + Send>,
+    ) -> Self {
         use state::DBBuilder;
 
-        let db = DBBuilder::default().user::<State>().build(path).unwrap();
+        let db = DBBuilder::default()
+            .user::<State>()
+            .user::<Scoreboard>()
+            .user::<ChannelStorage>()
+            .user::<ChainWatcher>()
+            .user::<TowerClient>()
+            .user::<TowerServer>()
+            .build(path)
+            .unwrap();
         let p_db = Arc::new(RwLock::new(db));
+        let mut blockchain = Blockchain::bitcoin(wallet.clone());
+        blockchain.sync();
+        let secret = SecretKey::from_slice(&secret[..]).unwrap();
+        let bus = EventBus::default();
+        let chain = ChainWatcher::new(p_db.clone(), blockchain.height(), bus.clone());
+        let shared_state = SharedState(Arc::new(RwLock::new(State::new(p_db.clone()))));
+        let chain_hash = chain_hash(network);
+        let mut channel_policy = channel_policy;
+        channel_policy.chain_hash = chain_hash;
+        let towers = TowerClient::new(p_db.clone(), chain_hash);
+        let keeper = ChannelKeeper::new(
+            ChannelStorage::new(p_db.clone()),
+            secret.clone(),
+            shared_state.clone(),
+            wallet.clone(),
+            network,
+            Arc::new(Mutex::new(fee_estimator)),
+            chain.clone(),
+            towers.clone(),
+            bus.clone(),
+        );
 
         Node {
             peers: Vec::new(),
-            channels: HashMap::new(),
-            shared_state: SharedState(Arc::new(RwLock::new(State::new(p_db.clone())))),
-            db: p_db,
-            secret: SecretKey::from_slice(&secret[..]).unwrap(),
-            blockchain: Blockchain::bitcoin(wallet.clone()),
+            bus: bus,
+            shared_state: shared_state,
+            scoreboard: Arc::new(Scoreboard::new(ban_policy, p_db.clone())),
+            recorder: MessageRecorder::default(),
+            metrics: Metrics::default(),
+            secret: secret,
+            chain: chain,
+            blockchain: blockchain,
             wallet: wallet,
+            keeper: keeper,
+            channel_policy: channel_policy,
+            acceptor: ChannelAcceptor::default(),
+            towers: towers,
+            tower: TowerServer::new(p_db.clone(), chain_hash),
+            tower_address: None,
         }
     }
 
+    // the peer has one session, the second connection with the same key is refused,
+    // the peer is known as connected only when its session is accepted
     fn add(&mut self, remote_public: PublicKey, address: String) -> Either<PublicKey, Remote> {
-        let peer_info = PeerInfo {
+        if self.peers.iter().any(|peer_info| peer_info.key.eq(&remote_public)) {
+            return Either::Left(remote_public);
+        }
+        self.scoreboard.connected(&remote_public, ip_of(&address));
+        self.bus.publish(Event::Peer(PeerEvent::Connected(remote_public.clone())));
+        self.peers.push(PeerInfo {
             key: remote_public.clone(),
             address: address,
-        };
-        if self.peers.contains(&peer_info) {
-            Either::Left(remote_public)
-        } else {
-            let (sender, receiver) = mpsc::channel(16);
-            self.peers.push(peer_info);
-            self.channels.insert(remote_public.clone(), receiver);
-            Either::Right(Remote {
-                db: self.db.clone(),
-                wallet: self.wallet.clone(),
-                public: remote_public,
-                channel: ChannelState::new(),
-                sender: sender,
-            })
+        });
+        Either::Right(Remote {
+            public: remote_public,
+            keeper: self.keeper.clone(),
+            policy: self.channel_policy.clone(),
+            acceptor: self.acceptor.clone(),
+            features: PeerFeatures::default(),
+            deferred: Vec::new(),
+        })
+    }
+
+    // the session of the peer is finished, the peer which is not connected is left alone
+    fn remove(&mut self, remote_public: &PublicKey) {
+        let count = self.peers.len();
+        self.peers.retain(|peer_info| peer_info.key.ne(remote_public));
+        if self.peers.len() == count {
+            return;
         }
+        self.scoreboard.disconnected(remote_public);
+        self.keeper.disconnected(remote_public);
+        self.bus.publish(Event::Peer(PeerEvent::Disconnected(remote_public.clone())));
     }
 
-    fn process_connection<S>(&self, peer: Remote, connection: Connection<S>) -> Spawn
+    fn process_connection<S>(p_self: Arc<RwLock<Self>>, peer: Remote, connection: Connection<S>) -> Spawn
     where
         S: AsyncRead + AsyncWrite + Send + 'static,
     {
-        use tokio::prelude::stream::Stream;
+        use tokio::prelude::stream::{Stream, once};
         use processor::MessageConsumerChain;
+        use std::io;
+
+        fn topics_of<P: MessageConsumerChain>(_: &P) -> Vec<Topic> {
+            P::topics()
+        }
 
         let (sink, stream) = connection.split();
 
         println!("INFO: new peer {}", peer.public);
         let peer_pubkey = peer.public.clone();
 
-        let p_graph = self.shared_state.clone();
-        let processor = (p_graph, (PingContext::default(), (peer, ())));
-        // TODO(mkl)
+        let recorder = p_self.read().unwrap().recorder.clone();
+        let (recorder_sent, peer_pubkey_sent) = (recorder.clone(), peer_pubkey.clone());
+        let sink = sink.with(move |message: MessageExt| {
+            recorder_sent.record(&peer_pubkey_sent, Direction::Sent, &message);
+            Ok::<_, WireError>(message)
+        });
+        let peer_pubkey_received = peer_pubkey.clone();
+        let stream = stream.inspect(move |item| {
+            if let Either::Left(ref message) = item {
+                recorder.record(&peer_pubkey_received, Direction::Received, message);
+            }
+        });
+
+        let scoreboard = p_self.read().unwrap().scoreboard.clone();
+        let reporter = PeerReporter::new(peer_pubkey.clone(), scoreboard.clone());
+        let p_graph = p_self.read().unwrap().shared_state.peer(reporter.clone());
+        // the observer goes first, so the channels are reestablished after our `init`
+        let init = InitObserver::new(peer_pubkey.clone(), peer.features.clone(), p_self.read().unwrap().bus.clone());
+        let processor = (init, (p_graph, (PingContext::new(reporter), (peer, ()))));
+
+        // the events of the bus are mixed into the peer's stream,
+        // `None` marks the end of the peer's stream, so the connection is finished,
+        // the backlog of both is measured, the events wait in memory anyway
+        let metrics = p_self.read().unwrap().metrics.clone();
+        let stream = Backlog::new(stream, "stream", metrics.clone(), READ_AHEAD);
+        let topics = topics_of(&processor);
+        let events = p_self.read().unwrap().bus.subscribe(topics.as_slice());
+        let events = Backlog::new(events, "bus", metrics.clone(), usize::max_value())
+            .map(|event| Some(Either::Right(event)))
+            .map_err(|()| WireError::from(io::Error::new(io::ErrorKind::BrokenPipe, "event bus is broken")));
+        let stream = stream
+            .map(Some)
+            .chain(once(Ok(None)))
+            .select(events)
+            .take_while(|item| Ok(item.is_some()))
+            .map(Option::unwrap);
+
+        let probe = Probe::new(peer_pubkey.clone(), metrics);
+        let (p_self_err, peer_pubkey_err) = (p_self.clone(), peer_pubkey.clone());
         let connection = stream
-            .fold((processor, sink), |(processor, sink), message| {
-                processor.process(sink, message)
+            .fold((processor, sink), move |(processor, sink), message| {
+                processor.process(sink, message, &probe)
             })
             .map_err(move |err| {
-                // TODO(mkl): correctly delete peer from list of connected peers
-                println!("ERROR with peer: {:?}, {:?}", &peer_pubkey, err);
+                use binformat::WireErrorKind;
+
+                println!("ERROR with peer: {:?}, {:?}", &peer_pubkey_err, err);
+                // io errors are transport problems, anything else means the peer sent garbage
+                match *err {
+                    WireErrorKind::Io(_) => (),
+                    _ => {
+                        let _ = scoreboard.report(&peer_pubkey_err, Misbehavior::MalformedMessage);
+                    },
+                }
+                p_self_err.write().unwrap().remove(&peer_pubkey_err);
                 ()
             })
             .map(move |_| {
-                // TODO(mkl): correctly delete peer from list of connected peers
                 println!("finished processing connection with peer: {:?}", &peer_pubkey);
+                p_self.write().unwrap().remove(&peer_pubkey);
             });
 
         tokio::spawn(connection)
@@ -184,7 +461,48 @@ impl Node {
         A: AbstractAddress + Send + Display + 'static,
     {
         use tokio::prelude::stream::Stream;
-        use futures::future::ok;
+        use tokio::timer::Interval;
+        use futures::future::{ok, empty};
+        use std::time::{Duration, Instant};
+
+        // the tower follows the blocks the chain watcher publishes, so it starts first, the clients know it by the node key
+        let tower: Box<dyn Future<Item=(), Error=()> + Send> = {
+            let node = p_self.read().unwrap();
+            match node.tower_address {
+                Some(ref tower_address) => {
+                    println!("watchtower listen at: {}", tower_address);
+                    node.tower.clone().run(node.wallet.clone(), &node.bus);
+                    Box::new(node.tower.listen(tower_address, node.secret.clone())?)
+                },
+                None => Box::new(empty()),
+            }
+        };
+
+        // the confirmations of the channels' transactions, the channels handle them
+        // along with the commands whether their peers are connected or not
+        let channels = {
+            let node = p_self.read().unwrap();
+            node.chain.clone().run();
+            node.keeper.clone().run()
+        };
+
+        // drives keep alive and liveness checks of every peer, see `PingContext`
+        let bus = p_self.read().unwrap().bus.clone();
+        let ticks = Interval::new_interval(Duration::from_secs(1))
+            .map_err(|e| println!("timer error: {:?}", e))
+            .for_each(move |_| {
+                bus.publish(Event::TimerTick);
+                Ok(())
+            });
+
+        // the updates the towers did not acknowledge are uploaded again
+        let towers = p_self.read().unwrap().towers.clone();
+        let uploads = Interval::new(Instant::now(), Duration::from_secs(TOWER_RETRY_INTERVAL))
+            .map_err(|e| println!("timer error: {:?}", e))
+            .for_each(move |_| {
+                towers.flush();
+                Ok(())
+            });
 
         let secret = p_self.read().unwrap().secret.clone();
         let server = ConnectionStream::listen(address, control, secret)?
@@ -192,6 +510,12 @@ impl Node {
             .for_each(move |(connection, address)| {
                 let remote_public = connection.remote_key();
                 println!("NEW CONNECTION FROM: {:?}@{}", remote_public, address);
+                let banned = p_self.read().unwrap().scoreboard
+                    .is_peer_banned(&remote_public, ip_of(&format!("{}", address)));
+                if banned {
+                    println!("WARNING: {} is banned, dropping the connection", remote_public);
+                    return tokio::spawn(ok(()));
+                }
                 // TODO(mkl): rewrite this
                 let maybe_peer = p_self.write().unwrap().add(remote_public, format!("{}", address));
                 match maybe_peer {
@@ -199,10 +523,14 @@ impl Node {
                         println!("WARNING: {} is connected, ignoring", pk);
                         tokio::spawn(ok(()))
                     },
-                    Either::Right(peer) => p_self.read().unwrap().process_connection(peer, connection),
+                    Either::Right(peer) => Self::process_connection(p_self.clone(), peer, connection),
                 }
             });
-        tokio::run(server);
+        // the timer stops when the server is terminated
+        let timers = ticks.select(uploads).map(|_| ()).map_err(|_| ());
+        let services = timers.select(tower).map(|_| ()).map_err(|_| ())
+            .select(channels).map(|_| ()).map_err(|_| ());
+        tokio::run(server.select(services).map(|_| ()).map_err(|_| ()));
         Ok(())
     }
 
@@ -219,8 +547,66 @@ impl Node {
         signed.signature.0
     }
 
-    pub fn take_channel_status_stream(&mut self, remote_public: &PublicKey) -> Option<mpsc::Receiver<ChannelStatus>> {
-        self.channels.remove(remote_public)
+    #[cfg(feature = "rpc")]
+    pub fn list_bans(&self) -> Result<Vec<Ban>, DBError> {
+        self.scoreboard.bans()
+    }
+
+    // the peer is banned for `duration` seconds, or according to the policy if `None`,
+    // the sessions of the banned peers which are connected now are dropped
+    #[cfg(feature = "rpc")]
+    pub fn ban(&self, target: BanTarget, duration: Option<i64>, reason: String) -> Result<Ban, DBError> {
+        let duration = duration.unwrap_or(self.scoreboard.policy().ban_duration);
+        let ban = self.scoreboard.ban(target, duration, reason)?;
+        for peer in self.scoreboard.banned_peers(&ban.target) {
+            println!("INFO: dropping the session of banned peer {}", peer);
+            self.bus.publish(Event::Peer(PeerEvent::Banned(peer)));
+        }
+        Ok(ban)
+    }
+
+    #[cfg(feature = "rpc")]
+    pub fn unban(&self, target: &BanTarget) -> Result<(), DBError> {
+        self.scoreboard.unban(target)
+    }
+
+    pub fn recorder(&self) -> MessageRecorder {
+        self.recorder.clone()
+    }
+
+    // the client which vets the channels opened by the peers subscribes to it
+    pub fn acceptor(&self) -> ChannelAcceptor {
+        self.acceptor.clone()
+    }
+
+    // the towers are configured there, the justice is uploaded while the node listens
+    pub fn watchtowers(&self) -> TowerClient {
+        self.towers.clone()
+    }
+
+    // the embedded tower accepts the clients at the address while the node listens
+    pub fn serve_watchtower(&mut self, address: SocketAddr) {
+        self.tower_address = Some(address);
+    }
+
+    // the statistics of every consumer of the peers' messages, summed for all peers
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    // the bus is shared with rpc and other subsystems, they should not lock the node to use it
+    pub fn bus(&self) -> EventBus {
+        self.bus.clone()
+    }
+
+    pub fn subscribe_peer_channels(&self, remote_public: &PublicKey) -> Box<dyn Stream<Item=(ChannelId, ChannelStatus), Error=()> + Send> {
+        ChannelSubscriptions::new(self.bus.clone()).subscribe_peer(remote_public.clone())
+    }
+
+    pub fn subscribe_channel(&self, channel_id: &ChannelId) -> Box<dyn Stream<Item=ChannelStatus, Error=String> + Send> {
+        let stream = ChannelSubscriptions::new(self.bus.clone()).subscribe_channel(channel_id.clone())
+            .map(|event| event.status);
+        Box::new(stream)
     }
 
     // TODO: add missing fields:
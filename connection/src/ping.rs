@@ -1,27 +1,91 @@
-use dependencies::tokio;
 use dependencies::either;
 use dependencies::chrono;
 
-use wire::{Message, MessageExt, Ping, Pong};
-use processor::{MessageConsumer, MessageFiltered, RelevantEvent, ConsumingFuture};
-use internal_event::Event;
+use wire::{Message, MessageExt, MessageSize, Ping, Pong};
+use processor::{SingleResponseConsumer, MessageFiltered, RelevantEvent, Misbehavior, PeerReporter};
+use internal_event::{Event, Topic};
 use binformat::WireError;
-use tokio::prelude::Sink;
 use either::Either;
 
-#[derive(Default, Debug)]
+use std::io;
+
+// the peer must answer our ping within this time, otherwise the connection is dead
+const PING_TIMEOUT_MS: i64 = 30_000;
+
+// the length of pong we ask the peer for
+const PONG_LENGTH: MessageSize = 256;
+
+// BOLT 1: the node should respond only if `num_pong_bytes` is less than 65532
+const MAX_PONG_LENGTH: MessageSize = 65531;
+
+// BOLT 1 allows to ignore the peer which sends significantly more than one ping per 30 seconds,
+// we tolerate few extra pings in the window and ignore the rest
+const PING_WINDOW_MS: i64 = 30_000;
+const MAX_PINGS_PER_WINDOW: u32 = 5;
+
+#[derive(Debug)]
+struct OutstandingPing {
+    sent: i64,
+    pong_length: MessageSize,
+}
+
+#[derive(Debug)]
 pub struct PingContext {
+    reporter: PeerReporter,
     timestamp: i64,
     tick: u8,
+    outstanding: Option<OutstandingPing>,
+    // round trip time of the last answered ping, in milliseconds
+    rtt: Option<i64>,
+    window_start: i64,
+    pings_in_window: u32,
+}
+
+impl PingContext {
+    pub fn new(reporter: PeerReporter) -> Self {
+        PingContext {
+            reporter: reporter,
+            timestamp: 0,
+            tick: 0,
+            outstanding: None,
+            rtt: None,
+            window_start: 0,
+            pings_in_window: 0,
+        }
+    }
+
+    // returns false if the peer sends pings too often
+    fn ping_allowed(&mut self, now: i64) -> bool {
+        if now - self.window_start >= PING_WINDOW_MS {
+            self.window_start = now;
+            self.pings_in_window = 0;
+        }
+        self.pings_in_window += 1;
+        self.pings_in_window <= MAX_PINGS_PER_WINDOW
+    }
+}
+
+fn timeout_error(description: String) -> WireError {
+    WireError::from(io::Error::new(io::ErrorKind::TimedOut, description))
+}
+
+// the peer is scored for the violation already, so it is an io error,
+// otherwise the node would score the peer once more for the malformed message
+fn protocol_error(description: String) -> WireError {
+    WireError::from(io::Error::new(io::ErrorKind::InvalidData, description))
 }
 
 #[derive(Debug)]
-pub struct PingMessage(Ping);
+pub enum PingMessage {
+    Ping(Ping),
+    Pong(Pong),
+}
 
 impl MessageFiltered for PingMessage {
     fn filter(v: MessageExt) -> Result<Self, MessageExt> {
         match v.message {
-            Message::Ping(ping) => Ok(PingMessage(ping)),
+            Message::Ping(ping) => Ok(PingMessage::Ping(ping)),
+            Message::Pong(pong) => Ok(PingMessage::Pong(pong)),
             _ => Err(v),
         }
     }
@@ -31,6 +95,10 @@ impl MessageFiltered for PingMessage {
 pub struct PingEvent;
 
 impl RelevantEvent for PingEvent {
+    fn topics() -> Vec<Topic> {
+        vec![Topic::Timer]
+    }
+
     fn filter(v: Event) -> Result<Self, Event> {
         match v {
             Event::TimerTick => Ok(PingEvent),
@@ -39,35 +107,136 @@ impl RelevantEvent for PingEvent {
     }
 }
 
-impl MessageConsumer for PingContext {
+impl SingleResponseConsumer for PingContext {
     type Message = PingMessage;
     type Relevant = PingEvent;
 
-    fn consume<S>(self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
-    where
-        S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
-    {
+    const NAME: &'static str = "ping";
+
+    fn consume_single_response(self, message: Either<Self::Message, Self::Relevant>) -> Result<(Self, Option<MessageExt>), WireError> {
         use chrono::prelude::*;
 
         dbg!(&message);
 
         let mut this = self;
+        let now = Utc::now().timestamp_millis();
         match message {
-            Either::Left(PingMessage(ping)) => {
-                this.timestamp = Utc::now().timestamp();
-                let pong = Message::Pong(Pong::new(&ping));
-                ConsumingFuture::from_send(this, sink.send(pong.into()))
+            Either::Left(PingMessage::Ping(ping)) => {
+                this.timestamp = now;
+                if ping.pong_length() > MAX_PONG_LENGTH {
+                    println!("WARNING: ping requests too long pong {}, ignoring", ping.pong_length());
+                    Ok((this, None))
+                } else if !this.ping_allowed(now) {
+                    println!("WARNING: peer sends pings too often, ignoring");
+                    this.reporter.report(Misbehavior::Spam)?;
+                    Ok((this, None))
+                } else {
+                    let pong = Message::Pong(Pong::new(&ping));
+                    Ok((this, Some(pong.into())))
+                }
+            },
+            Either::Left(PingMessage::Pong(pong)) => {
+                match this.outstanding.take() {
+                    Some(outstanding) => {
+                        if pong.length() != outstanding.pong_length {
+                            let _ = this.reporter.report(Misbehavior::ProtocolViolation);
+                            let description = format!(
+                                "pong length {} does not match requested {}",
+                                pong.length(), outstanding.pong_length,
+                            );
+                            return Err(protocol_error(description));
+                        }
+                        this.rtt = Some(now - outstanding.sent);
+                        println!("INFO: ping rtt {:?} ms", this.rtt);
+                        Ok((this, None))
+                    },
+                    None => {
+                        println!("WARNING: unsolicited pong, ignoring");
+                        this.reporter.report(Misbehavior::Spam)?;
+                        Ok((this, None))
+                    },
+                }
             },
             Either::Right(PingEvent) => {
+                if let Some(ref outstanding) = this.outstanding {
+                    if now - outstanding.sent >= PING_TIMEOUT_MS {
+                        let description = format!("no pong within {} ms, disconnecting", PING_TIMEOUT_MS);
+                        return Err(timeout_error(description));
+                    }
+                    return Ok((this, None));
+                }
+
                 this.tick += 1;
-                if this.tick == 30 || Utc::now().timestamp() - this.timestamp >= 30 {
+                if this.tick == 30 || now - this.timestamp >= 30_000 {
                     this.tick = 0;
-                    let ping = Message::Ping(Ping::new(256, 256).unwrap());
-                    ConsumingFuture::from_send(this, sink.send(ping.into()))
+                    let ping = Ping::new(PONG_LENGTH, PONG_LENGTH).unwrap();
+                    this.outstanding = Some(OutstandingPing {
+                        sent: now,
+                        pong_length: ping.pong_length(),
+                    });
+                    let ping = Message::Ping(ping);
+                    Ok((this, Some(ping.into())))
                 } else {
-                    ConsumingFuture::ok(this, sink)
+                    Ok((this, None))
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dependencies::secp256k1;
+
+    use processor::ScoreKeeper;
+    use binformat::WireErrorKind;
+    use secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+    use std::sync::Arc;
+
+    struct Keeper;
+
+    impl ScoreKeeper for Keeper {
+        fn report(&self, _peer: &PublicKey, _misbehavior: Misbehavior) -> bool {
+            false
+        }
+    }
+
+    fn context() -> PingContext {
+        let secret = SecretKey::from_slice(&[1; 32]).unwrap();
+        let peer = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret);
+        PingContext::new(PeerReporter::new(peer, Arc::new(Keeper)))
+    }
+
+    fn error_kind(error: WireError) -> io::ErrorKind {
+        match *error {
+            WireErrorKind::Io(ref e) => e.kind(),
+            ref e => panic!("not an io error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn wrong_pong_length_is_protocol_error() {
+        let mut context = context();
+        context.outstanding = Some(OutstandingPing {
+            sent: 0,
+            pong_length: PONG_LENGTH,
+        });
+        let pong = Pong::new(&Ping::new(4, 8).unwrap());
+        let error = context.consume_single_response(Either::Left(PingMessage::Pong(pong))).unwrap_err();
+        assert_eq!(error_kind(error), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn missing_pong_is_timeout() {
+        let mut context = context();
+        context.outstanding = Some(OutstandingPing {
+            sent: 0,
+            pong_length: PONG_LENGTH,
+        });
+        let error = context.consume_single_response(Either::Right(PingEvent)).unwrap_err();
+        assert_eq!(error_kind(error), io::ErrorKind::TimedOut);
+    }
+}
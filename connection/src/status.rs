@@ -0,0 +1,141 @@
+use dependencies::secp256k1;
+use dependencies::futures;
+
+use secp256k1::PublicKey;
+use futures::stream::Stream;
+use wire::ChannelId;
+use internal_event::{Event, Topic, EventBus, ChannelEvent};
+use channel_machine::{ChannelState, OpeningState};
+
+pub use internal_event::ChannelStatus;
+
+// the status is reported only when the state changes
+pub fn status_of(state: &ChannelState) -> Option<ChannelStatus> {
+    match state {
+        &ChannelState::Opening(OpeningState::WaitAcceptChannel(_)) => Some(ChannelStatus::Pending),
+        &ChannelState::Opening(OpeningState::WaitFundingSigned(_)) => Some(ChannelStatus::Pending),
+        &ChannelState::Opening(OpeningState::WaitFundingCreated(_)) => Some(ChannelStatus::Pending),
+        &ChannelState::Opening(OpeningState::WaitFundingLocked(_)) => Some(ChannelStatus::Confirmation),
+        &ChannelState::Opening(OpeningState::Error(ref description)) => Some(ChannelStatus::Error(description.clone())),
+        &ChannelState::Ready(_) => Some(ChannelStatus::Open),
+        &ChannelState::Closing(_) => Some(ChannelStatus::Closing),
+        &ChannelState::ForceClosing(_) => Some(ChannelStatus::Closing),
+        &ChannelState::DataLoss(_) => Some(ChannelStatus::Closing),
+        &ChannelState::Closed(_) => Some(ChannelStatus::Closed),
+        &ChannelState::PunishingPartner(_) => Some(ChannelStatus::Closing),
+        &ChannelState::PunishedPartner(_) => Some(ChannelStatus::Closed),
+        &ChannelState::Robbed(_) => Some(ChannelStatus::Closed),
+        &ChannelState::PunishedByPartner(_) => Some(ChannelStatus::Closed),
+        _ => None,
+    }
+}
+
+/// Delivers status updates of channels, the subscriber might listen
+/// the particular channel or all channels with the peer
+#[derive(Clone)]
+pub struct ChannelSubscriptions(EventBus);
+
+impl ChannelSubscriptions {
+    pub fn new(bus: EventBus) -> Self {
+        ChannelSubscriptions(bus)
+    }
+
+    /// The channel is followed from its temporary id to the final id,
+    /// the stream fails if the command to the channel finds no channel
+    pub fn subscribe_channel(&self, channel_id: ChannelId) -> Box<dyn Stream<Item=ChannelEvent, Error=String> + Send> {
+        let stream = self.0.subscribe(&[Topic::Channel])
+            .map_err(|()| "the event bus is broken".to_owned())
+            .and_then(move |event| match event {
+                Event::UnknownChannel(id) => if id.eq(&channel_id) {
+                    Err(format!("unknown channel {:?}", channel_id))
+                } else {
+                    Ok(None)
+                },
+                Event::Channel(event) => {
+                    let relevant = event.channel_id.eq(&channel_id)
+                        || event.temporary_channel_id.as_ref().map(|id| id.eq(&channel_id)).unwrap_or(false);
+                    Ok(if relevant { Some(event) } else { None })
+                },
+                _ => Ok(None),
+            })
+            .filter_map(|event| event);
+        Box::new(stream)
+    }
+
+    pub fn subscribe_peer(&self, peer: PublicKey) -> Box<dyn Stream<Item=(ChannelId, ChannelStatus), Error=()> + Send> {
+        let stream = self.0.subscribe(&[Topic::Channel])
+            .filter_map(move |event| match event {
+                Event::Channel(event) => if event.peer.eq(&peer) {
+                    Some((event.channel_id, event.status))
+                } else {
+                    None
+                },
+                _ => None,
+            });
+        Box::new(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{Secp256k1, SecretKey};
+
+    fn peer(secret: u8) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[secret; 32]).unwrap())
+    }
+
+    fn event(channel_id: ChannelId, temporary_channel_id: Option<ChannelId>, status: ChannelStatus) -> Event {
+        event_of(peer(1), channel_id, temporary_channel_id, status)
+    }
+
+    fn event_of(peer: PublicKey, channel_id: ChannelId, temporary_channel_id: Option<ChannelId>, status: ChannelStatus) -> Event {
+        Event::Channel(ChannelEvent {
+            peer: peer,
+            channel_id: channel_id,
+            temporary_channel_id: temporary_channel_id,
+            status: status,
+            funding_outpoint: None,
+            closing_txid: None,
+        })
+    }
+
+    #[test]
+    fn channel_is_followed_to_final_id() {
+        let bus = EventBus::default();
+        let temporary_channel_id = ChannelId::from([1; 32]);
+        let channel_id = ChannelId::from([2; 32]);
+        let mut updates = ChannelSubscriptions::new(bus.clone())
+            .subscribe_channel(temporary_channel_id)
+            .wait();
+
+        bus.publish(event(temporary_channel_id, None, ChannelStatus::Pending));
+        bus.publish(event(ChannelId::from([3; 32]), None, ChannelStatus::Pending));
+        bus.publish(event(channel_id, Some(temporary_channel_id), ChannelStatus::Confirmation));
+        bus.publish(Event::UnknownChannel(ChannelId::from([3; 32])));
+        bus.publish(Event::UnknownChannel(temporary_channel_id));
+
+        let status = |update: Option<Result<ChannelEvent, String>>| update.unwrap().unwrap().status;
+        assert_eq!(status(updates.next()), ChannelStatus::Pending);
+        assert_eq!(status(updates.next()), ChannelStatus::Confirmation);
+        assert!(updates.next().unwrap().is_err());
+    }
+
+    // the open stream follows only the opened channel, not the other channels of the peer
+    #[test]
+    fn channels_of_peer_are_told_apart() {
+        let bus = EventBus::default();
+        let (first, second, other) = (ChannelId::from([1; 32]), ChannelId::from([2; 32]), ChannelId::from([3; 32]));
+        let subscriptions = ChannelSubscriptions::new(bus.clone());
+        let mut channel_updates = subscriptions.subscribe_channel(first).wait();
+        let mut peer_updates = subscriptions.subscribe_peer(peer(1)).wait();
+
+        bus.publish(event(second, None, ChannelStatus::Open));
+        bus.publish(event_of(peer(2), other, None, ChannelStatus::Pending));
+        bus.publish(event(first, None, ChannelStatus::Pending));
+
+        assert_eq!(channel_updates.next().unwrap().unwrap().channel_id, first);
+        assert_eq!(peer_updates.next().unwrap().unwrap(), (second, ChannelStatus::Open));
+        assert_eq!(peer_updates.next().unwrap().unwrap(), (first, ChannelStatus::Pending));
+    }
+}
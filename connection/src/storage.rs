@@ -0,0 +1,161 @@
+use std::sync::{Arc, RwLock};
+
+use dependencies::secp256k1;
+use dependencies::hex;
+
+use secp256k1::PublicKey;
+use serde_derive::{Serialize, Deserialize};
+
+use state::{DB, DBValue, DBBuilder, DBUser, DBError};
+use common_types::RawPublicKey;
+use wire::ChannelId;
+use channel_machine::ChannelState;
+
+/// The channel as it is stored in the db, keyed by the final channel id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredChannel {
+    pub peer: RawPublicKey,
+    // the channels opened by us are addressed by the temporary id before `accept_channel`
+    pub temporary_channel_id: Option<ChannelId>,
+    pub state: ChannelState,
+}
+
+impl DBValue for StoredChannel {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "channel"
+    }
+}
+
+/// Keeps the channels durable, the channel is stored after every transition
+/// before the response is sent to the peer, so the node never tells the peer
+/// about the state it can lose
+#[derive(Clone)]
+pub struct ChannelStorage {
+    db: Arc<RwLock<DB>>,
+}
+
+impl DBUser for ChannelStorage {
+    fn db_prepare(builder: DBBuilder) -> DBBuilder {
+        builder.register::<StoredChannel>()
+    }
+}
+
+impl ChannelStorage {
+    pub fn new(db: Arc<RwLock<DB>>) -> Self {
+        ChannelStorage {
+            db: db,
+        }
+    }
+
+    fn key(channel_id: &ChannelId) -> String {
+        hex::encode(&channel_id.data[..])
+    }
+
+    // The channel is stored by the id under which the node keeps it. The channel which has
+    // no final id yet is not stored, losing it loses no funds, the channel which failed
+    // before it is funded has nothing to keep, its record is deleted
+    pub fn save(&self, peer: &PublicKey, channel_id: ChannelId, temporary_channel_id: Option<ChannelId>, state: &ChannelState) -> Result<(), DBError> {
+        if state.channel_id().is_none() {
+            return self.db.read().unwrap().delete::<String, StoredChannel>(&Self::key(&channel_id));
+        }
+        let stored = StoredChannel {
+            peer: peer.clone().into(),
+            temporary_channel_id: temporary_channel_id,
+            state: state.clone(),
+        };
+        self.db.read().unwrap().put(&Self::key(&channel_id), stored)
+    }
+
+    pub fn load(&self) -> Result<Vec<StoredChannel>, DBError> {
+        let channels = self.db.read().unwrap().get_all::<String, StoredChannel>()?;
+        Ok(channels.into_iter().map(|(_, stored)| stored).collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{fs, io};
+
+    use secp256k1::{Secp256k1, SecretKey};
+    use wire::{Message, FundingLocked, Error, RawFeatureVector};
+    use channel_machine::{OpenChannelParams, ChannelPolicy};
+    use channel_machine::testing::MockWallet;
+
+    fn public_key(byte: u8) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+    }
+
+    fn storage(path: &str) -> ChannelStorage {
+        let () = fs::remove_dir_all(path)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<ChannelStorage>()
+            .build(path)
+            .unwrap();
+        ChannelStorage::new(Arc::new(RwLock::new(db)))
+    }
+
+    // the funder and the fundee after `funding_signed`, both wait for the funding to be locked
+    pub(crate) fn funded() -> (ChannelState, ChannelState) {
+        let mut wallet = MockWallet::default();
+        let (funder, open_channel) = ChannelState::open(OpenChannelParams::new(1_000_000, 0), &RawFeatureVector::new(), &mut wallet);
+        let (fundee, accept_channel) = ChannelState::with_policy(ChannelPolicy::default(), &RawFeatureVector::new()).next(open_channel.unwrap(), &mut wallet);
+        let (funder, funding_created) = funder.next(accept_channel.unwrap(), &mut wallet);
+        let (fundee, funding_signed) = fundee.next(funding_created.unwrap(), &mut wallet);
+        let (funder, _) = funder.next(funding_signed.unwrap(), &mut wallet);
+        (funder, fundee)
+    }
+
+    // the stored channel is the same after it is loaded, the force closing one keeps the commitments
+    #[test]
+    fn stored_channel_round_trip() {
+        let storage = storage("../target/db/channel-storage-round-trip");
+        let peer = public_key(2);
+        let (funder, fundee) = funded();
+        let channel_id = fundee.channel_id().expect("the funded channel has no id");
+        let (force_closing, _) = fundee.next(Message::Error(Error {
+            channel_id: channel_id,
+            data: b"internal error".to_vec(),
+        }), &mut MockWallet::default());
+        match &force_closing {
+            &ChannelState::ForceClosing(_) => (),
+            state => panic!("the channel is not force closed: {:?}", state),
+        }
+
+        for state in vec![funder, force_closing] {
+            storage.save(&peer, channel_id, None, &state).unwrap();
+            let loaded = storage.load().unwrap();
+            assert_eq!(loaded.len(), 1);
+            assert_eq!(loaded[0].peer.as_ref(), &peer);
+            assert_eq!(format!("{:?}", loaded[0].state), format!("{:?}", state));
+        }
+    }
+
+    // the channel failed before it is funded, the record of it is deleted, so it is not restored
+    #[test]
+    fn failed_channel_is_deleted() {
+        let storage = storage("../target/db/channel-storage-failed");
+        let peer = public_key(2);
+        let (_, fundee) = funded();
+        let channel_id = fundee.channel_id().expect("the funded channel has no id");
+        storage.save(&peer, channel_id, None, &fundee).unwrap();
+        assert_eq!(storage.load().unwrap().len(), 1);
+
+        let (failed, _) = fundee.next(Message::FundingLocked(FundingLocked {
+            channel_id: ChannelId::all(),
+            next_per_commitment_point: public_key(3).into(),
+        }), &mut MockWallet::default());
+        assert!(failed.channel_id().is_none());
+        storage.save(&peer, channel_id, None, &failed).unwrap();
+        assert!(storage.load().unwrap().is_empty());
+    }
+}
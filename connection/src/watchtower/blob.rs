@@ -0,0 +1,51 @@
+use dependencies::bitcoin;
+use dependencies::bitcoin_hashes;
+use dependencies::chacha20_poly1305_aead;
+
+use bitcoin::Transaction;
+use bitcoin::consensus::encode;
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use wire::BreachHint;
+
+/// The only blob the client makes, the whole signed justice transaction
+pub const BLOB_TYPE_JUSTICE_TX: u16 = 1;
+
+// the tag of chacha20-poly1305 follows the encrypted transaction
+const TAG_SIZE: usize = 16;
+
+/// The tower finds the breach by the first half of the commitment txid
+pub fn breach_hint(commitment_txid: &sha256d::Hash) -> BreachHint {
+    let mut hint = [0; 16];
+    hint.copy_from_slice(&commitment_txid.into_inner()[..16]);
+    hint
+}
+
+// the hint does not reveal the key, the tower learns it only when the breach is mined,
+// every key encrypts the single blob, so the nonce is zero
+fn breach_key(commitment_txid: &sha256d::Hash) -> [u8; 32] {
+    sha256::Hash::hash(&commitment_txid.into_inner()[..]).into_inner()
+}
+
+/// The justice transaction encrypted with the breach txid, followed by the tag
+pub fn encrypt_justice(commitment_txid: &sha256d::Hash, justice_tx: &Transaction) -> Vec<u8> {
+    use chacha20_poly1305_aead::encrypt;
+
+    let mut blob = Vec::new();
+    let tag = encrypt(&breach_key(commitment_txid), &[0; 12], &[], &encode::serialize(justice_tx), &mut blob)
+        .expect("writing to the vector cannot fail");
+    blob.extend_from_slice(&tag[..]);
+    blob
+}
+
+/// Returns `None` if the blob is not the justice of the given breach
+pub fn decrypt_justice(commitment_txid: &sha256d::Hash, blob: &[u8]) -> Option<Transaction> {
+    use chacha20_poly1305_aead::decrypt;
+
+    if blob.len() < TAG_SIZE {
+        return None;
+    }
+    let (cipher, tag) = blob.split_at(blob.len() - TAG_SIZE);
+    let mut plain = Vec::new();
+    decrypt(&breach_key(commitment_txid), &[0; 12], &[], cipher, tag, &mut plain).ok()?;
+    encode::deserialize(&plain).ok()
+}
@@ -0,0 +1,481 @@
+use std::sync::{Arc, RwLock, Mutex};
+use std::collections::{HashSet, HashMap};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use std::fmt;
+
+use dependencies::secp256k1;
+use dependencies::tokio;
+use dependencies::futures;
+use dependencies::rand;
+
+use secp256k1::{SecretKey, PublicKey, Secp256k1};
+use tokio::prelude::{Future, Stream, Sink, FutureExt};
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use futures::future::{self, Loop};
+use brontide::TowerCodec;
+
+use state::{DB, DBBuilder, DBUser, DBError};
+use wire::{TowerMessage, Sha256, RawFeatureVector, TowerInit, CreateSession, StateUpdate, TowerCode, BreachHint};
+use channel_machine::BreachRemedy;
+
+use crate::address::AbstractAddress;
+use super::blob::{BLOB_TYPE_JUSTICE_TX, breach_hint, encrypt_justice};
+use super::storage::{StoredTower, StoredSession, StoredUpdate, key_of, update_key};
+
+// the updates of a single session, the next session is created when it is exhausted
+const MAX_UPDATES: u16 = 1024;
+
+// the rest is uploaded on the next flush
+const UPLOAD_TIMEOUT: u64 = 60;
+
+type TowerConnection = Framed<TcpStream, TowerCodec>;
+
+/// The tower's identity and the address it listens at, `pubkey@host:port`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TowerAddress {
+    pub public: PublicKey,
+    pub address: SocketAddr,
+}
+
+impl FromStr for TowerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '@');
+        let (public, address) = match (parts.next(), parts.next()) {
+            (Some(public), Some(address)) => (public, address),
+            _ => return Err(format!("expected pubkey@host:port, got {}", s)),
+        };
+        Ok(TowerAddress {
+            public: PublicKey::from_str(public)
+                .map_err(|e| format!("bad public key of the tower {}: {:?}", public, e))?,
+            address: address.parse()
+                .map_err(|e| format!("bad address of the tower {}: {}", address, e))?,
+        })
+    }
+}
+
+impl fmt::Display for TowerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.public, self.address)
+    }
+}
+
+/// Uploads the justice transactions of our channels to the towers, so the peer
+/// is punished for the breach while we are offline. The encrypted justice waits
+/// in the db until every tower acknowledges it
+#[derive(Clone)]
+pub struct TowerClient {
+    db: Arc<RwLock<DB>>,
+    chain_hash: Sha256,
+    // the towers being uploaded to, the update is not sent twice at the same time
+    busy: Arc<Mutex<HashSet<PublicKey>>>,
+    // the stored towers, every revocation asks for them
+    towers: Arc<RwLock<Vec<TowerAddress>>>,
+    // the hints of the stored updates of every tower in the order they are backed up
+    queued: Arc<Mutex<HashMap<PublicKey, Vec<BreachHint>>>>,
+}
+
+impl DBUser for TowerClient {
+    fn db_prepare(builder: DBBuilder) -> DBBuilder {
+        builder
+            .register::<StoredTower>()
+            .register::<StoredSession>()
+            .register::<StoredUpdate>()
+    }
+}
+
+impl TowerClient {
+    // the towers and the waiting updates are read once, then they are followed in memory
+    pub fn new(db: Arc<RwLock<DB>>, chain_hash: Sha256) -> Self {
+        let (towers, queued) = {
+            let db = db.read().unwrap();
+            let towers = db.get_all::<String, StoredTower>()
+                .unwrap_or_else(|e| {
+                    println!("ERROR: cannot load the watchtowers: {:?}", e);
+                    Vec::new()
+                })
+                .into_iter()
+                .filter_map(|(_, stored)| match stored.address.parse() {
+                    Ok(address) => Some(TowerAddress {
+                        public: stored.public.as_ref().clone(),
+                        address: address,
+                    }),
+                    Err(e) => {
+                        println!("WARNING: bad address of the watchtower {:?}: {}", stored.public, e);
+                        None
+                    },
+                })
+                .collect();
+            let mut queued = HashMap::new();
+            let updates = db.get_all::<String, StoredUpdate>()
+                .unwrap_or_else(|e| {
+                    println!("ERROR: cannot load the updates for the watchtowers: {:?}", e);
+                    Vec::new()
+                });
+            for (_, update) in updates {
+                queued.entry(update.tower.as_ref().clone()).or_insert(Vec::new()).push(update.hint);
+            }
+            (towers, queued)
+        };
+        TowerClient {
+            db: db,
+            chain_hash: chain_hash,
+            busy: Arc::new(Mutex::new(HashSet::new())),
+            towers: Arc::new(RwLock::new(towers)),
+            queued: Arc::new(Mutex::new(queued)),
+        }
+    }
+
+    pub fn add_tower(&self, tower: &TowerAddress) -> Result<(), DBError> {
+        let stored = StoredTower {
+            public: tower.public.clone().into(),
+            address: tower.address.to_string(),
+        };
+        self.db.read().unwrap().put(&key_of(&tower.public), stored)?;
+        let mut towers = self.towers.write().unwrap();
+        towers.retain(|known| known.public != tower.public);
+        towers.push(tower.clone());
+        Ok(())
+    }
+
+    pub fn towers(&self) -> Vec<TowerAddress> {
+        self.towers.read().unwrap().clone()
+    }
+
+    // the node builds the justice only when somebody will keep it
+    pub fn is_configured(&self) -> bool {
+        !self.towers.read().unwrap().is_empty()
+    }
+
+    // the justice is stored for every tower before anything is sent
+    pub fn backup(&self, remedy: &BreachRemedy) -> Result<(), DBError> {
+        let hint = breach_hint(&remedy.commitment_txid);
+        let blob = encrypt_justice(&remedy.commitment_txid, &remedy.justice_tx);
+        for tower in self.towers() {
+            let update = StoredUpdate {
+                tower: tower.public.clone().into(),
+                hint: hint,
+                blob: blob.clone(),
+            };
+            self.db.read().unwrap().put(&update_key(&tower.public, &hint), update)?;
+            let mut queued = self.queued.lock().unwrap();
+            let hints = queued.entry(tower.public.clone()).or_insert(Vec::new());
+            if !hints.contains(&hint) {
+                hints.push(hint);
+            }
+        }
+        Ok(())
+    }
+
+    // uploads the waiting updates to every tower, should be called within the runtime
+    pub fn flush(&self) {
+        for tower in self.towers() {
+            if !self.busy.lock().unwrap().insert(tower.public.clone()) {
+                continue;
+            }
+            let busy = self.busy.clone();
+            let public = tower.public.clone();
+            let name = tower.to_string();
+            tokio::spawn(self.upload(tower).then(move |result| -> Result<(), ()> {
+                busy.lock().unwrap().remove(&public);
+                match result {
+                    Ok(0) => (),
+                    Ok(count) => println!("INFO: uploaded {} justice transactions to the watchtower {}", count, name),
+                    Err(e) => println!("WARNING: cannot upload to the watchtower {}: {}", name, e),
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    fn pending(&self, tower: &PublicKey) -> Result<Vec<(String, StoredUpdate)>, DBError> {
+        let hints = self.queued.lock().unwrap().get(tower).cloned().unwrap_or_default();
+        let db = self.db.read().unwrap();
+        let mut updates = Vec::with_capacity(hints.len());
+        for hint in hints {
+            let key = update_key(tower, &hint);
+            if let Some(update) = db.get::<String, StoredUpdate>(&key)? {
+                updates.push((key, update));
+            }
+        }
+        Ok(updates)
+    }
+
+    // the session which has room for the updates, the new one is stored before it is created
+    fn session(&self, tower: &PublicKey) -> Result<(PublicKey, StoredSession), DBError> {
+        let context = Secp256k1::signing_only();
+        let sessions = self.db.read().unwrap().get_all::<String, StoredSession>()?;
+        let open = sessions.into_iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.tower.as_ref() == tower && session.last_applied < session.max_updates)
+            .filter_map(|session| {
+                SecretKey::from_slice(&session.secret[..]).ok()
+                    .map(|secret| (PublicKey::from_secret_key(&context, &secret), session))
+            })
+            .next();
+        if let Some(open) = open {
+            return Ok(open);
+        }
+
+        let secret = loop {
+            if let Ok(secret) = SecretKey::from_slice(&rand::random::<[u8; 32]>()[..]) {
+                break secret;
+            }
+        };
+        let mut session = StoredSession {
+            tower: tower.clone().into(),
+            secret: [0; 32],
+            max_updates: MAX_UPDATES,
+            created: false,
+            last_applied: 0,
+        };
+        session.secret.copy_from_slice(&secret[..]);
+        let public = PublicKey::from_secret_key(&context, &secret);
+        self.db.read().unwrap().put(&key_of(&public), session.clone())?;
+        Ok((public, session))
+    }
+
+    // the tower keeps the update, it is forgotten
+    fn acknowledge(&self, public: &PublicKey, session: &StoredSession, key: &str, update: &StoredUpdate) -> Result<(), String> {
+        let db = self.db.read().unwrap();
+        db.put(&key_of(public), session.clone())
+            .and_then(|()| db.delete::<_, StoredUpdate>(&key.to_owned()))
+            .map_err(|e| format!("cannot store the ack: {:?}", e))?;
+        if let Some(hints) = self.queued.lock().unwrap().get_mut(update.tower.as_ref()) {
+            hints.retain(|hint| hint != &update.hint);
+        }
+        Ok(())
+    }
+
+    fn create_session(&self, connection: TowerConnection, public: PublicKey, mut session: StoredSession)
+        -> Box<dyn Future<Item=(TowerConnection, PublicKey, StoredSession), Error=String> + Send>
+    {
+        if session.created {
+            return Box::new(future::ok((connection, public, session)));
+        }
+
+        let client = self.clone();
+        let request = CreateSession {
+            blob_type: BLOB_TYPE_JUSTICE_TX,
+            max_updates: session.max_updates,
+        };
+        Box::new(exchange(connection, TowerMessage::CreateSession(request))
+            .and_then(move |(reply, connection)| match reply {
+                TowerMessage::CreateSessionReply(reply) => match reply.code {
+                    // the tower knows the session, the updates continue after the last applied
+                    TowerCode::OK | TowerCode::SESSION_EXISTS => {
+                        session.created = true;
+                        session.last_applied = reply.last_applied;
+                        client.db.read().unwrap().put(&key_of(&public), session.clone())
+                            .map_err(|e| format!("cannot store the session: {:?}", e))?;
+                        Ok((connection, public, session))
+                    },
+                    code => Err(format!("the tower refused the session, code {}", code)),
+                },
+                reply => Err(unexpected(reply)),
+            }))
+    }
+
+    // returns the number of updates the tower acknowledged
+    pub(crate) fn upload(&self, tower: TowerAddress) -> Box<dyn Future<Item=usize, Error=String> + Send> {
+        let updates = match self.pending(&tower.public) {
+            Ok(ref updates) if updates.is_empty() => return Box::new(future::ok(0)),
+            Ok(updates) => updates,
+            Err(e) => return Box::new(future::err(format!("cannot load the updates: {:?}", e))),
+        };
+        let (public, session) = match self.session(&tower.public) {
+            Ok(v) => v,
+            Err(e) => return Box::new(future::err(format!("cannot store the session: {:?}", e))),
+        };
+        let secret = SecretKey::from_slice(&session.secret[..]).unwrap();
+
+        let chain_hash = self.chain_hash;
+        let init = TowerInit {
+            features: RawFeatureVector::new(),
+            chain_hash: chain_hash,
+        };
+        let client = self.clone();
+        let client_loop = self.clone();
+        let upload = tower.address.connect(secret, tower.public.clone())
+            .map_err(|e| format!("cannot connect: {:?}", e))
+            .and_then(move |(stream, _)| exchange(stream.framed_tower(), TowerMessage::TowerInit(init)))
+            .and_then(move |(reply, connection)| match reply {
+                TowerMessage::TowerInit(ref init) if init.chain_hash == chain_hash => Ok(connection),
+                TowerMessage::TowerInit(_) => Err("the tower serves another chain".to_owned()),
+                reply => Err(unexpected(reply)),
+            })
+            .and_then(move |connection| client.create_session(connection, public, session))
+            .and_then(move |(connection, public, session)| {
+                future::loop_fn((connection, session, updates.into_iter(), 0), move |(connection, mut session, mut updates, count)| {
+                    let (key, update) = match updates.next() {
+                        Some(next) if session.last_applied < session.max_updates => next,
+                        // the rest goes to the next session
+                        _ => return Box::new(future::ok(Loop::Break(count))) as Box<dyn Future<Item=_, Error=_> + Send>,
+                    };
+                    let seq_num = session.last_applied + 1;
+                    let request = StateUpdate {
+                        seq_num: seq_num,
+                        last_applied: session.last_applied,
+                        is_complete: if updates.len() == 0 { 1 } else { 0 },
+                        hint: update.hint,
+                        encrypted_blob: update.blob.clone(),
+                    };
+                    let client = client_loop.clone();
+                    let public = public.clone();
+                    Box::new(exchange(connection, TowerMessage::StateUpdate(request))
+                        .and_then(move |(reply, connection)| match reply {
+                            TowerMessage::StateUpdateReply(ref reply) if reply.code == TowerCode::OK => {
+                                session.last_applied = seq_num;
+                                client.acknowledge(&public, &session, &key, &update)?;
+                                Ok(Loop::Continue((connection, session, updates, count + 1)))
+                            },
+                            TowerMessage::StateUpdateReply(reply) => {
+                                Err(format!("the tower rejected the update {}, code {}", seq_num, reply.code))
+                            },
+                            reply => Err(unexpected(reply)),
+                        }))
+                })
+            })
+            .timeout(Duration::from_secs(UPLOAD_TIMEOUT))
+            .map_err(|e| e.into_inner().unwrap_or_else(|| "timeout".to_owned()));
+        Box::new(upload)
+    }
+}
+
+// sends the request and awaits the single reply
+fn exchange(connection: TowerConnection, request: TowerMessage) -> impl Future<Item=(TowerMessage, TowerConnection), Error=String> {
+    connection.send(request)
+        .map_err(|e| format!("cannot send: {:?}", e))
+        .and_then(|connection| connection.into_future().map_err(|(e, _)| format!("cannot receive: {:?}", e)))
+        .and_then(|(reply, connection)| match reply {
+            Some(reply) => Ok((reply, connection)),
+            None => Err("the tower closed the connection".to_owned()),
+        })
+}
+
+fn unexpected(reply: TowerMessage) -> String {
+    match reply {
+        TowerMessage::TowerError(e) => format!("the tower failed, code {}", e.code),
+        reply => format!("unexpected {} from the tower", reply.type_name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dependencies::bitcoin;
+    use dependencies::bitcoin_hashes;
+
+    use super::*;
+    use crate::watchtower::blob::decrypt_justice;
+    use bitcoin::{Transaction, TxIn, TxOut, OutPoint, Script};
+    use bitcoin_hashes::{sha256d, Hash};
+    use wire::{CreateSessionReply, StateUpdateReply};
+    use std::{fs, io};
+
+    // replies as a tower which accepts everything
+    fn reply(received: &Mutex<Vec<StateUpdate>>, message: TowerMessage) -> Option<TowerMessage> {
+        match message {
+            TowerMessage::TowerInit(init) => Some(TowerMessage::TowerInit(init)),
+            TowerMessage::CreateSession(_) => Some(TowerMessage::CreateSessionReply(CreateSessionReply {
+                code: TowerCode::OK,
+                last_applied: 0,
+                data: Vec::new(),
+            })),
+            TowerMessage::StateUpdate(update) => {
+                let seq_num = update.seq_num;
+                received.lock().unwrap().push(update);
+                Some(TowerMessage::StateUpdateReply(StateUpdateReply {
+                    code: TowerCode::OK,
+                    last_applied: seq_num,
+                }))
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn upload_to_local_tower() {
+        const DB_PATH: &'static str = "../target/db/watchtower-client-test";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default().user::<TowerClient>().build(DB_PATH).unwrap();
+        let client = TowerClient::new(Arc::new(RwLock::new(db)), Sha256::BITCOIN_CHAIN_HASH);
+
+        let tower_secret = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let tower = TowerAddress {
+            public: PublicKey::from_secret_key(&Secp256k1::signing_only(), &tower_secret),
+            address: "127.0.0.1:19735".parse().unwrap(),
+        };
+        client.add_tower(&tower).unwrap();
+        assert!(client.is_configured());
+
+        let justice_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::hash(b"revoked commitment"),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let remedy = BreachRemedy {
+            commitment_txid: sha256d::Hash::hash(b"revoked commitment"),
+            justice_tx: justice_tx.clone(),
+        };
+        client.backup(&remedy).unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server = {
+            let received = received.clone();
+            tower.address.listen(tower_secret).unwrap()
+                .take(1)
+                .map_err(|e| println!("{:?}", e))
+                .for_each(move |(stream, _)| {
+                    let received = received.clone();
+                    let (sink, stream) = stream.framed_tower().split();
+                    stream
+                        .filter_map(move |message| reply(&received, message))
+                        .forward(sink)
+                        .map(|_| ())
+                        .map_err(|e| println!("{:?}", e))
+                })
+        };
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server);
+
+        assert_eq!(runtime.block_on(client.upload(tower.clone())), Ok(1));
+        // the acknowledged update is not sent again
+        assert_eq!(runtime.block_on(client.upload(tower.clone())), Ok(0));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].seq_num, 1);
+        assert_eq!(received[0].hint, breach_hint(&remedy.commitment_txid));
+        assert_eq!(decrypt_justice(&remedy.commitment_txid, &received[0].encrypted_blob), Some(justice_tx));
+        // the blob is useless for anybody who does not know the breach
+        assert_eq!(decrypt_justice(&sha256d::Hash::hash(b"other commitment"), &received[0].encrypted_blob), None);
+
+        let db = client.db.read().unwrap();
+        let sessions = db.get_all::<String, StoredSession>().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].1.created);
+        assert_eq!(sessions[0].1.last_applied, 1);
+        // the acknowledged update is forgotten
+        assert!(db.get_all::<String, StoredUpdate>().unwrap().is_empty());
+        assert!(client.pending(&tower.public).unwrap().is_empty());
+    }
+}
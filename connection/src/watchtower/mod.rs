@@ -0,0 +1,8 @@
+mod blob;
+mod storage;
+mod client;
+mod server;
+
+pub use self::blob::{breach_hint, encrypt_justice, decrypt_justice};
+pub use self::client::{TowerClient, TowerAddress};
+pub use self::server::TowerServer;
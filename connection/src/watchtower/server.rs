@@ -0,0 +1,503 @@
+use std::sync::{Arc, RwLock, Mutex};
+use std::net::SocketAddr;
+use std::thread;
+
+use dependencies::secp256k1;
+use dependencies::tokio;
+use dependencies::bitcoin;
+
+use secp256k1::{SecretKey, PublicKey};
+use tokio::prelude::{Future, Stream};
+use tokio::net::TcpStream;
+use bitcoin::{Block, Transaction};
+use bitcoin::consensus::encode;
+use brontide::BrontideStream;
+use wallet_lib::interface::Wallet;
+use chainntfs::BlockSource;
+use internal_event::{Event, EventBus, Topic, ChainEvent};
+
+use state::{DB, DBBuilder, DBUser, DBError};
+use wire::{
+    TowerMessage, Sha256, RawFeatureVector, TowerInit,
+    CreateSession, CreateSessionReply, StateUpdate, StateUpdateReply, TowerCode,
+};
+
+use crate::address::{AbstractAddress, TransportError};
+use super::blob::{BLOB_TYPE_JUSTICE_TX, breach_hint, decrypt_justice};
+use super::storage::{ClientSession, BreachJustice, JusticeBlob, PendingJustice, key_of, hint_key};
+
+// the justice of the commitment with several dozens of HTLCs fits
+const MAX_BLOB_SIZE: usize = 16_384;
+
+// the same as the client asks
+const MAX_SESSION_UPDATES: u16 = 1024;
+
+// every session is a new key, so the sessions are limited by the client's ip
+const MAX_SESSIONS_PER_ADDRESS: usize = 16;
+
+// the blob is forgotten when the justice is that deep
+const JUSTICE_DEPTH: u32 = 6;
+
+// the justice which does not confirm for so long is invalid or the output is swept otherwise
+const JUSTICE_EXPIRY: u32 = 2016;
+
+/// Keeps the encrypted justice transactions of the clients and broadcasts them
+/// when the breach appears in the block, the tower learns nothing about
+/// the client's channels before that
+#[derive(Clone)]
+pub struct TowerServer {
+    db: Arc<RwLock<DB>>,
+    chain_hash: Sha256,
+}
+
+impl DBUser for TowerServer {
+    fn db_prepare(builder: DBBuilder) -> DBBuilder {
+        builder
+            .register::<ClientSession>()
+            .register::<BreachJustice>()
+            .register::<PendingJustice>()
+    }
+}
+
+impl TowerServer {
+    pub fn new(db: Arc<RwLock<DB>>, chain_hash: Sha256) -> Self {
+        TowerServer {
+            db: db,
+            chain_hash: chain_hash,
+        }
+    }
+
+    // serves the clients until the runtime stops, the client is known by its brontide key
+    pub fn listen(&self, address: &SocketAddr, secret: SecretKey) -> Result<impl Future<Item=(), Error=()> + Send, TransportError> {
+        let server = self.clone();
+        let incoming = address.listen(secret)?;
+        Ok(incoming
+            // the failed handshake does not stop the tower
+            .then(|result| Ok::<_, ()>(result))
+            .for_each(move |result| {
+                match result {
+                    Ok((stream, address)) => {
+                        tokio::spawn(server.serve(stream, address));
+                    },
+                    Err(e) => println!("WARNING: watchtower handshake failed: {:?}", e),
+                }
+                Ok(())
+            }))
+    }
+
+    fn serve(&self, stream: BrontideStream<TcpStream>, address: SocketAddr) -> impl Future<Item=(), Error=()> + Send {
+        let server = self.clone();
+        let client = stream.remote_key();
+        let name = client.clone();
+        let address = address.ip().to_string();
+        let (sink, stream) = stream.framed_tower().split();
+        stream
+            .filter_map(move |message| server.reply(&client, &address, message))
+            .forward(sink)
+            .map(|_| ())
+            .map_err(move |e| println!("WARNING: watchtower client {} failed: {:?}", name, e))
+    }
+
+    fn reply(&self, client: &PublicKey, address: &str, message: TowerMessage) -> Option<TowerMessage> {
+        match message {
+            TowerMessage::TowerInit(_) => Some(TowerMessage::TowerInit(TowerInit {
+                features: RawFeatureVector::new(),
+                chain_hash: self.chain_hash,
+            })),
+            TowerMessage::CreateSession(request) => {
+                let reply = self.create_session(client, address, request)
+                    .unwrap_or_else(|e| {
+                        println!("ERROR: watchtower cannot store the session of {}: {:?}", client, e);
+                        CreateSessionReply {
+                            code: TowerCode::TEMPORARY_FAILURE,
+                            last_applied: 0,
+                            data: Vec::new(),
+                        }
+                    });
+                Some(TowerMessage::CreateSessionReply(reply))
+            },
+            TowerMessage::StateUpdate(update) => {
+                let reply = self.update(client, update)
+                    .unwrap_or_else(|e| {
+                        println!("ERROR: watchtower cannot store the update of {}: {:?}", client, e);
+                        StateUpdateReply {
+                            code: TowerCode::TEMPORARY_FAILURE,
+                            last_applied: 0,
+                        }
+                    });
+                Some(TowerMessage::StateUpdateReply(reply))
+            },
+            _ => None,
+        }
+    }
+
+    fn create_session(&self, client: &PublicKey, address: &str, request: CreateSession) -> Result<CreateSessionReply, DBError> {
+        let reply = |code, last_applied| CreateSessionReply {
+            code: code,
+            last_applied: last_applied,
+            data: Vec::new(),
+        };
+        if request.blob_type != BLOB_TYPE_JUSTICE_TX {
+            return Ok(reply(TowerCode::UNSUPPORTED_BLOB_TYPE, 0));
+        }
+        if request.max_updates > MAX_SESSION_UPDATES {
+            return Ok(reply(TowerCode::REJECT_MAX_UPDATES, 0));
+        }
+
+        // the sessions are counted and created at once
+        let db = self.db.write().unwrap();
+        // the client continues the session after the last applied update
+        if let Some(session) = db.get::<String, ClientSession>(&key_of(client))? {
+            return Ok(reply(TowerCode::SESSION_EXISTS, session.last_applied));
+        }
+        let sessions = db.get_all::<String, ClientSession>()?.into_iter()
+            .filter(|&(_, ref session)| session.address == address)
+            .count();
+        if sessions >= MAX_SESSIONS_PER_ADDRESS {
+            println!("WARNING: watchtower refused the session of {}, {} has {} sessions", client, address, sessions);
+            return Ok(reply(TowerCode::PERMANENT_FAILURE, 0));
+        }
+        let session = ClientSession {
+            client: client.clone().into(),
+            blob_type: request.blob_type,
+            max_updates: request.max_updates,
+            last_applied: 0,
+            address: address.to_owned(),
+        };
+        db.put(&key_of(client), session)?;
+        Ok(reply(TowerCode::OK, 0))
+    }
+
+    fn update(&self, client: &PublicKey, update: StateUpdate) -> Result<StateUpdateReply, DBError> {
+        let reply = |code, last_applied| StateUpdateReply {
+            code: code,
+            last_applied: last_applied,
+        };
+
+        // the blobs of the hint are read and written back, the other sessions may append the same hint
+        let db = self.db.write().unwrap();
+        let mut session = match db.get::<String, ClientSession>(&key_of(client))? {
+            Some(session) => session,
+            None => return Ok(reply(TowerCode::PERMANENT_FAILURE, 0)),
+        };
+        // the client missed the reply, the update is applied already
+        if update.seq_num <= session.last_applied {
+            return Ok(reply(TowerCode::OK, session.last_applied));
+        }
+        if update.seq_num > session.max_updates {
+            return Ok(reply(TowerCode::MAX_UPDATES_EXCEEDED, session.last_applied));
+        }
+        if update.seq_num != session.last_applied + 1 {
+            return Ok(reply(TowerCode::SEQ_NUM_OUT_OF_ORDER, session.last_applied));
+        }
+        if update.encrypted_blob.len() > MAX_BLOB_SIZE {
+            return Ok(reply(TowerCode::PERMANENT_FAILURE, session.last_applied));
+        }
+
+        let key = hint_key(&update.hint);
+        let mut justice = db.get::<String, BreachJustice>(&key)?.unwrap_or_default();
+        justice.blobs.push(JusticeBlob {
+            session: client.clone().into(),
+            seq_num: update.seq_num,
+            blob: update.encrypted_blob,
+        });
+        db.put(&key, justice)?;
+        session.last_applied = update.seq_num;
+        db.put(&key_of(client), session)?;
+        Ok(reply(TowerCode::OK, update.seq_num))
+    }
+
+    // the justice to broadcast after the block at the height, the justice of the breaches
+    // in the block and the unconfirmed justice found before, the blob is forgotten
+    // when its justice is buried
+    fn connect(&self, height: u32, block: &Block) -> Vec<Transaction> {
+        let db = self.db.write().unwrap();
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            let hint = breach_hint(&txid);
+            let justice = match db.get::<String, BreachJustice>(&hint_key(&hint)) {
+                Ok(Some(justice)) => justice,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("ERROR: watchtower cannot load the justice of {}: {:?}", txid, e);
+                    continue;
+                },
+            };
+
+            // the hint may match by chance, only the breach itself decrypts the blob
+            for blob in justice.blobs {
+                let justice_tx = match decrypt_justice(&txid, &blob.blob) {
+                    Some(justice_tx) => justice_tx,
+                    None => continue,
+                };
+                // the breach is found again after the reorg
+                let key = justice_tx.txid().to_string();
+                match db.get::<String, PendingJustice>(&key) {
+                    Ok(None) => (),
+                    Ok(Some(_)) => continue,
+                    Err(e) => {
+                        println!("ERROR: watchtower cannot load the justice {}: {:?}", key, e);
+                        continue;
+                    },
+                }
+                println!("INFO: watchtower found the breach {} of the client {:?}", txid, blob.session);
+                let pending = PendingJustice {
+                    hint: hint,
+                    session: blob.session,
+                    seq_num: blob.seq_num,
+                    transaction: encode::serialize(&justice_tx),
+                    found: height,
+                    confirmed: None,
+                };
+                if let Err(e) = db.put(&key, pending) {
+                    println!("ERROR: watchtower cannot store the justice {}: {:?}", key, e);
+                }
+            }
+        }
+
+        let pending = match db.get_all::<String, PendingJustice>() {
+            Ok(pending) => pending,
+            Err(e) => {
+                println!("ERROR: watchtower cannot load the pending justice: {:?}", e);
+                return Vec::new();
+            },
+        };
+        let mut transactions = Vec::new();
+        for (key, mut pending) in pending {
+            let justice_tx = match encode::deserialize::<Transaction>(&pending.transaction) {
+                Ok(justice_tx) => justice_tx,
+                Err(e) => {
+                    println!("ERROR: watchtower cannot read the justice {}: {:?}", key, e);
+                    continue;
+                },
+            };
+            // the block at the height replaces the reorganized one
+            if pending.confirmed.map(|confirmed| confirmed >= height).unwrap_or(false) {
+                pending.confirmed = None;
+            }
+            if pending.confirmed.is_none() && block.txdata.iter().any(|tx| tx.txid() == justice_tx.txid()) {
+                pending.confirmed = Some(height);
+            }
+            let result = match pending.confirmed {
+                Some(confirmed) if height + 1 - confirmed >= JUSTICE_DEPTH => {
+                    println!("INFO: watchtower justice {} is confirmed", key);
+                    self.forget(&db, &key, &pending)
+                },
+                None if height >= pending.found + JUSTICE_EXPIRY => {
+                    println!("WARNING: watchtower justice {} is not confirmed, forget it", key);
+                    self.forget(&db, &key, &pending)
+                },
+                Some(_) => db.put(&key, pending),
+                None => {
+                    transactions.push(justice_tx);
+                    db.put(&key, pending)
+                },
+            };
+            if let Err(e) = result {
+                println!("ERROR: watchtower cannot store the justice {}: {:?}", key, e);
+            }
+        }
+        transactions
+    }
+
+    fn forget(&self, db: &DB, key: &String, pending: &PendingJustice) -> Result<(), DBError> {
+        let hint = hint_key(&pending.hint);
+        if let Some(mut justice) = db.get::<String, BreachJustice>(&hint)? {
+            justice.blobs.retain(|blob| blob.session != pending.session || blob.seq_num != pending.seq_num);
+            if justice.blobs.is_empty() {
+                db.delete::<_, BreachJustice>(&hint)?;
+            } else {
+                db.put(&hint, justice)?;
+            }
+        }
+        db.delete::<_, PendingJustice>(key)
+    }
+
+    // subscribed at once, so the blocks published by the chain watcher while it catches up
+    // are not missed, the watcher undoes the reorganized blocks and tells the height
+    // of the replacing ones, the bodies are read from bitcoind in the own thread,
+    // as the rpc is blocking
+    pub fn run(self, wallet: Arc<Mutex<Box<dyn Wallet + Send>>>, bus: &EventBus) -> thread::JoinHandle<()> {
+        let events = bus.subscribe(&[Topic::Chain]);
+        thread::spawn(move || {
+            let source = match BlockSource::new() {
+                Ok(source) => source,
+                Err(e) => {
+                    println!("ERROR: watchtower cannot connect to bitcoind: {}", e);
+                    return;
+                },
+            };
+            for event in events.wait() {
+                let (height, hash) = match event {
+                    Ok(Event::Chain(ChainEvent::NewBlock { height, hash })) => (height, hash),
+                    Ok(_) => continue,
+                    Err(()) => break,
+                };
+                let block = match source.block(&hash) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        println!("ERROR: watchtower cannot read the block {} at {}: {}", hash, height, e);
+                        continue;
+                    },
+                };
+                for justice_tx in self.connect(height, &block) {
+                    match wallet.lock().unwrap().publish_tx(&justice_tx) {
+                        Ok(_) => println!("INFO: watchtower broadcast the justice {}", justice_tx.txid()),
+                        Err(e) => println!("ERROR: watchtower cannot broadcast the justice {}: {:?}", justice_tx.txid(), e),
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dependencies::bitcoin_hashes;
+
+    use super::*;
+    use crate::watchtower::{TowerClient, TowerAddress};
+    use secp256k1::Secp256k1;
+    use bitcoin::{BlockHeader, TxIn, TxOut, OutPoint, Script};
+    use bitcoin_hashes::{sha256d, Hash};
+    use channel_machine::BreachRemedy;
+    use std::{fs, io};
+
+    fn spending(txid: sha256d::Hash) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: txid,
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn block(txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: sha256d::Hash::default(),
+                merkle_root: sha256d::Hash::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: txdata,
+        }
+    }
+
+    #[test]
+    fn tower_finds_justice_of_breach() {
+        const DB_PATH: &'static str = "../target/db/watchtower-server-test";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<TowerClient>()
+            .user::<TowerServer>()
+            .build(DB_PATH)
+            .unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let client = TowerClient::new(db.clone(), Sha256::BITCOIN_CHAIN_HASH);
+        let server = TowerServer::new(db, Sha256::BITCOIN_CHAIN_HASH);
+
+        let tower_secret = SecretKey::from_slice(&[0x22; 32]).unwrap();
+        let tower = TowerAddress {
+            public: PublicKey::from_secret_key(&Secp256k1::signing_only(), &tower_secret),
+            address: "127.0.0.1:19736".parse().unwrap(),
+        };
+        client.add_tower(&tower).unwrap();
+
+        let commitment_tx = spending(sha256d::Hash::hash(b"funding"));
+        let justice_tx = spending(commitment_tx.txid());
+        client.backup(&BreachRemedy {
+            commitment_txid: commitment_tx.txid(),
+            justice_tx: justice_tx.clone(),
+        }).unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.listen(&tower.address, tower_secret).unwrap());
+        assert_eq!(runtime.block_on(client.upload(tower.clone())), Ok(1));
+
+        // the tower keeps the session of the client
+        let sessions = server.db.read().unwrap().get_all::<String, ClientSession>().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].1.last_applied, 1);
+
+        let other_tx = spending(sha256d::Hash::hash(b"other"));
+        assert_eq!(server.connect(101, &block(vec![other_tx.clone()])), Vec::new());
+        assert_eq!(server.connect(102, &block(vec![other_tx, commitment_tx.clone()])), vec![justice_tx.clone()]);
+        // the justice is broadcast again until it confirms
+        assert_eq!(server.connect(103, &block(Vec::new())), vec![justice_tx.clone()]);
+        assert_eq!(server.connect(104, &block(vec![justice_tx.clone()])), Vec::new());
+        // the block is reorganized, the justice is broadcast again
+        assert_eq!(server.connect(104, &block(Vec::new())), vec![justice_tx.clone()]);
+        assert_eq!(server.connect(105, &block(vec![justice_tx.clone()])), Vec::new());
+
+        // the blob is kept until the justice is buried
+        let hint = hint_key(&breach_hint(&commitment_tx.txid()));
+        for height in 106..110 {
+            assert_eq!(server.connect(height, &block(Vec::new())), Vec::new());
+        }
+        assert!(server.db.read().unwrap().get::<String, BreachJustice>(&hint).unwrap().is_some());
+        assert_eq!(server.connect(110, &block(Vec::new())), Vec::new());
+        assert!(server.db.read().unwrap().get::<String, BreachJustice>(&hint).unwrap().is_none());
+        assert!(server.db.read().unwrap().get_all::<String, PendingJustice>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tower_limits_the_client() {
+        const DB_PATH: &'static str = "../target/db/watchtower-server-limits-test";
+
+        let () = fs::remove_dir_all(DB_PATH)
+            .or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .unwrap();
+        let db = DBBuilder::default()
+            .user::<TowerServer>()
+            .build(DB_PATH)
+            .unwrap();
+        let server = TowerServer::new(Arc::new(RwLock::new(db)), Sha256::BITCOIN_CHAIN_HASH);
+
+        let context = Secp256k1::signing_only();
+        let client = |i: u8| PublicKey::from_secret_key(&context, &SecretKey::from_slice(&[i + 1; 32]).unwrap());
+        let request = |max_updates| CreateSession {
+            blob_type: BLOB_TYPE_JUSTICE_TX,
+            max_updates: max_updates,
+        };
+
+        let reply = server.create_session(&client(0), "10.0.0.1", request(MAX_SESSION_UPDATES + 1)).unwrap();
+        assert_eq!(reply.code, TowerCode::REJECT_MAX_UPDATES);
+
+        for i in 0..MAX_SESSIONS_PER_ADDRESS as u8 {
+            let reply = server.create_session(&client(i), "10.0.0.1", request(MAX_SESSION_UPDATES)).unwrap();
+            assert_eq!(reply.code, TowerCode::OK);
+        }
+        let last = client(MAX_SESSIONS_PER_ADDRESS as u8);
+        let reply = server.create_session(&last, "10.0.0.1", request(MAX_SESSION_UPDATES)).unwrap();
+        assert_eq!(reply.code, TowerCode::PERMANENT_FAILURE);
+        let reply = server.create_session(&last, "10.0.0.2", request(MAX_SESSION_UPDATES)).unwrap();
+        assert_eq!(reply.code, TowerCode::OK);
+
+        let update = |size| StateUpdate {
+            seq_num: 1,
+            last_applied: 0,
+            is_complete: 0,
+            hint: [0; 16],
+            encrypted_blob: vec![0; size],
+        };
+        assert_eq!(server.update(&last, update(MAX_BLOB_SIZE + 1)).unwrap().code, TowerCode::PERMANENT_FAILURE);
+        assert_eq!(server.update(&last, update(MAX_BLOB_SIZE)).unwrap().code, TowerCode::OK);
+    }
+}
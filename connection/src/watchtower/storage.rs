@@ -0,0 +1,166 @@
+use dependencies::secp256k1;
+use dependencies::hex;
+
+use secp256k1::PublicKey;
+use serde_derive::{Serialize, Deserialize};
+
+use state::DBValue;
+use common_types::RawPublicKey;
+use wire::BreachHint;
+
+/// The tower the justice transactions are uploaded to, keyed by its public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTower {
+    pub public: RawPublicKey,
+    pub address: String,
+}
+
+impl DBValue for StoredTower {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_tower"
+    }
+}
+
+/// The session with the tower, the tower knows the client by the brontide key
+/// of the session, keyed by its public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub tower: RawPublicKey,
+    pub secret: [u8; 32],
+    pub max_updates: u16,
+    // the tower accepted `create_session`
+    pub created: bool,
+    // the last update acknowledged by the tower
+    pub last_applied: u16,
+}
+
+impl DBValue for StoredSession {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_session"
+    }
+}
+
+/// The encrypted justice transaction waiting for the tower, keyed by the tower and the hint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredUpdate {
+    pub tower: RawPublicKey,
+    pub hint: BreachHint,
+    pub blob: Vec<u8>,
+}
+
+impl DBValue for StoredUpdate {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_update"
+    }
+}
+
+/// The session the tower keeps for the client, keyed by the client's public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientSession {
+    pub client: RawPublicKey,
+    pub blob_type: u16,
+    pub max_updates: u16,
+    pub last_applied: u16,
+    // the ip the session was created from, the sessions per address are limited
+    #[serde(default)]
+    pub address: String,
+}
+
+impl DBValue for ClientSession {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_client_session"
+    }
+}
+
+/// The encrypted justice the tower keeps for the clients, keyed by the breach hint
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BreachJustice {
+    pub blobs: Vec<JusticeBlob>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JusticeBlob {
+    pub session: RawPublicKey,
+    pub seq_num: u16,
+    pub blob: Vec<u8>,
+}
+
+impl DBValue for BreachJustice {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_breach_justice"
+    }
+}
+
+/// The justice the tower broadcasts until it is buried, keyed by the justice txid,
+/// the blob is kept until then, so the tower finds the breach again after a reorg
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingJustice {
+    pub hint: BreachHint,
+    pub session: RawPublicKey,
+    pub seq_num: u16,
+    pub transaction: Vec<u8>,
+    // the height the breach is found at
+    pub found: u32,
+    // the height of the block which includes the justice
+    pub confirmed: Option<u32>,
+}
+
+impl DBValue for PendingJustice {
+    type Extension = ();
+
+    fn extend(self, e: Self::Extension) -> Self {
+        let _ = e;
+        self
+    }
+
+    fn cf_name() -> &'static str {
+        "watchtower_pending_justice"
+    }
+}
+
+pub(crate) fn key_of(public: &PublicKey) -> String {
+    hex::encode(&public.serialize()[..])
+}
+
+pub(crate) fn update_key(tower: &PublicKey, hint: &BreachHint) -> String {
+    format!("{}{}", key_of(tower), hint_key(hint))
+}
+
+pub(crate) fn hint_key(hint: &BreachHint) -> String {
+    hex::encode(&hint[..])
+}
@@ -35,4 +35,5 @@ pub extern crate pretty_assertions;
 pub extern crate tls_api;
 pub extern crate tls_api_rustls;
 pub extern crate httpbis;
-pub extern crate ctrlc;
\ No newline at end of file
+pub extern crate ctrlc;
+pub extern crate tracing;
\ No newline at end of file
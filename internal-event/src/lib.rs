@@ -1,17 +1,211 @@
 #![forbid(unsafe_code)]
 
-#[derive(Debug)]
+use dependencies::secp256k1;
+use dependencies::bitcoin_hashes;
+use dependencies::futures;
+use dependencies::bitcoin;
+
+use secp256k1::PublicKey;
+use bitcoin_hashes::sha256d;
+use bitcoin::Transaction;
+use futures::sync::mpsc;
+use wire::{Message, ChannelId, Satoshi, MilliSatoshi, CsvDelay, SatoshiPerKiloWeight, OnionBlob, RawFeatureVector};
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
 pub enum Event {
     DirectCommand(DirectCommand),
+    ChannelCommand {
+        channel_id: ChannelId,
+        command: ChannelCommand,
+    },
+    // the message of our channel to the peer, the peer session sends it
+    PeerMessage {
+        peer: PublicKey,
+        message: Message,
+    },
     TimerTick,
+    Chain(ChainEvent),
+    Peer(PeerEvent),
+    Channel(ChannelEvent),
+    // the channel command goes to no channel
+    UnknownChannel(ChannelId),
+    // the peer fulfilled the HTLC we offered, the preimage settles the HTLC offered to us upstream
+    HtlcFulfilled {
+        channel_id: ChannelId,
+        id: u64,
+        payment_preimage: [u8; 32],
+    },
+    // our own gossip, every peer session sends it to the peer
+    Gossip(Message),
 }
 
-#[derive(Debug)]
+/// Events are grouped by topics, the subscriber receives only events of chosen topics
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Topic {
+    // the command to the particular peer or channel
+    Command,
+    Timer,
+    Chain,
+    Peer,
+    Channel,
+    Gossip,
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            &Event::DirectCommand(_) => Topic::Command,
+            &Event::ChannelCommand { .. } => Topic::Command,
+            &Event::PeerMessage { .. } => Topic::Command,
+            &Event::TimerTick => Topic::Timer,
+            &Event::Chain(_) => Topic::Chain,
+            &Event::Peer(_) => Topic::Peer,
+            &Event::Channel(_) => Topic::Channel,
+            &Event::UnknownChannel(_) => Topic::Channel,
+            &Event::HtlcFulfilled { .. } => Topic::Channel,
+            &Event::Gossip(_) => Topic::Gossip,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Event::DirectCommand(_) => "DirectCommand",
+            &Event::ChannelCommand { .. } => "ChannelCommand",
+            &Event::PeerMessage { .. } => "PeerMessage",
+            &Event::TimerTick => "TimerTick",
+            &Event::Chain(_) => "Chain",
+            &Event::Peer(_) => "Peer",
+            &Event::Channel(_) => "Channel",
+            &Event::UnknownChannel(_) => "UnknownChannel",
+            &Event::HtlcFulfilled { .. } => "HtlcFulfilled",
+            &Event::Gossip(_) => "Gossip",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum DirectCommand {
-    NewChannel,
+    NewChannel(NewChannel),
 }
 
-#[derive(Debug)]
+/// The request to open the channel with the peer, we are the funder,
+/// the node's defaults are used for missing parameters
+#[derive(Debug, Clone)]
+pub struct NewChannel {
+    // chosen by the requester, so it can follow the status of the channel
+    pub temporary_channel_id: ChannelId,
+    pub funding: Satoshi,
+    pub push: MilliSatoshi,
+    pub htlc_minimum: Option<MilliSatoshi>,
+    // the delay of the peer's to-self outputs
+    pub csv_delay: Option<CsvDelay>,
+    pub fee: Option<SatoshiPerKiloWeight>,
+    // the channel will not be announced
+    pub private: bool,
+}
+
+#[derive(Debug, Clone)]
 pub enum ChannelCommand {
-    CloseChannel,
+    // the forced close does not need the peer, our commitment is broadcast
+    CloseChannel {
+        force: bool,
+    },
+    // offer the HTLC to the peer
+    AddHtlc {
+        amount: MilliSatoshi,
+        payment_hash: [u8; 32],
+        expiry: u32,
+        onion_blob: OnionBlob,
+    },
+    // settle the HTLC offered by the peer
+    FulfillHtlc {
+        id: u64,
+        payment_preimage: [u8; 32],
+    },
+    FailHtlc {
+        id: u64,
+        reason: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    NewBlock {
+        height: u32,
+        hash: sha256d::Hash,
+    },
+    // the transaction is given, so its outputs can be checked
+    Confirmed {
+        txid: sha256d::Hash,
+        confirmations: u32,
+        transaction: Transaction,
+        // the position of the transaction in the chain, the short channel id is made of it
+        block_height: u32,
+        tx_index: u32,
+    },
+    Spent {
+        txid: sha256d::Hash,
+        vout: u32,
+        spending_tx: Transaction,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Connected(PublicKey),
+    // the peer sent `init` with its local features, the messages of the channels can go
+    Initialized(PublicKey, RawFeatureVector),
+    Disconnected(PublicKey),
+    // the peer is banned while connected, its session is dropped
+    Banned(PublicKey),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ChannelStatus {
+    Pending,
+    Confirmation,
+    Open,
+    Closing,
+    Closed,
+    // the channel failed to open, or rejected the command
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelEvent {
+    pub peer: PublicKey,
+    pub channel_id: ChannelId,
+    // the channel had this id before the funding transaction was created
+    pub temporary_channel_id: Option<ChannelId>,
+    pub status: ChannelStatus,
+    // the txid and the output index, known when the funding transaction is created
+    pub funding_outpoint: Option<(sha256d::Hash, u32)>,
+    // known when the closing transaction is broadcast
+    pub closing_txid: Option<sha256d::Hash>,
+}
+
+/// In-process publish/subscribe, cheap to clone, every clone refers to the same bus
+#[derive(Clone, Default)]
+pub struct EventBus(Arc<Mutex<HashMap<Topic, Vec<mpsc::UnboundedSender<Event>>>>>);
+
+impl EventBus {
+    pub fn subscribe(&self, topics: &[Topic]) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut subscribers = self.0.lock().unwrap();
+        for topic in topics {
+            subscribers.entry(topic.clone()).or_insert(Vec::new()).push(sender.clone());
+        }
+        receiver
+    }
+
+    // subscribers which dropped the receiver are forgotten
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.0.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(&event.topic()) {
+            senders.retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+        }
+    }
 }
@@ -1,16 +1,25 @@
 #![forbid(unsafe_code)]
 
+mod metrics;
+pub use self::metrics::{Metrics, ConsumerStats, QueueStats, Probe, Backlog};
+
 use dependencies::either;
 use dependencies::tokio;
 use dependencies::futures;
+use dependencies::secp256k1;
+use dependencies::tracing;
 
 use binformat::WireError;
 use wire::{Message, MessageExt};
-use internal_event::Event;
+use internal_event::{Event, Topic};
 
 use tokio::prelude::{Future, Sink, Poll};
 use futures::sink;
 use either::Either;
+use secp256k1::PublicKey;
+
+use std::sync::Arc;
+use std::fmt;
 
 pub trait MessageFiltered
 where
@@ -35,19 +44,101 @@ pub trait RelevantEvent
 where
     Self: Sized,
 {
+    // the topics of the event bus the consumer subscribes to,
+    // the events directed to the peer are delivered regardless of the topics
+    fn topics() -> Vec<Topic>;
+
     fn filter(v: Event) -> Result<Self, Event>;
 }
 
 // unit means any event is irrelevant, change `()` to `!` when it stabilized
 impl RelevantEvent for () {
+    fn topics() -> Vec<Topic> {
+        Vec::new()
+    }
+
     fn filter(v: Event) -> Result<Self, Event> {
         Err(v)
     }
 }
 
-pub enum MessageConsumerType {
-    SingleResponse,
-    MultipleResponse,
+// the kinds of the peer's misbehavior the consumer might report
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Misbehavior {
+    InvalidSignature,
+    MalformedMessage,
+    Spam,
+    ProtocolViolation,
+}
+
+impl Misbehavior {
+    // how much the peer's score increases
+    pub fn penalty(&self) -> u32 {
+        use self::Misbehavior::*;
+
+        match self {
+            &InvalidSignature => 50,
+            &MalformedMessage => 50,
+            &Spam => 10,
+            &ProtocolViolation => 25,
+        }
+    }
+}
+
+// keeps the score of all peers, implemented by the node
+pub trait ScoreKeeper {
+    // returns true if the peer should be disconnected
+    fn report(&self, peer: &PublicKey, misbehavior: Misbehavior) -> bool;
+}
+
+// the handle to report the misbehavior of the particular peer,
+// each connection has its own
+#[derive(Clone)]
+pub struct PeerReporter {
+    peer: PublicKey,
+    keeper: Arc<dyn ScoreKeeper + Send + Sync>,
+}
+
+impl fmt::Debug for PeerReporter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerReporter")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl PeerReporter {
+    pub fn new(peer: PublicKey, keeper: Arc<dyn ScoreKeeper + Send + Sync>) -> Self {
+        PeerReporter {
+            peer: peer,
+            keeper: keeper,
+        }
+    }
+
+    pub fn peer(&self) -> &PublicKey {
+        &self.peer
+    }
+
+    // the error should be returned from the consumer, it terminates the connection
+    pub fn report(&self, misbehavior: Misbehavior) -> Result<(), WireError> {
+        use std::io;
+
+        println!("WARNING: peer {} misbehaves: {:?}", self.peer, misbehavior);
+        if self.keeper.report(&self.peer, misbehavior) {
+            let description = format!("peer {} is disconnected for misbehavior: {:?}", self.peer, misbehavior);
+            Err(WireError::from(io::Error::new(io::ErrorKind::ConnectionAborted, description)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How the chain treats the message accepted by the consumer
+pub enum MessageRouting {
+    // the consumer handles the message, the rest of the chain does not see it
+    Exclusive,
+    // the consumer only observes the message, it goes further along the chain
+    Observe,
 }
 
 pub struct ConsumingFuture<C, S>(Box<dyn Future<Item=(C, S), Error=WireError> + Send + 'static>)
@@ -79,10 +170,34 @@ where
         ConsumingFuture(Box::new(Ok((consumer, sink)).into_future()))
     }
 
+    // the error terminates processing of the peer's stream, so the connection will be closed
+    pub fn err(error: WireError) -> Self {
+        use tokio::prelude::future::IntoFuture;
+
+        ConsumingFuture(Box::new(Err(error).into_future()))
+    }
+
+    // sends the response if any, the error terminates the connection
+    pub fn from_response(response: Result<(C, Option<MessageExt>), WireError>, sink: S) -> Self {
+        match response {
+            Ok((consumer, Some(response))) => ConsumingFuture::from_send(consumer, sink.send(response)),
+            Ok((consumer, None)) => ConsumingFuture::ok(consumer, sink),
+            Err(e) => ConsumingFuture::err(e),
+        }
+    }
+
     pub fn from_send(consumer: C, send: sink::Send<S>) -> Self {
         ConsumingFuture(Box::new(send.map(|s| (consumer, s))))
     }
 
+    // the messages are sent in order
+    pub fn from_send_all(consumer: C, sink: S, messages: Vec<MessageExt>) -> Self {
+        use futures::stream::iter_ok;
+
+        let send = sink.send_all(iter_ok::<_, WireError>(messages));
+        ConsumingFuture(Box::new(send.map(|(s, _)| (consumer, s))))
+    }
+
     pub fn new<F>(f: F) -> Self
     where
         F: Future<Item=(C, S), Error=WireError> + Send + 'static,
@@ -95,39 +210,123 @@ pub trait MessageConsumer {
     type Message: MessageFiltered;
     type Relevant: RelevantEvent;
 
-    // determines which method will call
-    // if TYPE is `SingleResponse` then `consume_single_response` will call and `consume` might be unimplemented
-    // if TYPE is `MultipleResponse` then `consume` will call and `consume_single_response` might be unimplemented
-    // TODO: uncomment it
-    // const TYPE: MessageConsumerType;
+    const ROUTING: MessageRouting = MessageRouting::Exclusive;
 
-    // without response or single message response
-    // fn consume_single_response(self, message: Either<Self::Message, Self::Event>) -> (Self, Option<MessageExt>)
-    // where
-    //     Self: Sized;
+    // identifies the consumer in metrics and traces
+    const NAME: &'static str = "unnamed";
 
-    // consumes message and return future with the sink and maybe modified self
-    // works synchronously
+    // consumes message and return future with the sink and maybe modified self,
+    // the consumer which answers with at most one message should be `SingleResponseConsumer`
     fn consume<S>(self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
     where
         Self: Sized + Send + 'static,
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static;
 }
 
+/// The consumer which answers with at most one message, the response is sent before the next message
+/// is consumed, the error terminates the connection
+pub trait SingleResponseConsumer {
+    type Message: MessageFiltered;
+    type Relevant: RelevantEvent;
+
+    const ROUTING: MessageRouting = MessageRouting::Exclusive;
+
+    const NAME: &'static str = "unnamed";
+
+    fn consume_single_response(self, message: Either<Self::Message, Self::Relevant>) -> Result<(Self, Option<MessageExt>), WireError>
+    where
+        Self: Sized;
+}
+
+impl<T> MessageConsumer for T
+where
+    T: SingleResponseConsumer,
+{
+    type Message = T::Message;
+    type Relevant = T::Relevant;
+
+    const ROUTING: MessageRouting = T::ROUTING;
+
+    const NAME: &'static str = T::NAME;
+
+    fn consume<S>(self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
+    where
+        Self: Sized + Send + 'static,
+        S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
+    {
+        ConsumingFuture::from_response(self.consume_single_response(message), sink)
+    }
+}
+
+// calls the consumer, records the metrics and the trace
+fn consume_by_type<X, S>(x: X, sink: S, message: Either<X::Message, X::Relevant>, type_name: &'static str, probe: &Probe) -> ConsumingFuture<X, S>
+where
+    X: MessageConsumer + Send + 'static,
+    S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
+{
+    use std::time::Instant;
+
+    let is_event = message.is_right();
+    let span = tracing::trace_span!("consume", consumer = X::NAME, peer = %probe.peer(), message_type = type_name);
+    let _enter = span.enter();
+
+    let metrics = probe.metrics().clone();
+    let start = Instant::now();
+    let future = x.consume(sink, message);
+
+    // the latency includes sending the response
+    let span = span.clone();
+    ConsumingFuture::new(future.then(move |result| {
+        let latency = start.elapsed();
+        metrics.finished(X::NAME, is_event, latency, result.is_err());
+        let _enter = span.enter();
+        match &result {
+            &Ok(_) => tracing::trace!(latency_us = latency.as_micros() as u64, "consumed"),
+            &Err(ref e) => tracing::warn!(latency_us = latency.as_micros() as u64, error = ?e, "consumer failed"),
+        }
+        result
+    }))
+}
+
+/// The chain of consumers, the earlier consumer has the higher priority.
+/// The message goes along the chain until some `Exclusive` consumer accepts it,
+/// every `Observe` consumer on the way sees the message as well.
 pub trait MessageConsumerChain {
-    fn process<S>(self, sink: S, message: Either<MessageExt, Event>) -> ConsumingFuture<Self, S>
+    // all topics the consumers of the chain subscribe to
+    fn topics() -> Vec<Topic>;
+
+    // `handled` is true if some consumer earlier in the chain has accepted the message
+    fn route<S>(self, sink: S, message: Either<MessageExt, Event>, handled: bool, probe: Probe) -> ConsumingFuture<Self, S>
     where
         Self: Sized + Send + 'static,
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static;
+
+    fn process<S>(self, sink: S, message: Either<MessageExt, Event>, probe: &Probe) -> ConsumingFuture<Self, S>
+    where
+        Self: Sized + Send + 'static,
+        S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
+    {
+        self.route(sink, message, false, probe.clone())
+    }
 }
 
+// the fallback for messages nobody has handled, the message is known to us,
+// so it is skipped whatever its type is, the unknown messages are handled by the codec
 impl MessageConsumerChain for () {
-    fn process<S>(self, sink: S, message: Either<MessageExt, Event>) -> ConsumingFuture<Self, S>
+    fn topics() -> Vec<Topic> {
+        Vec::new()
+    }
+
+    fn route<S>(self, sink: S, message: Either<MessageExt, Event>, handled: bool, probe: Probe) -> ConsumingFuture<Self, S>
     where
         Self: Sized + Send,
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
     {
-        println!("WARNING: skipped message {:?}", message);
+        let _ = probe;
+
+        if !handled {
+            println!("WARNING: skipped message {:?}", message);
+        }
         ConsumingFuture::ok(self, sink)
     }
 }
@@ -137,20 +336,225 @@ where
     X: MessageConsumer + Send + 'static,
     XS: MessageConsumerChain + Send + 'static,
 {
-    fn process<S>(self, s: S, message: Either<MessageExt, Event>) -> ConsumingFuture<Self, S>
+    fn topics() -> Vec<Topic> {
+        let mut topics = XS::topics();
+        for topic in <X::Relevant as RelevantEvent>::topics() {
+            if !topics.contains(&topic) {
+                topics.push(topic);
+            }
+        }
+        topics
+    }
+
+    fn route<S>(self, s: S, message: Either<MessageExt, Event>, handled: bool, probe: Probe) -> ConsumingFuture<Self, S>
     where
         Self: Sized + Send + 'static,
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
     {
-        let m = match message {
-            Either::Left(m) => <X::Message as MessageFiltered>::filter(m).map(Either::Left).map_err(Either::Left),
-            Either::Right(c) => <X::Relevant as RelevantEvent>::filter(c).map(Either::Right).map_err(Either::Right),
+        fn filter<X>(message: Either<MessageExt, Event>) -> Result<Either<X::Message, X::Relevant>, Either<MessageExt, Event>>
+        where
+            X: MessageConsumer,
+        {
+            match message {
+                Either::Left(m) => <X::Message as MessageFiltered>::filter(m).map(Either::Left).map_err(Either::Left),
+                Either::Right(c) => <X::Relevant as RelevantEvent>::filter(c).map(Either::Right).map_err(Either::Right),
+            }
+        }
+
+        let type_name = match &message {
+            &Either::Left(ref m) => m.message.type_name(),
+            &Either::Right(ref e) => e.name(),
         };
 
         let (x, xs) = self;
-        match m {
-            Ok(m) => ConsumingFuture::new(x.consume(s, m).map(|(x, s)| ((x, xs), s))),
-            Err(m) => ConsumingFuture::new(xs.process(s, m).map(|(xs, s)| ((x, xs), s))),
+        match X::ROUTING {
+            MessageRouting::Exclusive => match filter::<X>(message) {
+                Ok(m) => ConsumingFuture::new(consume_by_type(x, s, m, type_name, &probe).map(|(x, s)| ((x, xs), s))),
+                Err(m) => ConsumingFuture::new(xs.route(s, m, handled, probe).map(|(xs, s)| ((x, xs), s))),
+            },
+            MessageRouting::Observe => match filter::<X>(message.clone()) {
+                Ok(m) => ConsumingFuture::new(
+                    consume_by_type(x, s, m, type_name, &probe)
+                        .and_then(move |(x, s)| xs.route(s, message, true, probe).map(|(xs, s)| ((x, xs), s)))
+                ),
+                Err(m) => ConsumingFuture::new(xs.route(s, m, handled, probe).map(|(xs, s)| ((x, xs), s))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageConsumer, MessageConsumerChain, MessageFiltered, MessageRouting, RelevantEvent, SingleResponseConsumer};
+    use super::{ConsumingFuture, Metrics, Probe};
+
+    use dependencies::either;
+    use dependencies::tokio;
+    use dependencies::futures;
+    use dependencies::secp256k1;
+
+    use binformat::WireError;
+    use wire::{Message, MessageExt, Ping, Pong};
+    use internal_event::{Event, Topic};
+
+    use either::Either;
+    use tokio::prelude::{Future, Sink};
+    use futures::sink::SinkMapErr;
+    use secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+    // sees every message, but handles nothing
+    #[derive(Default)]
+    struct Observer {
+        seen: Vec<&'static str>,
+    }
+
+    impl MessageConsumer for Observer {
+        type Message = MessageExt;
+        type Relevant = ();
+
+        const ROUTING: MessageRouting = MessageRouting::Observe;
+
+        const NAME: &'static str = "observer";
+
+        fn consume<S>(mut self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
+        where
+            S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
+        {
+            if let Either::Left(m) = message {
+                self.seen.push(m.message.type_name());
+            }
+            ConsumingFuture::ok(self, sink)
+        }
+    }
+
+    struct PingOnly(Ping);
+
+    impl MessageFiltered for PingOnly {
+        fn filter(v: MessageExt) -> Result<Self, MessageExt> {
+            match v.message {
+                Message::Ping(ping) => Ok(PingOnly(ping)),
+                _ => Err(v),
+            }
+        }
+    }
+
+    struct Tick;
+
+    impl RelevantEvent for Tick {
+        fn topics() -> Vec<Topic> {
+            vec![Topic::Timer]
+        }
+
+        fn filter(v: Event) -> Result<Self, Event> {
+            match v {
+                Event::TimerTick => Ok(Tick),
+                v @ _ => Err(v),
+            }
+        }
+    }
+
+    // answers the ping exclusively
+    #[derive(Default)]
+    struct Responder {
+        pings: usize,
+        ticks: usize,
+    }
+
+    impl SingleResponseConsumer for Responder {
+        type Message = PingOnly;
+        type Relevant = Tick;
+
+        const NAME: &'static str = "responder";
+
+        fn consume_single_response(mut self, message: Either<Self::Message, Self::Relevant>) -> Result<(Self, Option<MessageExt>), WireError> {
+            match message {
+                Either::Left(PingOnly(ping)) => {
+                    self.pings += 1;
+                    let pong = Message::Pong(Pong::new(&ping));
+                    Ok((self, Some(pong.into())))
+                },
+                Either::Right(Tick) => {
+                    self.ticks += 1;
+                    Ok((self, None))
+                },
+            }
         }
     }
+
+    type TestSink = SinkMapErr<Vec<MessageExt>, fn(()) -> WireError>;
+
+    fn sink() -> TestSink {
+        fn never(_: ()) -> WireError {
+            unreachable!()
+        }
+
+        Vec::new().sink_map_err(never as fn(()) -> WireError)
+    }
+
+    fn probe() -> Probe {
+        let secret = SecretKey::from_slice(&[1; 32]).unwrap();
+        Probe::new(PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret), Metrics::default())
+    }
+
+    fn ping() -> Either<MessageExt, Event> {
+        Either::Left(Message::Ping(Ping::new(4, 8).unwrap()).into())
+    }
+
+    #[test]
+    fn observer_sees_message_handled_later() {
+        let chain = (Observer::default(), (Responder::default(), ()));
+        let ((observer, (responder, ())), sink) = chain.process(sink(), ping(), &probe()).wait().unwrap();
+
+        assert_eq!(observer.seen, vec!["Ping"]);
+        assert_eq!(responder.pings, 1);
+        let sent = sink.into_inner();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].message.type_name(), "Pong");
+    }
+
+    #[test]
+    fn exclusive_consumer_hides_message() {
+        let chain = (Responder::default(), (Observer::default(), ()));
+        let ((responder, (observer, ())), _) = chain.process(sink(), ping(), &probe()).wait().unwrap();
+
+        assert_eq!(responder.pings, 1);
+        assert!(observer.seen.is_empty());
+    }
+
+    #[test]
+    fn event_goes_to_relevant_consumer() {
+        let chain = (Observer::default(), (Responder::default(), ()));
+        let event = Either::Right(Event::TimerTick);
+        let ((observer, (responder, ())), sink) = chain.process(sink(), event, &probe()).wait().unwrap();
+
+        assert!(observer.seen.is_empty());
+        assert_eq!(responder.ticks, 1);
+        assert!(sink.into_inner().is_empty());
+        assert_eq!(<(Observer, (Responder, ())) as MessageConsumerChain>::topics(), vec![Topic::Timer]);
+    }
+
+    #[test]
+    fn unhandled_known_message_is_skipped() {
+        // nobody handles the ping, its type is even, but it is known, so the connection stays
+        let chain = (Observer::default(), ());
+        let ((observer, ()), sink) = chain.process(sink(), ping(), &probe()).wait().unwrap();
+
+        assert_eq!(observer.seen, vec!["Ping"]);
+        assert!(sink.into_inner().is_empty());
+    }
+
+    #[test]
+    fn metrics_count_consumed_messages() {
+        let probe = probe();
+        let chain = (Observer::default(), (Responder::default(), ()));
+        let (chain, sink) = chain.process(sink(), ping(), &probe).wait().unwrap();
+        let _ = chain.process(sink, Either::Right(Event::TimerTick), &probe).wait().unwrap();
+
+        let snapshot = probe.metrics().snapshot();
+        let stats = |name| snapshot.iter().find(|&&(n, _)| n == name).map(|&(_, ref s)| s.clone()).unwrap();
+        assert_eq!(stats("observer").messages, 1);
+        assert_eq!(stats("observer").events, 0);
+        assert_eq!(stats("responder").messages, 1);
+        assert_eq!(stats("responder").events, 1);
+    }
 }
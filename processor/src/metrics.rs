@@ -0,0 +1,303 @@
+use dependencies::secp256k1;
+use dependencies::tokio;
+
+use secp256k1::PublicKey;
+use tokio::prelude::{Stream, Poll, Async};
+
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerStats {
+    pub messages: u64,
+    pub events: u64,
+    pub errors: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl ConsumerStats {
+    pub fn average_latency(&self) -> Duration {
+        let count = self.messages + self.events;
+        if count == 0 {
+            Duration::default()
+        } else {
+            // the count does not fit `u32` after a long run
+            let nanos = self.total_latency.as_nanos() / (count as u128);
+            Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueueStats {
+    // the items waiting to be processed right now, for all peers
+    pub depth: u64,
+    // the longest backlog of a single peer
+    pub max_depth: u64,
+}
+
+#[derive(Default)]
+struct Statistics {
+    consumers: BTreeMap<&'static str, ConsumerStats>,
+    queues: BTreeMap<&'static str, QueueStats>,
+}
+
+/// The statistics of each consumer, keyed by `MessageConsumer::NAME`,
+/// and of each queue in front of the consumers, keyed by the name given to `Backlog`,
+/// cheap to clone, every clone refers to the same statistics
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<Statistics>>);
+
+impl Metrics {
+    pub(crate) fn finished(&self, consumer: &'static str, is_event: bool, latency: Duration, failed: bool) {
+        let mut statistics = self.0.lock().unwrap();
+        let stats = statistics.consumers.entry(consumer).or_insert(ConsumerStats::default());
+        if is_event {
+            stats.events += 1;
+        } else {
+            stats.messages += 1;
+        }
+        if failed {
+            stats.errors += 1;
+        }
+        stats.total_latency += latency;
+        if latency > stats.max_latency {
+            stats.max_latency = latency;
+        }
+    }
+
+    // the backlog of the peer's queue has changed from `previous` to `current` items
+    fn backlog_changed(&self, queue: &'static str, previous: usize, current: usize) {
+        let mut statistics = self.0.lock().unwrap();
+        let stats = statistics.queues.entry(queue).or_insert(QueueStats::default());
+        stats.depth = stats.depth + (current as u64) - (previous as u64);
+        if current as u64 > stats.max_depth {
+            stats.max_depth = current as u64;
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, ConsumerStats)> {
+        self.0.lock().unwrap()
+            .consumers
+            .iter()
+            .map(|(name, stats)| (*name, stats.clone()))
+            .collect()
+    }
+
+    pub fn queues(&self) -> Vec<(&'static str, QueueStats)> {
+        self.0.lock().unwrap()
+            .queues
+            .iter()
+            .map(|(name, stats)| (*name, stats.clone()))
+            .collect()
+    }
+}
+
+/// Reads ahead at most `limit` items which are ready in the stream, so the number of the items
+/// waiting for the processor is known. The processor polls the stream when it is done with
+/// the previous item, the backlog is measured at that moment.
+pub struct Backlog<S>
+where
+    S: Stream,
+{
+    inner: S,
+    queue: &'static str,
+    metrics: Metrics,
+    limit: usize,
+    ready: VecDeque<S::Item>,
+    // the end of the stream or its error, it goes after the ready items
+    end: Option<Result<(), S::Error>>,
+    reported: usize,
+}
+
+impl<S> Backlog<S>
+where
+    S: Stream,
+{
+    pub fn new(inner: S, queue: &'static str, metrics: Metrics, limit: usize) -> Self {
+        Backlog {
+            inner: inner,
+            queue: queue,
+            metrics: metrics,
+            limit: limit,
+            ready: VecDeque::new(),
+            end: None,
+            reported: 0,
+        }
+    }
+
+    fn report(&mut self) {
+        let current = self.ready.len();
+        if current != self.reported {
+            self.metrics.backlog_changed(self.queue, self.reported, current);
+            self.reported = current;
+        }
+    }
+}
+
+impl<S> Stream for Backlog<S>
+where
+    S: Stream,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while self.end.is_none() && self.ready.len() < self.limit {
+            match self.inner.poll() {
+                Ok(Async::Ready(Some(item))) => self.ready.push_back(item),
+                Ok(Async::Ready(None)) => self.end = Some(Ok(())),
+                Ok(Async::NotReady) => break,
+                Err(e) => self.end = Some(Err(e)),
+            }
+        }
+
+        // the item taken now is not waiting anymore
+        let item = self.ready.pop_front();
+        self.report();
+        match item {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None => match self.end.take() {
+                // the stream ends after the error
+                Some(Err(e)) => {
+                    self.end = Some(Ok(()));
+                    Err(e)
+                },
+                Some(Ok(())) => {
+                    self.end = Some(Ok(()));
+                    Ok(Async::Ready(None))
+                },
+                None => Ok(Async::NotReady),
+            },
+        }
+    }
+}
+
+impl<S> Drop for Backlog<S>
+where
+    S: Stream,
+{
+    // the items of the closed connection are not waiting anymore
+    fn drop(&mut self) {
+        self.metrics.backlog_changed(self.queue, self.reported, 0);
+    }
+}
+
+/// Instruments the processing of the particular peer's stream
+#[derive(Clone)]
+pub struct Probe {
+    peer: PublicKey,
+    metrics: Metrics,
+}
+
+impl Probe {
+    pub fn new(peer: PublicKey, metrics: Metrics) -> Self {
+        Probe {
+            peer: peer,
+            metrics: metrics,
+        }
+    }
+
+    pub fn peer(&self) -> &PublicKey {
+        &self.peer
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backlog, Metrics, ConsumerStats};
+    use std::time::Duration;
+
+    use dependencies::tokio;
+    use dependencies::futures;
+
+    use tokio::prelude::{Future, Stream, Async};
+    use futures::sync::mpsc;
+    use futures::future::lazy;
+    use futures::stream::{iter_ok, iter_result};
+
+    // the depth and the max depth of the queue
+    fn depth(metrics: &Metrics, queue: &'static str) -> (u64, u64) {
+        metrics.queues().into_iter()
+            .find(|&(name, _)| name == queue)
+            .map(|(_, stats)| (stats.depth, stats.max_depth))
+            .unwrap_or((0, 0))
+    }
+
+    #[test]
+    fn average_latency_of_many_messages() {
+        let stats = ConsumerStats {
+            messages: 1 << 32,
+            events: 1 << 32,
+            total_latency: Duration::from_secs(3 << 33),
+            ..ConsumerStats::default()
+        };
+        assert_eq!(stats.average_latency(), Duration::from_secs(3));
+        assert_eq!(ConsumerStats::default().average_latency(), Duration::default());
+    }
+
+    #[test]
+    fn bus_backlog_is_measured() {
+        let metrics = Metrics::default();
+        let (sender, receiver) = mpsc::unbounded::<u32>();
+        for event in 0..3 {
+            sender.unbounded_send(event).unwrap();
+        }
+
+        // the receiver is polled within the task
+        lazy(move || {
+            let mut backlog = Backlog::new(receiver, "bus", metrics.clone(), usize::max_value());
+            assert_eq!(backlog.poll(), Ok(Async::Ready(Some(0))));
+            assert_eq!(depth(&metrics, "bus"), (2, 2));
+            assert_eq!(backlog.poll(), Ok(Async::Ready(Some(1))));
+            assert_eq!(depth(&metrics, "bus"), (1, 2));
+
+            sender.unbounded_send(3).unwrap();
+            assert_eq!(backlog.poll(), Ok(Async::Ready(Some(2))));
+            assert_eq!(depth(&metrics, "bus"), (1, 2));
+            assert_eq!(backlog.poll(), Ok(Async::Ready(Some(3))));
+            assert_eq!(depth(&metrics, "bus"), (0, 2));
+            assert_eq!(backlog.poll(), Ok(Async::NotReady));
+
+            drop(sender);
+            assert_eq!(backlog.poll(), Ok(Async::Ready(None)));
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn read_ahead_is_limited_and_error_goes_last() {
+        let metrics = Metrics::default();
+        let stream = iter_result(vec![Ok(1), Ok(2), Ok(3), Err("broken")]);
+        let mut backlog = Backlog::new(stream, "stream", metrics.clone(), 2);
+
+        assert_eq!(backlog.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(depth(&metrics, "stream"), (1, 1));
+        assert_eq!(backlog.poll(), Ok(Async::Ready(Some(2))));
+        assert_eq!(backlog.poll(), Ok(Async::Ready(Some(3))));
+        assert_eq!(depth(&metrics, "stream"), (0, 1));
+        assert_eq!(backlog.poll(), Err("broken"));
+        assert_eq!(backlog.poll(), Ok(Async::Ready(None)));
+    }
+
+    #[test]
+    fn backlog_of_closed_connection_is_gone() {
+        let metrics = Metrics::default();
+        let mut first = Backlog::new(iter_ok::<_, ()>(0..3), "stream", metrics.clone(), 16);
+        let mut second = Backlog::new(iter_ok::<_, ()>(0..5), "stream", metrics.clone(), 16);
+        assert_eq!(first.poll(), Ok(Async::Ready(Some(0))));
+        assert_eq!(second.poll(), Ok(Async::Ready(Some(0))));
+        assert_eq!(depth(&metrics, "stream"), (6, 4));
+
+        drop(second);
+        assert_eq!(depth(&metrics, "stream"), (2, 4));
+        drop(first);
+        assert_eq!(depth(&metrics, "stream"), (0, 4));
+    }
+}
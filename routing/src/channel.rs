@@ -24,6 +24,7 @@ use super::node::{NodeRef, Node, NodeLinks};
 use serde_derive::{Serialize, Deserialize};
 
 use common_types::RawPublicKey;
+use processor::Misbehavior;
 
 #[derive(Component, Eq, PartialEq)]
 pub struct Peer {
@@ -115,7 +116,7 @@ impl Edge for ChannelRef {
 
 // TODO: add subsystem to poll if founding output is still there
 // TODO: add rebroadcasting subsystem
-impl<'a> System<'a> for GenericSystem<AnnouncementChannel, ()> {
+impl<'a> System<'a> for GenericSystem<AnnouncementChannel, Option<Misbehavior>> {
     type SystemData = (
         Entities<'a>,
         Read<'a, LazyUpdate>,
@@ -162,21 +163,18 @@ impl<'a> System<'a> for GenericSystem<AnnouncementChannel, ()> {
                     .verify_key_inside(&context, |data| &data.bitcoin_key.1.as_ref())
             };
             let announcement_channel = match r() {
-                Err(_) => {
-                    // TODO: fail the connection
-                    return;
-                },
+                Err(_) => return Some(Misbehavior::InvalidSignature),
                 Ok(Data(s)) => s,
             };
 
             // TODO: check channel id, check if chain hash known
 
-            // check if nodes is not blacklisted
+            // check if nodes is not blacklisted, the announcement is ignored,
+            // the peer which relays it did nothing wrong
             for (_, peer) in (blacklist_mark, &peer).join() {
                 let (ref left, ref right) = announcement_channel.node_id;
                 if left.as_ref().eq(&peer.id) || right.as_ref().eq(&peer.id) {
-                    // fail the connection
-                    return;
+                    return None;
                 }
             }
 
@@ -234,8 +232,8 @@ impl<'a> System<'a> for GenericSystem<AnnouncementChannel, ()> {
                     }
                 }
 
-                // fail the connection
-                return;
+                // the conflicting nodes are blacklisted, not the peer which relays their announcement
+                return None;
             }
 
             let channel_ref = entities.create();
@@ -256,11 +254,13 @@ impl<'a> System<'a> for GenericSystem<AnnouncementChannel, ()> {
             update.insert(channel_ref, id);
             update.insert(channel_ref, this_parties);
             update.insert(channel_ref, ChannelHistory::default());
+
+            None
         });
     }
 }
 
-impl<'a> System<'a> for GenericSystem<UpdateChannel, ()> {
+impl<'a> System<'a> for GenericSystem<UpdateChannel, Option<Misbehavior>> {
     type SystemData = (
         ReadStorage<'a, ChannelId>,
         ReadStorage<'a, ChannelParties>,
@@ -291,7 +291,7 @@ impl<'a> System<'a> for GenericSystem<UpdateChannel, ()> {
                     let update_channel = match (r0, r1) {
                         (Ok(()), _) => update_channel.verify(&context, &parties.lightning.0.as_ref()).unwrap().0,
                         (_, Ok(())) => update_channel.verify(&context, &parties.lightning.1.as_ref()).unwrap().0,
-                        _ => break,
+                        _ => return Some(Misbehavior::InvalidSignature),
                     };
                     history.records.push(ChannelPolicy {
                         timestamp: update_channel.timestamp,
@@ -306,6 +306,8 @@ impl<'a> System<'a> for GenericSystem<UpdateChannel, ()> {
                     break;
                 }
             }
+
+            None
         });
     }
 }
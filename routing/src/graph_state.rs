@@ -21,7 +21,7 @@ use either::Either;
 use secp256k1::PublicKey;
 
 use wire::{Message, MessageExt, Init, AnnouncementNode, AnnouncementChannel, UpdateChannel};
-use processor::{MessageFiltered, MessageConsumer, ConsumingFuture};
+use processor::{MessageFiltered, MessageConsumer, ConsumingFuture, Misbehavior, PeerReporter};
 
 use binformat::WireError;
 
@@ -48,9 +48,9 @@ impl DBUser for State {
 impl State {
     pub fn new(db: Arc<RwLock<DB>>) -> Self {
         let mut world = World::new();
-        world.setup::<<GenericSystem<AnnouncementChannel, ()> as System>::SystemData>();
-        world.setup::<<GenericSystem<UpdateChannel, ()> as System>::SystemData>();
-        world.setup::<<GenericSystem<AnnouncementNode, ()> as System>::SystemData>();
+        world.setup::<<GenericSystem<AnnouncementChannel, Option<Misbehavior>> as System>::SystemData>();
+        world.setup::<<GenericSystem<UpdateChannel, Option<Misbehavior>> as System>::SystemData>();
+        world.setup::<<GenericSystem<AnnouncementNode, Option<Misbehavior>> as System>::SystemData>();
         world.setup::<<GenericSystem<LoadNodes, Result<(), DBError>> as System>::SystemData>();
         world.setup::<<GenericSystem<StoreNodes, Result<(), DBError>> as System>::SystemData>();
         world.setup::<<GenericSystem<LogNodes, ()> as System>::SystemData>();
@@ -76,6 +76,18 @@ impl State {
         system.output()
     }
 
+    // our own public channel, it is checked as the announcement of any other channel,
+    // the update refers to the channel, so the announcement is applied first
+    pub fn announce_channel(&mut self, announcement: AnnouncementChannel, update: UpdateChannel) -> Option<Misbehavior> {
+        if let Some(misbehavior) = self.run(announcement) {
+            return Some(misbehavior);
+        }
+        self.world.maintain();
+        let misbehavior = self.run(update);
+        self.world.maintain();
+        misbehavior
+    }
+
     pub fn load(&mut self) -> Result<(), DBError> {
         self.run(LoadChannels)?;
         self.run(LoadNodes)
@@ -219,28 +231,53 @@ impl MessageFiltered for TopologyMessage {
 #[derive(Clone)]
 pub struct SharedState(pub Arc<RwLock<State>>);
 
-impl MessageConsumer for SharedState {
+impl SharedState {
+    // the consumer of the topology messages from the particular peer
+    pub fn peer(&self, reporter: PeerReporter) -> TopologyPeer {
+        TopologyPeer {
+            state: self.clone(),
+            reporter: reporter,
+        }
+    }
+}
+
+pub struct TopologyPeer {
+    state: SharedState,
+    reporter: PeerReporter,
+}
+
+impl MessageConsumer for TopologyPeer {
     type Message = TopologyMessage;
     type Relevant = ();
 
+    const NAME: &'static str = "routing";
+
     fn consume<S>(self, sink: S, message: Either<Self::Message, Self::Relevant>) -> ConsumingFuture<Self, S>
     where
         S: Sink<SinkItem=MessageExt, SinkError=WireError> + Send + 'static,
     {
         use wire::{Init, RawFeatureVector, FeatureBit::*};
         // TODO(mkl): move init processing somewhere
-        match message.left().unwrap() {
+        let misbehavior: Option<Misbehavior> = match message.left().unwrap() {
             TopologyMessage::Init(_) => {
-                let local = RawFeatureVector::new().set_bit(DataLossProtectRequired).set_bit(DataLossProtectOptional);
+                let local = RawFeatureVector::new()
+                    .set_bit(DataLossProtectRequired)
+                    .set_bit(DataLossProtectOptional)
+                    .set_bit(UpfrontShutdownScriptOptional)
+                    .set_bit(StaticRemoteKeyOptional)
+                    .set_bit(AnchorsZeroFeeHtlcTxOptional);
                 let init = Message::Init(Init::new(RawFeatureVector::new(), local));
                 return ConsumingFuture::from_send(self, sink.send(init.into()));
             },
-            TopologyMessage::AnnouncementNode(v) => self.0.write().unwrap().run(v),
-            TopologyMessage::AnnouncementChannel(v) => self.0.write().unwrap().run(v),
-            TopologyMessage::UpdateChannel(v) => self.0.write().unwrap().run(v),
+            TopologyMessage::AnnouncementNode(v) => self.state.0.write().unwrap().run(v),
+            TopologyMessage::AnnouncementChannel(v) => self.state.0.write().unwrap().run(v),
+            TopologyMessage::UpdateChannel(v) => self.state.0.write().unwrap().run(v),
         };
-        self.0.write().unwrap().world.maintain();
+        self.state.0.write().unwrap().world.maintain();
 
-        ConsumingFuture::ok(self, sink)
+        match misbehavior.map(|m| self.reporter.report(m)) {
+            Some(Err(e)) => ConsumingFuture::err(e),
+            _ => ConsumingFuture::ok(self, sink),
+        }
     }
 }
@@ -7,5 +7,5 @@ mod channel;
 mod tools;
 
 use dependencies::rocksdb;
-pub use self::graph_state::{State, SharedState};
+pub use self::graph_state::{State, SharedState, TopologyPeer};
 pub use rocksdb::Error as DBError;
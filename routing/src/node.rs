@@ -21,6 +21,7 @@ use state::{DB, DBValue};
 use super::channel::{ChannelParties, ChannelLinks, ChannelRef, Side};
 use super::tools::GenericSystem;
 use common_types::RawPublicKey;
+use processor::Misbehavior;
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct NodeRef(pub Entity);
@@ -44,7 +45,7 @@ impl Node {
 }
 
 // TODO: add rebroadcasting subsystem
-impl<'a> System<'a> for GenericSystem<AnnouncementNode, ()> {
+impl<'a> System<'a> for GenericSystem<AnnouncementNode, Option<Misbehavior>> {
     type SystemData = (
         Entities<'a>,
         Read<'a, LazyUpdate>,
@@ -73,8 +74,7 @@ impl<'a> System<'a> for GenericSystem<AnnouncementNode, ()> {
             let context = Secp256k1::verification_only();
             let announcement_node = match announcement_node.verify_key_inside(&context, |s| &s.node_id.as_ref()) {
                 Ok(s) => s.0,
-                // TODO: fail the connection
-                Err(SignError::IncorrectSignature) => return,
+                Err(SignError::IncorrectSignature) => return Some(Misbehavior::InvalidSignature),
                 Err(e) => panic!("error {:?}", e),
             };
 
@@ -109,6 +109,8 @@ impl<'a> System<'a> for GenericSystem<AnnouncementNode, ()> {
 
                 update.insert(node_ref, links);
             }
+
+            None
         });
     }
 }
@@ -75,6 +75,58 @@ pub enum Command {
     #[structopt(name="list-peers")]
     ListPeers,
 
+    /// List banned nodes and addresses
+    #[structopt(name="list-bans")]
+    ListBans,
+
+    /// Ban the node or the ip address
+    #[structopt(name="ban")]
+    Ban {
+        /// the identity pubkey of the node
+        #[structopt(long = "pubkey")]
+        pub_key: Option<String>,
+        /// the ip address, used if pubkey is not specified
+        #[structopt(long = "address")]
+        address: Option<String>,
+        /// duration of the ban in seconds, the node's default if not specified
+        #[structopt(long = "duration")]
+        duration: Option<i64>,
+        #[structopt(long = "reason", default_value = "manual ban")]
+        reason: String,
+    },
+
+    /// Remove the ban of the node or the ip address
+    #[structopt(name="unban")]
+    Unban {
+        /// the identity pubkey of the node
+        #[structopt(long = "pubkey")]
+        pub_key: Option<String>,
+        /// the ip address, used if pubkey is not specified
+        #[structopt(long = "address")]
+        address: Option<String>,
+    },
+
+    /// Switch recording of peer messages, the output is readable by dump-reader
+    #[structopt(name="set-message-dump")]
+    SetMessageDump {
+        /// enable recording, disable if not set
+        #[structopt(long = "enable")]
+        enable: bool,
+        /// the file to write, the node's default if not specified
+        #[structopt(long = "path")]
+        path: Option<String>,
+        /// record only messages of this peer, can be repeated
+        #[structopt(long = "peer")]
+        peers: Vec<String>,
+        /// record only messages of this type, e.g. Ping, can be repeated
+        #[structopt(long = "type")]
+        types: Vec<String>,
+    },
+
+    /// Display the statistics of each consumer of peer messages and the backlog of their queues
+    #[structopt(name="processor-metrics")]
+    ProcessorMetrics,
+
     /// Generate a new p2wkh address (np2wkh is not supported yet)
     #[structopt(name="new-address")]
     NewAddress {
@@ -116,7 +168,7 @@ impl Command {
         use self::Command::*;
         use interface::{
             routing_grpc::{RoutingServiceClient, RoutingService},
-            routing::{ConnectPeerRequest, LightningAddress as LightningAddressRPC, ChannelGraphRequest},
+            routing::{ConnectPeerRequest, LightningAddress as LightningAddressRPC, ChannelGraphRequest, BanRequest, BanTarget, MessageDumpRequest},
             wallet_grpc::{WalletClient, Wallet},
             wallet::{NewAddressRequest, AddressType, GetUtxoListRequest, WalletBalanceRequest, SyncWithTipRequest, SendCoinsRequest},
             common::Void,
@@ -125,6 +177,16 @@ impl Command {
 
         let routing_service = RoutingServiceClient::with_client(client.clone());
         let wallet_service = WalletClient::with_client(client.clone());
+
+        let ban_target = |pub_key: &Option<String>, address: &Option<String>| -> Result<BanTarget, Error> {
+            let mut target = BanTarget::new();
+            match (pub_key, address) {
+                (Some(pub_key), _) => target.set_pub_key(pub_key.clone()),
+                (None, Some(address)) => target.set_address(address.clone()),
+                (None, None) => Err(Error::description("either pubkey or address should be specified"))?,
+            }
+            Ok(target)
+        };
         match self {
             GetInfo => {
                 let response = routing_service
@@ -191,6 +253,57 @@ impl Command {
                 println!("{:?}", response);
                 Ok(())
             },
+            ListBans => {
+                let response = routing_service
+                    .list_bans(Default::default(), Void::new())
+                    .drop_metadata().wait()
+                    .map_err(|e| Error::new(e, "cannot list bans"))?;
+                println!("{:?}", response);
+                Ok(())
+            },
+            Ban { pub_key, address, duration, reason } => {
+                let mut request = BanRequest::new();
+                request.set_target(ban_target(pub_key, address)?);
+                request.set_duration(duration.unwrap_or(0));
+                request.set_reason(reason.clone());
+                let response = routing_service
+                    .ban(Default::default(), request)
+                    .drop_metadata().wait()
+                    .map_err(|e| Error::new(e, "cannot ban"))?;
+                println!("{:?}", response);
+                Ok(())
+            },
+            Unban { pub_key, address } => {
+                let response = routing_service
+                    .unban(Default::default(), ban_target(pub_key, address)?)
+                    .drop_metadata().wait()
+                    .map_err(|e| Error::new(e, "cannot unban"))?;
+                println!("{:?}", response);
+                Ok(())
+            },
+            SetMessageDump { enable, path, peers, types } => {
+                let mut request = MessageDumpRequest::new();
+                request.set_enabled(*enable);
+                if let Some(path) = path {
+                    request.set_path(path.clone());
+                }
+                request.set_peers(peers.clone().into());
+                request.set_types(types.clone().into());
+                let response = routing_service
+                    .set_message_dump(Default::default(), request)
+                    .drop_metadata().wait()
+                    .map_err(|e| Error::new(e, "cannot set message dump"))?;
+                println!("{:?}", response);
+                Ok(())
+            },
+            ProcessorMetrics => {
+                let response = routing_service
+                    .processor_metrics(Default::default(), Void::new())
+                    .drop_metadata().wait()
+                    .map_err(|e| Error::new(e, "cannot get processor metrics"))?;
+                println!("{:?}", response);
+                Ok(())
+            },
             NewAddress { address_type } => {
                 let mut request = NewAddressRequest::new();
                 if address_type == "p2wkh" {
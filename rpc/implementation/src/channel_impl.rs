@@ -1,28 +1,59 @@
 use dependencies::grpc;
 use dependencies::futures;
 use dependencies::secp256k1;
+use dependencies::bitcoin_hashes;
+use dependencies::rand;
+use dependencies::hex;
 
-use grpc::{rt::ServerServiceDefinition, RequestOptions, SingleResponse, StreamingResponse};
+use grpc::{rt::ServerServiceDefinition, RequestOptions, SingleResponse, StreamingRequest, StreamingResponse};
 use grpc::Error;
 use interface::channel_grpc::{ChannelServiceServer, ChannelService};
 use interface::channel::{
     ChannelFilter, ChannelList, PendingChannelsResponse,
     OpenChannelRequest, OpenStatusUpdate, CloseChannelRequest, CloseStatusUpdate,
+    ChannelAcceptRequest, ChannelAcceptResponse,
 };
 use interface::common::Void;
-use connection::{AbstractAddress, Command};
-use connection::Node;
-use std::sync::{RwLock, Arc};
+use connection::{AbstractAddress, Command, ChannelAcceptor, ChannelRequest, ChannelDecision, ChannelOverrides, ChannelSubscriptions};
 use std::net::SocketAddr;
 use std::fmt::Debug;
+use futures::{stream, Stream};
 use futures::sync::mpsc::Sender;
 use secp256k1::PublicKey;
-use internal_event::{DirectCommand, ChannelCommand};
+use internal_event::{Event, EventBus, DirectCommand, ChannelCommand, NewChannel};
 
-pub fn service(node: Arc<RwLock<Node>>, control: Sender<Command<SocketAddr>>) -> ServerServiceDefinition {
+// BOLT 2: the channel id is the funding txid XOR the output index, the string is the txid
+// hex-encoded in the reversed byte order, as the bitcoin displays it
+fn channel_id(request: CloseChannelRequest) -> Result<wire::ChannelId, Error> {
+    let mut request = request;
+    let mut point = request.take_channel_point();
+    let mut txid = point.take_funding_txid_bytes();
+    if txid.is_empty() {
+        txid = hex::decode(point.take_funding_txid_str().as_bytes())
+            .map_err(|e| Error::Panic(format!("wrong funding txid: {:?}", e)))?;
+        txid.reverse();
+    }
+    if txid.len() != 32 {
+        return Err(Error::Panic("wrong size of founding txid".to_owned()))
+    }
+    let output_index = point.get_output_index();
+    if output_index > 0xffff {
+        return Err(Error::Panic(format!("wrong funding output index {}", output_index)))
+    }
+    let mut data = [0; 32];
+    data.copy_from_slice(txid.as_slice());
+    data[30] ^= (output_index >> 8) as u8;
+    data[31] ^= (output_index & 0xff) as u8;
+    Ok(wire::ChannelId {
+        data: data,
+    })
+}
+
+pub fn service(control: Sender<Command<SocketAddr>>, bus: EventBus, acceptor: ChannelAcceptor) -> ServerServiceDefinition {
     ChannelServiceServer::new_service_def(ChannelImpl {
-        node: node,
         control: control,
+        bus: bus,
+        acceptor: acceptor,
     })
 }
 
@@ -30,14 +61,113 @@ struct ChannelImpl<A>
 where
     A: AbstractAddress,
 {
-    node: Arc<RwLock<Node>>,
     control: Sender<Command<A>>,
+    bus: EventBus,
+    acceptor: ChannelAcceptor,
 }
 
 fn error<E>(e: E) -> Error where E: Debug {
     Error::Panic(format!("{:?}", e))
 }
 
+// the updates of the channel, the stream ends right after the error or the last update
+fn until_last<S, F>(updates: S, last: F) -> impl Stream<Item=S::Item, Error=Error>
+where
+    S: Stream<Error=Error>,
+    F: Fn(&S::Item) -> bool,
+{
+    updates
+        .then(move |update| {
+            // `None` marks the end of the stream
+            let end = match &update {
+                &Ok(ref update) if !last(update) => None,
+                _ => Some(None),
+            };
+            Ok(stream::iter_ok::<_, Error>(Some(Some(update)).into_iter().chain(end)))
+        })
+        .flatten()
+        .take_while(|update| Ok(update.is_some()))
+        .and_then(|update| update.unwrap())
+}
+
+fn accept_request(request: ChannelRequest) -> ChannelAcceptRequest {
+    use interface::channel::CommitmentType;
+    use interface::common::{Satoshi, MilliSatoshi};
+
+    let satoshi = |value: wire::Satoshi| {
+        let mut v = Satoshi::new();
+        v.set_value(u64::from(value));
+        v
+    };
+    let millisatoshi = |value: wire::MilliSatoshi| {
+        let mut v = MilliSatoshi::new();
+        v.set_value(u64::from(value));
+        v
+    };
+
+    let msg = request.open_channel;
+    let mut r = ChannelAcceptRequest::new();
+    r.set_node_pubkey(request.peer.serialize().to_vec());
+    r.set_chain_hash(msg.chain_hash.as_ref().to_vec());
+    r.set_pending_chan_id(msg.temporary_channel_id.data.to_vec());
+    r.set_funding_amt(satoshi(msg.funding));
+    r.set_push_amt(millisatoshi(msg.push));
+    r.set_dust_limit(satoshi(msg.dust_limit));
+    r.set_max_value_in_flight(millisatoshi(msg.max_in_flight));
+    r.set_channel_reserve(satoshi(msg.channel_reserve));
+    r.set_min_htlc(millisatoshi(msg.htlc_minimum));
+    r.set_fee_per_kw(u32::from(msg.fee) as u64);
+    r.set_csv_delay(u16::from(msg.csv_delay) as u32);
+    r.set_max_accepted_htlcs(msg.max_accepted_htlc_number as u32);
+    r.set_channel_flags(msg.flags.0 as u32);
+    r.set_commitment_type(if request.anchors {
+        CommitmentType::ANCHORS
+    } else if request.static_remotekey {
+        CommitmentType::STATIC_REMOTE_KEY
+    } else {
+        CommitmentType::LEGACY
+    });
+    r
+}
+
+// the peer, the temporary channel id and the decision on it, zero parameters mean the node's policy
+fn decision(response: ChannelAcceptResponse) -> Result<((PublicKey, wire::ChannelId), ChannelDecision), Error> {
+    let mut response = response;
+    let peer = PublicKey::from_slice(response.get_node_pubkey()).map_err(error)?;
+    let channel_id = response.take_pending_chan_id();
+    if channel_id.len() != 32 {
+        return Err(Error::Panic("wrong size of pending channel id".to_owned()));
+    }
+    let mut data = [0; 32];
+    data.copy_from_slice(channel_id.as_slice());
+    let channel_id = wire::ChannelId {
+        data: data,
+    };
+    if !response.get_accept() {
+        let reason = response.take_error();
+        let reason = if reason.is_empty() { "the channel is rejected".to_owned() } else { reason };
+        return Ok(((peer, channel_id), ChannelDecision::Reject(reason)));
+    }
+
+    let csv_delay = response.get_csv_delay();
+    if csv_delay > u16::max_value() as u32 {
+        return Err(Error::Panic("the csv delay is too large".to_owned()));
+    }
+    let max_htlc_count = response.get_max_htlc_count();
+    if max_htlc_count > u16::max_value() as u32 {
+        return Err(Error::Panic("the maximum number of htlcs is too large".to_owned()));
+    }
+    let min_htlc_in = response.get_min_htlc_in().get_value();
+    let min_accept_depth = response.get_min_accept_depth();
+    let overrides = ChannelOverrides {
+        csv_delay: if csv_delay == 0 { None } else { Some(csv_delay as u16) },
+        htlc_minimum: if min_htlc_in == 0 { None } else { Some(min_htlc_in) },
+        max_accepted_htlc_number: if max_htlc_count == 0 { None } else { Some(max_htlc_count as u16) },
+        minimum_depth: if min_accept_depth == 0 { None } else { Some(min_accept_depth) },
+    };
+    Ok(((peer, channel_id), ChannelDecision::Accept(overrides)))
+}
+
 impl ChannelService for ChannelImpl<SocketAddr> {
     fn list(&self, o: RequestOptions, p: ChannelFilter) -> SingleResponse<ChannelList> {
         let _ = (o, p);
@@ -50,83 +180,218 @@ impl ChannelService for ChannelImpl<SocketAddr> {
     }
 
     fn open(&self, o: RequestOptions, p: OpenChannelRequest) -> StreamingResponse<OpenStatusUpdate> {
-        use futures::{Sink, Future, future, stream::Stream};
-        use connection::ChannelStatus;
-        use interface::channel::ChannelOpenUpdate;
+        use futures::{Sink, Future, future};
+        use internal_event::ChannelStatus;
+        use interface::channel::{ChannelOpenUpdate, ChannelPoint, PendingUpdate, ConfirmationUpdate};
+        use bitcoin_hashes::Hash;
+        use wire::ChannelId;
 
         let _ = o;
 
-        fn open_channel_command(request: OpenChannelRequest) -> Result<(Command<SocketAddr>, PublicKey), Error> {
+        fn open_channel_command(request: OpenChannelRequest) -> Result<(Command<SocketAddr>, ChannelId), Error> {
+            use wire::{Satoshi, MilliSatoshi, CsvDelay};
+
             let pk = PublicKey::from_slice(request.get_node_pubkey()).map_err(error)?;
+            let funding = request.get_local_funding_amount().get_value();
+            if funding == 0 {
+                return Err(Error::Panic("the local funding amount should be set".to_owned()));
+            }
+            let push = request.get_push().get_value();
+            if push > funding {
+                return Err(Error::Panic("the push amount exceeds the local funding amount".to_owned()));
+            }
+            let min_htlc = request.get_min_htlc().get_value();
+            let csv_delay = request.get_remote_csv_delay();
+            if csv_delay > u16::max_value() as u32 {
+                return Err(Error::Panic("the remote csv delay is too large".to_owned()));
+            }
+            // zero means the node's default
+            let temporary_channel_id = ChannelId::from(rand::random::<[u8; 32]>());
+            let new_channel = NewChannel {
+                temporary_channel_id: temporary_channel_id,
+                funding: Satoshi::from(funding),
+                push: MilliSatoshi::from(push * 1000),
+                htlc_minimum: if min_htlc == 0 { None } else { Some(MilliSatoshi::from(min_htlc)) },
+                csv_delay: if csv_delay == 0 { None } else { Some(CsvDelay::from(csv_delay as u16)) },
+                fee: None,
+                private: request.get_private(),
+            };
             let command = Command::DirectCommand {
-                destination: pk.clone(),
-                command: DirectCommand::NewChannel,
+                destination: pk,
+                command: DirectCommand::NewChannel(new_channel),
             };
-            Ok((command, pk))
+            Ok((command, temporary_channel_id))
         }
 
         match open_channel_command(p) {
             Err(e) => StreamingResponse::no_metadata(future::err(e).into_stream()),
-            Ok((command, public_key)) => {
-                let receiver = self.node.write().unwrap()
-                    .take_channel_status_stream(&public_key).expect("peer is not connected yet");
-                let stream = self.control.clone()
+            Ok((command, temporary_channel_id)) => {
+                // subscribe before sending the command, so no update is missed,
+                // the channel is followed from the temporary id to the final one
+                let updates = ChannelSubscriptions::new(self.bus.clone())
+                    .subscribe_channel(temporary_channel_id)
+                    .map_err(Error::Panic);
+                let updates = self.control.clone()
                     .send(command)
                     .map_err(error)
-                    .map(|_| receiver.map_err(|()| Error::Panic("Cannot send channel update".to_owned())))
+                    .map(|_| updates)
                     .into_stream()
                     .flatten()
-                    .map(|status: ChannelStatus| {
+                    .and_then(|event| {
                         let mut response = OpenStatusUpdate::new();
-                        match status {
+                        match event.status {
+                            ChannelStatus::Pending => response.set_chan_pending(PendingUpdate::new()),
+                            ChannelStatus::Confirmation => response.set_confirmation(ConfirmationUpdate::new()),
                             ChannelStatus::Open => {
                                 let mut update = ChannelOpenUpdate::new();
-                                update.set_channel_point(unimplemented!());
+                                if let Some((txid, output_index)) = event.funding_outpoint {
+                                    let mut point = ChannelPoint::new();
+                                    point.set_funding_txid_bytes(txid.into_inner().to_vec());
+                                    point.set_output_index(output_index);
+                                    update.set_channel_point(point);
+                                }
                                 response.set_chan_open(update)
                             },
-                            _ => unimplemented!(),
+                            ChannelStatus::Closing => return Ok(None),
+                            ChannelStatus::Closed => return Err(Error::Panic("the channel is closed".to_owned())),
+                            ChannelStatus::Error(description) => return Err(Error::Panic(description)),
                         };
-                        response
-                    });
+                        Ok(Some(response))
+                    })
+                    .filter_map(|response| response);
+                // the stream is finished when the channel is open, or with the error
+                let stream = until_last(updates, OpenStatusUpdate::has_chan_open);
                 StreamingResponse::no_metadata(stream)
             }
         }
     }
 
     fn close(&self, o: RequestOptions, p: CloseChannelRequest) -> StreamingResponse<CloseStatusUpdate> {
-        use futures::{Sink, Future, future, stream::Stream};
-        use wire::ChannelId;
+        use futures::{Future, future};
+        use bitcoin_hashes::Hash;
 
         let _ = o;
 
-        fn channel_id(request: CloseChannelRequest) -> Result<ChannelId, Error> {
-
-            let mut request = request;
-            let mut point = request.take_channel_point();
-            let mut channel_id = point.take_funding_txid_bytes();
-            if channel_id.is_empty() {
-                channel_id = point.take_funding_txid_str().into_bytes();
-            }
-            if channel_id.len() != 32 {
-                return Err(Error::Panic("wrong size of founding txid".to_owned()))
-            }
-            let mut data = [0; 32];
-            data.copy_from_slice(channel_id.as_slice());
-            Ok(ChannelId {
-                data: data,
-            })
-        }
+        let force = p.get_force();
 
         match channel_id(p) {
             Err(e) => StreamingResponse::no_metadata(future::err(e).into_stream()),
             Ok(channel_id) => {
-                let command = Command::<SocketAddr>::ChannelCommand {
-                    destination: channel_id,
-                    command: ChannelCommand::CloseChannel,
-                };
+                use internal_event::ChannelStatus;
+                use interface::channel::{PendingUpdate, ChannelCloseUpdate};
 
-                unimplemented!()
+                let updates = ChannelSubscriptions::new(self.bus.clone())
+                    .subscribe_channel(channel_id)
+                    .map_err(Error::Panic);
+                self.bus.publish(Event::ChannelCommand {
+                    channel_id: channel_id,
+                    command: ChannelCommand::CloseChannel { force: force },
+                });
+
+                let updates = updates
+                    .and_then(|event| {
+                        let mut response = CloseStatusUpdate::new();
+                        match event.status {
+                            ChannelStatus::Closing => {
+                                let mut update = PendingUpdate::new();
+                                if let Some(txid) = event.closing_txid {
+                                    update.set_txid(txid.into_inner().to_vec());
+                                }
+                                response.set_close_pending(update)
+                            },
+                            ChannelStatus::Closed => {
+                                let mut update = ChannelCloseUpdate::new();
+                                if let Some(txid) = event.closing_txid {
+                                    update.set_closing_txid(txid.into_inner().to_vec());
+                                }
+                                update.set_success(true);
+                                response.set_chan_close(update)
+                            },
+                            ChannelStatus::Error(description) => return Err(Error::Panic(description)),
+                            _ => return Ok(None),
+                        };
+                        Ok(Some(response))
+                    })
+                    .filter_map(|response| response);
+                // the stream is finished when the channel is closed, or with the error
+                // if the channel is unknown or rejects the command
+                let stream = until_last(updates, CloseStatusUpdate::has_chan_close);
+                StreamingResponse::no_metadata(stream)
             }
         }
     }
+
+    fn channel_acceptor(&self, o: RequestOptions, p: StreamingRequest<ChannelAcceptResponse>) -> StreamingResponse<ChannelAcceptRequest> {
+        use futures::sync::oneshot;
+        use std::sync::{Arc, Mutex};
+        use std::collections::HashMap;
+
+        let _ = o;
+
+        // the requests wait for the decisions of the client, they are rejected if the client is gone
+        let pending = Arc::new(Mutex::new(HashMap::<(PublicKey, wire::ChannelId), oneshot::Sender<ChannelDecision>>::new()));
+        let pending_requests = pending.clone();
+        let requests = self.acceptor.subscribe()
+            .map_err(|()| Error::Panic("the channel acceptor is gone".to_owned()))
+            .map(move |(request, sender)| {
+                let key = (request.peer.clone(), request.open_channel.temporary_channel_id);
+                pending_requests.lock().unwrap().insert(key, sender);
+                Some(accept_request(request))
+            });
+        // the decisions produce nothing, they are mixed into the stream of the requests to be consumed
+        let decisions = p.0
+            .map(move |response| {
+                match decision(response) {
+                    Ok((key, decision)) => match pending.lock().unwrap().remove(&key) {
+                        Some(sender) => {
+                            let _ = sender.send(decision);
+                        },
+                        None => println!("WARNING: the decision on unknown channel {:?} of {}", key.1, key.0),
+                    },
+                    Err(e) => println!("WARNING: wrong decision of the channel acceptor: {:?}", e),
+                }
+                None
+            });
+        let stream = requests
+            .select(decisions)
+            .filter_map(|request| request);
+        StreamingResponse::no_metadata(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel_id, CloseChannelRequest};
+    use interface::channel::ChannelPoint;
+
+    fn request(point: ChannelPoint) -> CloseChannelRequest {
+        let mut request = CloseChannelRequest::new();
+        request.set_channel_point(point);
+        request
+    }
+
+    #[test]
+    fn channel_id_of_the_channel_point() {
+        let mut txid = [0; 32];
+        txid[0] = 0x01;
+        txid[31] = 0x42;
+        let mut expected = txid;
+        expected[30] ^= 0x01;
+        expected[31] ^= 0x02;
+
+        let mut point = ChannelPoint::new();
+        point.set_funding_txid_bytes(txid.to_vec());
+        point.set_output_index(0x0102);
+        assert_eq!(channel_id(request(point)).unwrap().data, expected);
+
+        // the string is the reversed byte order
+        let mut point = ChannelPoint::new();
+        point.set_funding_txid_str(format!("42{}01", "00".repeat(30)));
+        point.set_output_index(0x0102);
+        assert_eq!(channel_id(request(point)).unwrap().data, expected);
+
+        let mut point = ChannelPoint::new();
+        point.set_funding_txid_str("not a txid".to_owned());
+        assert!(channel_id(request(point)).is_err());
+    }
 }
@@ -7,8 +7,10 @@ use grpc::{rt::ServerServiceDefinition, RequestOptions, SingleResponse};
 use grpc::Error;
 use interface::routing_grpc::{RoutingServiceServer, RoutingService};
 use interface::routing::{SignMessageRequest, SignMessageResponse, ConnectPeerRequest, PeerList, Info, ChannelGraphRequest, ChannelGraph, QueryRoutesRequest, RouteList};
+use interface::routing::{BanList, BanInfo, BanRequest, BanTarget as BanTargetRPC, MessageDumpRequest};
+use interface::routing::{ConsumerMetrics, QueueMetrics, ProcessorMetricsResponse};
 use interface::common::Void;
-use connection::{Node, Command, AbstractAddress};
+use connection::{Node, Command, AbstractAddress, Ban, BanTarget, DumpFilter};
 use std::sync::{RwLock, Arc};
 use std::net::SocketAddr;
 use std::fmt::Debug;
@@ -33,6 +35,35 @@ fn error<E>(e: E) -> Error where E: Debug {
     Error::Panic(format!("{:?}", e))
 }
 
+fn parse_ban_target(target: BanTargetRPC) -> Result<BanTarget, Error> {
+    use secp256k1::PublicKey;
+
+    let mut target = target;
+    let pk = target.take_pub_key();
+    if !pk.is_empty() {
+        let pk = hex::decode(pk.as_bytes()).map_err(error)?;
+        let pk = PublicKey::from_slice(pk.as_slice()).map_err(error)?;
+        Ok(BanTarget::Node(pk.into()))
+    } else {
+        let address = target.take_address().parse().map_err(error)?;
+        Ok(BanTarget::Address(address))
+    }
+}
+
+fn ban_info(ban: Ban) -> BanInfo {
+    let mut target = BanTargetRPC::new();
+    match ban.target {
+        BanTarget::Node(pk) => target.set_pub_key(pk.to_hex()),
+        BanTarget::Address(address) => target.set_address(address.to_string()),
+    }
+
+    let mut info = BanInfo::new();
+    info.set_target(target);
+    info.set_until(ban.until);
+    info.set_reason(ban.reason);
+    info
+}
+
 impl RoutingService for RoutingImpl<SocketAddr> {
     fn sign_message(&self, o: RequestOptions, p: SignMessageRequest) -> SingleResponse<SignMessageResponse> {
         let _ = o;
@@ -158,4 +189,122 @@ impl RoutingService for RoutingImpl<SocketAddr> {
             Err(e) => SingleResponse::no_metadata(err(e)),
         }
     }
+
+    fn list_bans(&self, o: RequestOptions, p: Void) -> SingleResponse<BanList> {
+        use futures::future::err;
+
+        let _ = (o, p);
+
+        match self.node.read().unwrap().list_bans() {
+            Ok(bans) => {
+                let mut response = BanList::new();
+                response.set_bans(bans.into_iter().map(ban_info).collect());
+                SingleResponse::completed(response)
+            },
+            Err(e) => SingleResponse::no_metadata(err(error(e))),
+        }
+    }
+
+    fn ban(&self, o: RequestOptions, p: BanRequest) -> SingleResponse<BanInfo> {
+        use futures::future::err;
+
+        let _ = o;
+
+        let mut request = p;
+        let duration = match request.get_duration() {
+            0 => None,
+            d => Some(d),
+        };
+        let reason = request.take_reason();
+        let r = parse_ban_target(request.take_target())
+            .and_then(|target| {
+                self.node.read().unwrap().ban(target, duration, reason).map_err(error)
+            });
+        match r {
+            Ok(ban) => SingleResponse::completed(ban_info(ban)),
+            Err(e) => SingleResponse::no_metadata(err(e)),
+        }
+    }
+
+    fn unban(&self, o: RequestOptions, p: BanTargetRPC) -> SingleResponse<Void> {
+        use futures::future::err;
+
+        let _ = o;
+
+        let r = parse_ban_target(p)
+            .and_then(|target| {
+                self.node.read().unwrap().unban(&target).map_err(error)
+            });
+        match r {
+            Ok(()) => SingleResponse::completed(Void::new()),
+            Err(e) => SingleResponse::no_metadata(err(e)),
+        }
+    }
+
+    fn set_message_dump(&self, o: RequestOptions, p: MessageDumpRequest) -> SingleResponse<Void> {
+        use futures::future::err;
+        use secp256k1::PublicKey;
+        use std::path::PathBuf;
+
+        let _ = o;
+
+        let recorder = self.node.read().unwrap().recorder();
+        if !p.get_enabled() {
+            recorder.disable();
+            return SingleResponse::completed(Void::new());
+        }
+
+        fn parse_filter(request: &MessageDumpRequest) -> Result<DumpFilter, Error> {
+            let mut filter = DumpFilter::default();
+            for pk in request.get_peers() {
+                let pk = hex::decode(pk.as_bytes()).map_err(error)?;
+                filter.peers.insert(PublicKey::from_slice(pk.as_slice()).map_err(error)?);
+            }
+            filter.types = request.get_types().iter().cloned().collect();
+            Ok(filter)
+        }
+
+        match parse_filter(&p) {
+            Ok(filter) => {
+                let path = match p.get_path() {
+                    "" => None,
+                    path => Some(PathBuf::from(path)),
+                };
+                recorder.enable(path, filter);
+                SingleResponse::completed(Void::new())
+            },
+            Err(e) => SingleResponse::no_metadata(err(e)),
+        }
+    }
+
+    fn processor_metrics(&self, o: RequestOptions, p: Void) -> SingleResponse<ProcessorMetricsResponse> {
+        let _ = (o, p);
+
+        let metrics = self.node.read().unwrap().metrics();
+        let consumers = metrics.snapshot().into_iter()
+            .map(|(name, stats)| {
+                let mut consumer = ConsumerMetrics::new();
+                consumer.set_name(name.to_owned());
+                consumer.set_messages(stats.messages);
+                consumer.set_events(stats.events);
+                consumer.set_errors(stats.errors);
+                consumer.set_average_latency_us(stats.average_latency().as_micros() as u64);
+                consumer.set_max_latency_us(stats.max_latency.as_micros() as u64);
+                consumer
+            })
+            .collect::<Vec<_>>();
+        let queues = metrics.queues().into_iter()
+            .map(|(name, stats)| {
+                let mut queue = QueueMetrics::new();
+                queue.set_name(name.to_owned());
+                queue.set_depth(stats.depth);
+                queue.set_max_depth(stats.max_depth);
+                queue
+            })
+            .collect::<Vec<_>>();
+        let mut response = ProcessorMetricsResponse::new();
+        response.set_consumers(consumers.into());
+        response.set_queues(queues.into());
+        SingleResponse::completed(response)
+    }
 }
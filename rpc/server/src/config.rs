@@ -1,5 +1,6 @@
 use dependencies::tls_api;
 use dependencies::tls_api_rustls;
+use dependencies::bitcoin;
 
 use tls_api_rustls::TlsAcceptor;
 use tls_api::Error as TlsError;
@@ -9,6 +10,8 @@ use std::path::PathBuf;
 use std::fs::File;
 
 use structopt::StructOpt;
+use connection::TowerAddress;
+use bitcoin::network::constants::Network;
 use std::io::Read;
 
 use tls_api_rustls::{
@@ -31,6 +34,10 @@ pub struct Config {
     #[structopt(long="db-path", parse(from_os_str), default_value="target/db")]
     pub db_path: PathBuf,
 
+    /// Network of the wallet and the channels: mainnet, testnet or regtest
+    #[structopt(long="network", default_value="regtest", parse(try_from_str = parse_network))]
+    pub network: Network,
+
     /// Do not use TLS for rpc connections
     #[structopt(long="rpc-no-tls")]
     pub rpc_no_tls: bool,
@@ -43,6 +50,126 @@ pub struct Config {
     #[structopt(long="rpc-tls-cert-path", parse(from_os_str))]
     pub rpc_tls_cert_path: Option<PathBuf>,
 
+    /// File containing the passphrase to encrypt the node's identity key stored in the db path,
+    /// the passphrase is taken from LPD_IDENTITY_PASSPHRASE environment variable if not set
+    #[structopt(long="identity-passphrase-file", parse(from_os_str))]
+    pub identity_passphrase_file: Option<PathBuf>,
+
+    /// Store the node's identity key without passphrase
+    #[structopt(long="no-identity-passphrase")]
+    pub no_identity_passphrase: bool,
+
+    /// Import hex encoded node's identity secret key and exit, useful for migration
+    #[structopt(long="import-identity")]
+    pub import_identity: Option<String>,
+
+    /// Print hex encoded node's identity secret key and exit
+    #[structopt(long="export-identity")]
+    pub export_identity: bool,
+
+    /// Misbehavior score at which the peer is disconnected
+    #[structopt(long="disconnect-score", default_value="50")]
+    pub disconnect_score: u32,
+
+    /// Misbehavior score at which the peer is banned
+    #[structopt(long="ban-score", default_value="100")]
+    pub ban_score: u32,
+
+    /// Duration of the ban in seconds
+    #[structopt(long="ban-duration", default_value="86400")]
+    pub ban_duration: i64,
+
+    /// Seconds after which one point of the misbehavior score is forgiven
+    #[structopt(long="score-decay", default_value="60")]
+    pub score_decay: i64,
+
+    /// Ban the public ip address of the banned peer too
+    #[structopt(long="ban-address")]
+    pub ban_address: bool,
+
+    /// Minimal size in satoshi of the channel which the peer opens with us
+    #[structopt(long="min-channel-size", default_value="20000")]
+    pub min_channel_size: u64,
+
+    /// Maximal size in satoshi of the channel which the peer opens with us
+    #[structopt(long="max-channel-size", default_value="16777215")]
+    pub max_channel_size: u64,
+
+    /// Maximal delay in blocks of our funds which the peer may require when the channel is closed
+    #[structopt(long="max-csv-delay", default_value="2016")]
+    pub max_csv_delay: u16,
+
+    /// Delay in blocks of the peer's funds when the channel is closed
+    #[structopt(long="csv-delay", default_value="144")]
+    pub csv_delay: u16,
+
+    /// Minimal reserve in satoshi which the peer may require us to keep
+    #[structopt(long="min-channel-reserve", default_value="546")]
+    pub min_channel_reserve: u64,
+
+    /// Maximal reserve which the peer may require us to keep, in percents of the funding
+    #[structopt(long="max-channel-reserve-percent", default_value="20")]
+    pub max_channel_reserve_percent: u64,
+
+    /// Reserve which we require the peer to keep, in percents of the funding
+    #[structopt(long="channel-reserve-percent", default_value="1")]
+    pub channel_reserve_percent: u64,
+
+    /// Minimal dust limit in satoshi of the peer's commitment
+    #[structopt(long="min-dust-limit", default_value="354")]
+    pub min_dust_limit: u64,
+
+    /// Maximal dust limit in satoshi of the peer's commitment
+    #[structopt(long="max-dust-limit", default_value="20000")]
+    pub max_dust_limit: u64,
+
+    /// Dust limit in satoshi of our commitment
+    #[structopt(long="dust-limit", default_value="546")]
+    pub dust_limit: u64,
+
+    /// Maximal number of HTLCs which the peer may offer us at once, at most 483
+    #[structopt(long="max-accepted-htlcs", default_value="483")]
+    pub max_accepted_htlcs: u16,
+
+    /// Confirmations of the funding transaction before the channel opened by the peer operates
+    #[structopt(long="minimum-depth", default_value="3")]
+    pub minimum_depth: u32,
+
+    /// Seconds to wait for the decision of the channel acceptor client on the channel opened by the peer
+    #[structopt(long="channel-acceptor-timeout", default_value="30")]
+    pub channel_acceptor_timeout: u64,
+
+    /// Fee rate in satoshi per vbyte of the commitment transactions while bitcoind has no estimate,
+    /// the channels opened by us follow the estimate
+    #[structopt(long="fee-rate", default_value="1")]
+    pub fee_rate: u64,
+
+    /// Do not follow bitcoind's fee estimate, the fee rate is always `--fee-rate`
+    #[structopt(long="static-fee-rate")]
+    pub static_fee_rate: bool,
+
+    /// Watchtower as pubkey@host:port, the justice transactions of our channels are uploaded to it,
+    /// may be repeated
+    #[structopt(long="watchtower")]
+    pub watchtowers: Vec<TowerAddress>,
+
+    /// Address to serve the watchtower clients at, the justice transactions they upload
+    /// are broadcast when the breach is mined. The tower is disabled if not set
+    #[structopt(long="watchtower-listen")]
+    pub watchtower_address: Option<SocketAddr>,
+
+    /// Record all peer messages into the file readable by dump-reader, can be switched by rpc
+    #[structopt(long="dump-path", parse(from_os_str))]
+    pub dump_path: Option<PathBuf>,
+
+    /// Size of the message dump file in bytes after which it is rotated
+    #[structopt(long="dump-max-size", default_value="67108864")]
+    pub dump_max_size: u64,
+
+    /// Number of rotated message dump files to keep
+    #[structopt(long="dump-max-files", default_value="4")]
+    pub dump_max_files: usize,
+
     /// Print configuration information and exit. Useful for debugging
     #[structopt(long="print-config")]
     pub print_config: bool,
@@ -53,6 +180,15 @@ pub struct Config {
 }
 
 
+fn parse_network(s: &str) -> Result<Network, String> {
+    match s {
+        "mainnet" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(format!("unknown network {}, expected mainnet, testnet or regtest", s)),
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Tls {
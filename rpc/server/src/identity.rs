@@ -0,0 +1,305 @@
+use std::path::Path;
+use std::{env, fs};
+use std::io::{self, Write};
+
+use dependencies::chacha20_poly1305_aead;
+use dependencies::hmac;
+use dependencies::hex;
+use dependencies::rand;
+use dependencies::secp256k1;
+
+use secp256k1::SecretKey;
+use common_types::Sha256HashEngine;
+
+// the file in the db path where the encrypted identity key is stored
+const IDENTITY_FILE_NAME: &str = "identity";
+
+// the environment variable holding the passphrase, when the passphrase file is not given
+const PASSPHRASE_VAR: &str = "LPD_IDENTITY_PASSPHRASE";
+
+// version || iterations || salt || ciphertext || tag,
+// the nonce is always zero because the key is unique per salt
+const VERSION: u8 = 2;
+const ITERATIONS_SIZE: usize = 4;
+const SALT_SIZE: usize = 32;
+const KEY_SIZE: usize = 32;
+const MAC_SIZE: usize = 16;
+const HEADER_SIZE: usize = 1 + ITERATIONS_SIZE;
+const FILE_SIZE: usize = HEADER_SIZE + SALT_SIZE + KEY_SIZE + MAC_SIZE;
+
+// pbkdf2 iterations for the new identity files, the stored count is used for decryption
+const ITERATIONS: u32 = 200_000;
+// refuse the files which would make the key derivation too cheap or too slow
+const MIN_ITERATIONS: u32 = 1_000;
+const MAX_ITERATIONS: u32 = 100_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        inner: io::Error,
+        description: String,
+    },
+    // wrong passphrase or the file is corrupted
+    Decrypt,
+    BadFormat {
+        description: String,
+    },
+    AlreadyExists {
+        description: String,
+    },
+    NoPassphrase {
+        description: String,
+    },
+}
+
+impl Error {
+    fn io(inner: io::Error, description: &str) -> Self {
+        Error::Io {
+            inner: inner,
+            description: description.to_owned(),
+        }
+    }
+
+    fn bad_format(description: &str) -> Self {
+        Error::BadFormat {
+            description: description.to_owned(),
+        }
+    }
+}
+
+// pbkdf2 hmac sha256, only one block is needed for the 32 bytes key
+fn encryption_key(salt: &[u8], passphrase: &str, iterations: u32) -> [u8; KEY_SIZE] {
+    use hmac::{Hmac, Mac};
+
+    let prf = Hmac::<Sha256HashEngine>::new_varkey(passphrase.as_bytes()).unwrap();
+
+    let mut mac = prf.clone();
+    mac.input(salt);
+    mac.input(&1u32.to_be_bytes());
+    let mut u = [0; KEY_SIZE];
+    u.copy_from_slice(mac.result().code().as_slice());
+
+    let mut okm = u;
+    for _ in 1..iterations {
+        let mut mac = prf.clone();
+        mac.input(&u[..]);
+        u.copy_from_slice(mac.result().code().as_slice());
+        okm.iter_mut().zip(u.iter()).for_each(|(o, u)| *o ^= u);
+    }
+    okm
+}
+
+fn encrypt(secret: &[u8; KEY_SIZE], passphrase: &str, iterations: u32) -> Vec<u8> {
+    use chacha20_poly1305_aead::encrypt;
+
+    let salt: [u8; SALT_SIZE] = rand::random();
+    let key = encryption_key(&salt[..], passphrase, iterations);
+
+    let mut data = Vec::with_capacity(FILE_SIZE);
+    data.push(VERSION);
+    data.extend_from_slice(&iterations.to_be_bytes());
+    data.extend_from_slice(&salt[..]);
+    // the header is authenticated, so the cost cannot be lowered by tampering the file
+    let header = data.clone();
+    let tag = encrypt(&key[..], &[0; 12], header.as_slice(), &secret[..], &mut data).unwrap();
+    data.extend_from_slice(&tag[..]);
+    data
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<[u8; KEY_SIZE], Error> {
+    use chacha20_poly1305_aead::decrypt;
+
+    if data.len() != FILE_SIZE {
+        return Err(Error::bad_format("wrong size of the identity file"));
+    }
+    if data[0] != VERSION {
+        return Err(Error::bad_format("unknown version of the identity file"));
+    }
+
+    let mut iterations = [0; ITERATIONS_SIZE];
+    iterations.copy_from_slice(&data[1..HEADER_SIZE]);
+    let iterations = u32::from_be_bytes(iterations);
+    if iterations < MIN_ITERATIONS || iterations > MAX_ITERATIONS {
+        return Err(Error::bad_format("wrong key derivation cost of the identity file"));
+    }
+
+    let header = &data[..(HEADER_SIZE + SALT_SIZE)];
+    let salt = &data[HEADER_SIZE..(HEADER_SIZE + SALT_SIZE)];
+    let cipher_text = &data[(HEADER_SIZE + SALT_SIZE)..(HEADER_SIZE + SALT_SIZE + KEY_SIZE)];
+    let tag = &data[(HEADER_SIZE + SALT_SIZE + KEY_SIZE)..];
+    let key = encryption_key(salt, passphrase, iterations);
+
+    let mut plain_text = Vec::with_capacity(KEY_SIZE);
+    decrypt(&key[..], &[0; 12], header, cipher_text, tag, &mut plain_text)
+        .map_err(|_| Error::Decrypt)?;
+
+    let mut secret = [0; KEY_SIZE];
+    secret.copy_from_slice(plain_text.as_slice());
+    Ok(secret)
+}
+
+fn store<P>(db_path: &P, secret: &[u8; KEY_SIZE], passphrase: &str) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    use std::fs::OpenOptions;
+    #[cfg(unix)]
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::create_dir_all(db_path.as_ref())
+        .map_err(|e| Error::io(e, "cannot create db directory"))?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // only the owner may read the identity file, it is created with these permissions
+    // so the key is never readable by others even for a moment
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(db_path.as_ref().join(IDENTITY_FILE_NAME))
+        .map_err(|e| Error::io(e, "cannot create identity file"))?;
+    file.write_all(encrypt(secret, passphrase, ITERATIONS).as_slice())
+        .and_then(|()| file.sync_all())
+        .map_err(|e| Error::io(e, "cannot write identity file"))
+}
+
+fn load<P>(db_path: &P, passphrase: &str) -> Result<Option<[u8; KEY_SIZE]>, Error>
+where
+    P: AsRef<Path>,
+{
+    match fs::read(db_path.as_ref().join(IDENTITY_FILE_NAME)) {
+        Ok(data) => decrypt(data.as_slice(), passphrase).map(Some),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::io(e, "cannot read identity file")),
+    }
+}
+
+/// Reads the passphrase of the identity key from the file, or from the `LPD_IDENTITY_PASSPHRASE`
+/// environment variable, so it never appears in the process arguments.
+/// The empty passphrase is only allowed explicitly
+pub fn read_passphrase<P>(passphrase_file: Option<&P>, no_passphrase: bool) -> Result<String, Error>
+where
+    P: AsRef<Path>,
+{
+    let passphrase = match passphrase_file {
+        Some(path) => fs::read_to_string(path.as_ref())
+            .map(|passphrase| passphrase.trim_end_matches(|c| c == '\n' || c == '\r').to_owned())
+            .map_err(|e| Error::io(e, "cannot read identity passphrase file"))?,
+        None => env::var(PASSPHRASE_VAR).unwrap_or_default(),
+    };
+
+    match (passphrase.is_empty(), no_passphrase) {
+        (false, false) => Ok(passphrase),
+        (true, true) => Ok(passphrase),
+        (false, true) => Err(Error::NoPassphrase {
+            description: "the passphrase is given, but the empty passphrase is requested".to_owned(),
+        }),
+        (true, false) => Err(Error::NoPassphrase {
+            description: format!(
+                "the identity key needs a passphrase, use --identity-passphrase-file or {}, \
+                 or --no-identity-passphrase to store the key unprotected",
+                PASSPHRASE_VAR,
+            ),
+        }),
+    }
+}
+
+/// Loads the node's identity secret key, generates and stores the new one at the first start
+pub fn load_or_create_identity<P>(db_path: &P, passphrase: &str) -> Result<[u8; KEY_SIZE], Error>
+where
+    P: AsRef<Path>,
+{
+    match load(db_path, passphrase)? {
+        Some(secret) => Ok(secret),
+        None => {
+            // the random array is a valid secret key with overwhelming probability, but check it
+            let secret = loop {
+                let secret: [u8; KEY_SIZE] = rand::random();
+                if SecretKey::from_slice(&secret[..]).is_ok() {
+                    break secret;
+                }
+            };
+            store(db_path, &secret, passphrase)?;
+            println!("INFO: new identity key is generated");
+            Ok(secret)
+        },
+    }
+}
+
+/// Stores the hex encoded secret key as the node's identity,
+/// refuses to overwrite the different existing identity
+pub fn import_identity<P>(db_path: &P, passphrase: &str, secret_hex: &str) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let bytes = hex::decode(secret_hex.trim())
+        .map_err(|_| Error::bad_format("the identity key should be hex encoded"))?;
+    SecretKey::from_slice(bytes.as_slice())
+        .map_err(|_| Error::bad_format("the identity key is not a valid secret key"))?;
+    let mut secret = [0; KEY_SIZE];
+    secret.copy_from_slice(bytes.as_slice());
+
+    match load(db_path, passphrase) {
+        Ok(Some(ref existing)) if existing.eq(&secret) => Ok(()),
+        Ok(None) => store(db_path, &secret, passphrase),
+        Ok(Some(_)) | Err(_) => Err(Error::AlreadyExists {
+            description: format!(
+                "the node already has an identity, remove {:?} first",
+                db_path.as_ref().join(IDENTITY_FILE_NAME),
+            ),
+        }),
+    }
+}
+
+/// Returns the hex encoded identity secret key
+pub fn export_identity<P>(db_path: &P, passphrase: &str) -> Result<String, Error>
+where
+    P: AsRef<Path>,
+{
+    match load(db_path, passphrase)? {
+        Some(secret) => Ok(hex::encode(&secret[..])),
+        None => Err(Error::io(
+            io::Error::from(io::ErrorKind::NotFound),
+            "the node has no identity yet",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encrypt, decrypt, Error, KEY_SIZE, MIN_ITERATIONS, FILE_SIZE};
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let secret = [0x11; KEY_SIZE];
+        let data = encrypt(&secret, "passphrase", MIN_ITERATIONS);
+        assert_eq!(data.len(), FILE_SIZE);
+        assert_eq!(decrypt(data.as_slice(), "passphrase").unwrap(), secret);
+
+        // the salt is random, the same secret is encrypted differently
+        assert_ne!(encrypt(&secret, "passphrase", MIN_ITERATIONS), data);
+
+        match decrypt(data.as_slice(), "wrong passphrase") {
+            Err(Error::Decrypt) => (),
+            r => panic!("expected decryption error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn tampered_cost_is_rejected() {
+        let secret = [0x22; KEY_SIZE];
+        let mut data = encrypt(&secret, "passphrase", MIN_ITERATIONS + 1);
+
+        // lower the cost, the header is authenticated
+        data[1..5].copy_from_slice(&MIN_ITERATIONS.to_be_bytes());
+        match decrypt(data.as_slice(), "passphrase") {
+            Err(Error::Decrypt) => (),
+            r => panic!("expected decryption error, got {:?}", r),
+        }
+
+        data[1..5].copy_from_slice(&1u32.to_be_bytes());
+        match decrypt(data.as_slice(), "passphrase") {
+            Err(Error::BadFormat { .. }) => (),
+            r => panic!("expected format error, got {:?}", r),
+        }
+    }
+}
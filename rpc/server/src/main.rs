@@ -3,6 +3,9 @@ use self::config::{Config, Error as CommandLineReadError, create_tls_acceptor};
 
 mod wallet;
 
+mod identity;
+use self::identity::Error as IdentityError;
+
 use dependencies::httpbis;
 use dependencies::futures;
 use dependencies::ctrlc;
@@ -27,6 +30,8 @@ enum Error {
     WalletError(WalletError, String),
     SendError(ctrlc::Error),
     TransportError(connection::TransportError),
+    Identity(IdentityError),
+    Watchtower(String),
     FileNotSpecified {
         description: String,
     }
@@ -37,12 +42,14 @@ fn print_version() {
 }
 
 fn main() -> Result<(), Error> {
-    use std::{sync::{Mutex, RwLock, Arc}, path::PathBuf};
+    use std::{sync::{Mutex, RwLock, Arc}, path::PathBuf, time::Duration};
     use grpc::ServerBuilder;
     use implementation::{Node, Command, routing_service, channel_service, payment_service, wallet_service};
+    use connection::{BanPolicy, ChannelPolicy, DumpConfig, DumpFilter, FeeEstimator, StaticFeeEstimator, ChainFeeEstimator, SatoshiPerVByte};
     use futures::{sync::mpsc, Future, Sink};
     use self::Error::*;
     use self::wallet::create_wallet;
+    use self::identity::{read_passphrase, load_or_create_identity, import_identity, export_identity};
 
     let config: Config = Config::from_args();
 
@@ -56,16 +63,34 @@ fn main() -> Result<(), Error> {
         return Ok(());
     }
 
+    let passphrase = read_passphrase(config.identity_passphrase_file.as_ref(), config.no_identity_passphrase)
+        .map_err(Identity)?;
+
+    if let Some(ref secret_hex) = config.import_identity {
+        import_identity(&config.db_path, &passphrase, secret_hex).map_err(Identity)?;
+        println!("identity key is imported");
+        return Ok(());
+    }
+
+    if config.export_identity {
+        println!("{}", export_identity(&config.db_path, &passphrase).map_err(Identity)?);
+        return Ok(());
+    }
+
+    if passphrase.is_empty() {
+        println!("WARNING: the identity key is stored without passphrase");
+    }
+
     let wallet = {
         let mut wallet_db_path = PathBuf::from(config.db_path.clone());
         wallet_db_path.push("wallet");
-        let wallet = create_wallet(&wallet_db_path.as_path()).map_err(|err| {
+        let wallet = create_wallet(&wallet_db_path.as_path(), config.network).map_err(|err| {
             WalletError(err, "cannot create bitcoin onchain wallet".to_owned())
         })?;
         Arc::new(Mutex::new(wallet))
     };
 
-    let (node, tx, rx) = {
+    let (node, bus, channel_acceptor, tx, rx) = {
         let (tx, rx) = mpsc::channel(1);
 
         let tx_wait = tx.clone();
@@ -75,13 +100,8 @@ fn main() -> Result<(), Error> {
             println!("the command is propagated, terminating...");
         }).map_err(SendError)?;
 
-        // TODO: generate and store it somehow
-        let secret = [
-            0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12,
-            0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12,
-            0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12,
-            0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12, 0x12,
-        ];
+        let secret = load_or_create_identity(&config.db_path, &passphrase)
+            .map_err(Identity)?;
 
         let ctx = secp256k1::Secp256k1::new();
         let priv_key = secp256k1::SecretKey::from_slice(&secret).unwrap();
@@ -96,7 +116,60 @@ fn main() -> Result<(), Error> {
         let mut node_db_path = PathBuf::from(config.db_path.clone());
         node_db_path.push("node");
 
-        (Arc::new(RwLock::new(Node::new(wallet.clone(), secret, node_db_path))), tx, rx)
+        let ban_policy = BanPolicy {
+            disconnect_threshold: config.disconnect_score,
+            ban_threshold: config.ban_score,
+            ban_duration: config.ban_duration,
+            score_decay: config.score_decay,
+            ban_address: config.ban_address,
+        };
+
+        let mut channel_policy = ChannelPolicy::default();
+        channel_policy.min_funding = config.min_channel_size;
+        channel_policy.max_funding = config.max_channel_size;
+        channel_policy.max_csv_delay = config.max_csv_delay;
+        channel_policy.csv_delay = config.csv_delay;
+        channel_policy.minimum_depth = config.minimum_depth;
+        channel_policy.min_channel_reserve = config.min_channel_reserve;
+        channel_policy.max_channel_reserve_percent = config.max_channel_reserve_percent;
+        channel_policy.channel_reserve_percent = config.channel_reserve_percent;
+        channel_policy.min_dust_limit = config.min_dust_limit;
+        channel_policy.max_dust_limit = config.max_dust_limit;
+        channel_policy.dust_limit = config.dust_limit;
+        channel_policy.max_accepted_htlc_number = config.max_accepted_htlcs;
+
+        let fee_rate = SatoshiPerVByte::from(config.fee_rate);
+        let fee_estimator: Box<dyn FeeEstimator + Send> = if config.static_fee_rate {
+            Box::new(StaticFeeEstimator::new(fee_rate))
+        } else {
+            Box::new(ChainFeeEstimator::new(fee_rate))
+        };
+
+        let mut node = Node::new(wallet.clone(), config.network, secret, node_db_path, ban_policy, channel_policy, fee_estimator);
+
+        let mut dump_config = DumpConfig::default();
+        dump_config.path = config.dump_path.clone().unwrap_or(config.db_path.join("dump.json"));
+        dump_config.max_file_size = config.dump_max_size;
+        dump_config.max_files = config.dump_max_files;
+        node.recorder().configure(dump_config);
+        if config.dump_path.is_some() {
+            node.recorder().enable(None, DumpFilter::default());
+        }
+
+        if let Some(address) = config.watchtower_address {
+            node.serve_watchtower(address);
+        }
+        for tower in &config.watchtowers {
+            node.watchtowers().add_tower(tower)
+                .map_err(|e| Watchtower(format!("cannot add the watchtower {}: {:?}", tower, e)))?;
+        }
+
+        let channel_acceptor = node.acceptor();
+        channel_acceptor.set_timeout(Duration::from_secs(config.channel_acceptor_timeout));
+
+        let bus = node.bus();
+
+        (Arc::new(RwLock::new(node)), bus, channel_acceptor, tx, rx)
     };
 
     let server = {
@@ -121,7 +194,7 @@ fn main() -> Result<(), Error> {
         server.http.set_cpu_pool_threads(4);
         server.add_service(wallet_service(wallet.clone(), tx.clone()));
         server.add_service(routing_service(node.clone(), tx.clone()));
-        server.add_service(channel_service(node.clone(), tx.clone()));
+        server.add_service(channel_service(tx.clone(), bus.clone(), channel_acceptor));
         server.add_service(payment_service());
         server.build().map_err(Grpc)?
     };
@@ -2,8 +2,9 @@ use std::path::Path;
 
 use dependencies::bitcoin;
 use implementation::wallet_lib::{interface::Wallet, error::WalletError};
+use bitcoin::network::constants::Network;
 
-pub fn create_wallet<P>(db_path: &P) -> Result<Box<dyn Wallet + Send>, WalletError>
+pub fn create_wallet<P>(db_path: &P, network: Network) -> Result<Box<dyn Wallet + Send>, WalletError>
 where
     P: AsRef<Path>,
 {
@@ -11,7 +12,6 @@ where
         walletlibrary::{DEFAULT_SALT, WalletConfig, WalletLibraryMode, KeyGenConfig},
         electrumx::ElectrumxWallet,
     };
-    use bitcoin::network::constants::Network;
     //use std::io::stdin;
 
     //println!("enter password for wallet");
@@ -20,7 +20,7 @@ where
     let passphrase = "qwerty".to_owned();
 
     let config = |passphrase: String| WalletConfig::new(
-        Network::Regtest,
+        network,
         passphrase,
         DEFAULT_SALT.to_owned(),
         db_path.as_ref().to_str().unwrap().to_owned(),
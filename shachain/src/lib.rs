@@ -3,3 +3,5 @@ pub mod producer_tree;
 pub mod store_tree;
 mod util;
 mod error;
+
+pub use self::util::LeafIndex;
@@ -8,7 +8,7 @@ use state::DBValue;
 
 use super::util::{LeafIndex, get_nth_bit};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ProducerTree {
     seed: Sha256,
 }
@@ -60,11 +60,21 @@ impl Leaf {
     }
 }
 
+#[derive(Clone)]
 pub struct StoreTree {
     known: [Leaf; MAX_HEIGHT],
     next_index: LeafIndex,
 }
 
+// the array is too long to derive
+impl std::fmt::Debug for StoreTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StoreTree")
+            .field("next_index", &self.next_index)
+            .finish()
+    }
+}
+
 impl StoreTree {
     pub fn new() -> Self {
         Self {
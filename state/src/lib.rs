@@ -129,6 +129,18 @@ impl DB {
         self.put_cf(cf, key_bytes.as_ref(), value_bytes.as_ref())
     }
 
+    pub fn delete<K, V>(&self, key: &K) -> Result<(), DBError>
+    where
+        V: DBValue,
+        K: DBKey,
+    {
+        use binformat::BinarySD;
+
+        let cf = self.cf_handle(V::cf_name()).expect("call `register` first");
+        let mut key_bytes = Vec::new();
+        BinarySD::serialize(&mut key_bytes, key).unwrap();
+        self.delete_cf(cf, key_bytes.as_ref())
+    }
 }
 
 // The basis of the extension chain
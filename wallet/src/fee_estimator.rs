@@ -4,7 +4,6 @@ pub trait FeeEstimator {
     fn estimate(&mut self, num_blocks: u32) -> SatoshiPerVByte;
 }
 
-#[allow(dead_code)]
 pub struct StaticFeeEstimator {
     rate: SatoshiPerVByte,
 }
@@ -8,6 +8,8 @@ mod scoped_manager;
 mod account_manager;
 mod fee_estimator;
 
+pub use self::fee_estimator::{FeeEstimator, StaticFeeEstimator};
+
 use bitcoin::util::bip32::ChildNumber;
 use bitcoin_hashes::{hash160, sha256d};
 use bitcoin_hashes::Hash;
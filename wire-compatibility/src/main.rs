@@ -24,11 +24,7 @@ fn de(data: Vec<u8>) -> Result<Vec<MessageExt>, WireError> {
             extra_data.extend_from_slice(&cursor.get_ref()[(cursor.position() as usize)..((position + length) as usize)]);
             cursor.seek(SeekFrom::Current(extra_length as i64)).unwrap();
         }
-        let message_ext = MessageExt {
-            message: msg,
-            extra_data: extra_data,
-        };
-        messages.push(message_ext);
+        messages.push(MessageExt::new(msg, extra_data));
     }
 
     Ok(messages)
@@ -42,8 +38,9 @@ fn ser(messages: &Vec<MessageExt>) -> Result<Vec<u8>, WireError> {
             let length_position = x.len();
             BinarySD::serialize(&mut x, &0u16)?;
             let position = x.len();
-            BinarySD::serialize(&mut x, &message_ext.message)?;
-            x.extend_from_slice(message_ext.extra_data.as_ref());
+            let (message, extra_data) = message_ext.clone().into_raw_parts();
+            BinarySD::serialize(&mut x, &message)?;
+            x.extend_from_slice(extra_data.as_ref());
             let length = (x.len() - position) as u16;
             let mut y = Vec::with_capacity(2);
             BinarySD::serialize(&mut y, &length)?;
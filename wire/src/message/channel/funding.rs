@@ -109,6 +109,12 @@ impl FundingTxid {
     pub fn to_sha256d(&self) -> sha256d::Hash {
         sha256d::Hash::from_slice(&self.data[..]).unwrap()
     }
+
+    pub fn from_sha256d(hash: &sha256d::Hash) -> Self {
+        FundingTxid {
+            data: hash.into_inner(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -77,6 +77,11 @@ impl ChannelPrivateKeys {
         &self.payment
     }
 
+    // BOLT 3 `option_static_remotekey`, the payment basepoint is the key of the wallet
+    pub fn set_payment_sk(&mut self, payment: SecretKey) {
+        self.payment = payment;
+    }
+
     pub fn delayed_payment_sk(&self) -> &SecretKey {
         &self.delayed_payment
     }
@@ -90,6 +95,51 @@ impl ChannelPrivateKeys {
     }
 }
 
+// the private keys are stored along with the channel
+mod serde_m {
+    use dependencies::secp256k1;
+
+    use secp256k1::SecretKey;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use super::ChannelPrivateKeys;
+
+    fn to_bytes(key: &SecretKey) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(&key[..]);
+        bytes
+    }
+
+    impl Serialize for ChannelPrivateKeys {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+            (
+                to_bytes(&self.funding),
+                to_bytes(&self.revocation),
+                to_bytes(&self.payment),
+                to_bytes(&self.delayed_payment),
+                to_bytes(&self.htlc),
+                to_bytes(&self.first_per_commitment),
+            ).serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ChannelPrivateKeys {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+            use serde::de::Error;
+
+            let keys: ([u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32]) = Deserialize::deserialize(deserializer)?;
+            let key = |bytes: [u8; 32]| SecretKey::from_slice(&bytes[..]).map_err(D::Error::custom);
+            Ok(ChannelPrivateKeys {
+                funding: key(keys.0)?,
+                revocation: key(keys.1)?,
+                payment: key(keys.2)?,
+                delayed_payment: key(keys.3)?,
+                htlc: key(keys.4)?,
+                first_per_commitment: key(keys.5)?,
+            })
+        }
+    }
+}
+
 mod rand_m {
     use dependencies::secp256k1;
     use dependencies::rand;
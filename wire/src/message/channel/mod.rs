@@ -52,7 +52,7 @@ impl ChannelFlags {
 /// The unique identifier of the channel. It's derived from the funding transaction
 /// by combining the funding_txid and the funding_output_index, using big-endian exclusive-OR
 /// (i.e. funding_output_index alters the last 2 bytes).
-#[derive(Default, Serialize, Deserialize, Eq, PartialEq, Copy, Clone)]
+#[derive(Default, Serialize, Deserialize, Eq, PartialEq, Hash, Copy, Clone)]
 pub struct ChannelId {
     pub data: [u8; 32],
 }
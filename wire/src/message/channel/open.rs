@@ -58,11 +58,12 @@ pub struct OpenChannel {
     pub max_accepted_htlc_number: u16,
     pub keys: ChannelKeys,
     pub flags: ChannelFlags,
-}
-
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
-pub struct OpenChannelShutdownScript {
-    shutdown_script_pubkey: Vec<()>,
+    /// BOLT 2 `option_upfront_shutdown_script`, the channel may be closed only to this script,
+    /// the empty script means the sender does not commit to any. It follows the fixed fields
+    /// which the codec cannot tell from the end of the message, so it travels
+    /// in the extra data of the message, see `MessageExt::new`
+    #[serde(skip)]
+    pub shutdown_script: Option<Vec<u8>>,
 }
 
 /// This message contains information about a node and indicates its acceptance
@@ -79,6 +80,9 @@ pub struct AcceptChannel {
     pub csv_delay: CsvDelay,
     pub max_accepted_htlc_number: u16,
     pub keys: ChannelKeys,
+    /// See `OpenChannel::shutdown_script`
+    #[serde(skip)]
+    pub shutdown_script: Option<Vec<u8>>,
 }
 
 impl AcceptChannel {
@@ -93,10 +97,37 @@ impl AcceptChannel {
             csv_delay: open_channel.csv_delay.clone(),
             max_accepted_htlc_number: open_channel.max_accepted_htlc_number.clone(),
             keys: keys.clone(),
+            shutdown_script: None,
         }
     }
 }
 
+// the length prefixed script, returns the script and the rest of the data,
+// the data without the script is left as is
+pub(crate) fn read_shutdown_script(data: Vec<u8>) -> (Option<Vec<u8>>, Vec<u8>) {
+    if data.len() < 2 {
+        return (None, data);
+    }
+    let length = u16::from_be_bytes([data[0], data[1]]) as usize;
+    if data.len() < 2 + length {
+        return (None, data);
+    }
+    (Some(data[2..(2 + length)].to_vec()), data[(2 + length)..].to_vec())
+}
+
+pub(crate) fn write_shutdown_script(script: Option<Vec<u8>>, data: Vec<u8>) -> Vec<u8> {
+    match script {
+        Some(script) => {
+            let mut prefixed = Vec::with_capacity(2 + script.len() + data.len());
+            prefixed.extend_from_slice(&(script.len() as u16).to_be_bytes());
+            prefixed.extend(script);
+            prefixed.extend(data);
+            prefixed
+        },
+        None => data,
+    }
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
 pub struct ReestablishChannel {
     pub channel_id: ChannelId,
@@ -151,6 +182,7 @@ mod test {
             max_accepted_htlc_number: Default::default(),
             keys: ChannelKeys::new(&private),
             flags: ChannelFlags::FF_ANNOUNCE_CHANNEL,
+            shutdown_script: None,
         };
 
         // try to estimate size without aligning
@@ -202,6 +234,7 @@ mod test {
                 first_per_commitment: RawPublicKey::from_hex("028e95ee83d07fa9f2927a8a65152917bb5d41253a7b0b56664b083c596d35178a").unwrap(),
             },
             flags: ChannelFlags::from_u8(1),
+            shutdown_script: None,
         };
         let wrapped_msg_correct = Message::OpenChannel(msg_correct);
 
@@ -243,7 +276,8 @@ mod test {
                 delayed_payment: RawPublicKey::from_hex("0297557fc325a8de27eca45e7f77db44f22b85d16d2ec5853adf7b21464e3c3632").unwrap(),
                 htlc: RawPublicKey::from_hex("02c5871b00d8d1bdedb91db3fb487959291da00ce179ef5a9172042e1a563773c7").unwrap(),
                 first_per_commitment: RawPublicKey::from_hex("035281eef9aa59ce083ae6d614774bee20d586d2901262adfed1f8214dc5840e37").unwrap(),
-            }
+            },
+            shutdown_script: None,
         };
         let wrapped_msg_correct = Message::AcceptChannel(msg_correct);
 
@@ -19,6 +19,9 @@ pub use self::announce_signatures::*;
 mod gossip_timestamp_range;
 pub use self::gossip_timestamp_range::*;
 
+mod watchtower;
+pub use self::watchtower::*;
+
 use serde::Serialize;
 use serde::Serializer;
 use serde::Deserialize;
@@ -93,6 +96,31 @@ macro_rules! message {
                 }
             }
 
+            /// The name of the message type, same as the variant name
+            pub fn type_name(&self) -> &'static str {
+                use self::$name::*;
+                match self {
+                    $(
+                        &$variant(_) => stringify!($variant),
+                    )*
+                }
+            }
+
+            /// The runtime type of the message, odd types are optional for the receiver
+            pub fn type_id(&self) -> u16 {
+                use self::$name::*;
+                match self {
+                    $(
+                        &$variant(_) => $rtt,
+                    )*
+                }
+            }
+
+            /// Whether the runtime type is one of the variants
+            pub fn is_known_type(runtime_type: u16) -> bool {
+                [$($rtt),*].contains(&runtime_type)
+            }
+
             $(
                 pub fn $unwrap_method(self) -> Option<$variant> {
                     use self::$name::*;
@@ -117,6 +145,44 @@ macro_rules! message {
 
             }
         }
+
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+                use self::ser::SerializeStruct;
+
+                // The names provided only for documentation, serializer drops it
+                let mut s_struct = serializer.serialize_struct(stringify!($name), 2)?;
+                self.write_into(&mut s_struct)?;
+                s_struct.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+                use std::fmt;
+
+                struct Visitor;
+
+                impl<'de> de::Visitor<'de> for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                        formatter.write_str(
+                            "pair: 16-bit runtime type information, \
+                            the binary representation of the message"
+                        )
+                    }
+
+                    fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error> where
+                        A: de::SeqAccess<'de>,
+                    {
+                        $name::read_from(seq)
+                    }
+                }
+
+                deserializer.deserialize_tuple(2, Visitor)
+            }
+        }
     }
 }
 
@@ -156,45 +222,48 @@ message! {
     }
 }
 
-impl Serialize for Message {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        use self::ser::SerializeStruct;
-
-        // The names provided only for documentation, serializer drops it
-        let mut s_struct = serializer.serialize_struct("Message", 2)?;
-        self.write_into(&mut s_struct)?;
-        s_struct.end()
+/// The messages of the watchtower protocol, the client and the tower
+/// do not speak the peer protocol on the same connection
+message! {
+    pub enum TowerMessage {
+        TowerInit(600u16, as_tower_init),
+        TowerError(601u16, as_tower_error),
+        CreateSession(602u16, as_create_session),
+        CreateSessionReply(603u16, as_create_session_reply),
+        StateUpdate(604u16, as_state_update),
+        StateUpdateReply(605u16, as_state_update_reply)
     }
 }
 
-impl<'de> Deserialize<'de> for Message {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        use std::fmt;
-
-        struct Visitor;
-
-        impl<'de> de::Visitor<'de> for Visitor {
-            type Value = Message;
-
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str(
-                    "pair: 16-bit runtime type information, \
-                    the binary representation of the message"
-                )
-            }
-
-            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error> where
-                A: de::SeqAccess<'de>,
-            {
-                Message::read_from(seq)
-            }
+impl Message {
+    /// The channel the message is related to, the temporary id for the channel being opened
+    pub fn channel_id(&self) -> Option<ChannelId> {
+        use self::Message::*;
+
+        match self {
+            &Error(ref m) => Some(m.channel_id),
+            &OpenChannel(ref m) => Some(m.temporary_channel_id),
+            &AcceptChannel(ref m) => Some(m.temporary_channel_id),
+            &FundingCreated(ref m) => Some(m.temporary_channel_id),
+            &FundingSigned(ref m) => Some(m.channel_id),
+            &FundingLocked(ref m) => Some(m.channel_id),
+            &ShutdownChannel(ref m) => Some(m.channel_id),
+            &ClosingSigned(ref m) => Some(m.channel_id),
+            &UpdateAddHtlc(ref m) => Some(m.channel_id),
+            &UpdateFulfillHtlc(ref m) => Some(m.channel_id),
+            &UpdateFailHtlc(ref m) => Some(m.channel_id),
+            &UpdateFailMalformedHtlc(ref m) => Some(m.channel_id),
+            &CommitmentSigned(ref m) => Some(m.channel_id),
+            &RevokeAndAck(ref m) => Some(m.channel_id),
+            &UpdateFee(ref m) => Some(m.channel_id),
+            &ReestablishChannel(ref m) => Some(m.channel_id),
+            &AnnounceSignatures(ref m) => Some(m.channel_id),
+            _ => None,
         }
-
-        deserializer.deserialize_tuple(2, Visitor)
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct MessageExt {
     pub message: Message,
     pub extra_data: Vec<u8>,
@@ -209,6 +278,48 @@ impl From<Message> for MessageExt {
     }
 }
 
+impl MessageExt {
+    /// Makes the message as it is read from the wire, moves the optional fields
+    /// which follow the fixed ones from the extra data into the message
+    pub fn new(message: Message, extra_data: Vec<u8>) -> Self {
+        use self::Message::*;
+
+        let mut message = message;
+        let extra_data = match &mut message {
+            &mut OpenChannel(ref mut m) => {
+                let (script, extra_data) = read_shutdown_script(extra_data);
+                m.shutdown_script = script;
+                extra_data
+            },
+            &mut AcceptChannel(ref mut m) => {
+                let (script, extra_data) = read_shutdown_script(extra_data);
+                m.shutdown_script = script;
+                extra_data
+            },
+            _ => extra_data,
+        };
+
+        MessageExt {
+            message: message,
+            extra_data: extra_data,
+        }
+    }
+
+    /// The message and the extra data as they are written to the wire,
+    /// the inverse of `MessageExt::new`
+    pub fn into_raw_parts(self) -> (Message, Vec<u8>) {
+        use self::Message::*;
+
+        let mut message = self.message;
+        let extra_data = match &mut message {
+            &mut OpenChannel(ref mut m) => write_shutdown_script(m.shutdown_script.take(), self.extra_data),
+            &mut AcceptChannel(ref mut m) => write_shutdown_script(m.shutdown_script.take(), self.extra_data),
+            _ => self.extra_data,
+        };
+        (message, extra_data)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use binformat::BinarySD;
@@ -226,6 +337,15 @@ mod tests {
         assert!(init.is_some());
     }
 
+    #[test]
+    fn type_name() {
+        let data = vec![0, 16, 0, 0, 0, 1, 138];
+
+        let message: Message = BinarySD::deserialize(&data[..]).unwrap();
+        assert_eq!(message.type_name(), "Init");
+        assert_eq!(message.type_id(), 16);
+    }
+
     #[test]
     fn open_channel_message(){
         let msg_bytes = hex::decode(
@@ -243,7 +363,58 @@ mod tests {
         ).unwrap();
         let restored: Result<Message, _> = BinarySD::deserialize(msg_bytes.as_slice());
         let message = restored.unwrap();
+        assert_eq!(message.channel_id(), Some(ChannelId::from_hex(
+            "3283054b8d351cfd58a790cb502069a64c40e226a0d228eae7e83e316dd27917"
+        ).unwrap()));
         let open_channel = message.as_open_channel();
         assert!(open_channel.is_some());
     }
+
+    #[test]
+    fn upfront_shutdown_script() {
+        let data = vec![0, 16, 0, 0, 0, 1, 138];
+        let message: Message = BinarySD::deserialize(&data[..]).unwrap();
+        let message_ext = MessageExt::new(message.clone(), vec![0, 3, 1]);
+        // not the message with the shutdown script, the extra data is untouched
+        assert_eq!(message_ext.extra_data, vec![0, 3, 1]);
+
+        let mut open_channel = open_channel();
+        let (message, extra_data) = MessageExt::from(Message::OpenChannel(open_channel.clone())).into_raw_parts();
+        assert!(extra_data.is_empty());
+
+        // the script is followed by the unknown data
+        open_channel.shutdown_script = Some(vec![0x00, 0x14, 0xab]);
+        let message_ext = MessageExt {
+            message: Message::OpenChannel(open_channel.clone()),
+            extra_data: vec![0xff],
+        };
+        let (raw_message, extra_data) = message_ext.clone().into_raw_parts();
+        assert_eq!(raw_message, message);
+        assert_eq!(extra_data, vec![0, 3, 0x00, 0x14, 0xab, 0xff]);
+        assert_eq!(MessageExt::new(raw_message, extra_data), message_ext);
+
+        // the empty script means no commitment, but it is still sent
+        open_channel.shutdown_script = Some(Vec::new());
+        let (raw_message, extra_data) = MessageExt::from(Message::OpenChannel(open_channel.clone())).into_raw_parts();
+        assert_eq!(extra_data, vec![0, 0]);
+        assert_eq!(MessageExt::new(raw_message, extra_data).message, Message::OpenChannel(open_channel));
+    }
+
+    fn open_channel() -> OpenChannel {
+        let msg_bytes = hex::decode(
+            "002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188\
+            910f3283054b8d351cfd58a790cb502069a64c40e226a0d228eae7e83e316dd2\
+            791700000000000186a00000000000000000000000000000023d0000000005e6\
+            9ec000000000000003e800000000000003e8000030d4009001e302d254a0bc14\
+            d7c990d4c40e806bcaffc022ead28ba88eaa5450ef90565119020102859c2c7c\
+            7c0495198371dc0cb1329fdeca223972aeb089af2895c33b180cc6a20265ae92\
+            1bd8cd25b7c62eda488b0f87955b3df36ccdb72cb0c75336cc8d5dc7030363b7\
+            cea6090e2f78a67a29a7cc5b351695a0dc6c0f2bbf14dc9098ed6074a3230213\
+            f314dcc6dbdaea4fac352277f55d53f873901477d80b8d2da794b411e5102202\
+            e19840efe9d300361f2624dfb5516f254bc6381be106c85ba0d3c429a54166c2\
+            01"
+        ).unwrap();
+        let message: Message = BinarySD::deserialize(msg_bytes.as_slice()).unwrap();
+        message.as_open_channel().unwrap()
+    }
 }
@@ -5,8 +5,14 @@ pub enum FeatureBit {
     DataLossProtectRequired,
     DataLossProtectOptional,
     InitialRoutingSync,
+    UpfrontShutdownScriptRequired,
+    UpfrontShutdownScriptOptional,
     GossipQueriesRequired,
     GossipQueriesOptional,
+    StaticRemoteKeyRequired,
+    StaticRemoteKeyOptional,
+    AnchorsZeroFeeHtlcTxRequired,
+    AnchorsZeroFeeHtlcTxOptional,
     Custom(u16),
 }
 
@@ -17,8 +23,14 @@ impl From<u16> for FeatureBit {
             0 => DataLossProtectRequired,
             1 => DataLossProtectOptional,
             3 => InitialRoutingSync,
+            4 => UpfrontShutdownScriptRequired,
+            5 => UpfrontShutdownScriptOptional,
             6 => GossipQueriesRequired,
             7 => GossipQueriesOptional,
+            12 => StaticRemoteKeyRequired,
+            13 => StaticRemoteKeyOptional,
+            22 => AnchorsZeroFeeHtlcTxRequired,
+            23 => AnchorsZeroFeeHtlcTxOptional,
             c @ _ => Custom(c),
         }
     }
@@ -31,8 +43,14 @@ impl From<FeatureBit> for u16 {
             DataLossProtectRequired => 0,
             DataLossProtectOptional => 1,
             InitialRoutingSync => 3,
+            UpfrontShutdownScriptRequired => 4,
+            UpfrontShutdownScriptOptional => 5,
             GossipQueriesRequired => 6,
             GossipQueriesOptional => 7,
+            StaticRemoteKeyRequired => 12,
+            StaticRemoteKeyOptional => 13,
+            AnchorsZeroFeeHtlcTxRequired => 22,
+            AnchorsZeroFeeHtlcTxOptional => 23,
             Custom(c) => c,
         }
     }
@@ -108,6 +108,18 @@ mod wrappers {
         }
     }
 
+    impl From<SatoshiPerVByte> for u64 {
+        fn from(s: SatoshiPerVByte) -> Self {
+            return s.raw;
+        }
+    }
+
+    impl From<u64> for SatoshiPerVByte {
+        fn from(s: u64) -> SatoshiPerVByte {
+            SatoshiPerVByte{ raw: s }
+        }
+    }
+
     // TODO: write custom derive for `Wrapper` and `BiWrapper`
     impl Wrapper for Satoshi {
         type Wrapped = u64;
@@ -160,6 +172,9 @@ mod scaling {
 
     const MILE: u64 = 1000;
 
+    // the virtual size is the weight divided by 4
+    const VBYTES_PER_KILOWEIGHT: u64 = 250;
+
     impl From<Satoshi> for MilliSatoshi {
         fn from(v: Satoshi) -> Self {
             MilliSatoshi {
@@ -175,4 +190,12 @@ mod scaling {
             }
         }
     }
+
+    impl From<SatoshiPerVByte> for SatoshiPerKiloWeight {
+        fn from(v: SatoshiPerVByte) -> Self {
+            SatoshiPerKiloWeight {
+                raw: (v.raw * VBYTES_PER_KILOWEIGHT) as u32,
+            }
+        }
+    }
 }
@@ -0,0 +1,103 @@
+use super::types::{Sha256, RawFeatureVector};
+
+use serde_derive::{Serialize, Deserialize};
+
+/// The first half of the breach txid, the tower looks for it in the blocks
+pub type BreachHint = [u8; 16];
+
+/// The `init` of the watchtower protocol, the tower serves only the given chain
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct TowerInit {
+    pub features: RawFeatureVector,
+    pub chain_hash: Sha256,
+}
+
+/// The tower cannot serve the session
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct TowerError {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The client asks for the session, the session is known by the brontide key of the client
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct CreateSession {
+    pub blob_type: u16,
+    pub max_updates: u16,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct CreateSessionReply {
+    pub code: u16,
+    // the session exists, the client continues after this update
+    pub last_applied: u16,
+    pub data: Vec<u8>,
+}
+
+/// The encrypted justice transaction of the breach with the given hint,
+/// the updates of the session are numbered from 1
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct StateUpdate {
+    pub seq_num: u16,
+    pub last_applied: u16,
+    // the client has no more updates for now
+    pub is_complete: u8,
+    pub hint: BreachHint,
+    pub encrypted_blob: Vec<u8>,
+}
+
+/// The tower acknowledges the updates up to `last_applied`
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct StateUpdateReply {
+    pub code: u16,
+    pub last_applied: u16,
+}
+
+/// The codes of the replies and of the tower's errors
+pub struct TowerCode;
+
+impl TowerCode {
+    pub const OK: u16 = 0;
+    pub const TEMPORARY_FAILURE: u16 = 40;
+    pub const PERMANENT_FAILURE: u16 = 50;
+    pub const SESSION_EXISTS: u16 = 60;
+    pub const UNSUPPORTED_BLOB_TYPE: u16 = 62;
+    pub const REJECT_MAX_UPDATES: u16 = 63;
+    pub const CLIENT_BEHIND: u16 = 70;
+    pub const MAX_UPDATES_EXCEEDED: u16 = 71;
+    pub const SEQ_NUM_OUT_OF_ORDER: u16 = 72;
+}
+
+#[cfg(test)]
+mod test {
+    use dependencies::hex;
+    use dependencies::pretty_assertions;
+
+    use binformat::BinarySD;
+    use std::io::Cursor;
+    use crate::{TowerMessage, StateUpdate};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn state_update_test() {
+        let msg_hex = "025c000300020100112233445566778899aabbccddeeff0003deadbe";
+        let msg_bytes = hex::decode(msg_hex).unwrap();
+
+        let msg_correct = StateUpdate {
+            seq_num: 3,
+            last_applied: 2,
+            is_complete: 1,
+            hint: [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+            encrypted_blob: vec![0xde, 0xad, 0xbe],
+        };
+        let wrapped_msg_correct = TowerMessage::StateUpdate(msg_correct);
+
+        let mut cursor = Cursor::new(msg_bytes.clone());
+        let msg = BinarySD::deserialize::<TowerMessage, _>(&mut cursor).unwrap();
+        assert_eq!(&msg, &wrapped_msg_correct);
+
+        let mut new_msg_bytes = vec![];
+        BinarySD::serialize(&mut new_msg_bytes, &wrapped_msg_correct).unwrap();
+        assert_eq!(new_msg_bytes, msg_bytes);
+    }
+}
@@ -39,7 +39,8 @@ mod test{
                 delayed_payment: RawPublicKey::from_hex("03f5556856efebbd68f614c2beb4f1cd472f021abfecf062239783cb444bd2f081").unwrap(),
                 htlc: RawPublicKey::from_hex("034cd543ff7c56296132d1b28b7515fb6690f3637ad62da050f2979fc48c65cfeb").unwrap(),
                 first_per_commitment: RawPublicKey::from_hex("02a6b0c2fdd99e93a583ef6d7ae837a7fae6bea7a597196df5cad2bd8bd0776a91").unwrap(),
-            }
+            },
+            shutdown_script: None,
         };
         let wrapped_msg_correct = Message::AcceptChannel(msg_correct);
 
@@ -473,6 +474,7 @@ mod test{
                 first_per_commitment: RawPublicKey::from_hex("0280a006b33a23f2e6e53084cd4a3c4bd2206135b698a92fe4509535cd70763aad").unwrap(),
             },
             flags: ChannelFlags::from_u8(1),
+            shutdown_script: None,
         };
         let wrapped_msg_correct = Message::OpenChannel(msg_correct);
 